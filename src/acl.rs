@@ -0,0 +1,212 @@
+//! Access control of the users of memson
+//!
+//! Users can be granted read-only access, keys starting with a prefix, or both; users
+//! without a grant may evaluate any cmd. Users confined to a prefix can't name keys outside
+//! it, run cmds over every key, or see the events of subscriptions made by someone else.
+//! Only users allowed admin cmds may read the audit of rejected requests.
+
+use crate::auth::User;
+use crate::backend::{read_keys, written_keys};
+use crate::capability::{Capabilities, Family};
+use crate::cmd::Cmd;
+use crate::db::reads_every_key;
+use crate::err::Error;
+use crate::json_ops::Json;
+use std::collections::{BTreeSet, HashMap};
+
+/// What a user may do
+#[derive(Clone, Debug, PartialEq)]
+struct Grant {
+    /// the cmd families refused to the user
+    caps: Capabilities,
+    /// the prefix of the keys the user may read and write, if confined
+    prefix: Option<String>,
+}
+
+impl Grant {
+    fn parse(access: &str, prefix: Option<&str>) -> Option<Self> {
+        let mut disabled = match access {
+            "read" => vec!["write", "admin"],
+            "write" => vec![],
+            _ => return None,
+        };
+        let prefix = match prefix {
+            Some("") => return None,
+            Some(prefix) => {
+                // prepared queries are shared by name, and read keys unknown until executed
+                disabled.extend(["admin", "prepared"]);
+                Some(prefix.to_string())
+            }
+            None => None,
+        };
+        let caps = Capabilities::disabling(&disabled.join(",")).ok()?;
+        Some(Grant { caps, prefix })
+    }
+}
+
+/// The grants of the users of memson, and the subscriptions each user made
+#[derive(Clone, Debug, Default)]
+pub struct Acls {
+    grants: HashMap<String, Grant>,
+    /// the users owning each subscription
+    owners: HashMap<u64, String>,
+}
+
+impl Acls {
+    /// parses comma separated grants of `user:read` or `user:write`, optionally followed
+    /// by `:prefix` to confine the user to the keys starting with it
+    pub fn parse(s: &str) -> Result<Self, Error> {
+        let grants = s
+            .split(',')
+            .map(str::trim)
+            .filter(|x| !x.is_empty())
+            .map(|x| {
+                let mut parts = x.splitn(3, ':');
+                let (user, access) = (parts.next().unwrap_or(""), parts.next().unwrap_or(""));
+                match Grant::parse(access, parts.next()) {
+                    Some(grant) if !user.is_empty() => Ok((user.to_string(), grant)),
+                    _ => Err(Error::BadArg(x.into())),
+                }
+            })
+            .collect::<Result<_, _>>()?;
+        Ok(Acls {
+            grants,
+            owners: HashMap::new(),
+        })
+    }
+
+    /// checks the user may evaluate the cmd and its sub cmds
+    pub fn check(&self, user: Option<&User>, cmd: &Cmd) -> Result<(), Error> {
+        let (name, grant) = match user.and_then(|x| self.grants.get_key_value(&x.0)) {
+            Some(grant) => grant,
+            None => return Ok(()),
+        };
+        let forbidden = |what: String| Err(Error::Forbidden(format!("{} for {}", what, name)));
+        if let Err(Error::Disabled(family)) = grant.caps.check(cmd) {
+            return forbidden(format!("{} cmds", family));
+        }
+        let prefix = match &grant.prefix {
+            Some(prefix) => prefix,
+            None => return Ok(()),
+        };
+        if reads_every_key(cmd) {
+            return forbidden("every key".to_string());
+        }
+        let mut keys = BTreeSet::new();
+        read_keys(cmd, &mut keys);
+        written_keys(cmd, &mut keys);
+        subscribed_keys(cmd, &mut keys);
+        if let Some(key) = keys.iter().find(|x| !x.starts_with(prefix.as_str())) {
+            return forbidden(format!("key {}", key));
+        }
+        let mut ids = BTreeSet::new();
+        subscription_ids(cmd, &mut ids);
+        match ids.iter().find(|x| self.owners.get(x) != Some(name)) {
+            Some(id) => forbidden(format!("subscription {}", id)),
+            None => Ok(()),
+        }
+    }
+
+    /// checks the user may see what memson records of its clients, which needs a grant
+    /// allowing admin cmds
+    pub fn check_admin(&self, user: Option<&User>) -> Result<(), Error> {
+        match user.and_then(|x| self.grants.get_key_value(&x.0)) {
+            Some((name, grant)) if !grant.caps.enables(Family::Admin) => Err(Error::Forbidden(
+                format!("{} cmds for {}", Family::Admin.name(), name),
+            )),
+            _ => Ok(()),
+        }
+    }
+
+    /// keeps track of the subscription made or dropped by a cmd of the user, given its
+    /// result
+    pub fn track(&mut self, user: Option<&User>, cmd: &Cmd, res: &Json) {
+        match (cmd, user, res.as_u64()) {
+            (Cmd::Subscribe(_, _) | Cmd::SubscribeQuery(_), Some(user), Some(id)) => {
+                self.owners.insert(id, user.0.clone());
+            }
+            (Cmd::Unsubscribe(id), _, _) => {
+                self.owners.remove(id);
+            }
+            _ => (),
+        }
+    }
+}
+
+/// collects the keys subscribed to by a cmd
+fn subscribed_keys(cmd: &Cmd, out: &mut BTreeSet<String>) {
+    if let Cmd::Subscribe(keys, _) = cmd {
+        out.extend(keys.iter().cloned());
+    }
+    for child in cmd.children() {
+        subscribed_keys(child, out);
+    }
+}
+
+/// collects the ids of the subscriptions a cmd reads or drops
+fn subscription_ids(cmd: &Cmd, out: &mut BTreeSet<u64>) {
+    if let Cmd::Events(id) | Cmd::Ack(id, _) | Cmd::Unsubscribe(id) = cmd {
+        out.insert(*id);
+    }
+    for child in cmd.children() {
+        subscription_ids(child, out);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    fn parse(val: Json) -> Cmd {
+        Cmd::parse(val).unwrap()
+    }
+
+    #[test]
+    fn grants_confine_users() {
+        let mut acls = Acls::parse("ci:read, t1:write:t1_, t2:read:t2_").unwrap();
+        let user = |name: &str| User(name.to_string());
+        let (ci, t1, t2, ops) = (user("ci"), user("t1"), user("t2"), user("ops"));
+        let forbidden = |msg: &str| Err(Error::Forbidden(msg.to_string()));
+        let set = parse(json!({"set": ["t1_a", 1]}));
+        assert_eq!(forbidden("write cmds for ci"), acls.check(Some(&ci), &set));
+        assert_eq!(Ok(()), acls.check(Some(&t1), &set));
+        assert_eq!(forbidden("write cmds for t2"), acls.check(Some(&t2), &set));
+        assert_eq!(Ok(()), acls.check(Some(&ops), &set));
+        assert_eq!(Ok(()), acls.check(None, &set));
+        let read = parse(json!({"batch": [{"key": "t1_a"}, {"key": "t2_a"}]}));
+        assert_eq!(Ok(()), acls.check(Some(&ci), &read));
+        assert_eq!(forbidden("key t2_a for t1"), acls.check(Some(&t1), &read));
+        let qry = parse(json!({"query": {"from": "t2_rows"}}));
+        assert_eq!(forbidden("key t2_rows for t1"), acls.check(Some(&t1), &qry));
+        let keys = parse(json!({"keys": null}));
+        assert_eq!(forbidden("every key for t1"), acls.check(Some(&t1), &keys));
+        let history = parse(json!({"metricsHistory": {"minutes": 1}}));
+        assert_eq!(
+            forbidden("admin cmds for t1"),
+            acls.check(Some(&t1), &history)
+        );
+        let subscribe = parse(json!({"subscribe": ["t2_a"]}));
+        assert_eq!(
+            forbidden("key t2_a for t1"),
+            acls.check(Some(&t1), &subscribe)
+        );
+        assert_eq!(Ok(()), acls.check(Some(&t2), &subscribe));
+        acls.track(Some(&t2), &subscribe, &json!(7));
+        assert_eq!(Ok(()), acls.check(Some(&t2), &Cmd::Events(7)));
+        let events = forbidden("subscription 7 for t1");
+        assert_eq!(events, acls.check(Some(&t1), &Cmd::Events(7)));
+        acls.track(Some(&t2), &Cmd::Unsubscribe(7), &json!(true));
+        let events = forbidden("subscription 7 for t2");
+        assert_eq!(events, acls.check(Some(&t2), &Cmd::Events(7)));
+        assert_eq!(forbidden("admin cmds for ci"), acls.check_admin(Some(&ci)));
+        assert_eq!(forbidden("admin cmds for t1"), acls.check_admin(Some(&t1)));
+        assert_eq!(Ok(()), acls.check_admin(Some(&ops)));
+        assert_eq!(Ok(()), acls.check_admin(None));
+        let admin = Acls::parse("root:write").unwrap();
+        assert_eq!(Ok(()), admin.check_admin(Some(&user("root"))));
+        for bad in &["ci", "ci:admin", ":read", "t1:write:"] {
+            assert_eq!(Err(Error::BadArg(json!(bad))), Acls::parse(bad).map(|_| ()));
+        }
+    }
+}
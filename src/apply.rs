@@ -1,21 +1,23 @@
 use crate::cmd::{Cmd, Range};
 use crate::db::PAGE_SIZE;
-use crate::json::{
+use crate::json_ops::{
     gt, gte, json_add2, json_and, json_bar, json_fold_add, json_gt, json_gte, json_lt, json_lte,
-    json_map, json_median, json_not_eq, json_numsort, json_or, json_reduce_add, json_slice,
+    json_map, json_matches, json_median, json_not_eq, json_numsort, json_or, json_reduce_add,
     json_sort, json_sortby, json_var, lt, lte, noteq, Json,
 };
-use crate::json::{
-    json_add, json_avg, json_count, json_dev, json_div, json_eq, json_first, json_flat, json_get,
-    json_in, json_last, json_max, json_min, json_mul, json_reverse, json_sub, json_sum,
-    json_tostring, json_unique,
+use crate::json_ops::{
+    json_add, json_avg, json_between, json_collect, json_count, json_count_distinct,
+    json_count_vals, json_dev, json_diff, json_div, json_eq, json_first, json_flat, json_get,
+    json_heavy_hitters, json_in, json_join, json_last, json_len, json_max, json_min, json_mul,
+    json_path, json_reverse, json_sub, json_sum, json_tostring, json_unique,
 };
 use crate::{Error, Res};
 use rayon::prelude::*;
+use serde_json::json;
 
 /// retrieves the key/val entry from a row by key
 fn get_key(row: &Json, key: &str) -> Json {
-    row.get(key).cloned().unwrap_or(Json::Null)
+    json_path(row, key).cloned().unwrap_or(Json::Null)
 }
 
 /// apply an unary function
@@ -49,19 +51,45 @@ where
 fn apply_key(key: String, rows: &[Json]) -> Json {
     Json::Array(
         rows.par_iter()
-            .map(|x| x.get(&key))
+            .map(|x| json_path(x, &key))
             .filter(|x| x.is_some())
             .map(|x| x.unwrap().clone())
             .collect(),
     )
 }
 
+/// checks if the cmd only references fields of a single row
+fn is_row_expr(cmd: &Cmd) -> bool {
+    match cmd {
+        Cmd::Key(_) | Cmd::Json(_) => true,
+        Cmd::Add(x, y) | Cmd::Sub(x, y) | Cmd::Mul(x, y) | Cmd::Div(x, y) => {
+            is_row_expr(x) && is_row_expr(y)
+        }
+        _ => false,
+    }
+}
+
+/// checks if the cmd is a computed expression that must be evaluated row by row
+fn is_computed(cmd: &Cmd) -> bool {
+    matches!(
+        cmd,
+        Cmd::Add(_, _) | Cmd::Sub(_, _) | Cmd::Mul(_, _) | Cmd::Div(_, _)
+    ) && is_row_expr(cmd)
+}
+
+/// apply a computed expression to each row so fields from the same row are combined
+fn apply_per_row(cmd: Cmd, rows: &[Json]) -> Res {
+    let vals: Result<Vec<Json>, Error> =
+        rows.par_iter().map(|row| apply(cmd.clone(), row)).collect();
+    Ok(Json::Array(vals?))
+}
+
 fn apply_sum(arg: Cmd, rows: &[Json]) -> Res {
     match arg {
         Cmd::Key(key) => {
             let val: Json = rows
                 .par_iter()
-                .map(|x| x.get(&key))
+                .map(|x| json_path(x, &key))
                 .filter(|x| x.is_some())
                 .map(|x| x.unwrap())
                 .fold(|| Json::from(0), |x, y| json_add2(&x, y))
@@ -80,20 +108,20 @@ fn apply_max(arg: Cmd, rows: &[Json]) -> Res {
         Cmd::Key(key) => {
             let val: Option<&Json> = rows
                 .par_iter()
-                .map(|x| x.get(&key))
+                .map(|x| json_path(x, &key))
                 .filter(|x| x.is_some())
                 .map(|x| x.unwrap())
                 .fold(
                     || None,
                     |x, y| {
                         if let Some(x) = x {
-                            if gt(x, &y) {
+                            if gt(x, y) {
                                 Some(x)
                             } else {
-                                Some(&y)
+                                Some(y)
                             }
                         } else {
-                            Some(&y)
+                            Some(y)
                         }
                     },
                 )
@@ -143,11 +171,27 @@ fn apply_flat(arg: Cmd, rows: &[Json]) -> Res {
 fn apply_has(key: String, rows: &[Json]) -> Res {
     Ok(Json::Array(
         rows.par_iter()
-            .map(|x| Json::Bool(x.get(&key).is_some()))
+            .map(|x| Json::Bool(json_path(x, &key).is_some()))
             .collect(),
     ))
 }
 
+fn apply_is_null(key: String, rows: &[Json]) -> Res {
+    Ok(Json::Array(
+        rows.par_iter()
+            .map(|x| Json::Bool(json_path(x, &key).is_none_or(Json::is_null)))
+            .collect(),
+    ))
+}
+
+/// test a row, or each row of an array, for a field
+fn apply_field_test<F: Fn(&Json) -> bool>(val: &Json, f: F) -> Json {
+    match val {
+        Json::Array(arr) => Json::Array(arr.iter().map(|x| Json::Bool(f(x))).collect()),
+        val => Json::Bool(f(val)),
+    }
+}
+
 fn apply_reverse(arg: Cmd, rows: &[Json]) -> Res {
     let mut val = apply_rows(arg, rows)?;
     json_reverse(&mut val);
@@ -162,11 +206,9 @@ fn apply_sort(arg: Cmd, descend: Option<bool>, rows: &[Json]) -> Res {
 
 fn apply_keys(page: Option<Range>, rows: &[Json]) -> Res {
     if let Some(page) = page {
-        let start = page.start.unwrap_or(0);
-        let n = page.size.unwrap_or(PAGE_SIZE);
-        Ok(Json::Array(
-            rows.par_iter().skip(start).take(n).cloned().collect(),
-        ))
+        let at = page.or_size(PAGE_SIZE).positions(rows.len())?;
+        let page: Vec<Json> = at.pick(rows.iter()).cloned().collect();
+        Ok(json!({"keys": page, "total": rows.len()}))
     } else {
         Ok(Json::Array(
             rows.par_iter().take(PAGE_SIZE).cloned().collect(),
@@ -174,31 +216,51 @@ fn apply_keys(page: Option<Range>, rows: &[Json]) -> Res {
     }
 }
 
+fn apply_count(arg: Option<Box<Cmd>>, rows: &[Json]) -> Res {
+    match arg {
+        Some(arg) => apply_unr_fn(*arg, rows, |x| Ok(json_count_vals(x))),
+        None => Ok(Json::from(rows.len())),
+    }
+}
+
 fn apply_numsort(arg: Cmd, descend: bool, rows: &[Json]) -> Res {
     let val = apply_rows(arg, rows)?;
     Ok(json_numsort(val, descend))
 }
 
 fn apply_slice(arg: Cmd, range: Range, rows: &[Json]) -> Res {
-    let val = apply_rows(arg, rows)?;
-    json_slice(val, range)
+    range.slice(apply_rows(arg, rows)?)
 }
 
 /// apply a cmd to rows of json
 pub fn apply_rows(cmd: Cmd, rows: &[Json]) -> Res {
     match cmd {
+        cmd if is_computed(&cmd) => apply_per_row(cmd, rows),
         Cmd::Key(key) => Ok(apply_key(key, rows)),
         Cmd::Sum(arg) => apply_sum(*arg, rows),
         Cmd::Max(arg) => apply_max(*arg, rows),
         Cmd::Append(_, _) => Err(Error::BadCmd),
         Cmd::Apply(_, _) => Err(Error::BadCmd),
         Cmd::Bar(lhs, rhs) => apply_bin_fn(*lhs, *rhs, rows, json_bar),
-        Cmd::Set(_, _) => Err(Error::BadCmd),
+        Cmd::Set(_, _, _) | Cmd::GetSet(_, _) => Err(Error::BadCmd),
         Cmd::Min(arg) => apply_unr_fn(*arg, rows, |x| {
             Ok(json_min(x).cloned().unwrap_or(Json::Null))
         }),
         Cmd::Avg(arg) => apply_unr_fn(*arg, rows, json_avg),
-        Cmd::Delete(_) => Err(Error::BadCmd),
+        Cmd::Between(arg, lo, hi) => {
+            let lo = apply_rows(*lo, rows)?;
+            let hi = apply_rows(*hi, rows)?;
+            apply_unr_fn(*arg, rows, |x| Ok(json_between(x, &lo, &hi)))
+        }
+        Cmd::Collect(arg) => apply_unr_fn(*arg, rows, |x| Ok(json_collect(x))),
+        Cmd::Like(arg, pat) | Cmd::Regex(arg, pat) => {
+            apply_unr_fn(*arg, rows, |x| Ok(json_matches(x, |s| pat.is_match(s))))
+        }
+        Cmd::Join(arg, sep) => apply_unr_fn(*arg, rows, |x| Ok(json_join(x, &sep))),
+        Cmd::Count(arg) => apply_count(arg, rows),
+        Cmd::CountDistinct(arg) => apply_unr_fn(*arg, rows, |x| Ok(json_count_distinct(x))),
+        Cmd::HeavyHitters(arg, k) => apply_unr_fn(*arg, rows, |x| Ok(json_heavy_hitters(x, k))),
+        Cmd::Delete(_) | Cmd::DeleteMany(_) | Cmd::DeletePattern(_) => Err(Error::BadCmd),
         Cmd::Dev(arg) => apply_unr_fn(*arg, rows, json_dev),
         Cmd::Add(lhs, rhs) => apply_bin_fn(*lhs, *rhs, rows, json_add),
         Cmd::Sub(lhs, rhs) => apply_bin_fn(*lhs, *rhs, rows, json_sub),
@@ -210,12 +272,35 @@ pub fn apply_rows(cmd: Cmd, rows: &[Json]) -> Res {
         Cmd::Push(_, _) => Err(Error::BadCmd),
         Cmd::Pop(_) => Err(Error::BadCmd),
         Cmd::Query(_) => Err(Error::BadCmd),
-        Cmd::Insert(_, _) => Err(Error::BadCmd),
-        Cmd::Keys(page) => apply_keys(page, rows),
-        Cmd::Len(arg) => apply_unr_fn(*arg, rows, |x| Ok(json_count(x))),
+        Cmd::Validate(_) => Err(Error::BadCmd),
+        Cmd::Prepare(_, _) | Cmd::Execute(_, _) => Err(Error::BadCmd),
+        Cmd::CreateIndex { .. } => Err(Error::BadCmd),
+        Cmd::Param(name) => Err(Error::BadParam(name)),
+        Cmd::Explain(_) | Cmd::Estimate(_) => Err(Error::BadCmd),
+        Cmd::Backup(_) | Cmd::Restore(_) => Err(Error::BadCmd),
+        Cmd::Insert(_, _) | Cmd::Generate(_) | Cmd::Merge(_, _) | Cmd::MoveRows { .. } => {
+            Err(Error::BadCmd)
+        }
+        Cmd::Keys(page, _) => apply_keys(page, rows),
+        Cmd::HotKeys(_) | Cmd::Parallelism(_) | Cmd::MetricsHistory { .. } => Err(Error::BadCmd),
+        Cmd::Compress(_) => Err(Error::BadCmd),
+        Cmd::Batch(_) | Cmd::Tx(_) | Cmd::Script(_) => Err(Error::BadCmd),
+        Cmd::MGet(_) | Cmd::Lookup(_) => Err(Error::BadCmd),
+        Cmd::Expire(_, _) | Cmd::ExpireAt(_, _) | Cmd::Ttl(_) => Err(Error::BadCmd),
+        Cmd::Subscribe(_, _)
+        | Cmd::SubscribeQuery(_)
+        | Cmd::Events(_)
+        | Cmd::Ack(_, _)
+        | Cmd::Unsubscribe(_) => Err(Error::BadCmd),
+        Cmd::Intern(_) => Err(Error::BadCmd),
+        Cmd::SizeOf(_) => Err(Error::BadCmd),
+        Cmd::Chaos(_) => Err(Error::BadCmd),
+        Cmd::KeyRange(_) | Cmd::Scan(_) | Cmd::Traverse(_) => Err(Error::BadCmd),
+        Cmd::Len(Some(arg)) => apply_unr_fn(*arg, rows, |x| Ok(json_len(x))),
+        Cmd::Len(None) => Err(Error::BadCmd),
         Cmd::Unique(arg) => apply_unr_fn(*arg, rows, |x| Ok(json_unique(x))),
         Cmd::Json(val) => Ok(val),
-        Cmd::Summary => Err(Error::BadCmd),
+        Cmd::Summary(_) => Err(Error::BadCmd),
         Cmd::Get(key, arg) => apply_get(key, *arg, rows),
         Cmd::ToString(arg) => apply_unr_fn(*arg, rows, |x| Ok(Json::from(json_tostring(x)))),
         Cmd::Sort(arg, descend) => apply_sort(*arg, descend, rows),
@@ -233,9 +318,11 @@ pub fn apply_rows(cmd: Cmd, rows: &[Json]) -> Res {
         Cmd::Or(lhs, rhs) => apply_bin_fn(*lhs, *rhs, rows, json_or),
         Cmd::Map(arg, f) => apply_map(*arg, f, rows),
         Cmd::In(lhs, rhs) => apply_bin_fn(*lhs, *rhs, rows, |x, y| Ok(json_in(x, y))),
+        Cmd::Diff(lhs, rhs) => apply_bin_fn(*lhs, *rhs, rows, |x, y| Ok(json_diff(x, y))),
         Cmd::Flat(arg) => apply_flat(*arg, rows),
         Cmd::NumSort(arg, descend) => apply_numsort(*arg, descend, rows),
         Cmd::Has(key) => apply_has(key, rows),
+        Cmd::IsNull(key) => apply_is_null(key, rows),
         Cmd::Slice(arg, range) => apply_slice(*arg, range, rows),
     }
 }
@@ -250,7 +337,7 @@ fn apply_key2(key: String, val: &Json) -> Res {
     Ok(match val {
         Json::Array(arr) => Json::Array(
             arr.par_iter()
-                .map(|x| x.get(&key))
+                .map(|x| json_path(x, &key))
                 .filter(|x| x.is_some())
                 .map(|x| x.unwrap().clone())
                 .collect(),
@@ -261,19 +348,14 @@ fn apply_key2(key: String, val: &Json) -> Res {
 
 fn apply_sum2(arg: Cmd, val: &Json) -> Res {
     match arg {
-        Cmd::Key(_key) => {
-            if let Some(arr) = val.as_array() {
-                let val: Json = arr
-                    .par_iter()
-                    .fold(|| Json::from(0), json_fold_add)
-                    .reduce(|| Json::from(0), json_reduce_add);
-                Ok(val)
-            } else if val.is_number() {
-                Ok(val.clone())
-            } else {
-                Ok(Json::Null)
-            }
-        }
+        Cmd::Key(key) => match val {
+            Json::Array(arr) => Ok(arr
+                .par_iter()
+                .filter_map(|x| json_path(x, &key))
+                .fold(|| Json::from(0), json_fold_add)
+                .reduce(|| Json::from(0), json_reduce_add)),
+            val => Ok(json_sum(&get_key(val, &key))),
+        },
         cmd => {
             let val = apply(cmd, val)?;
             Ok(json_sum(&val))
@@ -296,6 +378,7 @@ fn apply_bar2(lhs: Cmd, rhs: Cmd, val: &Json) -> Res {
 /// apply a command to a json value
 pub fn apply(cmd: Cmd, val: &Json) -> Res {
     match cmd {
+        cmd if is_computed(&cmd) && val.is_array() => apply_per_row(cmd, val.as_array().unwrap()),
         Cmd::Apply(_lhs, _rhs) => Err(Error::BadCmd),
         Cmd::Key(key) => apply_key2(key, val),
         Cmd::Sum(arg) => apply_sum2(*arg, val),
@@ -303,11 +386,25 @@ pub fn apply(cmd: Cmd, val: &Json) -> Res {
         Cmd::Json(val) => Ok(val),
         Cmd::Append(_, _) => Err(Error::BadCmd),
         Cmd::Bar(lhs, rhs) => apply_bar2(*lhs, *rhs, val),
-        Cmd::Set(_, _) => Err(Error::BadCmd),
+        Cmd::Set(_, _, _) | Cmd::GetSet(_, _) => Err(Error::BadCmd),
         Cmd::Max(arg) => Ok(json_max(&apply(*arg, val)?).cloned().unwrap_or(Json::Null)),
         Cmd::Min(arg) => Ok(json_min(&apply(*arg, val)?).cloned().unwrap_or(Json::Null)),
         Cmd::Avg(arg) => json_avg(&apply(*arg, val)?),
-        Cmd::Delete(_) => Err(Error::BadCmd),
+        Cmd::Between(arg, lo, hi) => Ok(json_between(
+            &apply(*arg, val)?,
+            &apply(*lo, val)?,
+            &apply(*hi, val)?,
+        )),
+        Cmd::Collect(arg) => Ok(json_collect(&apply(*arg, val)?)),
+        Cmd::Like(arg, pat) | Cmd::Regex(arg, pat) => {
+            Ok(json_matches(&apply(*arg, val)?, |s| pat.is_match(s)))
+        }
+        Cmd::Join(arg, sep) => Ok(json_join(&apply(*arg, val)?, &sep)),
+        Cmd::Count(Some(arg)) => Ok(json_count_vals(&apply(*arg, val)?)),
+        Cmd::Count(None) => Ok(json_count(val)),
+        Cmd::CountDistinct(arg) => Ok(json_count_distinct(&apply(*arg, val)?)),
+        Cmd::HeavyHitters(arg, k) => Ok(json_heavy_hitters(&apply(*arg, val)?, k)),
+        Cmd::Delete(_) | Cmd::DeleteMany(_) | Cmd::DeletePattern(_) => Err(Error::BadCmd),
         Cmd::Dev(arg) => json_dev(&apply(*arg, val)?),
         Cmd::Add(x, y) => json_add(&apply(*x, val)?, &apply(*y, val)?),
         Cmd::Sub(x, y) => json_sub(&apply(*x, val)?, &apply(*y, val)?),
@@ -319,11 +416,34 @@ pub fn apply(cmd: Cmd, val: &Json) -> Res {
         Cmd::Push(_, _) => Err(Error::BadCmd),
         Cmd::Pop(_) => Err(Error::BadCmd),
         Cmd::Query(_) => Err(Error::BadCmd),
-        Cmd::Insert(_, _) => Err(Error::BadCmd),
-        Cmd::Keys(_) => Err(Error::BadCmd),
-        Cmd::Len(arg) => Ok(json_count(&apply(*arg, val)?)),
+        Cmd::Validate(_) => Err(Error::BadCmd),
+        Cmd::Prepare(_, _) | Cmd::Execute(_, _) => Err(Error::BadCmd),
+        Cmd::CreateIndex { .. } => Err(Error::BadCmd),
+        Cmd::Param(name) => Err(Error::BadParam(name)),
+        Cmd::Explain(_) | Cmd::Estimate(_) => Err(Error::BadCmd),
+        Cmd::Backup(_) | Cmd::Restore(_) => Err(Error::BadCmd),
+        Cmd::Insert(_, _) | Cmd::Generate(_) | Cmd::Merge(_, _) | Cmd::MoveRows { .. } => {
+            Err(Error::BadCmd)
+        }
+        Cmd::Keys(_, _) => Err(Error::BadCmd),
+        Cmd::Chaos(_) => Err(Error::BadCmd),
+        Cmd::HotKeys(_) | Cmd::Parallelism(_) | Cmd::MetricsHistory { .. } => Err(Error::BadCmd),
+        Cmd::Compress(_) => Err(Error::BadCmd),
+        Cmd::Batch(_) | Cmd::Tx(_) | Cmd::Script(_) => Err(Error::BadCmd),
+        Cmd::MGet(_) | Cmd::Lookup(_) => Err(Error::BadCmd),
+        Cmd::Expire(_, _) | Cmd::ExpireAt(_, _) | Cmd::Ttl(_) => Err(Error::BadCmd),
+        Cmd::Subscribe(_, _)
+        | Cmd::SubscribeQuery(_)
+        | Cmd::Events(_)
+        | Cmd::Ack(_, _)
+        | Cmd::Unsubscribe(_) => Err(Error::BadCmd),
+        Cmd::Intern(_) => Err(Error::BadCmd),
+        Cmd::SizeOf(_) => Err(Error::BadCmd),
+        Cmd::KeyRange(_) | Cmd::Scan(_) | Cmd::Traverse(_) => Err(Error::BadCmd),
+        Cmd::Len(Some(arg)) => Ok(json_len(&apply(*arg, val)?)),
+        Cmd::Len(None) => Err(Error::BadCmd),
         Cmd::Unique(arg) => Ok(json_unique(&apply(*arg, val)?)),
-        Cmd::Summary => Err(Error::BadCmd),
+        Cmd::Summary(_) => Err(Error::BadCmd),
         Cmd::Get(key, arg) => Ok(json_get(&key, &apply(*arg, val)?).unwrap_or(Json::Null)),
         Cmd::ToString(arg) => Ok(Json::from(json_tostring(&apply(*arg, val)?))),
         Cmd::Sort(arg, descend) => {
@@ -362,23 +482,14 @@ pub fn apply(cmd: Cmd, val: &Json) -> Res {
         Cmd::Or(lhs, rhs) => json_or(&apply(*lhs, val)?, &apply(*rhs, val)?),
         Cmd::Map(arg, f) => json_map(&apply(*arg, val)?, f),
         Cmd::In(lhs, rhs) => Ok(json_in(&apply(*lhs, val)?, &apply(*rhs, val)?)),
+        Cmd::Diff(lhs, rhs) => Ok(json_diff(&apply(*lhs, val)?, &apply(*rhs, val)?)),
         Cmd::Flat(arg) => Ok(json_flat(apply(*arg, val)?)),
         Cmd::NumSort(arg, descend) => Ok(json_numsort(apply(*arg, val)?, descend)),
-        Cmd::Has(ref key) => {
-            let f = |x: &Json| Json::from(x.get(&key).is_some());
-            let out: Json = match val {
-                Json::Array(arr) => {
-                    let mut out = Vec::new();
-                    for val in arr {
-                        out.push(f(val));
-                    }
-                    Json::Array(out)
-                }
-                val => f(val),
-            };
-            Ok(out)
-        }
-        Cmd::Slice(arg, range) => json_slice(apply(*arg, val)?, range),
+        Cmd::Has(key) => Ok(apply_field_test(val, |x| json_path(x, &key).is_some())),
+        Cmd::IsNull(key) => Ok(apply_field_test(val, |x| {
+            json_path(x, &key).is_none_or(Json::is_null)
+        })),
+        Cmd::Slice(arg, range) => range.slice(apply(*arg, val)?),
     }
 }
 
@@ -0,0 +1,119 @@
+use crate::json_ops::Json;
+use serde_json::json;
+use std::collections::{BTreeMap, HashMap};
+use std::sync::Mutex;
+
+/// the most clients audited separately; rejections of any further clients are counted
+/// together, so a flood of addresses can't grow the audit without bound
+pub const MAX_AUDITED_CLIENTS: usize = 4096;
+/// the client the rejections beyond the audited clients are counted against
+const OTHER_CLIENTS: &str = "other";
+
+/// Why a request was rejected
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Rejection {
+    /// the body wasn't json, or wasn't a cmd or query
+    Malformed,
+    /// the client's address is denied by the ip filter
+    Denied,
+    /// the client had too many requests in flight
+    Busy,
+    /// the cmd belongs to a disabled family
+    Disabled,
+    /// the client didn't present valid credentials
+    Unauthenticated,
+}
+
+impl Rejection {
+    pub fn name(&self) -> &'static str {
+        match self {
+            Rejection::Malformed => "malformed",
+            Rejection::Denied => "denied",
+            Rejection::Busy => "busy",
+            Rejection::Disabled => "disabled",
+            Rejection::Unauthenticated => "unauthenticated",
+        }
+    }
+}
+
+#[derive(Debug, Default)]
+struct ClientAudit {
+    counts: BTreeMap<&'static str, u64>,
+    /// the detail of the last rejection
+    last: String,
+}
+
+/// The rejected requests of each client, so operators can spot misbehaving clients
+#[derive(Debug, Default)]
+pub struct Audit {
+    clients: Mutex<HashMap<String, ClientAudit>>,
+}
+
+impl Audit {
+    /// logs and counts a rejected request of a client
+    pub fn record(&self, client: &str, rejection: Rejection, detail: &str) {
+        println!(
+            "rejected {} request from {}: {}",
+            rejection.name(),
+            client,
+            detail
+        );
+        let mut clients = self.clients.lock().unwrap();
+        let full = clients.len() >= MAX_AUDITED_CLIENTS && !clients.contains_key(client);
+        let client = if full { OTHER_CLIENTS } else { client };
+        let audit = clients.entry(client.to_string()).or_default();
+        *audit.counts.entry(rejection.name()).or_insert(0) += 1;
+        audit.last = detail.to_string();
+    }
+
+    /// the rejections of each client, most rejected first
+    pub fn report(&self) -> Json {
+        let clients = self.clients.lock().unwrap();
+        let mut report: Vec<(&String, u64, &ClientAudit)> = clients
+            .iter()
+            .map(|(client, x)| (client, x.counts.values().sum(), x))
+            .collect();
+        report.sort_by(|(xc, x, _), (yc, y, _)| y.cmp(x).then_with(|| xc.cmp(yc)));
+        report
+            .into_iter()
+            .map(|(client, total, x)| {
+                json!({"client": client, "total": total, "counts": x.counts, "last": x.last})
+            })
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn rejections_counted_per_client() {
+        let audit = Audit::default();
+        audit.record("10.0.0.1", Rejection::Malformed, "bad cmd");
+        audit.record("10.0.0.2", Rejection::Denied, "denied");
+        audit.record("10.0.0.1", Rejection::Disabled, "write cmds are disabled");
+        let exp = json!([
+            {
+                "client": "10.0.0.1",
+                "total": 2,
+                "counts": {"disabled": 1, "malformed": 1},
+                "last": "write cmds are disabled",
+            },
+            {"client": "10.0.0.2", "total": 1, "counts": {"denied": 1}, "last": "denied"},
+        ]);
+        assert_eq!(exp, audit.report());
+    }
+
+    #[test]
+    fn clients_beyond_max_counted_together() {
+        let audit = Audit::default();
+        for i in 0..MAX_AUDITED_CLIENTS + 2 {
+            audit.record(&i.to_string(), Rejection::Busy, "busy");
+        }
+        let report = audit.report();
+        assert_eq!(MAX_AUDITED_CLIENTS + 1, report.as_array().unwrap().len());
+        assert_eq!(json!("other"), report[0]["client"]);
+        assert_eq!(json!(2), report[0]["total"]);
+    }
+}
@@ -0,0 +1,148 @@
+//! Authentication of the clients of memson
+//!
+//! Once any credentials are configured, every request needs them: an `Authorization`
+//! header of `Bearer <token>`, or `Basic` with a user and password. Websockets may instead
+//! send `{"auth": "<token>"}` or `{"auth": {"user": .., "password": ..}}` as their first
+//! message; other messages are refused until one succeeds.
+
+use crate::err::Error;
+use serde::Deserialize;
+
+/// The credentials presented by a client
+#[derive(Clone, Debug, Deserialize, PartialEq)]
+#[serde(untagged)]
+pub enum Credentials {
+    Token(String),
+    Password { user: String, password: String },
+}
+
+impl Credentials {
+    /// the credentials of an `Authorization` header, if it holds any
+    pub fn from_header(val: &str) -> Option<Self> {
+        let (scheme, param) = val.trim().split_once(' ')?;
+        let param = param.trim();
+        if scheme.eq_ignore_ascii_case("bearer") {
+            Some(Credentials::Token(param.to_string()))
+        } else if scheme.eq_ignore_ascii_case("basic") {
+            let decoded = String::from_utf8(base64::decode(param).ok()?).ok()?;
+            let (user, password) = decoded.split_once(':')?;
+            Some(Credentials::Password {
+                user: user.to_string(),
+                password: password.to_string(),
+            })
+        } else {
+            None
+        }
+    }
+}
+
+/// The name of an authenticated client
+#[derive(Clone, Debug, PartialEq)]
+pub struct User(pub String);
+
+/// The static tokens and user passwords accepted by memson; open to every client if
+/// there are none
+#[derive(Debug, Default)]
+pub struct Auth {
+    /// the users named by each token
+    tokens: Vec<(String, String)>,
+    passwords: Vec<(String, String)>,
+}
+
+impl Auth {
+    /// reads comma separated `name:secret` pairs of tokens and of user passwords
+    pub fn new(tokens: &str, passwords: &str) -> Result<Self, Error> {
+        Ok(Self {
+            tokens: parse_pairs(tokens)?,
+            passwords: parse_pairs(passwords)?,
+        })
+    }
+
+    /// checks if every client is let in
+    pub fn is_open(&self) -> bool {
+        self.tokens.is_empty() && self.passwords.is_empty()
+    }
+
+    /// the user the credentials belong to, if they're valid
+    pub fn authenticate(&self, creds: &Credentials) -> Result<User, Error> {
+        // every secret is compared, so the time taken doesn't tell which one matched
+        let mut found = None;
+        match creds {
+            Credentials::Token(token) => {
+                for (name, secret) in &self.tokens {
+                    if same(secret.as_bytes(), token.as_bytes()) {
+                        found = Some(name);
+                    }
+                }
+            }
+            Credentials::Password { user, password } => {
+                for (name, secret) in &self.passwords {
+                    if name == user && same(secret.as_bytes(), password.as_bytes()) {
+                        found = Some(name);
+                    }
+                }
+            }
+        }
+        found
+            .map(|name| User(name.clone()))
+            .ok_or(Error::BadCredentials)
+    }
+}
+
+fn parse_pairs(s: &str) -> Result<Vec<(String, String)>, Error> {
+    s.split(',')
+        .map(str::trim)
+        .filter(|x| !x.is_empty())
+        .map(|pair| match pair.split_once(':') {
+            Some((name, secret)) if !name.is_empty() && !secret.is_empty() => {
+                Ok((name.to_string(), secret.to_string()))
+            }
+            _ => Err(Error::BadArg(pair.into())),
+        })
+        .collect()
+}
+
+/// compares secrets in a time depending only on their lengths
+fn same(x: &[u8], y: &[u8]) -> bool {
+    x.len() == y.len() && x.iter().zip(y).fold(0, |acc, (a, b)| acc | (a ^ b)) == 0
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn tokens_and_passwords_authenticate() {
+        let auth = Auth::new("ci:t0ken, ops:s3cret", "ana:pw").unwrap();
+        assert!(!auth.is_open());
+        let token = |s: &str| Credentials::Token(s.to_string());
+        assert_eq!(
+            Ok(User("ops".to_string())),
+            auth.authenticate(&token("s3cret"))
+        );
+        assert_eq!(
+            Err(Error::BadCredentials),
+            auth.authenticate(&token("t0ke"))
+        );
+        let password = |user: &str, password: &str| Credentials::Password {
+            user: user.to_string(),
+            password: password.to_string(),
+        };
+        let ana = password("ana", "pw");
+        assert_eq!(Ok(User("ana".to_string())), auth.authenticate(&ana));
+        let wrong_user = password("ci", "pw");
+        assert_eq!(Err(Error::BadCredentials), auth.authenticate(&wrong_user));
+        assert_eq!(Some(ana), Credentials::from_header("Basic YW5hOnB3"));
+        assert_eq!(
+            Some(token("t0ken")),
+            Credentials::from_header("bearer t0ken")
+        );
+        assert_eq!(None, Credentials::from_header("Basic !!"));
+        assert_eq!(None, Credentials::from_header("Digest x"));
+        assert!(Auth::new("", "").unwrap().is_open());
+        assert_eq!(
+            Err(Error::BadArg("ci".into())),
+            Auth::new("ci", "").map(|_| ())
+        );
+    }
+}
@@ -0,0 +1,510 @@
+use crate::cmd::{Cmd, Generate, QueryCmd, Source, Traverse};
+use crate::err::Error;
+use crate::json_ops::Json;
+use crate::ondisk::OnDiskDb;
+use std::collections::{BTreeSet, HashMap, HashSet};
+use std::sync::mpsc::{channel, Receiver, Sender};
+use std::sync::{Arc, Condvar, Mutex, PoisonError};
+use std::thread;
+use std::time::{Duration, Instant};
+
+/// A datastore fronted by memson. Keys missing from memson are read through from the
+/// backend, and keys changed in memson are written through to it.
+pub trait Backend: Send + Sync {
+    /// loads the value of a key missing from memson; none if the backend does not have it
+    fn load(&mut self, _key: &str) -> Result<Option<Json>, Error> {
+        Ok(None)
+    }
+
+    /// stores the new value of a key changed in memson
+    fn store(&mut self, _key: &str, _val: &Json) -> Result<(), Error> {
+        Ok(())
+    }
+
+    /// removes a key deleted from memson
+    fn remove(&mut self, _key: &str) -> Result<(), Error> {
+        Ok(())
+    }
+}
+
+impl Backend for OnDiskDb {
+    fn load(&mut self, key: &str) -> Result<Option<Json>, Error> {
+        self.get(key)
+    }
+
+    fn store(&mut self, key: &str, val: &Json) -> Result<(), Error> {
+        self.set(key, val).map(|_| ())
+    }
+
+    fn remove(&mut self, key: &str) -> Result<(), Error> {
+        self.delete(key).map(|_| ())
+    }
+}
+
+type LoadResult = Result<Option<Json>, Error>;
+
+/// A load of a key from the backend that other loads of the same key wait on
+#[derive(Default)]
+struct Flight {
+    result: Mutex<Option<LoadResult>>,
+    done: Condvar,
+}
+
+impl Flight {
+    /// waits for the load to finish
+    fn wait(&self) -> LoadResult {
+        let mut result = self.result.lock().unwrap();
+        while result.is_none() {
+            result = self.done.wait(result).unwrap();
+        }
+        result.clone().unwrap()
+    }
+
+    /// publishes the result of the load to the waiting loads
+    fn finish(&self, res: LoadResult) {
+        *self.result.lock().unwrap() = Some(res);
+        self.done.notify_all();
+    }
+}
+
+/// Ends the flight of a load once the load returns or panics, so the loads waiting on
+/// it, and later loads of the key, don't wait forever
+struct Landing<'a> {
+    key: &'a str,
+    flight: Arc<Flight>,
+    flights: &'a Mutex<HashMap<String, Arc<Flight>>>,
+    /// the result of the load; none if it panicked
+    res: Option<LoadResult>,
+}
+
+impl Drop for Landing<'_> {
+    fn drop(&mut self) {
+        let mut flights = self.flights.lock().unwrap_or_else(PoisonError::into_inner);
+        flights.remove(self.key);
+        drop(flights);
+        self.flight
+            .finish(self.res.take().unwrap_or(Err(Error::BadIO)));
+    }
+}
+
+/// Wraps a backend so concurrent loads of the same key, from clones of the wrapper,
+/// share a single load from the backend rather than each hitting it
+#[derive(Clone)]
+pub struct SingleFlight<B> {
+    backend: B,
+    flights: Arc<Mutex<HashMap<String, Arc<Flight>>>>,
+}
+
+impl<B: Backend> SingleFlight<B> {
+    pub fn new(backend: B) -> Self {
+        Self {
+            backend,
+            flights: Arc::new(Mutex::new(HashMap::new())),
+        }
+    }
+}
+
+impl<B: Backend> Backend for SingleFlight<B> {
+    fn load(&mut self, key: &str) -> Result<Option<Json>, Error> {
+        let mut flights = self.flights.lock().unwrap();
+        if let Some(flight) = flights.get(key).cloned() {
+            drop(flights);
+            return flight.wait();
+        }
+        let flight = Arc::new(Flight::default());
+        flights.insert(key.to_string(), flight.clone());
+        drop(flights);
+        let mut landing = Landing {
+            key,
+            flight,
+            flights: &self.flights,
+            res: None,
+        };
+        let res = self.backend.load(key);
+        landing.res = Some(res.clone());
+        res
+    }
+
+    fn store(&mut self, key: &str, val: &Json) -> Result<(), Error> {
+        self.backend.store(key, val)
+    }
+
+    fn remove(&mut self, key: &str) -> Result<(), Error> {
+        self.backend.remove(key)
+    }
+}
+
+/// Soft expiry of entries loaded from a backend. A stale entry is still served while
+/// a background thread reloads it, and the reloaded value replaces it once ready.
+pub(crate) struct SoftTtl {
+    ttl: Duration,
+    loaded_at: HashMap<String, Instant>,
+    pending: HashSet<String>,
+    keys: Sender<String>,
+    vals: Mutex<Receiver<(String, LoadResult)>>,
+}
+
+impl SoftTtl {
+    /// spawns the thread reloading stale entries from the backend
+    pub(crate) fn spawn(ttl: Duration, mut backend: Box<dyn Backend>) -> Self {
+        let (keys, keys_rx) = channel::<String>();
+        let (vals_tx, vals) = channel();
+        thread::spawn(move || {
+            for key in keys_rx {
+                let res = backend.load(&key);
+                if vals_tx.send((key, res)).is_err() {
+                    break;
+                }
+            }
+        });
+        Self {
+            ttl,
+            loaded_at: HashMap::new(),
+            pending: HashSet::new(),
+            keys,
+            vals: Mutex::new(vals),
+        }
+    }
+
+    /// records that the entry was just loaded or written
+    pub(crate) fn touch(&mut self, key: &str) {
+        self.loaded_at.insert(key.to_string(), Instant::now());
+    }
+
+    /// requests a reload of the entry if it is stale and not already being reloaded
+    pub(crate) fn refresh_if_stale(&mut self, key: &str) {
+        let stale = match self.loaded_at.get(key) {
+            Some(at) => at.elapsed() >= self.ttl,
+            None => false,
+        };
+        if stale && self.pending.insert(key.to_string()) {
+            let _ = self.keys.send(key.to_string());
+        }
+    }
+
+    /// the reloads finished since last drained
+    pub(crate) fn drain(&mut self) -> Vec<(String, LoadResult)> {
+        let vals: Vec<(String, LoadResult)> = self.vals.get_mut().unwrap().try_iter().collect();
+        for (key, res) in &vals {
+            self.pending.remove(key);
+            if res.is_ok() {
+                self.touch(key);
+            }
+        }
+        vals
+    }
+}
+
+/// the top level part of a possibly nested key
+fn root_key(key: &str) -> &str {
+    key.split('.').next().unwrap_or(key)
+}
+
+/// collects the keys of memson entries read by a query
+pub(crate) fn query_read_keys(qry: &QueryCmd, out: &mut BTreeSet<String>) {
+    match &qry.from {
+        Source::Key(key) => {
+            out.insert(key.to_string());
+        }
+        Source::Keys(keys) => out.extend(keys.iter().cloned()),
+        Source::Query(qry) => query_read_keys(qry, out),
+    }
+}
+
+/// collects the keys of memson entries read by a cmd
+pub(crate) fn read_keys(cmd: &Cmd, out: &mut BTreeSet<String>) {
+    match cmd {
+        Cmd::Key(key) | Cmd::Has(key) | Cmd::IsNull(key) | Cmd::SizeOf(key) | Cmd::Lookup(key) => {
+            out.insert(root_key(key).to_string());
+        }
+        Cmd::Append(key, _)
+        | Cmd::Push(key, _)
+        | Cmd::Pop(key)
+        | Cmd::Insert(key, _)
+        | Cmd::Merge(key, _)
+        | Cmd::Expire(key, _)
+        | Cmd::ExpireAt(key, _)
+        | Cmd::Ttl(key)
+        | Cmd::Traverse(Traverse { from: key, .. })
+        | Cmd::CreateIndex { key, .. } => {
+            out.insert(key.to_string());
+        }
+        Cmd::MGet(keys) => {
+            out.extend(keys.iter().map(|key| root_key(key).to_string()));
+        }
+        Cmd::MoveRows { from, to, .. } => {
+            out.insert(from.to_string());
+            out.insert(to.to_string());
+        }
+        Cmd::Query(qry)
+        | Cmd::Explain(qry)
+        | Cmd::Estimate(qry)
+        | Cmd::Validate(qry)
+        | Cmd::SubscribeQuery(qry) => query_read_keys(qry, out),
+        _ => (),
+    }
+    for child in cmd.children() {
+        read_keys(child, out);
+    }
+}
+
+/// collects the keys of memson entries changed by a cmd
+pub(crate) fn written_keys(cmd: &Cmd, out: &mut BTreeSet<String>) {
+    match cmd {
+        Cmd::DeleteMany(keys) => out.extend(keys.iter().cloned()),
+        Cmd::MoveRows { from, to, .. } => {
+            out.insert(from.to_string());
+            out.insert(to.to_string());
+        }
+        Cmd::Set(key, _, _)
+        | Cmd::GetSet(key, _)
+        | Cmd::Delete(key)
+        | Cmd::Append(key, _)
+        | Cmd::Push(key, _)
+        | Cmd::Pop(key)
+        | Cmd::Insert(key, _)
+        | Cmd::Merge(key, _)
+        | Cmd::Generate(Generate { table: key, .. }) => {
+            out.insert(key.to_string());
+        }
+        _ => (),
+    }
+    for child in cmd.children() {
+        written_keys(child, out);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::eviction::Lfu;
+    use crate::inmem::{InMemDb, MAX_MISSES};
+    use serde_json::json;
+    use std::collections::HashMap;
+    use std::sync::Barrier;
+    use std::thread;
+    use std::time::Duration;
+
+    /// a backend over a shared map so tests can inspect what was written through
+    #[derive(Clone, Default)]
+    struct MapBackend(Arc<Mutex<HashMap<String, Json>>>, Arc<Mutex<usize>>);
+
+    impl MapBackend {
+        fn loads(&self) -> usize {
+            *self.1.lock().unwrap()
+        }
+    }
+
+    impl Backend for MapBackend {
+        fn load(&mut self, key: &str) -> Result<Option<Json>, Error> {
+            *self.1.lock().unwrap() += 1;
+            Ok(self.0.lock().unwrap().get(key).cloned())
+        }
+
+        fn store(&mut self, key: &str, val: &Json) -> Result<(), Error> {
+            self.0.lock().unwrap().insert(key.to_string(), val.clone());
+            Ok(())
+        }
+
+        fn remove(&mut self, key: &str) -> Result<(), Error> {
+            self.0.lock().unwrap().remove(key);
+            Ok(())
+        }
+    }
+
+    fn parse(val: Json) -> Cmd {
+        Cmd::parse(val).unwrap()
+    }
+
+    #[test]
+    fn read_through_on_miss() {
+        let backend = MapBackend::default();
+        backend
+            .0
+            .lock()
+            .unwrap()
+            .insert("a".to_string(), json!([1, 2, 3]));
+        let mut db = InMemDb::with_backend(Box::new(backend));
+        assert_eq!(Ok(json!(6)), db.eval(parse(json!({"sum": {"key": "a"}}))));
+        assert_eq!(Ok(&json!([1, 2, 3])), db.get("a"));
+        let err = Err(Error::BadKey("b".to_string()));
+        assert_eq!(err, db.eval(parse(json!({"key": "b"}))));
+    }
+
+    #[test]
+    fn read_through_query_from() {
+        let backend = MapBackend::default();
+        let rows = json!([{"qty": 1}, {"qty": 2}]);
+        backend.0.lock().unwrap().insert("orders".to_string(), rows);
+        let mut db = InMemDb::with_backend(Box::new(backend));
+        let qry = json!({"query": {"select": {"n": {"sum": {"key": "qty"}}}, "from": "orders"}});
+        assert_eq!(Ok(json!({"n": 3})), db.eval(parse(qry)));
+    }
+
+    #[test]
+    fn evicted_entries_read_through_again() {
+        let backend = MapBackend::default();
+        let mut db = InMemDb::with_backend(Box::new(backend));
+        db.set_max_memory(20, Box::new(Lfu));
+        db.eval(parse(json!({"set": ["a", "0123456789"]}))).unwrap();
+        db.eval(parse(json!({"key": "a"}))).unwrap();
+        db.eval(parse(json!({"set": ["b", "0123456789"]}))).unwrap();
+        db.eval(parse(json!({"set": ["c", "0123456789"]}))).unwrap();
+        assert!(db.has("a") && !db.has("b") && db.has("c"));
+        assert_eq!(20, db.size());
+        let val = db.eval(parse(json!({"key": "b"})));
+        assert_eq!(Ok(json!("0123456789")), val);
+        assert!(db.has("a") && db.has("b") && !db.has("c"));
+        assert!(db.invariant_violations(&BTreeSet::new()).is_empty());
+    }
+
+    #[test]
+    fn write_through_set_push_and_delete() {
+        let backend = MapBackend::default();
+        let mut db = InMemDb::with_backend(Box::new(backend.clone()));
+        db.eval(parse(json!({"set": ["a", [1]]}))).unwrap();
+        db.eval(parse(json!({"push": ["a", 2]}))).unwrap();
+        assert_eq!(Some(&json!([1, 2])), backend.0.lock().unwrap().get("a"));
+        db.eval(parse(json!({"del": "a"}))).unwrap();
+        assert_eq!(None, backend.0.lock().unwrap().get("a"));
+    }
+
+    #[test]
+    fn invariants_hold_after_write_through() {
+        let backend = MapBackend::default();
+        let mut db = InMemDb::with_backend(Box::new(backend.clone()));
+        db.eval(parse(json!({"set": ["a", [1]]}))).unwrap();
+        let writes: BTreeSet<String> = vec!["a".to_string()].into_iter().collect();
+        assert!(db.invariant_violations(&writes).is_empty());
+        backend.0.lock().unwrap().remove("a");
+        let exp = vec!["a differs from the backend".to_string()];
+        assert_eq!(exp, db.invariant_violations(&writes));
+        db.set("b", json!(1));
+        let writes: BTreeSet<String> = vec!["b".to_string()].into_iter().collect();
+        let exp = vec![
+            "b was written without a recorded write".to_string(),
+            "b differs from the backend".to_string(),
+        ];
+        assert_eq!(exp, db.invariant_violations(&writes));
+    }
+
+    #[test]
+    fn negative_cache_of_missing_keys() {
+        let backend = MapBackend::default();
+        let mut db = InMemDb::with_backend(Box::new(backend.clone()));
+        db.set_negative_ttl(Some(Duration::from_secs(60)));
+        let cmd = json!({"key": "a"});
+        assert!(db.eval(parse(cmd.clone())).is_err());
+        assert!(db.eval(parse(cmd.clone())).is_err());
+        assert_eq!(1, backend.loads());
+        db.set_negative_ttl(Some(Duration::from_secs(0)));
+        assert!(db.eval(parse(cmd.clone())).is_err());
+        assert!(db.eval(parse(cmd)).is_err());
+        assert_eq!(3, backend.loads());
+    }
+
+    #[test]
+    fn negative_cache_bounded() {
+        let mut db = InMemDb::with_backend(Box::new(MapBackend::default()));
+        db.set_negative_ttl(Some(Duration::from_secs(60)));
+        for i in 0..MAX_MISSES + 10 {
+            assert!(db.eval(Cmd::Key(format!("k{}", i))).is_err());
+        }
+        assert_eq!(MAX_MISSES, db.known_misses());
+        db.set_negative_ttl(Some(Duration::from_millis(1)));
+        thread::sleep(Duration::from_millis(5));
+        assert!(db.eval(Cmd::Key("a".to_string())).is_err());
+        assert_eq!(1, db.known_misses());
+    }
+
+    #[test]
+    fn negative_cache_cleared_on_write() {
+        let backend = MapBackend::default();
+        let mut db = InMemDb::with_backend(Box::new(backend));
+        db.set_negative_ttl(Some(Duration::from_secs(60)));
+        assert!(db.eval(parse(json!({"key": "a"}))).is_err());
+        db.eval(parse(json!({"set": ["a", 1]}))).unwrap();
+        assert_eq!(Ok(json!(1)), db.eval(parse(json!({"key": "a"}))));
+    }
+
+    /// a backend whose loads are slow, to overlap concurrent loads
+    #[derive(Clone, Default)]
+    struct SlowBackend(MapBackend);
+
+    impl Backend for SlowBackend {
+        fn load(&mut self, key: &str) -> Result<Option<Json>, Error> {
+            thread::sleep(Duration::from_millis(100));
+            self.0.load(key)
+        }
+    }
+
+    #[test]
+    fn single_flight_shares_concurrent_loads() {
+        let backend = SlowBackend::default();
+        backend
+            .0
+             .0
+            .lock()
+            .unwrap()
+            .insert("a".to_string(), json!(1));
+        let single = SingleFlight::new(backend.clone());
+        let barrier = Arc::new(Barrier::new(8));
+        let handles: Vec<_> = (0..8)
+            .map(|_| {
+                let mut single = single.clone();
+                let barrier = barrier.clone();
+                thread::spawn(move || {
+                    barrier.wait();
+                    single.load("a")
+                })
+            })
+            .collect();
+        for handle in handles {
+            assert_eq!(Ok(Some(json!(1))), handle.join().unwrap());
+        }
+        assert_eq!(1, backend.0.loads());
+    }
+
+    /// a backend whose loads panic after a while
+    #[derive(Clone)]
+    struct PanickyBackend;
+
+    impl Backend for PanickyBackend {
+        fn load(&mut self, _key: &str) -> Result<Option<Json>, Error> {
+            thread::sleep(Duration::from_millis(100));
+            panic!("backend unreachable");
+        }
+    }
+
+    #[test]
+    fn single_flight_ends_with_panicking_load() {
+        let single = SingleFlight::new(PanickyBackend);
+        let mut leader = single.clone();
+        let leading = thread::spawn(move || leader.load("a"));
+        thread::sleep(Duration::from_millis(20));
+        let mut follower = single.clone();
+        let following = thread::spawn(move || follower.load("a"));
+        assert!(leading.join().is_err());
+        assert_eq!(Err(Error::BadIO), following.join().unwrap());
+        assert!(single.flights.lock().unwrap().is_empty());
+    }
+
+    #[test]
+    fn soft_ttl_serves_stale_then_refreshed() {
+        let backend = MapBackend::default();
+        backend.0.lock().unwrap().insert("a".to_string(), json!(1));
+        let mut db = InMemDb::with_backend(Box::new(backend.clone()));
+        db.set_soft_ttl(Duration::from_secs(0), Box::new(backend.clone()));
+        let cmd = json!({"key": "a"});
+        assert_eq!(Ok(json!(1)), db.eval(parse(cmd.clone())));
+        backend.0.lock().unwrap().insert("a".to_string(), json!(2));
+        assert_eq!(Ok(json!(1)), db.eval(parse(cmd.clone())));
+        for _ in 0..100 {
+            if db.eval(parse(cmd.clone())) == Ok(json!(2)) {
+                return;
+            }
+            thread::sleep(Duration::from_millis(10));
+        }
+        panic!("stale entry was not refreshed");
+    }
+}
@@ -0,0 +1,139 @@
+//! Replays a trace recorded by a memson server, set up with the `TRACE_PATH` env var,
+//! against another server:
+//!
+//!     replay <trace> <host:port> [speed]
+//!
+//! Events are re-issued as the cmds of the same shape on the keys of the same hashes, at
+//! the speed relative to the trace; 2 replays twice as fast, 0 as fast as possible. Point
+//! it at a test instance, as the replayed writes overwrite the keys they touch
+
+use memson::trace::{read_trace, TraceEvent};
+use serde_json::json;
+use std::env;
+use std::io::{self, BufRead, BufReader, Read, Write};
+use std::net::TcpStream;
+use std::process;
+use std::thread;
+use std::time::Instant;
+
+/// A keep-alive connection posting cmds to a server
+struct Client {
+    addr: String,
+    conn: Option<BufReader<TcpStream>>,
+}
+
+impl Client {
+    /// posts a cmd, reconnecting once if the connection was closed
+    fn post(&mut self, body: &[u8]) -> io::Result<u16> {
+        match self.try_post(body) {
+            Ok(status) => Ok(status),
+            Err(_) => {
+                self.conn = None;
+                self.try_post(body)
+            }
+        }
+    }
+
+    fn try_post(&mut self, body: &[u8]) -> io::Result<u16> {
+        if self.conn.is_none() {
+            let stream = TcpStream::connect(&self.addr)?;
+            stream.set_nodelay(true)?;
+            self.conn = Some(BufReader::new(stream));
+        }
+        let conn = self.conn.as_mut().unwrap();
+        // the head and body go out in one write, so the request isn't held back waiting
+        // on the ack of its head
+        let mut req = format!(
+            "POST /cmd HTTP/1.1\r\nHost: {}\r\nContent-Type: application/json\r\nContent-Length: {}\r\n\r\n",
+            self.addr,
+            body.len()
+        )
+        .into_bytes();
+        req.extend_from_slice(body);
+        conn.get_mut().write_all(&req)?;
+        let mut line = String::new();
+        conn.read_line(&mut line)?;
+        let status = line
+            .split_whitespace()
+            .nth(1)
+            .and_then(|x| x.parse().ok())
+            .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidData, line.clone()))?;
+        let mut len = 0;
+        loop {
+            line.clear();
+            conn.read_line(&mut line)?;
+            let header = line.trim_end();
+            if header.is_empty() {
+                break;
+            }
+            if let Some((name, val)) = header.split_once(':') {
+                if name.eq_ignore_ascii_case("content-length") {
+                    len = val.trim().parse().unwrap_or(0);
+                }
+            }
+        }
+        io::copy(&mut conn.by_ref().take(len), &mut io::sink())?;
+        Ok(status)
+    }
+}
+
+/// the latency of the fraction of requests below it
+fn percentile(sorted: &[u64], p: f64) -> u64 {
+    if sorted.is_empty() {
+        return 0;
+    }
+    sorted[((sorted.len() - 1) as f64 * p).round() as usize]
+}
+
+fn replay(events: &[TraceEvent], addr: String, speed: f64) -> serde_json::Value {
+    let mut client = Client { addr, conn: None };
+    let mut latencies = Vec::with_capacity(events.len());
+    let (mut failed, mut late_micros) = (0, 0u64);
+    let start = Instant::now();
+    for event in events {
+        let due = event.replay_offset(speed);
+        match due.checked_sub(start.elapsed()) {
+            Some(wait) => thread::sleep(wait),
+            None => late_micros = late_micros.max((start.elapsed() - due).as_micros() as u64),
+        }
+        let body = event.replay_cmd().to_string();
+        let sent = Instant::now();
+        match client.post(body.as_bytes()) {
+            Ok(200) => latencies.push(sent.elapsed().as_micros() as u64),
+            _ => failed += 1,
+        }
+    }
+    latencies.sort_unstable();
+    json!({
+        "events": events.len(),
+        "failed": failed,
+        "elapsedMs": start.elapsed().as_millis() as u64,
+        "maxLateMicros": late_micros,
+        "latencyMicros": {
+            "p50": percentile(&latencies, 0.5),
+            "p99": percentile(&latencies, 0.99),
+            "max": latencies.last().copied().unwrap_or(0),
+        },
+    })
+}
+
+fn main() {
+    let args: Vec<String> = env::args().collect();
+    if args.len() < 3 {
+        eprintln!("usage: replay <trace> <host:port> [speed]");
+        process::exit(2);
+    }
+    let events = read_trace(&args[1]).unwrap_or_else(|err| {
+        eprintln!("cannot read trace {}: {}", args[1], err);
+        process::exit(1);
+    });
+    let speed = match args.get(3).map(|x| x.parse::<f64>()) {
+        Some(Ok(speed)) => speed,
+        Some(Err(_)) => {
+            eprintln!("speed must be a number");
+            process::exit(2);
+        }
+        None => 1.0,
+    };
+    println!("{}", replay(&events, args[2].clone(), speed));
+}
@@ -0,0 +1,200 @@
+use crate::cmd::{Cmd, QueryCmd, Source};
+use crate::err::Error;
+use std::collections::BTreeSet;
+use std::str::FromStr;
+
+/// A family of cmds that a deployment can disable
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord)]
+pub enum Family {
+    /// cmds changing entries
+    Write,
+    /// cmds tuning or inspecting the server, rather than entries
+    Admin,
+    /// subscriptions to the changes of entries
+    PubSub,
+    /// queries prepared by name and executed later
+    Prepared,
+    /// matching strings against caller supplied regular expressions
+    Regex,
+    /// scripts of cmds binding their results to variables
+    Script,
+}
+
+impl Family {
+    pub fn name(&self) -> &'static str {
+        match self {
+            Family::Write => "write",
+            Family::Admin => "admin",
+            Family::PubSub => "pubsub",
+            Family::Prepared => "prepared",
+            Family::Regex => "regex",
+            Family::Script => "script",
+        }
+    }
+
+    /// the family of a cmd, ignoring its sub cmds; none if it can't be disabled
+    pub fn of(cmd: &Cmd) -> Option<Family> {
+        match cmd {
+            Cmd::Set(_, _, _)
+            | Cmd::GetSet(_, _)
+            | Cmd::Delete(_)
+            | Cmd::DeleteMany(_)
+            | Cmd::DeletePattern(_)
+            | Cmd::Append(_, _)
+            | Cmd::Push(_, _)
+            | Cmd::Pop(_)
+            | Cmd::Insert(_, _)
+            | Cmd::Merge(_, _)
+            | Cmd::MoveRows { .. }
+            | Cmd::Generate(_)
+            | Cmd::Expire(_, _)
+            | Cmd::ExpireAt(_, _)
+            | Cmd::Tx(_) => Some(Family::Write),
+            Cmd::Chaos(_)
+            | Cmd::Compress(_)
+            | Cmd::Backup(_)
+            | Cmd::Restore(_)
+            | Cmd::Intern(_)
+            | Cmd::CreateIndex { .. }
+            | Cmd::HotKeys(_)
+            | Cmd::Parallelism(_)
+            | Cmd::MetricsHistory { .. } => Some(Family::Admin),
+            Cmd::Subscribe(_, _)
+            | Cmd::SubscribeQuery(_)
+            | Cmd::Events(_)
+            | Cmd::Ack(_, _)
+            | Cmd::Unsubscribe(_) => Some(Family::PubSub),
+            Cmd::Prepare(_, _) | Cmd::Execute(_, _) => Some(Family::Prepared),
+            Cmd::Regex(_, _) => Some(Family::Regex),
+            Cmd::Script(_) => Some(Family::Script),
+            _ => None,
+        }
+    }
+}
+
+impl FromStr for Family {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self, Error> {
+        match s {
+            "write" => Ok(Family::Write),
+            "admin" => Ok(Family::Admin),
+            "pubsub" => Ok(Family::PubSub),
+            "prepared" => Ok(Family::Prepared),
+            "regex" => Ok(Family::Regex),
+            "script" => Ok(Family::Script),
+            _ => Err(Error::BadArg(s.into())),
+        }
+    }
+}
+
+/// The cmd families disabled in a deployment; every family is enabled by default
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct Capabilities {
+    disabled: BTreeSet<Family>,
+}
+
+impl Capabilities {
+    /// parses a comma separated list of the names of the disabled families
+    pub fn disabling(names: &str) -> Result<Self, Error> {
+        let disabled = names
+            .split(',')
+            .map(str::trim)
+            .filter(|name| !name.is_empty())
+            .map(Family::from_str)
+            .collect::<Result<_, _>>()?;
+        Ok(Capabilities { disabled })
+    }
+
+    /// checks if the cmds of a family are enabled
+    pub fn enables(&self, family: Family) -> bool {
+        !self.disabled.contains(&family)
+    }
+
+    /// checks the cmd and its sub cmds, including those of its queries, are all enabled
+    pub fn check(&self, cmd: &Cmd) -> Result<(), Error> {
+        if self.disabled.is_empty() {
+            return Ok(());
+        }
+        if let Some(family) = Family::of(cmd).filter(|x| self.disabled.contains(x)) {
+            return Err(Error::Disabled(family.name().to_string()));
+        }
+        match cmd {
+            Cmd::Query(qry)
+            | Cmd::Explain(qry)
+            | Cmd::Estimate(qry)
+            | Cmd::Validate(qry)
+            | Cmd::SubscribeQuery(qry)
+            | Cmd::Prepare(_, qry) => self.check_query(qry)?,
+            Cmd::Subscribe(_, Some(filter)) => self.check(filter)?,
+            _ => (),
+        }
+        cmd.children().into_iter().try_for_each(|x| self.check(x))
+    }
+
+    fn check_query(&self, qry: &QueryCmd) -> Result<(), Error> {
+        if let Source::Query(qry) = &qry.from {
+            self.check_query(qry)?;
+        }
+        for val in qry.filter.iter().chain(qry.post.iter().flatten()) {
+            // filters and post cmds that don't parse fail when the query is evaluated
+            if let Ok(cmd) = Cmd::parse(val.clone()) {
+                self.check(&cmd)?;
+            }
+        }
+        let selects = qry.selects.iter().flat_map(|x| x.values());
+        selects
+            .chain(qry.by.as_deref())
+            .try_for_each(|x| self.check(x))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    fn parse(val: serde_json::Value) -> Cmd {
+        Cmd::parse(val).unwrap()
+    }
+
+    #[test]
+    fn disabled_families_refused() {
+        let caps = Capabilities::disabling("write, regex").unwrap();
+        let set = parse(json!({"set": ["a", 1]}));
+        assert_eq!(Err(Error::Disabled("write".to_string())), caps.check(&set));
+        let batch = parse(json!({"batch": [{"get": ["a", {"key": "b"}]}, {"del": "a"}]}));
+        assert_eq!(
+            Err(Error::Disabled("write".to_string())),
+            caps.check(&batch)
+        );
+        let qry = parse(json!({"query": {
+            "from": "t",
+            "where": {"regex": [{"key": "name"}, "^a"]},
+        }}));
+        assert_eq!(Err(Error::Disabled("regex".to_string())), caps.check(&qry));
+        let qry = parse(json!({"query": {"from": "t", "where": {"==": [{"key": "a"}, 1]}}}));
+        assert_eq!(Ok(()), caps.check(&qry));
+        assert_eq!(Ok(()), caps.check(&parse(json!({"subscribe": ["a"]}))));
+        assert_eq!(Ok(()), Capabilities::default().check(&set));
+        let script = parse(json!({"script": [{"let": "x", "cmd": {"del": "a"}}]}));
+        assert_eq!(
+            Err(Error::Disabled("write".to_string())),
+            caps.check(&script)
+        );
+        let caps = Capabilities::disabling("script").unwrap();
+        assert_eq!(
+            Err(Error::Disabled("script".to_string())),
+            caps.check(&script)
+        );
+    }
+
+    #[test]
+    fn unknown_family_rejected() {
+        assert_eq!(
+            Err(Error::BadArg(json!("scripting"))),
+            Capabilities::disabling("admin,scripting")
+        );
+        assert_eq!(Ok(Capabilities::default()), Capabilities::disabling(""));
+    }
+}
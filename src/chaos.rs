@@ -0,0 +1,139 @@
+use crate::cmd::Cmd;
+use crate::err::Error;
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeSet;
+use std::thread;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+/// A fault to inject into matching cmds; a rule without a cmd or key matches every cmd
+#[derive(Clone, Debug, Default, Serialize, Deserialize, PartialEq)]
+pub struct ChaosRule {
+    /// the name of the cmd, e.g. `sum` or `query`
+    pub cmd: Option<String>,
+    /// a key read or written by the cmd
+    pub key: Option<String>,
+    #[serde(rename = "latencyMs")]
+    pub latency_ms: Option<u64>,
+    /// the fraction of matching cmds that fail, between 0 and 1
+    #[serde(rename = "errorRate")]
+    pub error_rate: Option<f64>,
+}
+
+/// the name of a cmd as it is written in json
+pub fn cmd_name(cmd: &Cmd) -> Option<String> {
+    match serde_json::to_value(cmd).ok()? {
+        serde_json::Value::Object(obj) => obj.keys().next().cloned(),
+        serde_json::Value::String(s) => Some(s),
+        _ => None,
+    }
+}
+
+/// The faults injected into cmds; only built with the chaos feature to test clients
+#[derive(Debug)]
+pub struct Chaos {
+    rules: Vec<ChaosRule>,
+    rng: u64,
+}
+
+impl Default for Chaos {
+    fn default() -> Self {
+        let seed = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map(|d| d.as_nanos() as u64)
+            .unwrap_or(0);
+        Self {
+            rules: Vec::new(),
+            rng: seed | 1,
+        }
+    }
+}
+
+impl Chaos {
+    /// adds a rule, or clears all rules if none
+    pub fn set(&mut self, rule: Option<ChaosRule>) {
+        match rule {
+            Some(rule) => self.rules.push(rule),
+            None => self.rules.clear(),
+        }
+    }
+
+    /// checks if any faults are injected
+    pub fn is_active(&self) -> bool {
+        !self.rules.is_empty()
+    }
+
+    /// a pseudo random number in [0, 1) from a xorshift generator
+    fn next_f64(&mut self) -> f64 {
+        self.rng ^= self.rng << 13;
+        self.rng ^= self.rng >> 7;
+        self.rng ^= self.rng << 17;
+        (self.rng >> 11) as f64 / (1u64 << 53) as f64
+    }
+
+    /// sleeps and fails as the rules matching the cmd dictate
+    pub fn inject(&mut self, cmd: &Cmd, keys: &BTreeSet<String>) -> Result<(), Error> {
+        if self.rules.is_empty() {
+            return Ok(());
+        }
+        let name = cmd_name(cmd);
+        let matching: Vec<ChaosRule> = self
+            .rules
+            .iter()
+            .filter(|rule| rule.cmd.is_none() || rule.cmd == name)
+            .filter(|rule| rule.key.as_ref().is_none_or(|key| keys.contains(key)))
+            .cloned()
+            .collect();
+        for rule in matching {
+            if let Some(ms) = rule.latency_ms {
+                thread::sleep(Duration::from_millis(ms));
+            }
+            if let Some(rate) = rule.error_rate {
+                if self.next_f64() < rate {
+                    return Err(Error::Chaos);
+                }
+            }
+        }
+        Ok(())
+    }
+}
+
+#[cfg(all(test, feature = "chaos"))]
+mod tests {
+    use super::*;
+    use crate::inmem::InMemDb;
+    use serde_json::json;
+    use std::time::Instant;
+
+    fn parse(val: serde_json::Value) -> Cmd {
+        Cmd::parse(val).unwrap()
+    }
+
+    #[test]
+    fn chaos_error_on_matching_cmd() {
+        let mut db = InMemDb::new();
+        db.set("a", json!([1, 2]));
+        let rule = json!({"chaos": {"cmd": "sum", "errorRate": 1.0}});
+        db.eval(parse(rule)).unwrap();
+        assert_eq!(
+            Err(Error::Chaos),
+            db.eval(parse(json!({"sum": {"key": "a"}})))
+        );
+        assert_eq!(Ok(json!([1, 2])), db.eval(parse(json!({"key": "a"}))));
+        db.eval(parse(json!({"chaos": null}))).unwrap();
+        assert_eq!(Ok(json!(3)), db.eval(parse(json!({"sum": {"key": "a"}}))));
+    }
+
+    #[test]
+    fn chaos_latency_on_matching_key() {
+        let mut db = InMemDb::new();
+        db.set("a", json!(1));
+        db.set("b", json!(2));
+        db.eval(parse(json!({"chaos": {"key": "a", "latencyMs": 50}})))
+            .unwrap();
+        let start = Instant::now();
+        db.eval(parse(json!({"key": "b"}))).unwrap();
+        assert!(start.elapsed() < Duration::from_millis(50));
+        db.eval(parse(json!({"key": "a"}))).unwrap();
+        assert!(start.elapsed() >= Duration::from_millis(50));
+    }
+}
@@ -1,36 +1,388 @@
+use crate::chaos::ChaosRule;
+use crate::dump::Codec;
 use crate::err::Error;
-use crate::json::{Json, JsonObj};
+use crate::json_ops::{json_size, json_type, Json, JsonObj};
+use crate::vector::Metric;
+use regex::Regex;
 use serde::{Deserialize, Serialize};
-use std::collections::HashMap;
+use std::collections::{BTreeSet, HashMap};
+use std::convert::TryFrom;
 
 #[derive(Clone, Debug, Serialize, Deserialize, PartialEq)]
 pub struct QueryCmd {
     #[serde(rename = "select")]
     pub selects: Option<HashMap<String, Cmd>>,
-    pub from: String,
+    pub from: Source,
     pub by: Option<Box<Cmd>>,
     #[serde(rename = "where")]
     pub filter: Option<Json>,
     #[serde(rename = "sort")]
     pub sort: Option<String>,
     pub descend: Option<bool>,
+    pub limit: Option<usize>,
+    /// the position of the first row kept, counted from the end when negative
+    pub offset: Option<i64>,
+    pub after: Option<Json>,
+    pub timeout_ms: Option<u64>,
+    /// a field of arrays whose elements each become a row with the parent fields
+    pub unnest: Option<String>,
+    pub knn: Option<Knn>,
+    /// cmds shaping the result in turn, each referring to the result of the one before as
+    /// `"$result"`
+    pub post: Option<Vec<Json>>,
+}
+
+/// Keeps the k rows whose vector field is nearest a vector, nearest first
+#[derive(Clone, Debug, Serialize, Deserialize, PartialEq)]
+pub struct Knn {
+    pub key: String,
+    pub vector: Vec<f64>,
+    pub k: usize,
+    #[serde(default)]
+    pub metric: Metric,
+}
+
+/// The rows a query runs against; either a key or the output of a nested query
+#[derive(Clone, Debug, Serialize, Deserialize, PartialEq)]
+#[serde(untagged)]
+pub enum Source {
+    Key(String),
+    /// the rows of several keys concatenated, each tagged with its key in `_src`
+    Keys(Vec<String>),
+    Query(Box<QueryCmd>),
 }
 
 impl QueryCmd {
     fn parse(json: Json) -> Result<Self, Error> {
         serde_json::from_value(json).map_err(|_| Error::Serialize)
     }
+
+    /// replaces the `"$name"` strings of the statements with the values of the variables
+    /// of a script
+    fn bind_vars(&mut self, vars: &JsonObj) {
+        if let Source::Query(qry) = &mut self.from {
+            qry.bind_vars(vars);
+        }
+        let selects = self.selects.iter_mut().flat_map(|x| x.values_mut());
+        selects
+            .chain(self.by.as_deref_mut())
+            .for_each(|x| x.bind_vars(vars));
+        let post = self.post.iter_mut().flatten();
+        for val in self.filter.iter_mut().chain(&mut self.after).chain(post) {
+            bind_json_vars(val, vars);
+        }
+    }
 }
 
-#[derive(Clone, Debug, Serialize, Deserialize, PartialEq)]
+/// A slice of a sequence; `start` is inclusive and `end` exclusive, and either counts
+/// from the end when negative. `size` caps the items picked and `step` skips between them
+#[derive(Clone, Debug, Default, Serialize, Deserialize, PartialEq)]
 pub struct Range {
-    pub start: Option<usize>,
+    pub start: Option<i64>,
     pub size: Option<usize>,
+    pub end: Option<i64>,
+    pub step: Option<usize>,
+}
+
+/// The positions a range picks from a sequence of a known length
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Positions {
+    pub start: usize,
+    pub step: usize,
+    pub n: usize,
+}
+
+impl Positions {
+    /// the items at the positions
+    pub fn pick<I: Iterator>(&self, items: I) -> impl Iterator<Item = I::Item> {
+        items.skip(self.start).step_by(self.step).take(self.n)
+    }
+}
+
+/// Restricts listed keys to entries with a json type and an approximate size in bytes
+#[derive(Clone, Debug, Default, Serialize, Deserialize, PartialEq)]
+pub struct KeyFilter {
+    #[serde(rename = "type")]
+    pub kind: Option<String>,
+    #[serde(rename = "minSize")]
+    pub min_size: Option<usize>,
+    #[serde(rename = "maxSize")]
+    pub max_size: Option<usize>,
+}
+
+impl KeyFilter {
+    /// checks if the value of an entry passes the filter
+    pub fn matches(&self, val: &Json) -> bool {
+        if let Some(kind) = &self.kind {
+            if kind != json_type(val) {
+                return false;
+            }
+        }
+        if self.min_size.is_none() && self.max_size.is_none() {
+            return true;
+        }
+        let size = json_size(val);
+        self.min_size.is_none_or(|n| size >= n) && self.max_size.is_none_or(|n| size <= n)
+    }
+}
+
+/// The thresholds from which queries are evaluated across threads; unset thresholds are
+/// left as they are
+#[derive(Clone, Debug, Default, Serialize, Deserialize, PartialEq)]
+pub struct Parallelism {
+    /// the number of rows
+    pub rows: Option<usize>,
+    /// the approximate bytes of the rows, however few they are
+    pub bytes: Option<usize>,
+}
+
+/// A lexicographic range of keys; `from` is inclusive and `to` is exclusive
+#[derive(Clone, Debug, Default, Serialize, Deserialize, PartialEq)]
+pub struct KeyRange {
+    pub from: Option<String>,
+    pub to: Option<String>,
+    pub reverse: Option<bool>,
+    pub limit: Option<usize>,
+    pub values: Option<bool>,
+    /// a slice of the keys within the range, in their listed order
+    #[serde(flatten)]
+    pub page: Range,
+}
+
+/// Fills a key with n rows made from a template, to try queries without preparing a
+/// dataset; see `generate_rows` for the template syntax
+#[derive(Clone, Debug, Serialize, Deserialize, PartialEq)]
+pub struct Generate {
+    pub table: String,
+    pub n: usize,
+    pub template: Json,
+    /// makes the same rows for the same seed
+    pub seed: Option<u64>,
+}
+
+/// Writes a compressed snapshot of every entry to a file, read back by `restore`
+#[derive(Clone, Debug, Serialize, Deserialize, PartialEq)]
+pub struct Backup {
+    /// the name of the file in the backup dir
+    pub path: String,
+    #[serde(default)]
+    pub compress: Codec,
+}
+
+/// A statement of a script; its result is bound to the name, if any, so the statements
+/// after it can refer to it as `"$name"`
+#[derive(Clone, Debug, Serialize, Deserialize, PartialEq)]
+pub struct Stmt {
+    #[serde(rename = "let")]
+    pub name: Option<String>,
+    pub cmd: Cmd,
+}
+
+/// A page of the keys matching a glob pattern, starting after the cursor; the cursor is
+/// the last key of the page before
+#[derive(Clone, Debug, Serialize, Deserialize, PartialEq)]
+pub struct KeyScan {
+    #[serde(with = "glob_pattern")]
+    pub pattern: Pattern,
+    pub cursor: Option<String>,
+    pub count: Option<usize>,
+}
+
+impl KeyScan {
+    /// the literal text the matching keys start with, so only the keys within it are read
+    pub fn prefix(&self) -> &str {
+        let src = self.pattern.as_str();
+        &src[..src.find(['*', '?']).unwrap_or(src.len())]
+    }
+}
+
+/// Walks the links between rows out to a depth from the rows of a key. The edge field of
+/// a row links to rows by their `_id`: an id links to a row of the same key, and
+/// `{"key": .., "id": ..}` to a row of another key; the field may hold an array of links
+#[derive(Clone, Debug, Serialize, Deserialize, PartialEq)]
+#[serde(deny_unknown_fields)]
+pub struct Traverse {
+    pub from: String,
+    pub edge: String,
+    #[serde(default = "one")]
+    pub depth: usize,
+    /// the ids of the rows of the key to start from; every row if none
+    pub start: Option<Vec<Json>>,
+}
+
+fn one() -> usize {
+    1
+}
+
+/// A regular expression compiled once when the cmd is parsed
+#[derive(Clone, Debug, Serialize, Deserialize)]
+#[serde(try_from = "String", into = "String")]
+pub struct Pattern {
+    src: String,
+    re: Regex,
+}
+
+impl Pattern {
+    /// compile a regular expression
+    pub fn regex(src: String) -> Result<Self, Error> {
+        let re = Regex::new(&src).map_err(|_| Error::BadArg(Json::from(src.clone())))?;
+        Ok(Self { src, re })
+    }
+
+    /// compile a sql like pattern where `%` matches any text and `_` any single char
+    pub fn like(src: String) -> Result<Self, Error> {
+        let mut re = String::from("(?s)^");
+        for c in src.chars() {
+            match c {
+                '%' => re.push_str(".*"),
+                '_' => re.push('.'),
+                c => re.push_str(&regex::escape(&c.to_string())),
+            }
+        }
+        re.push('$');
+        let re = Regex::new(&re).map_err(|_| Error::BadArg(Json::from(src.clone())))?;
+        Ok(Self { src, re })
+    }
+
+    /// compile a glob pattern where `*` matches any text and `?` any single char
+    pub fn glob(src: String) -> Result<Self, Error> {
+        let mut re = String::from("(?s)^");
+        for c in src.chars() {
+            match c {
+                '*' => re.push_str(".*"),
+                '?' => re.push('.'),
+                c => re.push_str(&regex::escape(&c.to_string())),
+            }
+        }
+        re.push('$');
+        let re = Regex::new(&re).map_err(|_| Error::BadArg(Json::from(src.clone())))?;
+        Ok(Self { src, re })
+    }
+
+    /// checks if the pattern matches the text
+    pub fn is_match(&self, text: &str) -> bool {
+        self.re.is_match(text)
+    }
+
+    /// the pattern as written
+    pub fn as_str(&self) -> &str {
+        &self.src
+    }
+}
+
+impl PartialEq for Pattern {
+    fn eq(&self, other: &Self) -> bool {
+        self.src == other.src && self.re.as_str() == other.re.as_str()
+    }
+}
+
+impl TryFrom<String> for Pattern {
+    type Error = Error;
+
+    fn try_from(src: String) -> Result<Self, Error> {
+        Pattern::regex(src)
+    }
+}
+
+impl From<Pattern> for String {
+    fn from(pat: Pattern) -> String {
+        pat.src
+    }
+}
+
+/// serde support for like patterns, which are kept in their sql form
+mod like_pattern {
+    use super::Pattern;
+    use serde::de::Error;
+    use serde::{Deserialize, Deserializer, Serializer};
+
+    pub fn serialize<S: Serializer>(pat: &Pattern, s: S) -> Result<S::Ok, S::Error> {
+        s.serialize_str(&pat.src)
+    }
+
+    pub fn deserialize<'de, D: Deserializer<'de>>(d: D) -> Result<Pattern, D::Error> {
+        let src = String::deserialize(d)?;
+        Pattern::like(src).map_err(D::Error::custom)
+    }
+}
+
+/// The condition on whether a key exists for a set to happen
+#[derive(Clone, Copy, Debug, Serialize, Deserialize, PartialEq)]
+pub enum SetCond {
+    /// only set a missing key
+    #[serde(rename = "nx")]
+    Nx,
+    /// only set an existing key
+    #[serde(rename = "xx")]
+    Xx,
+}
+
+impl SetCond {
+    /// checks if a key that does or doesn't exist may be set
+    pub fn holds(self, exists: bool) -> bool {
+        match self {
+            SetCond::Nx => !exists,
+            SetCond::Xx => exists,
+        }
+    }
+}
+
+/// serde support for glob patterns, which are kept in their glob form
+mod glob_pattern {
+    use super::Pattern;
+    use serde::de::Error;
+    use serde::{Deserialize, Deserializer, Serializer};
+
+    pub fn serialize<S: Serializer>(pat: &Pattern, s: S) -> Result<S::Ok, S::Error> {
+        s.serialize_str(&pat.src)
+    }
+
+    pub fn deserialize<'de, D: Deserializer<'de>>(d: D) -> Result<Pattern, D::Error> {
+        let src = String::deserialize(d)?;
+        Pattern::glob(src).map_err(D::Error::custom)
+    }
 }
 
 impl Range {
     pub fn has_indices(&self) -> bool {
-        self.start.is_some() || self.size.is_some()
+        self.start.is_some() || self.size.is_some() || self.end.is_some() || self.step.is_some()
+    }
+
+    /// the range, picking at most n items unless it already has an end or a size
+    pub fn or_size(mut self, n: usize) -> Self {
+        if self.end.is_none() {
+            self.size = self.size.or(Some(n));
+        }
+        self
+    }
+
+    /// resolves the bounds of the range against a sequence of len items; bounds past
+    /// either end are clamped rather than an error
+    pub fn positions(&self, len: usize) -> Result<Positions, Error> {
+        let step = self.step.unwrap_or(1);
+        if step == 0 {
+            return Err(Error::BadArg(Json::from(step)));
+        }
+        let at = |i: i64| match usize::try_from(i) {
+            Ok(i) => i.min(len),
+            Err(_) => len.saturating_sub(i.unsigned_abs() as usize),
+        };
+        let start = self.start.map_or(0, at);
+        let end = self.end.map_or(len, at).max(start);
+        let n = (end - start).div_ceil(step);
+        let n = self.size.map_or(n, |size| size.min(n));
+        Ok(Positions { start, step, n })
+    }
+
+    /// the elements of an array within the range
+    pub fn slice(&self, val: Json) -> Result<Json, Error> {
+        match val {
+            Json::Array(arr) => {
+                let at = self.positions(arr.len())?;
+                Ok(at.pick(arr.into_iter()).collect())
+            }
+            _ => Err(Error::ExpectedArr),
+        }
     }
 }
 
@@ -40,16 +392,48 @@ pub enum Cmd {
     Add(Box<Cmd>, Box<Cmd>),
     #[serde(rename = "&&")]
     And(Box<Cmd>, Box<Cmd>),
+    #[serde(rename = "ack")]
+    Ack(u64, u64),
     #[serde(rename = "append")]
     Append(String, Box<Cmd>),
     #[serde(rename = "apply")]
     Apply(Box<Cmd>, Box<Cmd>),
     #[serde(rename = "avg")]
     Avg(Box<Cmd>),
+    #[serde(rename = "backup")]
+    Backup(Backup),
     #[serde(rename = "bar")]
     Bar(Box<Cmd>, Box<Cmd>),
+    #[serde(rename = "batch")]
+    Batch(Vec<Cmd>),
+    #[serde(rename = "between")]
+    Between(Box<Cmd>, Box<Cmd>, Box<Cmd>),
+    #[serde(rename = "chaos")]
+    Chaos(Option<ChaosRule>),
+    #[serde(rename = "collect")]
+    Collect(Box<Cmd>),
+    #[serde(rename = "compress")]
+    Compress(usize),
+    #[serde(rename = "count", with = "count_arg")]
+    Count(Option<Box<Cmd>>),
+    #[serde(rename = "count_distinct")]
+    CountDistinct(Box<Cmd>),
+    /// the approximate k most frequent values, for columns with too many distinct values
+    /// to group by
+    #[serde(rename = "heavy_hitters", with = "heavy_hitters_arg")]
+    HeavyHitters(Box<Cmd>, usize),
+    #[serde(rename = "generate")]
+    Generate(Generate),
+    #[serde(rename = "createIndex")]
+    CreateIndex { key: String, field: String },
     #[serde(rename = "del")]
     Delete(String),
+    #[serde(rename = "delMany")]
+    DeleteMany(Vec<String>),
+    #[serde(rename = "delPattern")]
+    DeletePattern(#[serde(with = "glob_pattern")] Pattern),
+    #[serde(rename = "diff")]
+    Diff(Box<Cmd>, Box<Cmd>),
     #[serde(rename = "/")]
     Div(Box<Cmd>, Box<Cmd>),
     #[serde(rename = "dev")]
@@ -58,6 +442,21 @@ pub enum Cmd {
     Eval(Vec<Cmd>),
     #[serde(rename = "==")]
     Eq(Box<Cmd>, Box<Cmd>),
+    /// approximates the rows and bytes of the result of a query without evaluating it
+    #[serde(rename = "estimate")]
+    Estimate(QueryCmd),
+    #[serde(rename = "events")]
+    Events(u64),
+    #[serde(rename = "execute")]
+    Execute(String, JsonObj),
+    /// expires a key after a number of seconds
+    #[serde(rename = "expire")]
+    Expire(String, f64),
+    /// expires a key at a unix time in seconds
+    #[serde(rename = "expireAt")]
+    ExpireAt(String, f64),
+    #[serde(rename = "explain")]
+    Explain(QueryCmd),
     #[serde(rename = "first")]
     First(Box<Cmd>),
     #[serde(rename = "flat")]
@@ -68,22 +467,42 @@ pub enum Cmd {
     Gt(Box<Cmd>, Box<Cmd>),
     #[serde(rename = ">=")]
     Gte(Box<Cmd>, Box<Cmd>),
+    #[serde(rename = "getSet")]
+    GetSet(String, Box<Cmd>),
     #[serde(rename = "has")]
     Has(String),
+    #[serde(rename = "hotKeys")]
+    HotKeys(usize),
     #[serde(rename = "in")]
     In(Box<Cmd>, Box<Cmd>),
     #[serde(rename = "insert")]
     Insert(String, Vec<JsonObj>),
+    #[serde(rename = "join")]
+    Join(Box<Cmd>, String),
+    #[serde(rename = "intern")]
+    Intern(usize),
+    #[serde(rename = "is_null")]
+    IsNull(String),
     #[serde(rename = "json")]
     Json(Json),
     #[serde(rename = "key")]
     Key(String),
     #[serde(rename = "keys")]
-    Keys(Option<Range>),
+    Keys(Option<Range>, Option<KeyFilter>),
+    #[serde(rename = "keyRange")]
+    KeyRange(KeyRange),
+    #[serde(rename = "scan")]
+    Scan(KeyScan),
+    #[serde(rename = "script")]
+    Script(Vec<Stmt>),
+    #[serde(rename = "traverse")]
+    Traverse(Traverse),
     #[serde(rename = "last")]
     Last(Box<Cmd>),
     #[serde(rename = "len")]
-    Len(Box<Cmd>),
+    Len(Option<Box<Cmd>>),
+    #[serde(rename = "like")]
+    Like(Box<Cmd>, #[serde(with = "like_pattern")] Pattern),
     #[serde(rename = "<")]
     Lt(Box<Cmd>, Box<Cmd>),
     #[serde(rename = "<=")]
@@ -94,6 +513,29 @@ pub enum Cmd {
     Max(Box<Cmd>),
     #[serde(rename = "median")]
     Median(Box<Cmd>),
+    #[serde(rename = "merge")]
+    Merge(String, Json),
+    /// the ops/s, p99 latency and memory of each of the last minutes with cmds, oldest
+    /// first
+    #[serde(rename = "metricsHistory")]
+    MetricsHistory { minutes: usize },
+    /// the value of a key, which may be a dotted path, in an envelope telling apart the
+    /// outcomes: `{"status": "found", "val": ..}`, `{"status": "null"}`,
+    /// `{"status": "missingKey"}` or `{"status": "missingPath"}`
+    #[serde(rename = "lookup")]
+    Lookup(String),
+    #[serde(rename = "mget")]
+    MGet(Vec<String>),
+    /// removes the rows of a table passing a filter and appends them to another, in one
+    /// step so no row is lost or copied. The filter reads the fields of each row, as a
+    /// query where does
+    #[serde(rename = "moveRows")]
+    MoveRows {
+        from: String,
+        to: String,
+        #[serde(rename = "where")]
+        filter: Box<Cmd>,
+    },
     #[serde(rename = "min")]
     Min(Box<Cmd>),
     #[serde(rename = "*")]
@@ -104,36 +546,126 @@ pub enum Cmd {
     NumSort(Box<Cmd>, bool),
     #[serde(rename = "||")]
     Or(Box<Cmd>, Box<Cmd>),
+    /// tunes the thresholds from which queries are evaluated across threads, returning
+    /// the thresholds in force
+    #[serde(rename = "parallelism")]
+    Parallelism(Parallelism),
+    #[serde(rename = "param")]
+    Param(String),
+    #[serde(rename = "prepare")]
+    Prepare(String, QueryCmd),
     #[serde(rename = "push")]
     Push(String, Box<Cmd>),
     #[serde(rename = "pop")]
     Pop(String),
     #[serde(rename = "query")]
     Query(QueryCmd),
+    #[serde(rename = "regex")]
+    Regex(Box<Cmd>, Pattern),
+    /// replaces the entries of the keys in a backup, named by its file in the backup dir,
+    /// with their values in it, in one tx; other entries are kept
+    #[serde(rename = "restore")]
+    Restore(String),
     #[serde(rename = "reverse")]
     Reverse(Box<Cmd>),
     #[serde(rename = "set")]
-    Set(String, Box<Cmd>),
+    Set(String, Box<Cmd>, Option<SetCond>),
+    #[serde(rename = "sizeOf")]
+    SizeOf(String),
     #[serde(rename = "slice")]
     Slice(Box<Cmd>, Range),
+    #[serde(rename = "subscribeQuery")]
+    SubscribeQuery(QueryCmd),
+    #[serde(rename = "subscribe")]
+    Subscribe(Vec<String>, Option<Box<Cmd>>),
     #[serde(rename = "sum")]
     Sum(Box<Cmd>),
     #[serde(rename = "-")]
     Sub(Box<Cmd>, Box<Cmd>),
     #[serde(rename = "summary")]
-    Summary,
+    Summary(Option<KeyFilter>),
     #[serde(rename = "sort")]
     Sort(Box<Cmd>, Option<bool>),
     #[serde(rename = "sortBy")]
     SortBy(Box<Cmd>, String),
     #[serde(rename = "str")]
     ToString(Box<Cmd>),
+    /// the seconds left before a key expires
+    #[serde(rename = "ttl")]
+    Ttl(String),
+    #[serde(rename = "tx")]
+    Tx(Vec<Cmd>),
+    #[serde(rename = "unsubscribe")]
+    Unsubscribe(u64),
     #[serde(rename = "unique")]
     Unique(Box<Cmd>),
+    #[serde(rename = "validate")]
+    Validate(QueryCmd),
     #[serde(rename = "var")]
     Var(Box<Cmd>),
 }
 
+/// serde support for the count argument, where `"*"` counts every row
+mod count_arg {
+    use super::Cmd;
+    use crate::json_ops::Json;
+    use serde::de::Error;
+    use serde::{Deserialize, Deserializer, Serialize, Serializer};
+
+    pub fn serialize<S: Serializer>(arg: &Option<Box<Cmd>>, s: S) -> Result<S::Ok, S::Error> {
+        match arg {
+            Some(cmd) => cmd.serialize(s),
+            None => s.serialize_str("*"),
+        }
+    }
+
+    pub fn deserialize<'de, D: Deserializer<'de>>(d: D) -> Result<Option<Box<Cmd>>, D::Error> {
+        match Json::deserialize(d)? {
+            Json::String(s) if s == "*" => Ok(None),
+            val => serde_json::from_value(val)
+                .map(|cmd| Some(Box::new(cmd)))
+                .map_err(D::Error::custom),
+        }
+    }
+}
+
+/// the `k` of a heavy hitters cmd sits alongside the cmd of its values, as in
+/// `{"key": "url", "k": 20}`
+mod heavy_hitters_arg {
+    use super::Cmd;
+    use crate::json_ops::Json;
+    use serde::de::Error;
+    use serde::{Deserialize, Deserializer, Serialize, Serializer};
+
+    /// splits the `k` from the cmd of the values; the bad k if it isn't a positive integer
+    pub fn split_k(mut val: Json) -> Result<(Json, usize), Json> {
+        let k = val
+            .as_object_mut()
+            .and_then(|obj| obj.remove("k"))
+            .unwrap_or(Json::Null);
+        match k.as_u64() {
+            Some(k) if k > 0 => Ok((val, k as usize)),
+            _ => Err(k),
+        }
+    }
+
+    #[allow(clippy::borrowed_box)]
+    pub fn serialize<S: Serializer>(arg: &Box<Cmd>, k: &usize, s: S) -> Result<S::Ok, S::Error> {
+        let mut val = serde_json::to_value(arg).map_err(serde::ser::Error::custom)?;
+        if let Json::Object(obj) = &mut val {
+            obj.insert("k".to_string(), Json::from(*k));
+        }
+        val.serialize(s)
+    }
+
+    pub fn deserialize<'de, D: Deserializer<'de>>(d: D) -> Result<(Box<Cmd>, usize), D::Error> {
+        let (val, k) = split_k(Json::deserialize(d)?)
+            .map_err(|k| D::Error::custom(format!("bad k: {}", k)))?;
+        let cmd = serde_json::from_value(val).map_err(D::Error::custom)?;
+        Ok((Box::new(cmd), k))
+    }
+}
+
 fn parse_bin_fn<F>(arg: Json, f: F) -> Result<Cmd, Error>
 where
     F: FnOnce(Box<Cmd>, Box<Cmd>) -> Cmd,
@@ -151,6 +683,24 @@ where
     }
 }
 
+fn parse_tri_fn<F>(arg: Json, f: F) -> Result<Cmd, Error>
+where
+    F: FnOnce(Box<Cmd>, Box<Cmd>, Box<Cmd>) -> Cmd,
+{
+    match arg {
+        Json::Array(mut arr) => {
+            if arr.len() != 3 {
+                return Err(Error::BadCmd);
+            }
+            let z = Box::new(Cmd::parse(arr.pop().unwrap())?);
+            let y = Box::new(Cmd::parse(arr.pop().unwrap())?);
+            let x = Box::new(Cmd::parse(arr.pop().unwrap())?);
+            Ok(f(x, y, z))
+        }
+        _ => Err(Error::BadCmd),
+    }
+}
+
 fn parse_unr_fn<F>(arg: Json, f: F) -> Result<Cmd, Error>
 where
     F: FnOnce(Box<Cmd>) -> Cmd,
@@ -159,6 +709,11 @@ where
     Ok(f(Box::new(val)))
 }
 
+fn parse_heavy_hitters(val: Json) -> Result<Cmd, Error> {
+    let (val, k) = heavy_hitters_arg::split_k(val).map_err(Error::BadArg)?;
+    Ok(Cmd::HeavyHitters(Box::new(Cmd::parse(val)?), k))
+}
+
 fn parse_b_str_fn<F>(val: Json, f: F) -> Result<Cmd, Error>
 where
     F: FnOnce(String, Box<Cmd>) -> Cmd,
@@ -179,6 +734,136 @@ where
     }
 }
 
+/// parses a key and value, optionally followed by `nx` or `xx`
+fn parse_set(val: Json) -> Result<Cmd, Error> {
+    let mut arr = match val {
+        Json::Array(arr) if arr.len() == 3 => arr,
+        val => return parse_b_str_fn(val, |key, arg| Cmd::Set(key, arg, None)),
+    };
+    let cond = arr.pop().unwrap();
+    let cond = serde_json::from_value(cond.clone()).map_err(|_| Error::BadArg(cond))?;
+    parse_b_str_fn(Json::Array(arr), |key, arg| Cmd::Set(key, arg, Some(cond)))
+}
+
+/// parses the statements of a script; either `{"let": name, "cmd": cmd}` or a bare cmd
+fn parse_script(val: Json) -> Result<Cmd, Error> {
+    #[derive(Deserialize)]
+    #[serde(deny_unknown_fields)]
+    struct Let {
+        #[serde(rename = "let")]
+        name: String,
+        cmd: Json,
+    }
+    let parse_stmt = |val: Json| {
+        if val.get("let").is_none() {
+            let cmd = Cmd::parse(val)?;
+            return Ok(Stmt { name: None, cmd });
+        }
+        match serde_json::from_value::<Let>(val.clone()) {
+            Ok(stmt) => Ok(Stmt {
+                name: Some(stmt.name),
+                cmd: Cmd::parse(stmt.cmd)?,
+            }),
+            Err(_) => Err(Error::BadArg(val)),
+        }
+    };
+    match val {
+        Json::Array(arr) => arr.into_iter().map(parse_stmt).collect::<Result<_, _>>(),
+        val => Err(Error::BadArg(val)),
+    }
+    .map(Cmd::Script)
+}
+
+/// replaces the `"$name"` strings within a value with the values of the variables
+fn bind_json_vars(val: &mut Json, vars: &JsonObj) {
+    match val {
+        Json::String(s) => {
+            if let Some(bound) = s.strip_prefix('$').and_then(|name| vars.get(name)) {
+                *val = bound.clone();
+            }
+        }
+        Json::Array(vals) => vals.iter_mut().for_each(|x| bind_json_vars(x, vars)),
+        Json::Object(obj) => obj.values_mut().for_each(|x| bind_json_vars(x, vars)),
+        _ => (),
+    }
+}
+
+/// parses the tables and where filter of a move of rows
+fn parse_move_rows(val: Json) -> Result<Cmd, Error> {
+    #[derive(Deserialize)]
+    #[serde(deny_unknown_fields)]
+    struct MoveRows {
+        from: String,
+        to: String,
+        #[serde(rename = "where")]
+        filter: Json,
+    }
+    let arg: MoveRows = serde_json::from_value(val.clone()).map_err(|_| Error::BadArg(val))?;
+    Ok(Cmd::MoveRows {
+        from: arg.from,
+        to: arg.to,
+        filter: Box::new(Cmd::parse(arg.filter)?),
+    })
+}
+
+/// parses an array of cmds
+fn parse_cmds(val: Json) -> Result<Vec<Cmd>, Error> {
+    match val {
+        Json::Array(arr) => arr.into_iter().map(Cmd::parse).collect(),
+        val => Err(Error::BadArg(val)),
+    }
+}
+
+/// parses the keys subscribed to, either alone or with a filter as in a query where
+fn parse_subscribe(val: Json) -> Result<Cmd, Error> {
+    #[derive(Deserialize)]
+    struct Subscription {
+        #[serde(default)]
+        keys: Vec<String>,
+        #[serde(rename = "where")]
+        filter: Option<Json>,
+    }
+    if let Ok(keys) = serde_json::from_value(val.clone()) {
+        return Ok(Cmd::Subscribe(keys, None));
+    }
+    let sub: Subscription = serde_json::from_value(val.clone()).map_err(|_| Error::BadArg(val))?;
+    let filter = match sub.filter {
+        Some(filter) => Some(Box::new(Cmd::parse(filter)?)),
+        None => None,
+    };
+    Ok(Cmd::Subscribe(sub.keys, filter))
+}
+
+fn parse_prepare(val: Json) -> Result<Cmd, Error> {
+    match val {
+        Json::Array(mut arr) if arr.len() == 2 => {
+            let qry = QueryCmd::parse(arr.pop().unwrap())?;
+            match arr.pop().unwrap() {
+                Json::String(name) => Ok(Cmd::Prepare(name, qry)),
+                val => Err(Error::BadArg(val)),
+            }
+        }
+        _ => Err(Error::BadCmd),
+    }
+}
+
+fn parse_execute(val: Json) -> Result<Cmd, Error> {
+    match val {
+        Json::String(name) => Ok(Cmd::Execute(name, JsonObj::new())),
+        Json::Array(mut arr) if arr.len() == 2 => {
+            let params = match arr.pop().unwrap() {
+                Json::Object(obj) => obj,
+                val => return Err(Error::BadArg(val)),
+            };
+            match arr.pop().unwrap() {
+                Json::String(name) => Ok(Cmd::Execute(name, params)),
+                val => Err(Error::BadArg(val)),
+            }
+        }
+        _ => Err(Error::BadCmd),
+    }
+}
+
 fn parse_insert(val: Json) -> Result<Cmd, Error> {
     match val {
         Json::Array(mut arr) => {
@@ -196,12 +881,12 @@ fn parse_insert(val: Json) -> Result<Cmd, Error> {
                     for val in arr {
                         match val {
                             Json::Object(obj) => rows.push(obj),
-                            _ => return Err(Error::BadCmd),
+                            val => return Err(Error::BadArg(val)),
                         }
                     }
                     rows
                 }
-                _ => return Err(Error::BadCmd),
+                val => return Err(Error::BadArg(val)),
             };
             Ok(Cmd::Insert(key, rows))
         }
@@ -209,6 +894,51 @@ fn parse_insert(val: Json) -> Result<Cmd, Error> {
     }
 }
 
+fn parse_count(arg: Json) -> Result<Cmd, Error> {
+    match arg {
+        Json::String(s) if s == "*" => Ok(Cmd::Count(None)),
+        val => Ok(Cmd::Count(Some(Box::new(Cmd::parse(val)?)))),
+    }
+}
+
+fn parse_pattern<F>(arg: Json, f: F) -> Result<Cmd, Error>
+where
+    F: FnOnce(Box<Cmd>, String) -> Result<Cmd, Error>,
+{
+    match arg {
+        Json::Array(mut arr) => {
+            if arr.len() != 2 {
+                return Err(Error::BadCmd);
+            }
+            let pat = match arr.pop().unwrap() {
+                Json::String(s) => s,
+                val => return Err(Error::BadArg(val)),
+            };
+            let arg = Cmd::parse(arr.pop().unwrap())?;
+            f(Box::new(arg), pat)
+        }
+        _ => Err(Error::BadCmd),
+    }
+}
+
+/// parses the paging and filtering of listed keys from a single object
+fn parse_keys<F>(arg: Json, f: F) -> Result<Cmd, Error>
+where
+    F: FnOnce(Option<Range>, Option<KeyFilter>) -> Cmd,
+{
+    match arg {
+        Json::Null => Ok(f(None, None)),
+        val @ Json::Object(_) => {
+            let page: Range = serde_json::from_value(val.clone()).map_err(|_| Error::BadCmd)?;
+            let filter: KeyFilter = serde_json::from_value(val).map_err(|_| Error::BadCmd)?;
+            let page = Some(page).filter(|x| x.has_indices());
+            let filter = Some(filter).filter(|x| *x != KeyFilter::default());
+            Ok(f(page, filter))
+        }
+        val => Err(Error::BadArg(val)),
+    }
+}
+
 fn parse_unr_str_fn<F>(arg: Json, f: F) -> Result<Cmd, Error>
 where
     F: FnOnce(String) -> Cmd,
@@ -220,6 +950,205 @@ where
 }
 
 impl Cmd {
+    /// checks if the cmd reduces many rows to a single value
+    pub fn is_aggregate(&self) -> bool {
+        matches!(
+            self,
+            Cmd::Avg(_)
+                | Cmd::Collect(_)
+                | Cmd::Count(_)
+                | Cmd::CountDistinct(_)
+                | Cmd::Dev(_)
+                | Cmd::First(_)
+                | Cmd::HeavyHitters(_, _)
+                | Cmd::Join(_, _)
+                | Cmd::Last(_)
+                | Cmd::Len(_)
+                | Cmd::Max(_)
+                | Cmd::Median(_)
+                | Cmd::Min(_)
+                | Cmd::Sum(_)
+                | Cmd::Var(_)
+        )
+    }
+
+    /// the sub commands that are arguments of this cmd
+    pub fn children(&self) -> Vec<&Cmd> {
+        match self {
+            Cmd::Add(x, y)
+            | Cmd::And(x, y)
+            | Cmd::Apply(x, y)
+            | Cmd::Bar(x, y)
+            | Cmd::Diff(x, y)
+            | Cmd::Div(x, y)
+            | Cmd::Eq(x, y)
+            | Cmd::Gt(x, y)
+            | Cmd::Gte(x, y)
+            | Cmd::In(x, y)
+            | Cmd::Lt(x, y)
+            | Cmd::Lte(x, y)
+            | Cmd::Mul(x, y)
+            | Cmd::NotEq(x, y)
+            | Cmd::Or(x, y)
+            | Cmd::Sub(x, y) => vec![x, y],
+            Cmd::Append(_, x)
+            | Cmd::Avg(x)
+            | Cmd::Collect(x)
+            | Cmd::CountDistinct(x)
+            | Cmd::Dev(x)
+            | Cmd::First(x)
+            | Cmd::Flat(x)
+            | Cmd::Get(_, x)
+            | Cmd::HeavyHitters(x, _)
+            | Cmd::Join(x, _)
+            | Cmd::Last(x)
+            | Cmd::Like(x, _)
+            | Cmd::Map(x, _)
+            | Cmd::Max(x)
+            | Cmd::Median(x)
+            | Cmd::Min(x)
+            | Cmd::NumSort(x, _)
+            | Cmd::Push(_, x)
+            | Cmd::Regex(x, _)
+            | Cmd::Reverse(x)
+            | Cmd::Set(_, x, _)
+            | Cmd::GetSet(_, x)
+            | Cmd::Slice(x, _)
+            | Cmd::Sort(x, _)
+            | Cmd::SortBy(x, _)
+            | Cmd::Sum(x)
+            | Cmd::ToString(x)
+            | Cmd::Unique(x)
+            | Cmd::Var(x) => vec![x],
+            Cmd::Count(Some(x)) | Cmd::Len(Some(x)) => vec![x],
+            Cmd::Between(x, lo, hi) => vec![x, lo, hi],
+            Cmd::Eval(cmds) | Cmd::Batch(cmds) | Cmd::Tx(cmds) => cmds.iter().collect(),
+            Cmd::Script(stmts) => stmts.iter().map(|x| &x.cmd).collect(),
+            _ => Vec::new(),
+        }
+    }
+
+    /// the sub cmds of a cmd that can be replaced
+    fn children_mut(&mut self) -> Vec<&mut Cmd> {
+        match self {
+            Cmd::Add(x, y)
+            | Cmd::And(x, y)
+            | Cmd::Apply(x, y)
+            | Cmd::Bar(x, y)
+            | Cmd::Diff(x, y)
+            | Cmd::Div(x, y)
+            | Cmd::Eq(x, y)
+            | Cmd::Gt(x, y)
+            | Cmd::Gte(x, y)
+            | Cmd::In(x, y)
+            | Cmd::Lt(x, y)
+            | Cmd::Lte(x, y)
+            | Cmd::Mul(x, y)
+            | Cmd::NotEq(x, y)
+            | Cmd::Or(x, y)
+            | Cmd::Sub(x, y) => vec![x, y],
+            Cmd::Append(_, x)
+            | Cmd::Avg(x)
+            | Cmd::Collect(x)
+            | Cmd::CountDistinct(x)
+            | Cmd::Dev(x)
+            | Cmd::First(x)
+            | Cmd::Flat(x)
+            | Cmd::Get(_, x)
+            | Cmd::HeavyHitters(x, _)
+            | Cmd::Join(x, _)
+            | Cmd::Last(x)
+            | Cmd::Like(x, _)
+            | Cmd::Map(x, _)
+            | Cmd::Max(x)
+            | Cmd::Median(x)
+            | Cmd::Min(x)
+            | Cmd::NumSort(x, _)
+            | Cmd::Push(_, x)
+            | Cmd::Regex(x, _)
+            | Cmd::Reverse(x)
+            | Cmd::Set(_, x, _)
+            | Cmd::GetSet(_, x)
+            | Cmd::Slice(x, _)
+            | Cmd::Sort(x, _)
+            | Cmd::SortBy(x, _)
+            | Cmd::Sum(x)
+            | Cmd::ToString(x)
+            | Cmd::Unique(x)
+            | Cmd::Var(x) => vec![x],
+            Cmd::Count(Some(x)) | Cmd::Len(Some(x)) => vec![x],
+            Cmd::Between(x, lo, hi) => vec![x, lo, hi],
+            Cmd::Eval(cmds) | Cmd::Batch(cmds) | Cmd::Tx(cmds) => cmds.iter_mut().collect(),
+            Cmd::Script(stmts) => stmts.iter_mut().map(|x| &mut x.cmd).collect(),
+            _ => Vec::new(),
+        }
+    }
+
+    /// replaces the param placeholders of the cmd with literal values
+    pub fn bind(&mut self, params: &JsonObj) -> Result<(), Error> {
+        if let Cmd::Param(name) = self {
+            let val = params
+                .get(name.as_str())
+                .ok_or_else(|| Error::BadParam(name.clone()))?;
+            *self = Cmd::Json(val.clone());
+            return Ok(());
+        }
+        for child in self.children_mut() {
+            child.bind(params)?;
+        }
+        Ok(())
+    }
+
+    /// replaces the `"$name"` strings of the cmd, including those within its literals and
+    /// queries, with the values of the variables of a script; other strings are kept
+    pub fn bind_vars(&mut self, vars: &JsonObj) {
+        match self {
+            Cmd::Json(val) => bind_json_vars(val, vars),
+            Cmd::Query(qry) | Cmd::Explain(qry) | Cmd::Estimate(qry) | Cmd::Validate(qry) => {
+                qry.bind_vars(vars)
+            }
+            _ => (),
+        }
+        for child in self.children_mut() {
+            child.bind_vars(vars);
+        }
+    }
+
+    /// replaces the expiries of the cmd after a number of seconds with deadlines counted
+    /// from a unix time, so evaluating it again later doesn't extend them
+    pub fn expire_from(&mut self, now: f64) {
+        if let Cmd::Expire(key, secs) = self {
+            *self = Cmd::ExpireAt(std::mem::take(key), now + *secs);
+        }
+        for child in self.children_mut() {
+            child.expire_from(now);
+        }
+    }
+
+    /// gives the rows generated by the cmd without a seed the given seed, so evaluating it
+    /// again later makes the same rows
+    pub fn seed_from(&mut self, seed: u64) {
+        if let Cmd::Generate(gen) = self {
+            gen.seed.get_or_insert(seed);
+        }
+        for child in self.children_mut() {
+            child.seed_from(seed);
+        }
+    }
+
+    /// collects the top level row fields the cmd reads when applied to rows
+    pub fn row_fields<'a>(&'a self, out: &mut BTreeSet<&'a str>) {
+        match self {
+            Cmd::Key(key) | Cmd::Has(key) | Cmd::IsNull(key) | Cmd::SortBy(_, key) => {
+                out.insert(key.split('.').next().unwrap_or(key));
+            }
+            _ => (),
+        }
+        for child in self.children() {
+            child.row_fields(out);
+        }
+    }
+
     pub fn parse_line(line: &str) -> Result<Self, Error> {
         let val = serde_json::from_str(line).map_err(|_| Error::BadIO)?;
         Self::parse(val)
@@ -240,19 +1169,102 @@ impl Cmd {
                         "<=" => parse_bin_fn(val, Cmd::Lte),
                         "||" => parse_bin_fn(val, Cmd::Or),
                         "+" | "add" => parse_bin_fn(val, Cmd::Add),
+                        "ack" => match serde_json::from_value(val.clone()) {
+                            Ok((id, seq)) => Ok(Cmd::Ack(id, seq)),
+                            Err(_) => Err(Error::BadArg(val)),
+                        },
                         "append" => parse_b_str_fn(val, Cmd::Append),
                         "avg" => parse_unr_fn(val, Cmd::Avg),
                         "bar" => parse_bin_fn(val, Cmd::Bar),
+                        "backup" => serde_json::from_value(val)
+                            .map(Cmd::Backup)
+                            .map_err(|_| Error::BadCmd),
+                        "batch" => parse_cmds(val).map(Cmd::Batch),
+                        "between" => parse_tri_fn(val, Cmd::Between),
+                        "chaos" => serde_json::from_value(val)
+                            .map(Cmd::Chaos)
+                            .map_err(|_| Error::BadCmd),
+                        "collect" => parse_unr_fn(val, Cmd::Collect),
+                        "count" => parse_count(val),
+                        "count_distinct" => parse_unr_fn(val, Cmd::CountDistinct),
+                        "heavy_hitters" => parse_heavy_hitters(val),
+                        "createIndex" => {
+                            serde_json::from_value(Json::Object(obj)).map_err(|_| Error::BadCmd)
+                        }
                         "del" => parse_unr_str_fn(val, Cmd::Delete),
+                        "moveRows" => parse_move_rows(val),
+                        "delMany" => serde_json::from_value(val.clone())
+                            .map(Cmd::DeleteMany)
+                            .map_err(|_| Error::BadArg(val)),
+                        "delPattern" => match val {
+                            Json::String(s) => Ok(Cmd::DeletePattern(Pattern::glob(s)?)),
+                            val => Err(Error::BadArg(val)),
+                        },
+                        "execute" => parse_execute(val),
                         "dev" => parse_unr_fn(val, Cmd::Dev),
+                        "diff" => parse_bin_fn(val, Cmd::Diff),
                         "/" | "div" => parse_bin_fn(val, Cmd::Div),
                         "first" => parse_unr_fn(val, Cmd::First),
                         "get" => parse_b_str_fn(val, Cmd::Get),
                         "insert" => parse_insert(val),
+                        "join" => {
+                            let arr = val.as_array_mut().ok_or(Error::ExpectedArr)?;
+                            if arr.len() != 2 {
+                                return Err(Error::BadCmd);
+                            }
+                            let val = arr.remove(1);
+                            let val_str = val.as_str().map(|x| x.to_string());
+                            let sep = val_str.ok_or(Error::BadArg(val))?;
+                            let arg = Cmd::parse(arr.remove(0))?;
+                            Ok(Cmd::Join(Box::new(arg), sep))
+                        }
                         "key" => parse_unr_str_fn(val, Cmd::Key),
+                        "keys" => parse_keys(val, Cmd::Keys),
+                        "generate" => serde_json::from_value(val)
+                            .map(Cmd::Generate)
+                            .map_err(|_| Error::BadCmd),
+                        "keyRange" => serde_json::from_value(val)
+                            .map(Cmd::KeyRange)
+                            .map_err(|_| Error::BadCmd),
+                        "traverse" => serde_json::from_value(val)
+                            .map(Cmd::Traverse)
+                            .map_err(|_| Error::BadCmd),
+                        "scan" => match val {
+                            Json::String(s) => Ok(Cmd::Scan(KeyScan {
+                                pattern: Pattern::glob(s)?,
+                                cursor: None,
+                                count: None,
+                            })),
+                            val => serde_json::from_value(val.clone())
+                                .map(Cmd::Scan)
+                                .map_err(|_| Error::BadArg(val)),
+                        },
                         "has" => parse_unr_str_fn(val, Cmd::Has),
+                        "hotKeys" => match val.as_u64() {
+                            Some(n) => Ok(Cmd::HotKeys(n as usize)),
+                            None => Err(Error::BadArg(val)),
+                        },
+                        "compress" => match val.as_u64() {
+                            Some(n) => Ok(Cmd::Compress(n as usize)),
+                            None => Err(Error::BadArg(val)),
+                        },
+                        "intern" => match val.as_u64() {
+                            Some(n) => Ok(Cmd::Intern(n as usize)),
+                            None => Err(Error::BadArg(val)),
+                        },
+                        "is_null" => parse_unr_str_fn(val, Cmd::IsNull),
+                        "sizeOf" => parse_unr_str_fn(val, Cmd::SizeOf),
                         "last" => parse_unr_fn(val, Cmd::Last),
-                        "len" => parse_unr_fn(val, Cmd::Len),
+                        "len" => match val {
+                            Json::Null => Ok(Cmd::Len(None)),
+                            val => Ok(Cmd::Len(Some(Box::new(Cmd::parse(val)?)))),
+                        },
+                        "like" => {
+                            parse_pattern(val, |arg, pat| Ok(Cmd::Like(arg, Pattern::like(pat)?)))
+                        }
+                        "regex" => {
+                            parse_pattern(val, |arg, pat| Ok(Cmd::Regex(arg, Pattern::regex(pat)?)))
+                        }
                         "flat" => parse_unr_fn(val, Cmd::Flat),
                         "map" => {
                             let arr = val.as_array_mut().ok_or(Error::ExpectedArr)?;
@@ -263,19 +1275,70 @@ impl Cmd {
                             Ok(Cmd::Map(Box::new(arg), f))
                         }
                         "max" => parse_unr_fn(val, Cmd::Max),
+                        "metricsHistory" => {
+                            serde_json::from_value(Json::Object(obj)).map_err(|_| Error::BadCmd)
+                        }
+                        "merge" => serde_json::from_value(val.clone())
+                            .map(|(key, patch)| Cmd::Merge(key, patch))
+                            .map_err(|_| Error::BadArg(val)),
+                        "lookup" => parse_unr_str_fn(val, Cmd::Lookup),
+                        "mget" => serde_json::from_value(val.clone())
+                            .map(Cmd::MGet)
+                            .map_err(|_| Error::BadArg(val)),
                         "median" => parse_unr_fn(val, Cmd::Median),
                         "min" => parse_unr_fn(val, Cmd::Min),
                         "*" | "mul" => parse_bin_fn(val, Cmd::Mul),
+                        "param" => parse_unr_str_fn(val, Cmd::Param),
+                        "parallelism" => serde_json::from_value(val.clone())
+                            .map(Cmd::Parallelism)
+                            .map_err(|_| Error::BadArg(val)),
                         "pop" => parse_unr_str_fn(val, Cmd::Pop),
+                        "prepare" => parse_prepare(val),
                         "push" => parse_b_str_fn(val, Cmd::Push),
                         "query" => {
                             let qry_cmd = QueryCmd::parse(val)?;
                             Ok(Cmd::Query(qry_cmd))
                         }
-                        "set" => parse_b_str_fn(val, Cmd::Set),
+                        "explain" => {
+                            let qry_cmd = QueryCmd::parse(val)?;
+                            Ok(Cmd::Explain(qry_cmd))
+                        }
+                        "estimate" => {
+                            let qry_cmd = QueryCmd::parse(val)?;
+                            Ok(Cmd::Estimate(qry_cmd))
+                        }
+                        "validate" => {
+                            let qry_cmd = QueryCmd::parse(val)?;
+                            Ok(Cmd::Validate(qry_cmd))
+                        }
+                        "restore" => parse_unr_str_fn(val, Cmd::Restore),
+                        "set" => parse_set(val),
+                        "getSet" => parse_b_str_fn(val, Cmd::GetSet),
                         "sub" | "-" => parse_bin_fn(val, Cmd::Sub),
                         "sum" => parse_unr_fn(val, Cmd::Sum),
+                        "summary" => parse_keys(val, |_, filter| Cmd::Summary(filter)),
                         "str" => parse_unr_fn(val, Cmd::ToString),
+                        "subscribe" => parse_subscribe(val),
+                        "subscribeQuery" => QueryCmd::parse(val).map(Cmd::SubscribeQuery),
+                        "events" => match val.as_u64() {
+                            Some(id) => Ok(Cmd::Events(id)),
+                            None => Err(Error::BadArg(val)),
+                        },
+                        "unsubscribe" => match val.as_u64() {
+                            Some(id) => Ok(Cmd::Unsubscribe(id)),
+                            None => Err(Error::BadArg(val)),
+                        },
+                        "tx" => parse_cmds(val).map(Cmd::Tx),
+                        "script" => parse_script(val),
+                        "expire" => match serde_json::from_value(val.clone()) {
+                            Ok((key, secs)) if secs >= 0.0 => Ok(Cmd::Expire(key, secs)),
+                            _ => Err(Error::BadArg(val)),
+                        },
+                        "expireAt" => match serde_json::from_value(val.clone()) {
+                            Ok((key, at)) if at >= 0.0 => Ok(Cmd::ExpireAt(key, at)),
+                            _ => Err(Error::BadArg(val)),
+                        },
+                        "ttl" => parse_unr_str_fn(val, Cmd::Ttl),
                         "unique" => parse_unr_fn(val, Cmd::Unique),
                         "var" => parse_unr_fn(val, Cmd::Var),
                         "sort" => match val {
@@ -305,7 +1368,9 @@ impl Cmd {
                 }
             }
             Json::String(s) => Ok(if s == "summary" {
-                Cmd::Summary
+                Cmd::Summary(None)
+            } else if s == "len" {
+                Cmd::Len(None)
             } else {
                 Cmd::Json(Json::from(s))
             }),
@@ -314,6 +1379,365 @@ impl Cmd {
     }
 }
 
+#[test]
+fn cmd_parse_prepare_execute() {
+    use serde_json::json;
+    let cmd = Cmd::parse(json!({"prepare": ["q", {"from": "orders"}]})).unwrap();
+    let qry = QueryCmd::parse(json!({"from": "orders"})).unwrap();
+    assert_eq!(Cmd::Prepare("q".to_string(), qry), cmd);
+    let cmd = Cmd::parse(json!({"execute": ["q", {"x": 1}]})).unwrap();
+    let params = json!({"x": 1}).as_object().unwrap().clone();
+    assert_eq!(Cmd::Execute("q".to_string(), params), cmd);
+    let cmd = Cmd::parse(json!({"execute": "q"})).unwrap();
+    assert_eq!(Cmd::Execute("q".to_string(), JsonObj::new()), cmd);
+}
+
+#[test]
+fn cmd_bind_params() {
+    use serde_json::json;
+    let mut cmd = Cmd::parse(json!({">": [{"key": "qty"}, {"param": "min"}]})).unwrap();
+    let params = json!({"min": {"key": "secret"}})
+        .as_object()
+        .unwrap()
+        .clone();
+    cmd.bind(&params).unwrap();
+    let lit = Cmd::Json(json!({"key": "secret"}));
+    let exp = Cmd::Gt(Box::new(Cmd::Key("qty".to_string())), Box::new(lit));
+    assert_eq!(exp, cmd);
+    let mut cmd = Cmd::Param("x".to_string());
+    assert_eq!(Err(Error::BadParam("x".to_string())), cmd.bind(&params));
+}
+
+#[test]
+fn cmd_parse_create_index() {
+    use serde_json::json;
+    let cmd = Cmd::parse(json!({"createIndex": {"key": "orders", "field": "qty"}})).unwrap();
+    let exp = Cmd::CreateIndex {
+        key: "orders".to_string(),
+        field: "qty".to_string(),
+    };
+    assert_eq!(exp, cmd);
+    assert_eq!(
+        Err(Error::BadCmd),
+        Cmd::parse(json!({"createIndex": "orders"}))
+    );
+}
+
+#[test]
+fn cmd_parse_len() {
+    use serde_json::json;
+    assert_eq!(Ok(Cmd::Len(None)), Cmd::parse(json!("len")));
+    assert_eq!(Ok(Cmd::Len(None)), Cmd::parse(json!({ "len": null })));
+    let cmd = Cmd::parse(json!({"len": {"key": "t"}})).unwrap();
+    assert_eq!(Cmd::Len(Some(Box::new(Cmd::Key("t".to_string())))), cmd);
+}
+
+#[test]
+fn cmd_parse_batch() {
+    use serde_json::json;
+    let cmd = Cmd::parse(json!({"batch": [{"key": "a"}, "summary"]})).unwrap();
+    let exp = Cmd::Batch(vec![Cmd::Key("a".to_string()), Cmd::Summary(None)]);
+    assert_eq!(exp, cmd);
+    assert_eq!(
+        Err(Error::BadArg(json!("a"))),
+        Cmd::parse(json!({"batch": "a"}))
+    );
+}
+
+#[test]
+fn cmd_parse_tx() {
+    use serde_json::json;
+    let cmd = Cmd::parse(json!({"tx": [{"pop": "a"}, {"key": "a"}]})).unwrap();
+    let exp = Cmd::Tx(vec![Cmd::Pop("a".to_string()), Cmd::Key("a".to_string())]);
+    assert_eq!(exp, cmd);
+    assert_eq!(Err(Error::BadArg(json!(1))), Cmd::parse(json!({"tx": 1})));
+}
+
+#[test]
+fn cmd_parse_script() {
+    use serde_json::json;
+    let cmd = Cmd::parse(json!({"script": [{"let": "x", "cmd": {"pop": "a"}}, "$x"]}));
+    let exp = Cmd::Script(vec![
+        Stmt {
+            name: Some("x".to_string()),
+            cmd: Cmd::Pop("a".to_string()),
+        },
+        Stmt {
+            name: None,
+            cmd: Cmd::Json(json!("$x")),
+        },
+    ]);
+    assert_eq!(Ok(exp), cmd);
+    let stmt = json!({"let": "x", "cmd": 1, "extra": 2});
+    let bad = json!({ "script": [stmt.clone()] });
+    assert_eq!(Err(Error::BadArg(stmt)), Cmd::parse(bad));
+    assert_eq!(
+        Err(Error::BadArg(json!(1))),
+        Cmd::parse(json!({"script": 1}))
+    );
+}
+
+#[test]
+fn cmd_bind_vars() {
+    use serde_json::json;
+    let mut vars = JsonObj::new();
+    vars.insert("n".to_string(), json!(2));
+    vars.insert("who".to_string(), json!(["ania"]));
+    let mut cmd = Cmd::parse(json!({"query": {
+        "from": "orders",
+        "where": {"in": [{"key": "customer"}, "$who"]},
+        "sort": "qty",
+        "after": {"qty": "$n"},
+    }}))
+    .unwrap();
+    cmd.bind_vars(&vars);
+    let exp = Cmd::parse(json!({"query": {
+        "from": "orders",
+        "where": {"in": [{"key": "customer"}, ["ania"]]},
+        "sort": "qty",
+        "after": {"qty": 2},
+    }}))
+    .unwrap();
+    assert_eq!(exp, cmd);
+    let mut cmd = Cmd::parse(json!({"set": ["a", ["$n", "$m", "n"]]})).unwrap();
+    cmd.bind_vars(&vars);
+    assert_eq!(Cmd::parse(json!({"set": ["a", [2, "$m", "n"]]})), Ok(cmd));
+}
+
+#[test]
+fn cmd_parse_subscriptions() {
+    use serde_json::json;
+    let cmd = Cmd::parse(json!({"subscribe": ["a", "b"]}));
+    let keys = vec!["a".to_string(), "b".to_string()];
+    assert_eq!(Ok(Cmd::Subscribe(keys, None)), cmd);
+    let cmd = Cmd::parse(json!({"subscribe": {"where": {"key": "ok"}}}));
+    let filter = Box::new(Cmd::Key("ok".to_string()));
+    assert_eq!(Ok(Cmd::Subscribe(Vec::new(), Some(filter))), cmd);
+    assert_eq!(Ok(Cmd::Events(1)), Cmd::parse(json!({"events": 1})));
+    assert_eq!(Ok(Cmd::Ack(1, 7)), Cmd::parse(json!({"ack": [1, 7]})));
+    assert_eq!(
+        Ok(Cmd::Unsubscribe(1)),
+        Cmd::parse(json!({"unsubscribe": 1}))
+    );
+    let bad = json!({"ack": [1]});
+    assert_eq!(Err(Error::BadArg(json!([1]))), Cmd::parse(bad));
+}
+
+#[test]
+fn cmd_parse_set() {
+    use serde_json::json;
+    let arg = Box::new(Cmd::Json(json!(1)));
+    let exp = Cmd::Set("a".to_string(), arg.clone(), None);
+    assert_eq!(Ok(exp), Cmd::parse(json!({"set": ["a", 1]})));
+    let exp = Cmd::Set("a".to_string(), arg.clone(), Some(SetCond::Nx));
+    assert_eq!(Ok(exp), Cmd::parse(json!({"set": ["a", 1, "nx"]})));
+    let exp = Cmd::GetSet("a".to_string(), arg);
+    assert_eq!(Ok(exp), Cmd::parse(json!({"getSet": ["a", 1]})));
+    let bad = json!({"set": ["a", 1, "yy"]});
+    assert_eq!(Err(Error::BadArg(json!("yy"))), Cmd::parse(bad));
+}
+
+#[test]
+fn cmd_parse_mget() {
+    use serde_json::json;
+    let cmd = Cmd::parse(json!({"mget": ["a", "t.name"]}));
+    assert_eq!(
+        Ok(Cmd::MGet(vec!["a".to_string(), "t.name".to_string()])),
+        cmd
+    );
+    assert_eq!(
+        Err(Error::BadArg(json!("a"))),
+        Cmd::parse(json!({"mget": "a"}))
+    );
+}
+
+#[test]
+fn cmd_parse_lookup() {
+    use serde_json::json;
+    let cmd = Cmd::parse(json!({"lookup": "t.name"}));
+    assert_eq!(Ok(Cmd::Lookup("t.name".to_string())), cmd);
+    assert_eq!(
+        Err(Error::BadArg(json!(["a"]))),
+        Cmd::parse(json!({"lookup": ["a"]}))
+    );
+}
+
+#[test]
+fn cmd_parse_deletes() {
+    use serde_json::json;
+    let cmd = Cmd::parse(json!({"delMany": ["a", "b"]}));
+    assert_eq!(
+        Ok(Cmd::DeleteMany(vec!["a".to_string(), "b".to_string()])),
+        cmd
+    );
+    let cmd = Cmd::parse(json!({"delPattern": "session:*"})).unwrap();
+    let pat = match cmd {
+        Cmd::DeletePattern(pat) => pat,
+        cmd => panic!("{:?}", cmd),
+    };
+    assert!(pat.is_match("session:1") && pat.is_match("session:"));
+    assert!(!pat.is_match("user:session:1"));
+    let pat = Pattern::glob("a?c.*".to_string()).unwrap();
+    assert!(pat.is_match("abc.json") && !pat.is_match("abbc.json"));
+}
+
+#[test]
+fn cmd_parse_heavy_hitters() {
+    use serde_json::json;
+    let exp = Cmd::HeavyHitters(Box::new(Cmd::Key("url".to_string())), 20);
+    let val = json!({"heavy_hitters": {"key": "url", "k": 20}});
+    assert_eq!(Ok(exp), Cmd::parse(val));
+    let val = json!({"heavy_hitters": {"key": "url", "k": 0}});
+    assert_eq!(Err(Error::BadArg(json!(0))), Cmd::parse(val));
+    let val = json!({"heavy_hitters": {"key": "url"}});
+    assert_eq!(Err(Error::BadArg(Json::Null)), Cmd::parse(val));
+    let val = json!({"heavy_hitters": {"key": "url", "k": 20}});
+    let cmd: Cmd = serde_json::from_value(val.clone()).unwrap();
+    assert_eq!(val, serde_json::to_value(cmd).unwrap());
+}
+
+#[test]
+fn cmd_parse_generate() {
+    use serde_json::json;
+    let val = json!({"generate": {"table": "t", "n": 10, "template": {"a": {"$int": [1, 2]}}}});
+    let exp = Cmd::Generate(Generate {
+        table: "t".to_string(),
+        n: 10,
+        template: json!({"a": {"$int": [1, 2]}}),
+        seed: None,
+    });
+    assert_eq!(Ok(exp), Cmd::parse(val));
+    assert_eq!(
+        Err(Error::BadCmd),
+        Cmd::parse(json!({"generate": {"table": "t"}}))
+    );
+}
+
+#[test]
+fn cmd_parse_backup() {
+    use serde_json::json;
+    let val = json!({"backup": {"path": "memson.bak", "compress": "gzip"}});
+    let exp = Cmd::Backup(Backup {
+        path: "memson.bak".to_string(),
+        compress: Codec::Gzip,
+    });
+    assert_eq!(Ok(exp), Cmd::parse(val));
+    let val = json!({"backup": {"path": "memson.bak"}});
+    assert!(matches!(
+        Cmd::parse(val),
+        Ok(Cmd::Backup(Backup {
+            compress: Codec::Zstd,
+            ..
+        }))
+    ));
+    let val = json!({"backup": {"path": "memson.bak", "compress": "lz4"}});
+    assert_eq!(Err(Error::BadCmd), Cmd::parse(val));
+    let exp = Cmd::Restore("memson.bak".to_string());
+    assert_eq!(Ok(exp), Cmd::parse(json!({"restore": "memson.bak"})));
+}
+
+#[test]
+fn cmd_parse_scan() {
+    use serde_json::json;
+    let cmd =
+        Cmd::parse(json!({"scan": {"pattern": "user:*:name", "cursor": "user:1", "count": 2}}));
+    let scan = match cmd {
+        Ok(Cmd::Scan(scan)) => scan,
+        cmd => panic!("{:?}", cmd),
+    };
+    assert_eq!("user:", scan.prefix());
+    assert_eq!(Some("user:1".to_string()), scan.cursor);
+    assert!(scan.pattern.is_match("user:2:name"));
+    let cmd = Cmd::parse(json!({"scan": "*"})).unwrap();
+    assert!(matches!(cmd, Cmd::Scan(KeyScan { count: None, .. })));
+    assert!(Cmd::parse(json!({"scan": {"cursor": "a"}})).is_err());
+}
+
+#[test]
+fn cmd_parse_expiry() {
+    use serde_json::json;
+    let cmd = Cmd::parse(json!({"expire": ["session", 30]}));
+    assert_eq!(Ok(Cmd::Expire("session".to_string(), 30.0)), cmd);
+    let cmd = Cmd::parse(json!({"expire": ["session", 0.5]}));
+    assert_eq!(Ok(Cmd::Expire("session".to_string(), 0.5)), cmd);
+    let cmd = Cmd::parse(json!({"expire": ["session", -1]}));
+    assert_eq!(Err(Error::BadArg(json!(["session", -1]))), cmd);
+    let cmd = Cmd::parse(json!({"expireAt": ["session", 1700000000.5]}));
+    assert_eq!(Ok(Cmd::ExpireAt("session".to_string(), 1700000000.5)), cmd);
+    let mut cmd = Cmd::parse(json!({"tx": [{"set": ["s", 1]}, {"expire": ["s", 30]}]})).unwrap();
+    cmd.expire_from(100.0);
+    let exp = json!({"tx": [{"set": ["s", 1]}, {"expireAt": ["s", 130.0]}]});
+    assert_eq!(Ok(cmd), Cmd::parse(exp));
+    let cmd = Cmd::parse(json!({"ttl": "session"}));
+    assert_eq!(Ok(Cmd::Ttl("session".to_string())), cmd);
+}
+
+#[test]
+fn cmd_parse_insert() {
+    use serde_json::json;
+    let cmd = Cmd::parse(json!({"insert": ["t", [{"a": 1}]]})).unwrap();
+    let rows = vec![json!({"a": 1}).as_object().unwrap().clone()];
+    assert_eq!(Cmd::Insert("t".to_string(), rows), cmd);
+    let err = Err(Error::BadArg(json!(1)));
+    assert_eq!(err, Cmd::parse(json!({"insert": ["t", [{"a": 1}, 1]]})));
+    let err = Err(Error::BadArg(json!({"a": 1})));
+    assert_eq!(err, Cmd::parse(json!({"insert": ["t", {"a": 1}]})));
+}
+
+#[test]
+fn cmd_parse_diff() {
+    use serde_json::json;
+    let cmd = Cmd::parse(json!({"diff": [{"key": "a"}, {"key": "b"}]}));
+    let (a, b) = (Cmd::Key("a".to_string()), Cmd::Key("b".to_string()));
+    assert_eq!(Ok(Cmd::Diff(Box::new(a), Box::new(b))), cmd);
+}
+
+#[test]
+fn cmd_parse_traverse() {
+    use serde_json::json;
+    let cmd = Cmd::parse(json!({"traverse": {"from": "users", "edge": "friends"}}));
+    let exp = Traverse {
+        from: "users".to_string(),
+        edge: "friends".to_string(),
+        depth: 1,
+        start: None,
+    };
+    assert_eq!(Ok(Cmd::Traverse(exp)), cmd);
+    let bad = json!({"traverse": {"from": "users", "edge": "friends", "depht": 2}});
+    assert_eq!(Err(Error::BadCmd), Cmd::parse(bad));
+}
+
+#[test]
+fn cmd_parse_move_rows() {
+    use serde_json::json;
+    let filter = json!({"==": [{"key": "status"}, "done"]});
+    let val = json!({"from": "active", "to": "archived", "where": filter.clone()});
+    let exp = Cmd::MoveRows {
+        from: "active".to_string(),
+        to: "archived".to_string(),
+        filter: Box::new(Cmd::parse(filter).unwrap()),
+    };
+    assert_eq!(Ok(exp), Cmd::parse(json!({ "moveRows": val })));
+    let bad = json!({"from": "active", "where": true});
+    assert_eq!(
+        Err(Error::BadArg(bad.clone())),
+        Cmd::parse(json!({ "moveRows": bad }))
+    );
+}
+
+#[test]
+fn cmd_parse_merge() {
+    use serde_json::json;
+    let patch = json!({"key": "a", "b": null});
+    let cmd = Cmd::parse(json!({"merge": ["doc", patch.clone()]}));
+    assert_eq!(Ok(Cmd::Merge("doc".to_string(), patch)), cmd);
+    let bad = json!(["doc"]);
+    assert_eq!(
+        Err(Error::BadArg(bad.clone())),
+        Cmd::parse(json!({ "merge": bad }))
+    );
+}
+
 #[test]
 fn cmd_parse_json_string() {
     use serde_json::json;
@@ -385,6 +1809,14 @@ fn cmd_parse_sort_ascend() {
     assert_eq!(exp, cmd);
 }
 
+#[test]
+fn cmd_parse_count() {
+    use serde_json::json;
+    assert_eq!(Ok(Cmd::Count(None)), Cmd::parse(json!({"count": "*"})));
+    let exp = Cmd::Count(Some(Box::new(Cmd::Key("k".to_string()))));
+    assert_eq!(Ok(exp), Cmd::parse(json!({"count": {"key": "k"}})));
+}
+
 #[test]
 fn cmd_parse_map() {
     use serde_json::json;
@@ -393,3 +1825,99 @@ fn cmd_parse_map() {
     let exp = Cmd::Map(Box::new(Cmd::Key("k".to_string())), "len".to_string());
     assert_eq!(exp, cmd);
 }
+
+#[test]
+fn cmd_parse_collect_and_join() {
+    use serde_json::json;
+    let key = Box::new(Cmd::Key("k".to_string()));
+    let exp = Cmd::Collect(key.clone());
+    assert_eq!(Ok(exp), Cmd::parse(json!({"collect": {"key": "k"}})));
+    let exp = Cmd::Join(key, ", ".to_string());
+    assert_eq!(Ok(exp), Cmd::parse(json!({"join": [{"key": "k"}, ", "]})));
+}
+
+#[test]
+fn cmd_parse_like_and_regex() {
+    use serde_json::json;
+    let key = Box::new(Cmd::Key("name".to_string()));
+    let cmd = Cmd::parse(json!({"like": [{"key": "name"}, "an%"]})).unwrap();
+    assert_eq!(
+        Cmd::Like(key.clone(), Pattern::like("an%".to_string()).unwrap()),
+        cmd
+    );
+    let cmd = Cmd::parse(json!({"regex": [{"key": "name"}, "^a.*"]})).unwrap();
+    assert_eq!(
+        Cmd::Regex(key, Pattern::regex("^a.*".to_string()).unwrap()),
+        cmd
+    );
+    let val = json!({"regex": [{"key": "name"}, "("]});
+    assert_eq!(Err(Error::BadArg(json!("("))), Cmd::parse(val));
+}
+
+#[test]
+fn pattern_like() {
+    let pat = Pattern::like("an_.%".to_string()).unwrap();
+    assert!(pat.is_match("ann.a"));
+    assert!(pat.is_match("ani."));
+    assert!(!pat.is_match("anna"));
+    assert!(!pat.is_match("xann.a"));
+}
+
+#[test]
+fn cmd_parse_between() {
+    use serde_json::json;
+    let val = json!({"between": [{"key": "age"}, 20, 30]});
+    let exp = Cmd::Between(
+        Box::new(Cmd::Key("age".to_string())),
+        Box::new(Cmd::Json(json!(20))),
+        Box::new(Cmd::Json(json!(30))),
+    );
+    assert_eq!(Ok(exp), Cmd::parse(val));
+    assert_eq!(Err(Error::BadCmd), Cmd::parse(json!({"between": [1, 2]})));
+}
+
+#[test]
+fn range_positions_from_either_end() {
+    use serde_json::json;
+    let range = |val: Json| serde_json::from_value::<Range>(val).unwrap();
+    let arr = json!([0, 1, 2, 3, 4, 5]);
+    let slice = |val: Json| range(val).slice(arr.clone());
+    assert_eq!(Ok(json!([4, 5])), slice(json!({"start": -2})));
+    assert_eq!(Ok(json!([1, 2, 3])), slice(json!({"start": 1, "end": -2})));
+    assert_eq!(Ok(json!([0, 2, 4])), slice(json!({"step": 2})));
+    assert_eq!(
+        Ok(json!([1, 4])),
+        slice(json!({"start": 1, "size": 2, "step": 3}))
+    );
+    assert_eq!(Ok(json!([])), slice(json!({"start": 4, "end": 2})));
+    assert_eq!(Ok(arr.clone()), slice(json!({"start": -100, "end": 100})));
+    assert_eq!(Err(Error::BadArg(json!(0))), slice(json!({"step": 0})));
+    assert_eq!(Err(Error::ExpectedArr), range(json!({})).slice(json!(1)));
+    let at = range(json!({"start": -3})).or_size(2).positions(10);
+    assert_eq!(
+        Ok(Positions {
+            start: 7,
+            step: 1,
+            n: 2
+        }),
+        at
+    );
+}
+
+#[test]
+fn cmd_parse_keys_with_filter() {
+    use serde_json::json;
+    let val = json!({"keys": {"size": 10, "type": "array", "minSize": 100}});
+    let page = Range {
+        size: Some(10),
+        ..Range::default()
+    };
+    let filter = KeyFilter {
+        kind: Some("array".to_string()),
+        min_size: Some(100),
+        max_size: None,
+    };
+    assert_eq!(Ok(Cmd::Keys(Some(page), Some(filter))), Cmd::parse(val));
+    assert_eq!(Ok(Cmd::Keys(None, None)), Cmd::parse(json!({"keys": null})));
+    assert_eq!(Ok(Cmd::Summary(None)), Cmd::parse(json!("summary")));
+}
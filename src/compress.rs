@@ -0,0 +1,177 @@
+use crate::err::Error;
+use crate::json_ops::{json_size, Json, JsonObj};
+use flate2::read::DeflateDecoder;
+use flate2::write::DeflateEncoder;
+use flate2::Compression;
+use serde_json::json;
+use std::collections::{BTreeMap, HashMap};
+use std::io::{Read, Write};
+
+/// the most distinct strings of a column encoded through a dictionary
+pub const MAX_DICT_SIZE: usize = 256;
+
+/// the tag of a value deflated as it is
+const PLAIN: u8 = 0;
+/// the tag of a table whose low cardinality string columns are dictionary encoded
+/// before being deflated
+const DICT: u8 = 1;
+
+/// checks if a value is a string or array of at least the given approximate size
+pub fn is_compressible(val: &Json, min_bytes: usize) -> bool {
+    matches!(val, Json::String(_) | Json::Array(_)) && json_size(val) >= min_bytes
+}
+
+/// checks if a value was dictionary encoded by compress
+pub fn is_dict_encoded(bytes: &[u8]) -> bool {
+    bytes.first() == Some(&DICT)
+}
+
+/// deflates the json encoding of a value, dictionary encoding the low cardinality
+/// string columns of tables first
+pub fn compress(val: &Json) -> Result<Vec<u8>, Error> {
+    match dict_encode(val) {
+        Some(encoded) => deflate(DICT, &encoded),
+        None => deflate(PLAIN, val),
+    }
+}
+
+fn deflate(tag: u8, val: &Json) -> Result<Vec<u8>, Error> {
+    let mut encoder = DeflateEncoder::new(vec![tag], Compression::default());
+    serde_json::to_writer(&mut encoder, val).map_err(|_| Error::Serialize)?;
+    encoder.flush().map_err(|_| Error::BadIO)?;
+    encoder.finish().map_err(|_| Error::BadIO)
+}
+
+/// inflates a value deflated by compress
+pub fn decompress(bytes: &[u8]) -> Result<Json, Error> {
+    let (tag, data) = bytes.split_first().ok_or(Error::BadIO)?;
+    let mut buf = Vec::new();
+    DeflateDecoder::new(data)
+        .read_to_end(&mut buf)
+        .map_err(|_| Error::BadIO)?;
+    let val = serde_json::from_slice(&buf).map_err(|_| Error::Serialize)?;
+    match *tag {
+        PLAIN => Ok(val),
+        DICT => dict_decode(val),
+        _ => Err(Error::BadIO),
+    }
+}
+
+/// the rows of a table with the strings of its low cardinality columns replaced by their
+/// positions in the dictionary of the column, as `{"dicts": {col: [..]}, "rows": [..]}`;
+/// none if it isn't a table or has no such columns
+pub fn dict_encode(val: &Json) -> Option<Json> {
+    let rows = val.as_array()?;
+    let mut columns: BTreeMap<&str, HashMap<&str, usize>> = BTreeMap::new();
+    let mut mixed = Vec::new();
+    for row in rows {
+        for (col, val) in row.as_object()? {
+            match val {
+                Json::String(s) => {
+                    let dict = columns.entry(col).or_default();
+                    let next = dict.len();
+                    dict.entry(s).or_insert(next);
+                }
+                _ => mixed.push(col.as_str()),
+            }
+        }
+    }
+    // a column repeating each string at least twice on average, only ever holding strings
+    columns.retain(|col, dict| {
+        dict.len() <= MAX_DICT_SIZE && dict.len() * 2 <= rows.len() && !mixed.contains(col)
+    });
+    if columns.is_empty() {
+        return None;
+    }
+    let encoded: Vec<Json> = rows
+        .iter()
+        .map(|row| {
+            let row = row.as_object().unwrap().iter().map(|(col, val)| {
+                let pos = columns.get(col.as_str()).zip(val.as_str());
+                let val = pos.map_or_else(|| val.clone(), |(dict, s)| Json::from(dict[s]));
+                (col.clone(), val)
+            });
+            Json::Object(row.collect())
+        })
+        .collect();
+    let dicts: JsonObj = columns
+        .into_iter()
+        .map(|(col, dict)| {
+            let mut strs = vec![""; dict.len()];
+            dict.into_iter().for_each(|(s, pos)| strs[pos] = s);
+            (col.to_string(), Json::from(strs))
+        })
+        .collect();
+    Some(json!({"dicts": dicts, "rows": encoded}))
+}
+
+/// the table a dictionary encoded table was encoded from
+pub fn dict_decode(mut val: Json) -> Result<Json, Error> {
+    let dicts = match val.get_mut("dicts").map(Json::take) {
+        Some(Json::Object(dicts)) => dicts,
+        _ => return Err(Error::Serialize),
+    };
+    let mut rows = match val.get_mut("rows").map(Json::take) {
+        Some(Json::Array(rows)) => rows,
+        _ => return Err(Error::Serialize),
+    };
+    for row in rows.iter_mut().filter_map(Json::as_object_mut) {
+        for (col, dict) in &dicts {
+            if let Some(val) = row.get_mut(col) {
+                let pos = val.as_u64().ok_or(Error::Serialize)? as usize;
+                *val = dict.get(pos).cloned().ok_or(Error::Serialize)?;
+            }
+        }
+    }
+    Ok(Json::Array(rows))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    #[test]
+    fn compress_round_trip() {
+        let lines: Vec<Json> = (0..100)
+            .map(|i| json!(format!("GET /index.html 200 {}", i % 3)))
+            .collect();
+        let val = Json::from(lines);
+        let bytes = compress(&val).unwrap();
+        assert!(bytes.len() < json_size(&val) / 4);
+        assert_eq!(Ok(val), decompress(&bytes));
+    }
+
+    #[test]
+    fn low_cardinality_columns_dictionary_encoded() {
+        let rows: Vec<Json> = (0..100)
+            .map(|i| {
+                let status = ["ok", "err"][i % 2];
+                json!({"id": format!("e{}", i), "status": status, "ms": i})
+            })
+            .collect();
+        let val = Json::from(rows);
+        let encoded = dict_encode(&val).unwrap();
+        assert_eq!(json!({"status": ["ok", "err"]}), encoded["dicts"]);
+        assert_eq!(
+            json!({"id": "e1", "status": 1, "ms": 1}),
+            encoded["rows"][1]
+        );
+        let bytes = compress(&val).unwrap();
+        assert!(is_dict_encoded(&bytes));
+        assert_eq!(Ok(val), decompress(&bytes));
+        let mixed = json!([{"a": "x"}, {"a": 1}, {"a": "x"}, {"a": "x"}]);
+        assert_eq!(None, dict_encode(&mixed));
+        assert_eq!(None, dict_encode(&json!([{"a": "x"}, {"a": "y"}])));
+        assert_eq!(None, dict_encode(&json!(["x", "x", "x"])));
+    }
+
+    #[test]
+    fn only_large_strings_and_arrays_compressible() {
+        assert!(is_compressible(&json!("hello"), 5));
+        assert!(!is_compressible(&json!("hello"), 6));
+        assert!(is_compressible(&json!([1, 2]), 16));
+        assert!(!is_compressible(&json!({"a": "hello"}), 1));
+        assert!(!is_compressible(&json!(1), 1));
+    }
+}
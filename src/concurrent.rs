@@ -0,0 +1,215 @@
+use crate::backend::read_keys;
+use crate::cmd::Cmd;
+use crate::db::Cancel;
+use crate::err::Error;
+use crate::eval::eval_shared;
+use crate::index::Index;
+use crate::inmem::InMemDb;
+use crate::json_ops::Json;
+use crate::Res;
+use std::collections::BTreeSet;
+use std::sync::{Arc, Mutex, RwLock, RwLockReadGuard, RwLockWriteGuard};
+
+/// the most shared cmds whose reads are kept before they're recorded in the access stats
+const MAX_PENDING_READS: usize = 1024;
+
+/// An in memory db shared between threads. Cmds that only read entries already in memory
+/// are evaluated in parallel under a read lock; every other cmd takes the write lock.
+///
+/// The entries aren't sharded, so queries and transactions over several keys still see
+/// a consistent db.
+#[derive(Clone, Debug, Default)]
+pub struct ConcurrentDb {
+    db: Arc<RwLock<InMemDb>>,
+    /// the keys read by shared cmds, recorded in the access stats by the next writer
+    reads: Arc<Mutex<Vec<BTreeSet<String>>>>,
+}
+
+impl ConcurrentDb {
+    pub fn new(db: InMemDb) -> Self {
+        ConcurrentDb {
+            db: Arc::new(RwLock::new(db)),
+            reads: Arc::default(),
+        }
+    }
+
+    /// evaluate a command
+    pub fn eval(&self, cmd: Cmd) -> Res {
+        if let Cmd::CreateIndex { key, field } = cmd {
+            self.create_index(&key, &field)?;
+            return Ok(Json::Null);
+        }
+        match self.share(cmd, &Cancel::default()) {
+            Ok(res) => res,
+            Err(cmd) => self.write().eval(*cmd),
+        }
+    }
+
+    /// evaluates a cmd under the read lock, alongside other readers, if it only reads
+    /// entries already in memory; otherwise hands it back, as it needs the write lock
+    pub fn share(&self, cmd: Cmd, cancel: &Cancel) -> Result<Res, Box<Cmd>> {
+        let db = self.read();
+        if !db.can_share(&cmd) {
+            return Err(Box::new(cmd));
+        }
+        let mut reads = BTreeSet::new();
+        read_keys(&cmd, &mut reads);
+        let res = eval_shared(&db, cmd, cancel);
+        drop(db);
+        self.record(reads);
+        Ok(res)
+    }
+
+    /// a shared reference to the value of an entry, without copying it. Later writes
+    /// copy the value rather than change it under the reference
+    pub fn get(&self, key: &str) -> Result<Arc<Json>, Error> {
+        let has = Cmd::Has(key.to_string());
+        let db = self.read();
+        if !db.can_share(&has) {
+            drop(db);
+            let mut db = self.write();
+            db.eval(has)?;
+            return db.get_shared(key);
+        }
+        let val = db.get_shared(key);
+        drop(db);
+        self.record(BTreeSet::from([key.to_string()]));
+        val
+    }
+
+    /// indexes the rows of a key by a field, replacing any index of the field. The rows
+    /// are scanned from a snapshot without holding the db, so other cmds go on meanwhile;
+    /// the index then catches up with their writes to the key and is swapped in
+    pub fn create_index(&self, key: &str, field: &str) -> Result<(), Error> {
+        let (id, val) = {
+            let mut db = self.write();
+            db.eval(Cmd::Has(key.to_string()))?;
+            db.begin_index(key, field)?
+        };
+        let index = Index::build(&val, field);
+        drop(val);
+        self.write().finish_index(id, index)
+    }
+
+    /// keeps the keys read by a shared cmd until the next writer records them
+    fn record(&self, reads: BTreeSet<String>) {
+        if reads.is_empty() {
+            return;
+        }
+        let full = {
+            let mut pending = self.reads.lock().unwrap();
+            pending.push(reads);
+            pending.len() >= MAX_PENDING_READS
+        };
+        if full {
+            drop(self.write());
+        }
+    }
+
+    /// locks the db for reading, alongside other readers
+    pub fn read(&self) -> RwLockReadGuard<'_, InMemDb> {
+        self.db.read().unwrap()
+    }
+
+    /// locks the db for writing, first recording the reads of the shared cmds since the
+    /// last writer
+    pub fn write(&self) -> RwLockWriteGuard<'_, InMemDb> {
+        let mut db = self.db.write().unwrap();
+        let pending = std::mem::take(&mut *self.reads.lock().unwrap());
+        for reads in &pending {
+            db.record_reads(reads);
+        }
+        db
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::{json, Value as Json};
+    use std::thread;
+
+    fn parse(val: Json) -> Cmd {
+        Cmd::parse(val).unwrap()
+    }
+
+    #[test]
+    fn readers_share_the_db() {
+        let db = ConcurrentDb::default();
+        db.eval(parse(json!({"set": ["a", [1, 2, 3]]}))).unwrap();
+        let read = db.read();
+        let handles: Vec<_> = (0..4)
+            .map(|_| {
+                let db = db.clone();
+                thread::spawn(move || db.eval(parse(json!({"key": "a"}))))
+            })
+            .collect();
+        for handle in handles {
+            assert_eq!(Ok(json!([1, 2, 3])), handle.join().unwrap());
+        }
+        drop(read);
+        let qry = parse(json!({"query": {"from": "a", "where": {">": [{"key": "_"}, 1]}}}));
+        assert!(db.read().can_share(&qry));
+        assert!(!db.read().can_share(&parse(json!({"del": "a"}))));
+    }
+
+    #[test]
+    fn shared_reads_recorded_by_writers() {
+        let db = ConcurrentDb::default();
+        db.eval(parse(json!({"set": ["a", 1]}))).unwrap();
+        db.eval(parse(json!({"key": "a"}))).unwrap();
+        db.eval(parse(json!({"key": "a"}))).unwrap();
+        assert_eq!(Some(0), db.read().stats().get("a").map(|x| x.reads));
+        assert_eq!(Some(2), db.write().stats().get("a").map(|x| x.reads));
+    }
+
+    #[test]
+    fn shared_values_copied_on_write() {
+        let db = ConcurrentDb::default();
+        db.eval(parse(json!({"set": ["a", [1, 2]]}))).unwrap();
+        let val = db.get("a").unwrap();
+        db.eval(parse(json!({"push": ["a", 3]}))).unwrap();
+        assert_eq!(json!([1, 2]), *val);
+        assert_eq!(json!([1, 2, 3]), *db.get("a").unwrap());
+        assert!(db.get("b").is_err());
+    }
+
+    #[test]
+    fn index_built_without_blocking_writers() {
+        let db = ConcurrentDb::default();
+        db.eval(parse(json!({"set": ["orders", [{"qty": 1}, {"qty": 2}]]})))
+            .unwrap();
+        let (id, val) = db.write().begin_index("orders", "qty").unwrap();
+        let insert = json!({"insert": ["orders", [{"qty": 2}]]});
+        db.eval(parse(insert)).unwrap();
+        db.write()
+            .finish_index(id, Index::build(&val, "qty"))
+            .unwrap();
+        let eq = parse(json!({"==": [{"key": "qty"}, 2]}));
+        let index = db.read().index("orders", "qty").unwrap().candidates(&eq);
+        assert_eq!(Some(vec![1, 2]), index);
+        let (id, val) = db.write().begin_index("orders", "qty").unwrap();
+        db.eval(parse(json!({"set": ["orders", [{"qty": 2}]]})))
+            .unwrap();
+        db.write()
+            .finish_index(id, Index::build(&val, "qty"))
+            .unwrap();
+        let index = db.read().index("orders", "qty").unwrap().candidates(&eq);
+        assert_eq!(Some(vec![0]), index);
+        let create = json!({"createIndex": {"key": "missing", "field": "qty"}});
+        assert!(db.eval(parse(create)).is_err());
+    }
+
+    #[test]
+    fn compressed_entries_read_under_write_lock() {
+        let db = ConcurrentDb::default();
+        let val = json!(vec!["a repeated string"; 100]);
+        db.eval(parse(json!({"set": ["a", val.clone()]}))).unwrap();
+        db.eval(parse(json!({"compress": 0}))).unwrap();
+        assert!(!db.read().can_share(&parse(json!({"key": "a"}))));
+        assert_eq!(Ok(val.clone()), db.eval(parse(json!({"key": "a"}))));
+        db.eval(parse(json!({"compress": 0}))).unwrap();
+        assert_eq!(val, *db.get("a").unwrap());
+        assert!(db.read().can_share(&parse(json!({"key": "a"}))));
+    }
+}
@@ -0,0 +1,151 @@
+//! Runs the wire protocol conformance suite
+//!
+//! Each `conformance/v<PROTOCOL_VERSION>/*.json` transcript seeds a fresh memson with its
+//! `data`, then sends each step's request and checks the status, headers and body of the
+//! response. The transcripts are the reference for clients implemented in other languages.
+
+use crate::audit::Audit;
+use crate::db::{Memson, DEFAULT_LIMIT};
+use crate::json_ops::Json;
+use crate::ondisk::OnDiskDb;
+use crate::tempdir::TempDir;
+use crate::{headers, routes, Db, DefaultLimit, PROTOCOL_HEADER, PROTOCOL_VERSION};
+use actix_web::http::Method;
+use actix_web::{test, App};
+use serde::Deserialize;
+use std::collections::BTreeMap;
+use std::env;
+use std::fs;
+use std::path::{Path, PathBuf};
+
+#[derive(Debug, Deserialize)]
+struct Transcript {
+    description: String,
+    #[serde(default)]
+    data: BTreeMap<String, Json>,
+    steps: Vec<Step>,
+}
+
+#[derive(Debug, Deserialize)]
+struct Step {
+    request: StepRequest,
+    response: StepResponse,
+}
+
+#[derive(Debug, Deserialize)]
+struct StepRequest {
+    method: String,
+    path: String,
+    #[serde(default)]
+    headers: BTreeMap<String, String>,
+    body: Option<Json>,
+}
+
+#[derive(Debug, Deserialize)]
+struct StepResponse {
+    status: u16,
+    #[serde(default)]
+    headers: BTreeMap<String, String>,
+    body: Json,
+}
+
+fn suite_dir() -> PathBuf {
+    Path::new(env!("CARGO_MANIFEST_DIR"))
+        .join("conformance")
+        .join(format!("v{}", PROTOCOL_VERSION))
+}
+
+/// runs the steps of a transcript, returning a description of each mismatch
+async fn run(name: &str, transcript: Transcript) -> Vec<String> {
+    let dir = TempDir::new(&format!("conformance-{}", name));
+    // seeds the db through the same handle, as sled may still hold the lock of a dropped one
+    let disk_db = OnDiskDb::open(&dir).unwrap();
+    for (key, val) in &transcript.data {
+        disk_db.set(key, val).unwrap();
+    }
+    let db = Db::start(Memson::from_disk(disk_db).unwrap(), None);
+    let mut app = test::init_service(
+        App::new()
+            .wrap_fn(headers)
+            .data(db)
+            .data(DefaultLimit(Some(DEFAULT_LIMIT)))
+            .data(Audit::default())
+            .configure(routes),
+    )
+    .await;
+    let mut failures = Vec::new();
+    for (i, step) in transcript.steps.into_iter().enumerate() {
+        let context = format!("{} step {} ({})", name, i, transcript.description);
+        let method = Method::from_bytes(step.request.method.as_bytes()).unwrap();
+        let mut req = test::TestRequest::default()
+            .method(method)
+            .uri(&step.request.path);
+        for (key, val) in &step.request.headers {
+            req = req.header(key.as_str(), val.as_str());
+        }
+        if let Some(body) = &step.request.body {
+            req = req.set_json(body);
+        }
+        let res = test::call_service(&mut app, req.to_request()).await;
+        let status = res.status().as_u16();
+        if status != step.response.status {
+            failures.push(format!(
+                "{}: expected status {}, got {}",
+                context, step.response.status, status
+            ));
+        }
+        for (key, expected) in &step.response.headers {
+            let actual = res
+                .headers()
+                .get(key.as_str())
+                .and_then(|x| x.to_str().ok());
+            if actual != Some(expected.as_str()) {
+                failures.push(format!(
+                    "{}: expected header {}: {}, got {:?}",
+                    context, key, expected, actual
+                ));
+            }
+        }
+        let actual = res
+            .headers()
+            .get(PROTOCOL_HEADER)
+            .and_then(|x| x.to_str().ok());
+        if actual != Some(PROTOCOL_VERSION) {
+            failures.push(format!("{}: missing protocol header", context));
+        }
+        let body = test::read_body(res).await;
+        let body: Json = serde_json::from_slice(&body).unwrap_or(Json::Null);
+        if body != step.response.body {
+            failures.push(format!(
+                "{}:\n  expected: {}\n  actual:   {}",
+                context, step.response.body, body
+            ));
+        }
+    }
+    failures
+}
+
+#[actix_rt::test]
+async fn conformance_suite() {
+    let dir = suite_dir();
+    let mut paths: Vec<PathBuf> = fs::read_dir(&dir)
+        .unwrap_or_else(|e| panic!("{}: {}", dir.display(), e))
+        .map(|entry| entry.unwrap().path())
+        .filter(|path| path.extension().is_some_and(|ext| ext == "json"))
+        .collect();
+    paths.sort();
+    assert!(!paths.is_empty(), "no transcripts in {}", dir.display());
+    let mut failures = Vec::new();
+    for path in paths {
+        let name = path.file_stem().unwrap().to_string_lossy().to_string();
+        let text = fs::read_to_string(&path).unwrap();
+        let transcript: Transcript =
+            serde_json::from_str(&text).unwrap_or_else(|e| panic!("{}: {}", path.display(), e));
+        failures.extend(run(&name, transcript).await);
+    }
+    assert!(
+        failures.is_empty(),
+        "conformance failures:\n{}",
+        failures.join("\n")
+    );
+}
@@ -1,1605 +1,4625 @@
-use crate::apply::apply_rows;
-use crate::cmd::{Cmd, QueryCmd};
+use crate::acl::Acls;
+use crate::apply::{apply, apply_rows};
+use crate::auth::User;
+use crate::backend::{read_keys, written_keys, Backend};
+use crate::capability::Capabilities;
+use crate::cmd::{Cmd, Knn, Positions, QueryCmd, Source};
+use crate::concurrent::ConcurrentDb;
+use crate::dump::{read_backup, restore, Format};
 use crate::err::Error;
 use crate::eval::*;
-use crate::inmem::InMemDb;
-use crate::json::*;
+use crate::eviction::Eviction;
+use crate::expiry::unix_secs;
+use crate::generate::clock_seed;
+use crate::index::{filter_field, Index};
+use crate::inmem::{scans_keys, InMemDb};
+use crate::json_ops::*;
+use crate::lint::lint;
+use crate::metrics::Metrics;
 use crate::ondisk::OnDiskDb;
+use crate::plan::{Output, Plan, Planner, Stage};
+use crate::preload::Preload;
+use crate::vector::nearest;
+use crate::wal::{mutates, Wal};
 use rayon::prelude::*;
-use serde_json::{Value as Json, Value};
-use std::collections::HashMap;
+use serde_json::{json, Value as Json, Value};
+use std::borrow::Borrow;
+use std::cmp::Ordering;
+use std::collections::{BTreeMap, BTreeSet, HashMap};
 use std::path::Path;
+use std::sync::atomic::{AtomicBool, Ordering as AtomicOrdering};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
 
 pub(crate) const PAGE_SIZE: usize = 50;
+/// the default number of rows returned by queries without selects or a limit
+pub const DEFAULT_LIMIT: usize = 50;
+/// the most rows sampled from the scanned keys to estimate the result of a query
+pub const ESTIMATE_SAMPLE: usize = 1000;
+/// the cmds logged between checkpoints, which save the entries they changed and empty
+/// the log
+pub const CHECKPOINT_EVERY: usize = 10_000;
 
 pub struct Memson {
-    mem_db: InMemDb,
+    /// the entries in memory, read by the cmds of the readers alongside each other
+    mem_db: ConcurrentDb,
     disk_db: OnDiskDb,
+    /// the entries still loading in the background
+    preload: Option<Preload>,
+    /// the cmd families disabled in this deployment
+    caps: Capabilities,
+    /// the log of the cmds changing entries since the last checkpoint, if any
+    wal: Option<Wal>,
+    /// the keys written by the cmds logged since the last checkpoint
+    logged: BTreeSet<String>,
+    /// the cmds logged since the last checkpoint
+    appends: usize,
+    /// the cmds logged between checkpoints
+    checkpoint_every: usize,
+    /// the metrics of the cmds of the last minutes, shared with the readers
+    metrics: Arc<Mutex<Metrics>>,
+    /// what each user may do
+    acls: Acls,
+    /// set while every entry is in memory, so reads needn't go to disk and can be shared
+    in_memory: Arc<AtomicBool>,
 }
 
 impl Memson {
     pub fn open<P: AsRef<Path>>(path: P) -> Result<Self, Error> {
-        let disk_db = OnDiskDb::open(path)?;
-        let mem_db = InMemDb::load(&disk_db)?;
-        Ok(Self { mem_db, disk_db })
+        Self::from_disk(OnDiskDb::open(path)?)
     }
 
-    pub(crate) fn eval(&mut self, cmd: Cmd) -> Result<Json, Error> {
-        match cmd {
-            Cmd::Set(key, arg) => {
-                let val = self.eval(*arg)?;
-                match self.disk_db.set(&key, &val)? {
-                    Some(val) => Ok(val),
-                    None => Ok(Json::Null),
-                }
-            }
-            cmd => self.mem_db.eval(cmd),
-        }
+    /// loads the entries of an open on disk db into memory
+    pub fn from_disk(disk_db: OnDiskDb) -> Result<Self, Error> {
+        let mem_db = InMemDb::load(&disk_db)?;
+        Ok(Self {
+            mem_db: ConcurrentDb::new(mem_db),
+            disk_db,
+            preload: None,
+            caps: Capabilities::default(),
+            wal: None,
+            logged: BTreeSet::new(),
+            appends: 0,
+            checkpoint_every: CHECKPOINT_EVERY,
+            metrics: Arc::default(),
+            acls: Acls::default(),
+            in_memory: Arc::new(AtomicBool::new(true)),
+        })
     }
 
-    pub(crate) fn query(&mut self, cmd: QueryCmd) -> Result<Json, Error> {
-        self.mem_db.query(cmd)
+    /// loads the priority keys, then answers cmds while the other entries load in the
+    /// background
+    pub fn open_with_priority<P: AsRef<Path>>(path: P, keys: &[String]) -> Result<Self, Error> {
+        Self::from_disk_with_priority(OnDiskDb::open(path)?, keys)
     }
-}
-
-pub struct Query<'a> {
-    pub(crate) db: &'a InMemDb,
-    pub(crate) cmd: QueryCmd,
-}
-
-/// Represents rows from a table from memson
-/// Avoids doing a deep copy
-enum Rows<'a> {
-    Val(Vec<Json>),
-    Ref(&'a [Json]),
-}
 
-impl<'a> Rows<'a> {
-    /// slice of rows
-    fn as_slice(&self) -> &[Json] {
-        match self {
-            Rows::Val(vec) => vec.as_slice(),
-            Rows::Ref(slice) => slice,
+    /// loads the priority keys of an open on disk db into memory, then the other entries
+    /// in the background
+    pub fn from_disk_with_priority(disk_db: OnDiskDb, keys: &[String]) -> Result<Self, Error> {
+        let mut mem_db = InMemDb::new();
+        for key in keys {
+            if let Some(val) = disk_db.get(key)? {
+                mem_db.set(key.clone(), val);
+            }
         }
+        let loaded = keys.iter().cloned().collect();
+        let preload = Preload::spawn(disk_db.sled.clone(), loaded);
+        Ok(Self {
+            mem_db: ConcurrentDb::new(mem_db),
+            disk_db,
+            preload: Some(preload),
+            caps: Capabilities::default(),
+            wal: None,
+            logged: BTreeSet::new(),
+            appends: 0,
+            checkpoint_every: CHECKPOINT_EVERY,
+            metrics: Arc::default(),
+            acls: Acls::default(),
+            in_memory: Arc::default(),
+        })
     }
-}
-
-/// merge two groupby maps together. if key exists in both then the array are merged together.
-fn merge_grouping(
-    mut x: HashMap<String, Vec<Json>>,
-    y: HashMap<String, Vec<Json>>,
-) -> HashMap<String, Vec<Json>> {
-    for (key, val) in y {
-        let vals = x.entry(key).or_insert_with(Vec::new);
-        vals.extend(val);
-    }
-    x
-}
 
-/// evaluation of sort by
-fn eval_sortby(rows: &[Json], key: &str, descend: bool) -> Vec<Json> {
-    let mut r = rows.to_vec();
-    if descend {
-        r.par_sort_by(|x, y| sortby_desc_key(key, x, y));
-    } else {
-        r.par_sort_by(|x, y| sortby_key(key, x, y));
+    /// adds the entries loaded in the background since the last cmd. Entries the cmd
+    /// reads or writes that aren't loaded yet, or were evicted, are loaded from disk, and
+    /// cmds over every entry wait for the rest to load
+    fn apply_preload(&mut self, cmd: &Cmd) -> Result<(), Error> {
+        if self.preload.is_none() && self.mem_db.read().max_memory().is_none() {
+            return Ok(());
+        }
+        let mut done = true;
+        if let Some(preload) = &mut self.preload {
+            let (entries, finished) = preload.drain(reads_every_key(cmd))?;
+            for (key, val) in entries {
+                if !self.mem_db.read().has(&key) {
+                    self.mem_db.write().set(key, val);
+                }
+            }
+            done = finished;
+        }
+        let mut keys = BTreeSet::new();
+        read_keys(cmd, &mut keys);
+        let mut writes = BTreeSet::new();
+        written_keys(cmd, &mut writes);
+        if let Some(preload) = &mut self.preload {
+            for key in &writes {
+                preload.skip(key);
+            }
+        }
+        keys.extend(writes);
+        // logged keys missing from memory were deleted since the checkpoint, as unsaved
+        // entries aren't evicted
+        for key in keys.difference(&self.logged) {
+            if !self.mem_db.read().has(key) {
+                if let Some(val) = self.disk_db.get(key)? {
+                    self.mem_db.write().set(key.clone(), val);
+                }
+            }
+        }
+        if done {
+            self.preload = None;
+            let evicts = self.mem_db.read().max_memory().is_some();
+            self.in_memory.store(!evicts, AtomicOrdering::Release);
+        }
+        Ok(())
     }
-    r
-}
 
-impl<'a> Query<'a> {
-    /// Create query from a reference to the key/value cache and query command
-    pub fn from(db: &'a InMemDb, cmd: QueryCmd) -> Self {
-        Self { db, cmd }
+    /// deletes the expired entries from memory and disk
+    fn expire_due(&mut self) -> Result<(), Error> {
+        let keys = self.mem_db.write().expire_due()?;
+        for key in keys {
+            if let Some(preload) = &mut self.preload {
+                preload.skip(&key);
+            }
+            self.disk_db.delete(&key)?;
+            if let Some(wal) = &mut self.wal {
+                wal.append(&Cmd::Delete(key))?;
+            }
+        }
+        Ok(())
     }
 
-    /// executes the query
-    pub fn exec(&self) -> Result<Json, Error> {
-        let rows = self.eval_rows()?;
-        if let Some(by) = &self.cmd.by {
-            self.eval_grouped_selects(by.as_ref(), rows)
-        } else {
-            self.eval_select(rows)
+    /// replays the cmds of the write-ahead log at a path, saves the entries they changed
+    /// to disk and empties the log, then logs every cmd changing entries to it rather than
+    /// saving the entries themselves
+    pub fn open_wal<P: AsRef<Path>>(&mut self, path: P, sync: bool) -> Result<(), Error> {
+        let (wal, cmds) = Wal::open(path, sync)?;
+        for cmd in cmds {
+            self.apply_preload(&cmd)?;
+            written_keys(&cmd, &mut self.logged);
+            self.mem_db.read().pattern_writes(&cmd, &mut self.logged);
+            // the cmds failing now failed when logged too, leaving the same entries
+            let _ = self.mem_db.write().eval(cmd);
+            self.mem_db
+                .write()
+                .keep_unsaved(self.logged.iter().cloned());
         }
+        self.wal = Some(wal);
+        self.checkpoint()
     }
 
-    /// evaluate the rows to query against
-    fn eval_rows(&self) -> Result<Rows, Error> {
-        let rows = self.eval_db_rows()?;
-        let descend = self.descend();
-        let rows = match (&self.cmd.filter, &self.cmd.sort) {
-            (Some(filter), Some(key)) => {
-                let cmd = Cmd::parse(filter.clone())?;
-                let rows = self.eval_where(rows, &cmd)?;
-                Rows::Val(eval_sortby(&rows, key, descend))
-            }
-            (Some(filter), None) => {
-                let cmd = Cmd::parse(filter.clone())?;
-                Rows::Val(self.eval_where(rows, &cmd)?)
-            }
-            (None, Some(key)) => Rows::Val(eval_sortby(rows, key, descend)),
-            (None, None) => Rows::Ref(rows),
+    /// saves the entries changed by the cmds logged since the last checkpoint to disk and
+    /// empties the log, so it doesn't grow for the whole uptime and isn't all replayed on
+    /// startup
+    fn checkpoint(&mut self) -> Result<(), Error> {
+        let wal = match &mut self.wal {
+            Some(wal) => wal,
+            None => return Ok(()),
         };
-        Ok(rows)
-    }
-
-    /// check if the sort order is descending
-    fn descend(&self) -> bool {
-        self.cmd.descend.unwrap_or(false)
+        let mem_db = self.mem_db.read();
+        for key in &self.logged {
+            match mem_db.get(key) {
+                Ok(val) => self.disk_db.set(key, val)?,
+                Err(_) => self.disk_db.delete(key)?,
+            };
+        }
+        self.disk_db.sled.flush().map_err(|_| Error::BadIO)?;
+        wal.truncate()?;
+        // deadlines aren't saved to disk, so they're logged again to outlive the next restart
+        let now = unix_secs();
+        for (key, ttl) in mem_db.expiring() {
+            wal.append(&Cmd::ExpireAt(key.to_string(), now + ttl.as_secs_f64()))?;
+        }
+        drop(mem_db);
+        self.mem_db.write().saved();
+        self.logged.clear();
+        self.appends = 0;
+        Ok(())
     }
 
-    /// evaulate the grouped selects
-    fn eval_grouped_selects(&self, by: &Cmd, rows: Rows) -> Result<Json, Error> {
-        let grouping = self.eval_grouping(by, rows.as_slice())?;
-        if let Some(selects) = &self.cmd.selects {
-            self.eval_grouped_select(grouping, selects)
-        } else {
-            let mut obj = JsonObj::new();
-            for (k, v) in grouping {
-                obj.insert(k, v.into_iter().map(Json::from).collect());
-            }
-            Ok(Json::from(obj))
+    /// evaluates a cmd changing entries in memory, then logs it; the entries are saved
+    /// and the log emptied every so many cmds, or once they take too much memory
+    fn eval_logged(&mut self, mut cmd: Cmd) -> Result<Json, Error> {
+        // expiries are logged with their deadlines, and rows generated with their seed, so
+        // replaying them doesn't extend the expiries or make other rows
+        cmd.expire_from(unix_secs());
+        cmd.seed_from(clock_seed());
+        let logged = cmd.clone();
+        let mut writes = BTreeSet::new();
+        written_keys(&cmd, &mut writes);
+        self.mem_db.read().pattern_writes(&cmd, &mut writes);
+        let val = self.mem_db.write().eval(cmd)?;
+        if let Some(wal) = &mut self.wal {
+            wal.append(&logged)?;
+            self.mem_db.write().keep_unsaved(writes.iter().cloned());
+            self.logged.extend(writes);
+            self.appends += 1;
+        }
+        // unsaved entries aren't evicted, so they're saved once they take too much memory
+        if self.appends >= self.checkpoint_every || self.mem_db.read().over_max_memory() {
+            self.checkpoint()?;
         }
+        Ok(val)
     }
 
-    /// evaulate the commands by grouped json values
-    fn eval_grouped_select(
-        &self,
-        grouping: HashMap<String, Vec<Json>>,
-        selects: &HashMap<String, Cmd>,
-    ) -> Result<Json, Error> {
-        let mut keyed_obj = JsonObj::new();
-        for (key, keyed_rows) in grouping {
-            let mut obj = JsonObj::new();
-            let keyed_val = Json::Array(keyed_rows);
-            for (col, cmd) in selects {
-                if let Some(v) = eval_rows_cmd(cmd.clone(), &keyed_val) {
-                    obj.insert(col.to_string(), v);
-                }
+    pub(crate) fn eval(&mut self, cmd: Cmd) -> Result<Json, Error> {
+        self.caps.check(&cmd)?;
+        self.apply_preload(&cmd)?;
+        self.expire_due()?;
+        match cmd {
+            Cmd::Batch(cmds) => Ok(eval_batch(cmds, |cmd| self.eval(cmd))),
+            Cmd::Restore(name) => {
+                let entries = read_backup(&self.mem_db.read().backup_path(&name)?)?;
+                self.restore_entries(entries)
+            }
+            Cmd::MetricsHistory { minutes } => {
+                let bytes = self.mem_db.read().size();
+                let metrics = self.metrics.lock().unwrap();
+                Ok(metrics.history(minutes, now_secs(), bytes))
+            }
+            cmd if self.wal.is_some() && mutates(&cmd) => self.eval_logged(cmd),
+            Cmd::Merge(key, patch) => {
+                let val = self.mem_db.write().eval(Cmd::Merge(key.clone(), patch))?;
+                self.disk_db.set(&key, &val)?;
+                Ok(val)
+            }
+            Cmd::MoveRows { from, to, filter } => {
+                let cmd = Cmd::MoveRows {
+                    from: from.clone(),
+                    to: to.clone(),
+                    filter,
+                };
+                let val = self.mem_db.write().eval(cmd)?;
+                let mem_db = self.mem_db.read();
+                let (from_rows, to_rows) = (mem_db.get(&from)?, mem_db.get(&to)?);
+                self.disk_db
+                    .set_many(&[(&from, Some(from_rows)), (&to, Some(to_rows))])?;
+                Ok(val)
+            }
+            // sets go through memory like any write, so conditions see the entries in
+            // memory and subscribers are notified, then the keys written are saved
+            cmd @ (Cmd::Set(_, _, _) | Cmd::GetSet(_, _) | Cmd::Tx(_) | Cmd::Script(_)) => {
+                self.eval_saved(cmd)
             }
-            keyed_obj.insert(key, Json::Object(obj));
+            // entries evicted are loaded from disk again, so every write is saved
+            cmd if mutates(&cmd) && self.mem_db.read().max_memory().is_some() => {
+                self.eval_saved(cmd)
+            }
+            cmd => self.mem_db.write().eval(cmd),
         }
-        Ok(Json::from(keyed_obj))
     }
 
-    /// evaulate the group by statements
-    // TODO(jaupe) refactor to change val type to Json from Vec<Json>
-    fn eval_grouping(&self, by: &Cmd, rows: &[Json]) -> Result<HashMap<String, Vec<Json>>, Error> {
-        match by {
-            Cmd::Key(key) => {
-                let g: HashMap<String, Vec<Value>> = rows
-                    .par_iter()
-                    .map(|row| (row, row.get(key)))
-                    .filter(|(_, x)| x.is_some())
-                    .map(|(row, x)| (row, x.unwrap()))
-                    .fold(HashMap::new, |mut g, (row, val)| {
-                        let entry: &mut Vec<Json> = g.entry(json_str(val)).or_insert_with(Vec::new);
-                        entry.push(row.clone());
-                        g
-                    })
-                    .reduce(HashMap::new, merge_grouping);
-                Ok(g)
-            }
-            _ => Err(Error::BadGroupBy),
+    /// evaluates a cmd changing entries in memory, then saves the entries of the keys it
+    /// wrote to disk
+    fn eval_saved(&mut self, cmd: Cmd) -> Result<Json, Error> {
+        let mut writes = BTreeSet::new();
+        written_keys(&cmd, &mut writes);
+        self.mem_db.read().pattern_writes(&cmd, &mut writes);
+        let val = self.mem_db.write().eval(cmd)?;
+        let mem_db = self.mem_db.read();
+        for key in &writes {
+            match mem_db.get(key) {
+                Ok(val) => self.disk_db.set(key, val)?,
+                Err(_) => self.disk_db.delete(key)?,
+            };
         }
+        Ok(val)
     }
 
-    /// evaulate the rows from the memson cache
-    fn eval_db_rows(&self) -> Result<&[Value], Error> {
-        let val = self.db.get(&self.cmd.from)?;
-        val.as_array()
-            .map(|x| x.as_slice())
-            .ok_or(Error::ExpectedArr)
+    /// evaluates a cmd of a user within the time left to the caller; the cmd, and every
+    /// query and cmd nested in it, times out once the token is cancelled
+    pub(crate) fn eval_within(
+        &mut self,
+        cmd: Cmd,
+        cancel: Cancel,
+        user: Option<&User>,
+    ) -> Result<Json, Error> {
+        let prev = self.mem_db.write().set_cancel(cancel);
+        let start = Instant::now();
+        let res = self.eval_as(cmd, user);
+        self.record(start, res.is_ok());
+        self.mem_db.write().set_cancel(prev);
+        res
     }
 
-    /// evaulate the where statement
-    fn eval_where(&self, rows: &[Json], filter: &Cmd) -> Result<Vec<Json>, Error> {
-        let mut filtered_rows = Vec::new();
-        for row in rows {
-            if let Some(obj) = row.as_object() {
-                if let Some(true) = eval_filter(filter.clone(), row) {
-                    filtered_rows.push(Json::from(obj.clone()));
-                }
-            }
-        }
-        Ok(filtered_rows)
+    /// evaluates a query of a user within the time left to the caller
+    pub(crate) fn query_within(
+        &mut self,
+        cmd: QueryCmd,
+        cancel: Cancel,
+        user: Option<&User>,
+    ) -> Result<Json, Error> {
+        let prev = self.mem_db.write().set_cancel(cancel);
+        let start = Instant::now();
+        let res = self.eval_as(Cmd::Query(cmd), user);
+        self.record(start, res.is_ok());
+        self.mem_db.write().set_cancel(prev);
+        res
     }
 
-    // TODO remove cloning
-    /// evaluate the select statements
-    fn eval_select(&self, rows: Rows) -> Result<Json, Error> {
-        match &self.cmd.selects {
-            Some(selects) => self.eval_obj_selects(selects, rows),
-            None => self.eval_select_all(rows.as_slice()),
+    /// evaluates a cmd if the user may, keeping track of the subscriptions it made
+    fn eval_as(&mut self, cmd: Cmd, user: Option<&User>) -> Result<Json, Error> {
+        self.acls.check(user, &cmd)?;
+        let pubsub = matches!(
+            cmd,
+            Cmd::Subscribe(_, _) | Cmd::SubscribeQuery(_) | Cmd::Unsubscribe(_)
+        );
+        let tracked = if pubsub { Some(cmd.clone()) } else { None };
+        let val = self.eval(cmd)?;
+        if let Some(cmd) = tracked {
+            self.acls.track(user, &cmd, &val);
         }
+        Ok(val)
     }
 
-    /// evaluate select statements when structured as a json object
-    fn eval_obj_selects(&self, selects: &HashMap<String, Cmd>, rows: Rows) -> Result<Json, Error> {
-        if selects.is_empty() {
-            return self.eval_select_all(rows.as_slice());
-        }
-        //todo the cmds vec is not neccessary
-        let mut projections = Map::new();
-        for (name, select) in selects {
-            let val = apply_rows(select.clone(), rows.as_slice())?;
-            projections.insert(name.to_string(), val);
-        }
-        Ok(Json::Object(projections))
+    /// begins indexing the rows of a key by a field for a user, as `createIndex` would;
+    /// returns the id of the build and a snapshot of the rows to scan without holding
+    /// memson, so other cmds go on meanwhile
+    pub(crate) fn begin_index(
+        &mut self,
+        key: &str,
+        field: &str,
+        user: Option<&User>,
+    ) -> Result<(u64, Arc<Json>), Error> {
+        let cmd = Cmd::CreateIndex {
+            key: key.to_string(),
+            field: field.to_string(),
+        };
+        self.acls.check(user, &cmd)?;
+        self.caps.check(&cmd)?;
+        self.apply_preload(&cmd)?;
+        self.expire_due()?;
+        let mut mem_db = self.mem_db.write();
+        mem_db.eval(Cmd::Has(key.to_string()))?;
+        mem_db.begin_index(key, field)
     }
 
-    //TODO paginate
-    /// evaluate select query without any select statements
-    fn eval_select_all(&self, rows: &[Json]) -> Result<Json, Error> {
-        let mut output = Vec::new();
-        for row in rows.iter().take(50).cloned() {
-            output.push(row);
-        }
-        Ok(Json::from(output))
+    /// swaps in an index scanned since `begin_index`, once it caught up with the writes
+    /// to its key
+    pub(crate) fn finish_index(&mut self, id: u64, index: Index) -> Result<(), Error> {
+        self.mem_db.write().finish_index(id, index)
     }
-}
 
-#[cfg(test)]
-mod tests {
-    use super::*;
-    use assert_approx_eq::assert_approx_eq;
+    /// checks a user may see the admin resources of memson
+    pub(crate) fn check_admin(&self, user: Option<&User>) -> Result<(), Error> {
+        self.acls.check_admin(user)
+    }
 
-    use serde_json::json;
+    /// records a cmd of a client started at the given instant in the metrics history
+    fn record(&mut self, start: Instant, ok: bool) {
+        record(&self.metrics, &self.mem_db, start, ok);
+    }
 
-    fn set<K: Into<String>>(key: K, arg: Cmd) -> Cmd {
-        Cmd::Set(key.into(), b(arg))
+    /// dumps the entries of the given keys, or of every entry, in a binary format, after
+    /// the entries still loading
+    pub fn dump(
+        &mut self,
+        keys: Option<&[String]>,
+        format: Format,
+        user: Option<&User>,
+    ) -> Result<Vec<u8>, Error> {
+        let cmd = match keys {
+            Some(keys) => Cmd::MGet(keys.to_vec()),
+            None => Cmd::Len(None),
+        };
+        self.acls.check(user, &cmd)?;
+        self.caps.check(&cmd)?;
+        self.apply_preload(&cmd)?;
+        self.expire_due()?;
+        self.mem_db.write().dump(keys, format)
     }
 
-    fn key<K: Into<String>>(k: K) -> Cmd {
-        Cmd::Key(k.into())
+    /// restores the entries of a dump of a user in one tx, replacing the entries of the
+    /// same keys; returns the number of entries restored
+    pub fn restore(
+        &mut self,
+        data: &[u8],
+        format: Format,
+        user: Option<&User>,
+    ) -> Result<Json, Error> {
+        let sets = restoring(restore(data, format)?);
+        let n = sets.len();
+        let tx = Cmd::Tx(sets);
+        self.acls.check(user, &tx)?;
+        self.eval(tx)?;
+        Ok(Json::from(n))
     }
 
-    fn b<T>(val: T) -> Box<T> {
-        Box::new(val)
+    /// sets the entries in one tx, saved to disk or logged as any tx
+    fn restore_entries(&mut self, entries: BTreeMap<String, Json>) -> Result<Json, Error> {
+        let sets = restoring(entries);
+        let n = sets.len();
+        self.eval(Cmd::Tx(sets))?;
+        Ok(Json::from(n))
     }
 
-    fn get<K: Into<String>>(k: K, arg: Cmd) -> Cmd {
-        Cmd::Get(k.into(), b(arg))
+    /// sets the number of rows returned by queries without selects or a limit; none for all rows
+    pub fn set_default_limit(&mut self, limit: Option<usize>) {
+        self.mem_db.write().set_default_limit(limit);
     }
 
-    fn avg(arg: Cmd) -> Cmd {
-        Cmd::Avg(b(arg))
+    /// caps the approximate bytes the entries take in memory, evicting entries chosen by
+    /// the policy beyond it; evicted entries are loaded from disk again when next named by
+    /// a cmd, but are missed by cmds over every entry
+    pub fn set_max_memory(&mut self, max_bytes: usize, eviction: Box<dyn Eviction>) {
+        self.mem_db.write().set_max_memory(max_bytes, eviction);
+        self.in_memory.store(false, AtomicOrdering::Release);
     }
 
-    fn first(arg: Cmd) -> Cmd {
-        Cmd::First(b(arg))
+    /// rejects the cmds that can grow entries once they take more bytes in memory than the
+    /// soft limit, while still serving reads and deletes
+    pub fn set_soft_limit(&mut self, bytes: usize) {
+        self.mem_db.write().set_soft_limit(bytes);
     }
 
-    fn last(arg: Cmd) -> Cmd {
-        Cmd::Last(b(arg))
+    /// evaluates queries on a pool of their own with n threads
+    pub fn set_query_threads(&mut self, n: usize) -> Result<(), Error> {
+        self.mem_db.write().set_query_threads(n)
     }
 
-    fn max(arg: Cmd) -> Cmd {
-        Cmd::Max(b(arg))
+    /// fronts another datastore: keys missing from memson are read through from it, and
+    /// keys changed in memson are written through to it
+    pub fn set_backend(&mut self, backend: Box<dyn Backend>) {
+        self.mem_db.write().set_backend(backend);
     }
 
-    fn min(arg: Cmd) -> Cmd {
-        Cmd::Min(b(arg))
+    /// sets the dir backups are written to and restored from; backups are refused until
+    /// it's set, and clients can only name files directly inside it
+    pub fn set_backup_dir<P: AsRef<Path>>(&mut self, dir: P) {
+        self.mem_db.write().set_backup_dir(dir);
     }
 
-    fn dev(arg: Cmd) -> Cmd {
-        Cmd::Dev(b(arg))
+    /// sets the cmd families refused by this deployment
+    pub fn set_capabilities(&mut self, caps: Capabilities) {
+        self.caps = caps;
     }
 
-    fn var(arg: Cmd) -> Cmd {
-        Cmd::Var(b(arg))
+    /// sets what each user may do
+    pub fn set_acls(&mut self, acls: Acls) {
+        self.acls = acls;
     }
 
-    fn mul(x: Cmd, y: Cmd) -> Cmd {
-        Cmd::Mul(b(x), b(y))
+    /// a handle evaluating the cmds only reading entries in memory alongside each other,
+    /// and alongside the cmd memson is evaluating. It keeps the capabilities and grants
+    /// set so far, so is made once memson is configured
+    pub fn reader(&self) -> Reader {
+        Reader {
+            mem_db: self.mem_db.clone(),
+            caps: self.caps.clone(),
+            acls: Arc::new(self.acls.clone()),
+            metrics: self.metrics.clone(),
+            in_memory: self.in_memory.clone(),
+        }
     }
+}
 
-    fn div(x: Cmd, y: Cmd) -> Cmd {
-        Cmd::Div(b(x), b(y))
+/// A handle evaluating the cmds of clients that only read entries already in memory under
+/// a lock shared with other readers, rather than waiting for memson to evaluate them in
+/// turn
+#[derive(Clone, Debug)]
+pub struct Reader {
+    mem_db: ConcurrentDb,
+    caps: Capabilities,
+    /// the grants of the users; subscriptions aren't read by the cmds shared
+    acls: Arc<Acls>,
+    metrics: Arc<Mutex<Metrics>>,
+    in_memory: Arc<AtomicBool>,
+}
+
+impl Reader {
+    /// evaluates a cmd of a user within the time left to the caller if it can be shared;
+    /// otherwise hands it back, to be evaluated by memson. Cmds the user may not evaluate
+    /// are handed back too, so memson refuses them as usual
+    pub fn eval_within(
+        &self,
+        cmd: Cmd,
+        cancel: &Cancel,
+        user: Option<&User>,
+    ) -> Result<Result<Json, Error>, Box<Cmd>> {
+        let allowed = self.caps.check(&cmd).is_ok() && self.acls.check(user, &cmd).is_ok();
+        if !allowed || !self.in_memory.load(AtomicOrdering::Acquire) {
+            return Err(Box::new(cmd));
+        }
+        let start = Instant::now();
+        let res = self.mem_db.share(cmd, cancel)?;
+        record(&self.metrics, &self.mem_db, start, res.is_ok());
+        Ok(res)
     }
+}
 
-    fn add(x: Cmd, y: Cmd) -> Cmd {
-        Cmd::Add(b(x), b(y))
+/// records a cmd of a client started at the given instant in the metrics history
+fn record(metrics: &Mutex<Metrics>, mem_db: &ConcurrentDb, start: Instant, ok: bool) {
+    let elapsed = start.elapsed();
+    let mut metrics = metrics.lock().unwrap();
+    metrics.record(now_secs(), elapsed, ok, || mem_db.read().size());
+}
+
+/// the cmds setting the entries of a dump or backup
+fn restoring(entries: BTreeMap<String, Json>) -> Vec<Cmd> {
+    entries
+        .into_iter()
+        .map(|(key, val)| Cmd::Set(key, Box::new(Cmd::Json(val)), None))
+        .collect()
+}
+
+/// the seconds since the unix epoch
+fn now_secs() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_secs())
+        .unwrap_or(0)
+}
+
+/// checks if a cmd depends on every entry, rather than only on the keys it names
+pub(crate) fn reads_every_key(cmd: &Cmd) -> bool {
+    let every_key = matches!(
+        cmd,
+        Cmd::Len(None) | Cmd::Count(None) | Cmd::Compress(_) | Cmd::Intern(_) | Cmd::Backup(_)
+    );
+    every_key || scans_keys(cmd) || cmd.children().into_iter().any(reads_every_key)
+}
+
+pub struct Query<'a> {
+    pub(crate) db: &'a InMemDb,
+    pub(crate) cmd: QueryCmd,
+    /// the where statement when parsed ahead of evaluation
+    pub(crate) filter: Option<Cmd>,
+    /// the rows returned without selects or a limit; subqueries are never capped
+    pub(crate) default_limit: Option<usize>,
+    cancel: Cancel,
+}
+
+/// A query parsed once and evaluated many times with different params
+#[derive(Clone, Debug, PartialEq)]
+pub struct Prepared {
+    cmd: QueryCmd,
+    filter: Option<Cmd>,
+}
+
+impl Prepared {
+    /// parse the where statement of a query so it isn't parsed when executed
+    pub fn new(cmd: QueryCmd) -> Result<Self, Error> {
+        let filter = cmd.filter.clone().map(Cmd::parse).transpose()?;
+        Ok(Self { cmd, filter })
     }
 
-    fn sub(x: Cmd, y: Cmd) -> Cmd {
-        Cmd::Sub(b(x), b(y))
+    /// create a query with the params bound to the placeholders of the prepared query
+    pub fn bind<'a>(&self, db: &'a InMemDb, params: &JsonObj) -> Result<Query<'a>, Error> {
+        let mut cmd = self.cmd.clone();
+        if let Some(selects) = &mut cmd.selects {
+            for select in selects.values_mut() {
+                select.bind(params)?;
+            }
+        }
+        if let Some(by) = &mut cmd.by {
+            by.bind(params)?;
+        }
+        let mut filter = self.filter.clone();
+        if let Some(filter) = &mut filter {
+            filter.bind(params)?;
+        }
+        let mut qry = Query::from(db, cmd);
+        qry.filter = filter;
+        Ok(qry)
     }
+}
 
-    fn bad_type() -> Result<Json, Error> {
-        Err(Error::BadType)
+/// Cancellation token shared by a query and its subqueries
+/// The query is cancelled once cancel is called or the deadline passes
+#[derive(Clone, Debug, Default)]
+pub struct Cancel {
+    deadline: Option<Instant>,
+    cancelled: Arc<AtomicBool>,
+    /// the flags of the tokens this token was derived from
+    parents: Vec<Arc<AtomicBool>>,
+}
+
+impl Cancel {
+    /// create a token that cancels after the timeout
+    pub fn after(timeout: Duration) -> Self {
+        Self {
+            deadline: Some(Instant::now() + timeout),
+            ..Self::default()
+        }
     }
 
-    fn eval(cmd: Cmd) -> Result<Json, Error> {
-        let mut db = InMemDb::new();
-        insert_data(&mut db);
-        db.eval(cmd)
+    /// a token cancelled along with this one, and after the timeout if any, but whose own
+    /// cancellation leaves this one running
+    pub fn child(&self, timeout: Option<Duration>) -> Self {
+        let deadline = timeout.map(|timeout| Instant::now() + timeout);
+        let mut parents = self.parents.clone();
+        parents.push(self.cancelled.clone());
+        Self {
+            deadline: match (self.deadline, deadline) {
+                (Some(x), Some(y)) => Some(x.min(y)),
+                (x, y) => x.or(y),
+            },
+            cancelled: Arc::new(AtomicBool::new(false)),
+            parents,
+        }
     }
 
-    fn orders_val() -> Json {
-        json!([
-                { "time": 0, "customer": "james", "qty": 2, "price": 9.0, "discount": 10 },
-                { "time": 1, "customer": "ania", "qty": 2, "price": 2.0 },
-                { "time": 2, "customer": "misha", "qty": 4, "price": 1.0 },
-                { "time": 3, "customer": "james", "qty": 10, "price": 16.0, "discount": 20 },
-                { "time": 4, "customer": "james", "qty": 1, "price": 16.0 },
-        ])
+    /// cancel the query
+    pub fn cancel(&self) {
+        self.cancelled.store(true, AtomicOrdering::Relaxed);
     }
 
-    fn insert_data(db: &mut InMemDb) {
-        db.set("a", json!([1, 2, 3, 4, 5]));
-        db.set("b", json!(true));
-        db.set("i", json!(2));
-        db.set("f", json!(3.3));
-        db.set("ia", json!([1, 2, 3, 4, 5]));
-        db.set("nia", json!([Json::Null, 2, Json::Null, 4, 5]));
-        db.set("nfa", json!([1, Json::Null, 3, Json::Null, 5]));
-        db.set("fa", json!([1.1, 2.2, 3.3, 4.4, 5.5]));
-        db.set("x", json!(4));
-        db.set("y", json!(5));
-        db.set("s", json!("hello"));
-        db.set("sa", json!(["a", "b", "c", "d"]));
-        db.set("t", table_data());
-        db.set("n", Json::Null);
-        db.set("orders", orders_val());
+    /// checks if the query has been cancelled or its deadline has passed
+    pub fn is_cancelled(&self) -> bool {
+        let flags = self.parents.iter().chain(Some(&self.cancelled));
+        if flags.into_iter().any(|x| x.load(AtomicOrdering::Relaxed)) {
+            return true;
+        }
+        match self.deadline {
+            Some(deadline) if Instant::now() >= deadline => {
+                self.cancel();
+                true
+            }
+            _ => false,
+        }
     }
 
-    #[test]
-    fn load_data() {
-        let path = "testdata";
-        let data = json!([
-                { "time": 0, "customer": "james", "qty": 2, "price": 9.0, "discount": 10 },
-                { "time": 1, "customer": "ania", "qty": 2, "price": 2.0 },
-                { "time": 2, "customer": "misha", "qty": 4, "price": 1.0 },
-                { "time": 3, "customer": "james", "qty": 10, "price": 16.0, "discount": 20 },
-                { "time": 4, "customer": "james", "qty": 1, "price": 16.0 },
-        ]);
-        {
-            let ondisk_db = OnDiskDb::open(path).unwrap();
-            ondisk_db.set("customers", &data).unwrap();
+    /// errors with a timeout if the query has been cancelled
+    pub(crate) fn check(&self) -> Result<(), Error> {
+        if self.is_cancelled() {
+            Err(Error::Timeout)
+        } else {
+            Ok(())
         }
-        let mut memson = Memson::open(path).unwrap();
-        assert_eq!(Ok(data), memson.eval(Cmd::Key("customers".to_string())));
     }
+}
 
-    #[test]
-    fn select_all_from_orders() {
-        let exp = json!([
-            { "time": 0, "customer": "james", "qty": 2, "price": 9.0, "discount": 10 },
-            { "time": 1, "customer": "ania", "qty": 2, "price": 2.0 },
-            { "time": 2, "customer": "misha", "qty": 4, "price": 1.0 },
-            { "time": 3, "customer": "james", "qty": 10, "price": 16.0, "discount": 20 },
-            { "time": 4, "customer": "james", "qty": 1, "price": 16.0 },
-        ]);
-        assert_eq!(Ok(exp), query(json!({"from": "orders"})));
+/// Represents rows from a table from memson
+/// Avoids doing a deep copy
+enum Rows<'a> {
+    Val(Vec<Json>),
+    Ref(&'a [Json]),
+}
+
+impl<'a> Rows<'a> {
+    /// slice of rows
+    fn as_slice(&self) -> &[Json] {
+        match self {
+            Rows::Val(vec) => vec.as_slice(),
+            Rows::Ref(slice) => slice,
+        }
     }
+}
 
-    #[test]
-    fn select_customer_from_orders() {
-        let exp = json!({"name":["james", "ania", "misha", "james", "james"]});
-        assert_eq!(
-            Ok(exp),
-            query(json!({
-                "select": {"name":{"key":"customer"}},
-                "from": "orders"
-            }))
-        );
+/// The estimated rows and bytes of the result of a query, the rows sampled to estimate
+/// them, and how the rows passing the stages of the query were estimated
+#[derive(Clone, Debug)]
+struct Estimate {
+    rows: f64,
+    bytes: f64,
+    sampled: usize,
+    method: &'static str,
+}
+
+/// The rows passed between the stages of a plan. Rows are filtered, sorted and paged as
+/// references into the scanned rows, and only copied once projected or output
+enum Stream<'a> {
+    Slice(&'a [Json]),
+    Refs(Vec<&'a Json>),
+    /// references into the rows of several keys, each with the position of its key; rows
+    /// are only copied and tagged with their key once materialized
+    Tagged {
+        keys: Vec<String>,
+        rows: Vec<(usize, &'a Json)>,
+    },
+    Val(Vec<Json>),
+}
+
+impl<'a> Stream<'a> {
+    fn len(&self) -> usize {
+        match self {
+            Stream::Slice(rows) => rows.len(),
+            Stream::Refs(rows) => rows.len(),
+            Stream::Tagged { rows, .. } => rows.len(),
+            Stream::Val(rows) => rows.len(),
+        }
     }
 
-    #[test]
-    fn select_customer_qty_from_orders() {
-        let exp =
-            json!({"name": ["james", "ania","misha","james","james"], "quantity": [2,2,4,10,1]});
-        assert_eq!(
-            Ok(exp),
-            query(json!({
-                "select": {
-                    "name":{"key":"customer"},
-                    "quantity":{"key": "qty"},
-                },
-                "from": "orders"
-            }))
-        );
+    fn first(&self) -> Option<&Json> {
+        match self {
+            Stream::Slice(rows) => rows.first(),
+            Stream::Refs(rows) => rows.first().copied(),
+            Stream::Tagged { rows, .. } => rows.first().map(|(_, row)| *row),
+            Stream::Val(rows) => rows.first(),
+        }
     }
 
-    #[test]
-    fn select_customer_qty_discount_from_orders() {
-        let exp = json!({
-            "name": ["james", "ania", "misha", "james", "james"],
-            "quantity": [2, 2, 4, 10, 1],
-            "discount": [10,20]
-        });
-        assert_eq!(
-            Ok(exp),
-            query(json!({
-                "select": {
-                    "name":{"key":"customer"},
-                    "quantity":{"key": "qty"},
-                    "discount":{"key": "discount"}
-                },
-                "from": "orders"
-            }))
-        );
+    /// references to the rows, unless they're owned
+    fn into_refs(self) -> Result<Vec<&'a Json>, Vec<Json>> {
+        match self {
+            Stream::Slice(rows) => Ok(rows.iter().collect()),
+            Stream::Refs(rows) => Ok(rows),
+            Stream::Tagged { keys, rows } => Err(rows
+                .into_iter()
+                .map(|(i, row)| tag_row(&keys[i], row))
+                .collect()),
+            Stream::Val(rows) => Err(rows),
+        }
     }
 
-    #[test]
-    fn select_unique_customer_from_orders() {
-        let exp = json!({ "uniqueNames": ["james", "ania", "misha"] });
-        assert_eq!(
-            Ok(exp),
-            query(json!({
-                "select": {
-                    "uniqueNames":{"unique": {"key":"customer"}},
-                },
-                "from": "orders"
-            }))
-        );
+    /// the rows at the positions
+    fn pick(self, at: Positions) -> Self {
+        match self {
+            Stream::Slice(rows) => Stream::Refs(at.pick(rows.iter()).collect()),
+            Stream::Refs(rows) => Stream::Refs(at.pick(rows.into_iter()).collect()),
+            Stream::Tagged { keys, rows } => Stream::Tagged {
+                keys,
+                rows: at.pick(rows.into_iter()).collect(),
+            },
+            Stream::Val(rows) => Stream::Val(at.pick(rows.into_iter()).collect()),
+        }
     }
 
-    #[test]
-    fn select_count_discount_count_quantity_from_orders() {
-        let exp = json!(
-            { "countDiscount": 2, "countCustomer": 5}
-        );
-        assert_eq!(
-            Ok(exp),
-            query(json!({
-                "select": {
-                    "countDiscount":{ "len": {"key": "discount"} },
-                    "countCustomer":{ "len": {"key": "customer"} },
-                },
-                "from": "orders"
-            }))
-        );
+    fn into_rows(self) -> Rows<'a> {
+        match self {
+            Stream::Slice(rows) => Rows::Ref(rows),
+            Stream::Refs(rows) => Rows::Val(rows.into_iter().cloned().collect()),
+            rows => match rows.into_refs() {
+                Ok(refs) => Rows::Val(refs.into_iter().cloned().collect()),
+                Err(rows) => Rows::Val(rows),
+            },
+        }
     }
+}
 
-    #[test]
-    fn select_mul_price_quantity_from_orders() {
-        let exp: Json = json!({ "value": [18.0, 4.0, 4.0, 160.0, 16.0] });
-        assert_eq!(
-            Ok(exp),
-            query(json!({
-                "select": {
-                    "value":{ "*": [{"key": "qty"}, {"key":"price"}] },
-                },
-                "from": "orders"
-            }))
-        );
+/// merge two groupby maps together. if key exists in both then the array are merged together.
+fn merge_grouping(
+    mut x: HashMap<String, Vec<Json>>,
+    y: HashMap<String, Vec<Json>>,
+) -> HashMap<String, Vec<Json>> {
+    for (key, val) in y {
+        let vals = x.entry(key).or_default();
+        vals.extend(val);
     }
+    x
+}
 
-    #[test]
-    fn select_sum_mul_price_quantity_from_orders() {
-        let exp = json!({ "totalValue": 202.0 });
-        assert_eq!(
-            Ok(exp),
-            query(json!({
-                "select": {
-                    "totalValue": {"sum": { "*": [{"key": "qty"}, {"key":"price"}]} },
-                },
-                "from": "orders"
-            }))
-        );
+/// the row field used to break ties between rows with equal sort keys
+pub(crate) const ID_KEY: &str = "_id";
+/// the field tagging rows from several keys with the key they came from
+pub(crate) const SRC_KEY: &str = "_src";
+/// the variable the post cmds of a query refer to the result before them by
+pub(crate) const POST_RESULT: &str = "result";
+
+/// copies rows, tagging each object with the key it came from
+pub(crate) fn tag_rows(key: &str, rows: &[Json]) -> Vec<Json> {
+    rows.iter().map(|row| tag_row(key, row)).collect()
+}
+
+/// copies a row, tagging an object with the key it came from
+pub(crate) fn tag_row(key: &str, row: &Json) -> Json {
+    let mut row = row.clone();
+    if let Json::Object(obj) = &mut row {
+        obj.insert(SRC_KEY.to_string(), Json::from(key));
     }
+    row
+}
 
-    #[test]
-    fn select_by_customer_from_orders() {
-        let exp = json!({
-                "james": [{ "time": 0, "customer": "james", "qty": 2, "price": 9.0, "discount": 10 },{ "time": 3, "customer": "james", "qty": 10, "price": 16.0, "discount": 20 },{ "time": 4, "customer": "james", "qty": 1, "price": 16.0 }],
-                "ania": [{ "time": 1, "customer": "ania", "qty": 2, "price": 2.0 }],
-                "misha": [{ "time": 2, "customer": "misha", "qty": 4, "price": 1.0 }],
-        });
-        assert_eq!(
-            Ok(exp),
-            query(json!({
-                "by": {"key":"customer"},
-                "from": "orders"
-            }))
-        );
+/// compares rows by key, breaking ties by id so the order is stable between requests
+fn cmp_rows(key: &str, descend: bool, x: &Json, y: &Json) -> Ordering {
+    let ord = if descend {
+        sortby_desc_key(key, x, y)
+    } else {
+        sortby_key(key, x, y)
+    };
+    ord.then_with(|| sortby_key(ID_KEY, x, y))
+}
+
+/// copies only the given fields of a row
+fn project(row: &Json, fields: &BTreeSet<&str>) -> Json {
+    match row {
+        Json::Object(obj) => Json::Object(
+            fields
+                .iter()
+                .filter_map(|key| obj.get(*key).map(|val| (key.to_string(), val.clone())))
+                .collect(),
+        ),
+        row => row.clone(),
     }
+}
 
-    #[test]
-    fn select_max_qty_by_customer_from_orders() {
-        let exp = json!({
-                "james": { "maxQty": 10 },
-                "ania": { "maxQty": 2 },
-                "misha": { "maxQty": 4 },
-        });
-        assert_eq!(
-            query(json!({
-                "select": {"maxQty":{"max":{"key":"qty"}}},
-                "by": {"key":"customer"},
-                "from": "orders"
-            })),
-            Ok(exp)
-        );
+/// a row for each element of the array field of each row, holding the element in place of
+/// the array. rows with an empty or missing array are dropped
+pub(crate) fn unnest<'r, I>(rows: I, field: &str) -> Vec<Json>
+where
+    I: IntoIterator<Item = &'r Json>,
+{
+    let mut out = Vec::new();
+    for row in rows {
+        let obj = match row {
+            Json::Object(obj) => obj,
+            _ => continue,
+        };
+        match obj.get(field) {
+            Some(Json::Array(elems)) => {
+                for elem in elems {
+                    let mut row = obj.clone();
+                    row.insert(field.to_string(), elem.clone());
+                    out.push(Json::Object(row));
+                }
+            }
+            Some(_) => out.push(row.clone()),
+            None => (),
+        }
     }
+    out
+}
 
-    #[test]
-    fn select_max_unique_qty_by_customer_from_orders() {
-        let exp = json!({
-                "james": { "maxUniqueQty": 10 },
-                "ania": { "maxUniqueQty": 2 },
-                "misha": { "maxUniqueQty": 4 },
-        });
-        assert_eq!(
-            query(json!({
-                "select": {"maxUniqueQty":{"max":{"unique":{"key":"qty"}}}},
-                "by": {"key":"customer"},
-                "from": "orders"
-            })),
-            Ok(exp)
-        );
+/// sorts rows in place by key; over row references the rows themselves are not moved
+fn sort_rows<R>(rows: &mut [R], key: &str, descend: bool)
+where
+    R: Borrow<Json> + Send,
+{
+    rows.par_sort_by(|x, y| cmp_rows(key, descend, x.borrow(), y.borrow()));
+}
+
+impl<'a> Query<'a> {
+    /// Create query from a reference to the key/value cache and query command; the query
+    /// is cancelled along with the cmd being evaluated by the cache
+    pub fn from(db: &'a InMemDb, cmd: QueryCmd) -> Self {
+        Self::within(db, cmd, db.cancel())
     }
 
-    #[test]
-    fn select_first_time_last_time_from_orders() {
-        let exp = json!({
-                "start": 0,
-                "end": 4,
-        });
-        assert_eq!(
-            query(json!({
-                "select": {
-                    "start": {"first":{"key":"time"}},
-                    "end": {"last":{"key":"time"}},
-                },
-                "from": "orders"
-            })),
-            Ok(exp)
-        );
+    /// create a query cancelled along with a token of its own, as for queries evaluated
+    /// alongside others through a shared db
+    pub fn within(db: &'a InMemDb, cmd: QueryCmd, cancel: &Cancel) -> Self {
+        let cancel = cancel.child(cmd.timeout_ms.map(Duration::from_millis));
+        Self {
+            db,
+            cmd,
+            filter: None,
+            default_limit: db.default_limit(),
+            cancel,
+        }
     }
 
-    #[test]
-    fn select_first_time_last_time_by_customer_from_orders() {
-        let exp = json!({
-                "james": { "startQty":2, "endQty":1 },
-                "ania": { "startQty":2, "endQty":2 },
-                "misha": { "startQty": 4, "endQty": 4},
-        });
-        assert_eq!(
-            query(json!({
-                "select": {
-                    "startQty":{"first":{"key":"qty"}},
-                    "endQty":{"last":{"key":"qty"}},
-                },
-                "by": {"key":"customer"},
-                "from": "orders"
-            })),
-            Ok(exp)
-        );
+    /// create a subquery that is cancelled along with this query
+    fn subquery(&self, cmd: QueryCmd) -> Query<'a> {
+        let mut qry = Query::from(self.db, cmd);
+        qry.default_limit = None;
+        qry.cancel = self
+            .cancel
+            .child(qry.cmd.timeout_ms.map(Duration::from_millis));
+        qry
     }
 
-    #[test]
-    fn select_order_volume_by_customer_from_orders() {
-        let exp = json!({
-                "james": { "orderVolume": 194.0 },
-                "ania": { "orderVolume": 4.0 },
-                "misha": { "orderVolume": 4.0 },
-        });
-        assert_eq!(
-            query(json!({
-                "select": {
-                    "orderVolume":{"sum":{"*": [{"key":"qty"},{"key":"price"}]}},
-                },
-                "by": {"key":"customer"},
-                "from": "orders"
-            })),
-            Ok(exp)
-        );
+    /// the token used to cancel the query
+    pub fn cancel(&self) -> &Cancel {
+        &self.cancel
     }
 
-    #[test]
-    fn select_order_value_by_customer_from_orders() {
-        let exp = json!({
-                "james":{ "orderVal": [18.0, 160.0, 16.0] },
-                "ania": { "orderVal": [4.0] },
+    /// plans the query with the default passes
+    pub fn plan(&self) -> Result<Plan<'_>, Error> {
+        Planner::default().plan(self)
+    }
+
+    /// executes the query
+    pub fn exec(&self) -> Result<Json, Error> {
+        let plan = self.plan()?;
+        let rows = self.eval_rows(&plan)?;
+        let val = match &plan.output {
+            Output::Group { by, selects } => self.eval_grouped_selects(by, *selects, rows),
+            Output::Select(selects) => self.eval_obj_selects(selects, rows),
+            Output::SelectAll { limit } => Ok(select_all(rows.as_slice(), *limit)),
+        }?;
+        let val = self.eval_post(val)?;
+        self.cancel.check()?;
+        Ok(val)
+    }
+
+    /// shapes the result through the post cmds in turn
+    fn eval_post(&self, mut val: Json) -> Result<Json, Error> {
+        for post in self.cmd.post.iter().flatten() {
+            let mut cmd = Cmd::parse(post.clone())?;
+            let mut vars = JsonObj::new();
+            vars.insert(POST_RESULT.to_string(), val);
+            cmd.bind_vars(&vars);
+            val = apply(cmd, &Json::Null)?;
+        }
+        Ok(val)
+    }
+
+    /// describes how the query would be evaluated without evaluating it
+    pub fn explain(&self) -> Json {
+        let plan = self.plan();
+        let from = match &self.cmd.from {
+            Source::Key(key) => Json::from(key.as_str()),
+            Source::Keys(keys) => Json::from(keys.clone()),
+            Source::Query(cmd) => {
+                json!({ "query": self.subquery(cmd.as_ref().clone()).explain() })
+            }
+        };
+        json!({
+            "from": from,
+            "unnest": self.cmd.unnest,
+            "where": self.cmd.filter,
+            "sort": self.cmd.sort.as_ref().map(|key| json!({"key": key, "descend": self.descend()})),
+            "after": self.cmd.after,
+            "limit": self.cmd.limit,
+            "defaultLimit": self.default_limit,
+            "path": self.select_path(),
+            "index": plan.as_ref().is_ok_and(Plan::uses_index),
+            "plan": plan.ok().map(|plan| plan.describe()),
+            "rowsScanned": self.estimate_rows_scanned(),
+            "warnings": lint(self.db, &self.cmd),
+        })
+    }
+
+    /// estimates the number of rows and bytes of the result without evaluating the query,
+    /// so callers can choose how to fetch it. The rows passing each stage are estimated
+    /// from the histograms of indexes, or from a sample of the scanned rows evaluated
+    /// through the plan; the bytes from the result of the sample. Post cmds are ignored
+    pub fn estimate(&self) -> Result<Json, Error> {
+        let est = self.estimate_result()?;
+        Ok(json!({
+            "rows": est.rows.round() as usize,
+            "bytes": est.bytes.round() as usize,
+            "sampled": est.sampled,
+            "method": est.method,
+        }))
+    }
+
+    fn estimate_result(&self) -> Result<Estimate, Error> {
+        let plan = self.plan()?;
+        let mut sample = Stream::Val(Vec::new());
+        let mut est = Estimate {
+            rows: 0.0,
+            bytes: 0.0,
+            sampled: 0,
+            method: "scan",
+        };
+        for stage in &plan.stages {
+            let before = sample.len();
+            match stage {
+                Stage::Scan(Source::Query(cmd)) => {
+                    est = self.subquery(cmd.as_ref().clone()).estimate_result()?;
+                    est.method = "subquery";
+                    continue;
+                }
+                Stage::Scan(from) => {
+                    let (scanned, n) = self.sample_from(from)?;
+                    if scanned.len() < n {
+                        est.method = "sample";
+                    }
+                    est.sampled = scanned.len();
+                    est.rows = n as f64;
+                    sample = scanned;
+                    continue;
+                }
+                // the rows kept are capped rather than sampled
+                Stage::Limit(n) | Stage::Knn(Knn { k: n, .. }) => {
+                    est.rows = est.rows.min(*n as f64);
+                    continue;
+                }
+                Stage::Slice(range) => {
+                    est.rows = range.positions(est.rows.round() as usize)?.n as f64;
+                    continue;
+                }
+                _ => sample = self.eval_stage(stage, sample)?,
+            }
+            est.rows *= match stage {
+                Stage::Filter {
+                    selectivity: Some(x),
+                    ..
+                } => {
+                    est.method = "index";
+                    *x
+                }
+                _ if before == 0 => 1.0,
+                _ => sample.len() as f64 / before as f64,
+            };
+            self.cancel.check()?;
+        }
+        let sample = sample.into_rows();
+        let sample = sample.as_slice();
+        let n = sample.len() as f64;
+        // the rows of the result, and of the result of the sample
+        let (rows, sample_rows) = match &plan.output {
+            Output::Group { by, .. } => {
+                let groups = self.eval_grouping(by, sample)?;
+                let seen = groups.len() as f64;
+                let singles = groups.values().filter(|x| x.len() == 1).count() as f64;
+                // groups seen once in the sample hint at the groups it missed
+                let missed = if n == 0.0 {
+                    0.0
+                } else {
+                    singles * (est.rows - n).max(0.0) / n
+                };
+                ((seen + missed).min(est.rows), seen)
+            }
+            Output::Select(selects) if selects.values().all(Cmd::is_aggregate) => (1.0, 1.0),
+            Output::Select(_) => (est.rows, n),
+            Output::SelectAll { limit } => {
+                let cap = limit.map_or(f64::MAX, |x| x as f64);
+                (est.rows.min(cap), n.min(cap))
+            }
+        };
+        let output = match &plan.output {
+            Output::Group { by, selects } => {
+                self.eval_grouped_selects(by, *selects, Rows::Ref(sample))?
+            }
+            Output::Select(selects) => self.eval_obj_selects(selects, Rows::Ref(sample))?,
+            Output::SelectAll { limit } => select_all(sample, *limit),
+        };
+        if sample_rows > 0.0 {
+            est.bytes = json_size(&output) as f64 * rows / sample_rows;
+        } else if est.method != "subquery" || rows == 0.0 {
+            est.bytes = 0.0;
+        }
+        est.rows = rows;
+        Ok(est)
+    }
+
+    /// a sample of the rows of the scanned keys, evenly spread over the rows, and the
+    /// number of rows scanned
+    fn sample_from(&self, from: &Source) -> Result<(Stream<'a>, usize), Error> {
+        let keys: Vec<&str> = match from {
+            Source::Key(key) => vec![key],
+            Source::Keys(keys) => keys.iter().map(String::as_str).collect(),
+            Source::Query(_) => return Err(Error::BadFrom),
+        };
+        let mut scanned = Vec::with_capacity(keys.len());
+        for key in &keys {
+            scanned.push(self.eval_db_rows(key)?);
+        }
+        let n: usize = scanned.iter().map(|rows| rows.len()).sum();
+        let step = n.div_ceil(ESTIMATE_SAMPLE).max(1);
+        let sample = match from {
+            Source::Keys(keys) => Stream::Tagged {
+                keys: keys.clone(),
+                rows: scanned
+                    .iter()
+                    .enumerate()
+                    .flat_map(|(i, rows)| rows.iter().step_by(step).map(move |row| (i, row)))
+                    .collect(),
+            },
+            _ => Stream::Refs(scanned[0].iter().step_by(step).collect()),
+        };
+        Ok((sample, n))
+    }
+
+    /// the evaluation path taken for the select statements
+    fn select_path(&self) -> &'static str {
+        let selects = match &self.cmd.selects {
+            Some(selects) if !selects.is_empty() => selects,
+            _ if self.cmd.by.is_some() => return "group_by",
+            _ => return "select_all",
+        };
+        let aggs = selects.values().filter(|cmd| cmd.is_aggregate()).count();
+        if self.cmd.by.is_some() {
+            "group_by"
+        } else if aggs == selects.len() {
+            "aggregate"
+        } else if aggs == 0 {
+            "project"
+        } else {
+            "invalid"
+        }
+    }
+
+    /// estimates the number of rows read from memson to evaluate the query
+    fn estimate_rows_scanned(&self) -> Option<usize> {
+        match &self.cmd.from {
+            Source::Key(key) => self.eval_db_rows(key).ok().map(|rows| rows.len()),
+            Source::Keys(keys) => keys
+                .iter()
+                .map(|key| self.eval_db_rows(key).ok().map(|rows| rows.len()))
+                .sum(),
+            Source::Query(cmd) => {
+                Query::from(self.db, cmd.as_ref().clone()).estimate_rows_scanned()
+            }
+        }
+    }
+
+    /// evaluate the stages of a plan, copying only the rows that remain
+    fn eval_rows(&self, plan: &Plan) -> Result<Rows<'a>, Error> {
+        Ok(self.eval_stream(plan)?.into_rows())
+    }
+
+    /// evaluate the stages of a plan over the rows of its scan
+    fn eval_stream(&self, plan: &Plan) -> Result<Stream<'a>, Error> {
+        let mut rows = Stream::Val(Vec::new());
+        for stage in &plan.stages {
+            rows = self.eval_stage(stage, rows)?;
+            self.cancel.check()?;
+        }
+        Ok(rows)
+    }
+
+    /// evaluate a stage of a plan over the rows of the stage before
+    fn eval_stage(&self, stage: &Stage, rows: Stream<'a>) -> Result<Stream<'a>, Error> {
+        let rows = match stage {
+            Stage::Scan(from) => self.eval_from(from)?,
+            Stage::Unnest(field) => Stream::Val(match rows.into_refs() {
+                Ok(refs) => unnest(refs, field),
+                Err(rows) => unnest(&rows, field),
+            }),
+            Stage::Filter { filter, index, .. } => self.eval_where(rows, filter, *index),
+            Stage::Knn(knn) => match rows.into_refs() {
+                Ok(refs) => Stream::Refs(nearest(refs, knn)),
+                Err(rows) => Stream::Val(nearest(rows, knn)),
+            },
+            Stage::Sort { key, descend } => match rows.into_refs() {
+                Ok(mut refs) => {
+                    sort_rows(&mut refs, key, *descend);
+                    Stream::Refs(refs)
+                }
+                Err(mut rows) => {
+                    sort_rows(&mut rows, key, *descend);
+                    Stream::Val(rows)
+                }
+            },
+            Stage::After { key, descend, row } => {
+                let after = |x: &Json| cmp_rows(key, *descend, x, row) == Ordering::Greater;
+                match rows.into_refs() {
+                    Ok(mut refs) => {
+                        refs.retain(|x| after(x));
+                        Stream::Refs(refs)
+                    }
+                    Err(mut rows) => {
+                        rows.retain(after);
+                        Stream::Val(rows)
+                    }
+                }
+            }
+            Stage::Slice(range) => {
+                let at = range.positions(rows.len())?;
+                rows.pick(at)
+            }
+            Stage::Limit(n) => match rows {
+                Stream::Slice(rows) => Stream::Slice(&rows[..(*n).min(rows.len())]),
+                Stream::Tagged { keys, mut rows } => {
+                    rows.truncate(*n);
+                    Stream::Tagged { keys, rows }
+                }
+                Stream::Refs(mut rows) => {
+                    rows.truncate(*n);
+                    Stream::Refs(rows)
+                }
+                Stream::Val(mut rows) => {
+                    rows.truncate(*n);
+                    Stream::Val(rows)
+                }
+            },
+            Stage::Project(fields) => Stream::Val(match rows {
+                Stream::Tagged { keys, rows } if fields.contains(SRC_KEY) => rows
+                    .into_iter()
+                    .map(|(i, row)| tag_row(&keys[i], &project(row, fields)))
+                    .collect(),
+                Stream::Tagged { rows, .. } => rows
+                    .into_iter()
+                    .map(|(_, row)| project(row, fields))
+                    .collect(),
+                rows => match rows.into_refs() {
+                    Ok(refs) => refs.into_iter().map(|row| project(row, fields)).collect(),
+                    Err(rows) => rows.iter().map(|row| project(row, fields)).collect(),
+                },
+            }),
+        };
+        Ok(rows)
+    }
+
+    /// the row fields read by the selects and by statement; none if whole rows are selected
+    pub(crate) fn projected_fields(&self) -> Option<BTreeSet<&str>> {
+        let selects = self.cmd.selects.as_ref().filter(|x| !x.is_empty())?;
+        let mut fields = BTreeSet::new();
+        for cmd in selects.values() {
+            cmd.row_fields(&mut fields);
+        }
+        if let Some(by) = &self.cmd.by {
+            by.row_fields(&mut fields);
+        }
+        Some(fields)
+    }
+
+    /// evaluate the from statement; either rows stored under one or more keys or the rows
+    /// of a subquery
+    fn eval_from(&self, from: &Source) -> Result<Stream<'a>, Error> {
+        let rows = match from {
+            Source::Key(key) => self.eval_db_rows(key).map(Stream::Slice)?,
+            Source::Keys(keys) => {
+                let mut rows = Vec::new();
+                for (i, key) in keys.iter().enumerate() {
+                    rows.extend(self.eval_db_rows(key)?.iter().map(|row| (i, row)));
+                }
+                let keys = keys.clone();
+                Stream::Tagged { keys, rows }
+            }
+            Source::Query(cmd) => {
+                let qry = self.subquery(cmd.as_ref().clone());
+                match qry.exec()? {
+                    Json::Array(rows) => Stream::Val(rows),
+                    _ => return Err(Error::BadFrom),
+                }
+            }
+        };
+        Ok(rows)
+    }
+
+    /// checks if there are enough rows to evaluate them across threads, or they're big
+    /// enough, going by the size of a row
+    fn is_parallel(&self, n: usize, row: Option<&Json>) -> bool {
+        n >= self.db.par_threshold()
+            || row.is_some_and(|x| n.saturating_mul(json_size(x)) >= self.db.par_bytes())
+    }
+
+    /// check if the sort order is descending
+    pub(crate) fn descend(&self) -> bool {
+        self.cmd.descend.unwrap_or(false)
+    }
+
+    /// evaulate the grouped selects
+    fn eval_grouped_selects(
+        &self,
+        by: &Cmd,
+        selects: Option<&HashMap<String, Cmd>>,
+        rows: Rows,
+    ) -> Result<Json, Error> {
+        let grouping = self.eval_grouping(by, rows.as_slice())?;
+        if let Some(selects) = selects {
+            self.eval_grouped_select(grouping, selects)
+        } else {
+            let mut obj = JsonObj::new();
+            for (k, v) in grouping {
+                obj.insert(k, v.into_iter().collect());
+            }
+            Ok(Json::from(obj))
+        }
+    }
+
+    /// evaulate the commands by grouped json values
+    fn eval_grouped_select(
+        &self,
+        grouping: HashMap<String, Vec<Json>>,
+        selects: &HashMap<String, Cmd>,
+    ) -> Result<Json, Error> {
+        let cancel = &self.cancel;
+        let eval_group = |(key, keyed_rows): (String, Vec<Json>)| {
+            let mut obj = JsonObj::new();
+            let keyed_val = Json::Array(keyed_rows);
+            for (col, cmd) in selects {
+                if cancel.is_cancelled() {
+                    break;
+                }
+                if let Some(v) = eval_rows_cmd(cmd.clone(), &keyed_val) {
+                    obj.insert(col.to_string(), v);
+                }
+            }
+            (key, Json::Object(obj))
+        };
+        let n: usize = grouping.values().map(|rows| rows.len()).sum();
+        let row = grouping.values().flatten().next();
+        let keyed_vals: Vec<(String, Json)> = if self.is_parallel(n, row) {
+            grouping.into_par_iter().map(eval_group).collect()
+        } else {
+            grouping.into_iter().map(eval_group).collect()
+        };
+        self.cancel.check()?;
+        Ok(Json::Object(keyed_vals.into_iter().collect()))
+    }
+
+    /// evaulate the group by statements
+    // TODO(jaupe) refactor to change val type to Json from Vec<Json>
+    fn eval_grouping(&self, by: &Cmd, rows: &[Json]) -> Result<HashMap<String, Vec<Json>>, Error> {
+        match by {
+            Cmd::Key(key) => {
+                let cancel = &self.cancel;
+                let g: HashMap<String, Vec<Value>> = rows
+                    .par_iter()
+                    .filter(|_| !cancel.is_cancelled())
+                    .map(|row| (row, json_path(row, key)))
+                    .filter(|(_, x)| x.is_some())
+                    .map(|(row, x)| (row, x.unwrap()))
+                    .fold(HashMap::new, |mut g, (row, val)| {
+                        let entry: &mut Vec<Json> = g.entry(json_str(val)).or_insert_with(Vec::new);
+                        entry.push(row.clone());
+                        g
+                    })
+                    .reduce(HashMap::new, merge_grouping);
+                self.cancel.check()?;
+                Ok(g)
+            }
+            _ => Err(Error::BadGroupBy),
+        }
+    }
+
+    /// evaulate the rows from the memson cache
+    fn eval_db_rows(&self, key: &str) -> Result<&'a [Value], Error> {
+        let val = self.db.get(key)?;
+        val.as_array()
+            .map(|x| x.as_slice())
+            .ok_or(Error::ExpectedArr)
+    }
+
+    /// the positions of the rows of the from key that may pass the filter, if indexed
+    pub(crate) fn where_candidates(&self, filter: &Cmd) -> Option<Vec<usize>> {
+        let key = match &self.cmd.from {
+            Source::Key(key) if self.cmd.unnest.is_none() => key,
+            _ => return None,
+        };
+        self.db
+            .index(key, filter_field(filter)?)?
+            .candidates(filter)
+    }
+
+    /// the estimated fraction of the rows of the scanned key passing the where statement,
+    /// from the histogram of its index; none unless the filter can be answered by an index
+    pub(crate) fn where_selectivity(&self, filter: &Cmd) -> Option<f64> {
+        let key = match &self.cmd.from {
+            Source::Key(key) if self.cmd.unnest.is_none() => key,
+            _ => return None,
+        };
+        self.db
+            .index(key, filter_field(filter)?)?
+            .selectivity(filter)
+    }
+
+    /// evaulate the where statement; rows of the scanned key are looked up through its
+    /// index if `index` is set
+    fn eval_where(&self, rows: Stream<'a>, filter: &Cmd, index: bool) -> Stream<'a> {
+        let cancel = &self.cancel;
+        let keep = |row: &Json| {
+            !cancel.is_cancelled()
+                && row.is_object()
+                && eval_filter(filter.clone(), row) == Some(true)
+        };
+        if let (Stream::Slice(rows), true) = (&rows, index) {
+            if let Some(positions) = self.where_candidates(filter) {
+                let refs = positions.into_iter().filter_map(|i| rows.get(i));
+                return Stream::Refs(refs.filter(|row| keep(row)).collect());
+            }
+        }
+        let parallel = self.is_parallel(rows.len(), rows.first());
+        let mut fields = BTreeSet::new();
+        filter.row_fields(&mut fields);
+        // rows of several keys are only tagged with their key once passing the filter,
+        // unless the filter reads the tag
+        let rows = match rows {
+            Stream::Tagged { keys, rows } if !fields.contains(SRC_KEY) => {
+                let rows = if parallel {
+                    rows.into_par_iter().filter(|(_, x)| keep(x)).collect()
+                } else {
+                    rows.into_iter().filter(|(_, x)| keep(x)).collect()
+                };
+                return Stream::Tagged { keys, rows };
+            }
+            rows => rows,
+        };
+        match rows.into_refs() {
+            Ok(refs) if parallel => {
+                Stream::Refs(refs.into_par_iter().filter(|x| keep(x)).collect())
+            }
+            Ok(refs) => Stream::Refs(refs.into_iter().filter(|x| keep(x)).collect()),
+            Err(rows) if parallel => Stream::Val(rows.into_par_iter().filter(keep).collect()),
+            Err(rows) => Stream::Val(rows.into_iter().filter(keep).collect()),
+        }
+    }
+
+    /// evaluate select statements when structured as a json object
+    fn eval_obj_selects(&self, selects: &HashMap<String, Cmd>, rows: Rows) -> Result<Json, Error> {
+        let aggs = selects.values().filter(|cmd| cmd.is_aggregate()).count();
+        if aggs != 0 && aggs != selects.len() {
+            return Err(Error::BadSelect);
+        }
+        let rows = rows.as_slice();
+        let cancel = &self.cancel;
+        let eval_select = |(name, select): (&String, &Cmd)| {
+            cancel.check()?;
+            apply_rows(select.clone(), rows).map(|val| (name.to_string(), val))
+        };
+        let projections: Result<Vec<(String, Json)>, Error> =
+            if self.is_parallel(rows.len(), rows.first()) {
+                selects.par_iter().map(eval_select).collect()
+            } else {
+                selects.iter().map(eval_select).collect()
+            };
+        Ok(Json::Object(projections?.into_iter().collect()))
+    }
+}
+
+/// the rows of a query without select statements, capped at a limit
+fn select_all(rows: &[Json], limit: Option<usize>) -> Json {
+    let rows = match limit {
+        Some(n) => &rows[..n.min(rows.len())],
+        None => rows,
+    };
+    Json::from(rows.to_vec())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::backend::SingleFlight;
+    use crate::eviction::Lru;
+    use crate::tempdir::TempDir;
+    use assert_approx_eq::assert_approx_eq;
+
+    use serde_json::json;
+
+    fn set<K: Into<String>>(key: K, arg: Cmd) -> Cmd {
+        Cmd::Set(key.into(), b(arg), None)
+    }
+
+    fn key<K: Into<String>>(k: K) -> Cmd {
+        Cmd::Key(k.into())
+    }
+
+    fn b<T>(val: T) -> Box<T> {
+        Box::new(val)
+    }
+
+    fn eval_sortby(rows: &[Json], key: &str, descend: bool) -> Vec<Json> {
+        let mut rows = rows.to_vec();
+        sort_rows(&mut rows, key, descend);
+        rows
+    }
+
+    fn get<K: Into<String>>(k: K, arg: Cmd) -> Cmd {
+        Cmd::Get(k.into(), b(arg))
+    }
+
+    fn avg(arg: Cmd) -> Cmd {
+        Cmd::Avg(b(arg))
+    }
+
+    fn first(arg: Cmd) -> Cmd {
+        Cmd::First(b(arg))
+    }
+
+    fn last(arg: Cmd) -> Cmd {
+        Cmd::Last(b(arg))
+    }
+
+    fn max(arg: Cmd) -> Cmd {
+        Cmd::Max(b(arg))
+    }
+
+    fn min(arg: Cmd) -> Cmd {
+        Cmd::Min(b(arg))
+    }
+
+    fn dev(arg: Cmd) -> Cmd {
+        Cmd::Dev(b(arg))
+    }
+
+    fn var(arg: Cmd) -> Cmd {
+        Cmd::Var(b(arg))
+    }
+
+    fn mul(x: Cmd, y: Cmd) -> Cmd {
+        Cmd::Mul(b(x), b(y))
+    }
+
+    fn div(x: Cmd, y: Cmd) -> Cmd {
+        Cmd::Div(b(x), b(y))
+    }
+
+    fn add(x: Cmd, y: Cmd) -> Cmd {
+        Cmd::Add(b(x), b(y))
+    }
+
+    fn sub(x: Cmd, y: Cmd) -> Cmd {
+        Cmd::Sub(b(x), b(y))
+    }
+
+    fn bad_type() -> Result<Json, Error> {
+        Err(Error::BadType)
+    }
+
+    fn eval(cmd: Cmd) -> Result<Json, Error> {
+        let mut db = InMemDb::new();
+        insert_data(&mut db);
+        db.eval(cmd)
+    }
+
+    fn orders_val() -> Json {
+        json!([
+                { "time": 0, "customer": "james", "qty": 2, "price": 9.0, "discount": 10 },
+                { "time": 1, "customer": "ania", "qty": 2, "price": 2.0 },
+                { "time": 2, "customer": "misha", "qty": 4, "price": 1.0 },
+                { "time": 3, "customer": "james", "qty": 10, "price": 16.0, "discount": 20 },
+                { "time": 4, "customer": "james", "qty": 1, "price": 16.0 },
+        ])
+    }
+
+    fn people_val() -> Json {
+        json!([
+                { "name": "james", "address": { "city": "London", "zip": "N1" }, "age": 35 },
+                { "name": "ania", "address": { "city": "Paris" }, "age": 28 },
+                { "name": "misha", "address": { "city": "London" }, "age": 9 },
+                { "name": "anna", "age": 40 },
+        ])
+    }
+
+    fn events_val() -> Json {
+        json!([
+                { "_id": 3, "time": 1, "kind": "click" },
+                { "_id": 1, "time": 1, "kind": "view" },
+                { "_id": 2, "time": 0, "kind": "view" },
+                { "_id": 5, "time": 2, "kind": "click" },
+                { "_id": 4, "time": 1, "kind": "buy" },
+        ])
+    }
+
+    fn insert_data(db: &mut InMemDb) {
+        db.set("a", json!([1, 2, 3, 4, 5]));
+        db.set("b", json!(true));
+        db.set("i", json!(2));
+        db.set("f", json!(3.3));
+        db.set("ia", json!([1, 2, 3, 4, 5]));
+        db.set("nia", json!([Json::Null, 2, Json::Null, 4, 5]));
+        db.set("nfa", json!([1, Json::Null, 3, Json::Null, 5]));
+        db.set("fa", json!([1.1, 2.2, 3.3, 4.4, 5.5]));
+        db.set("x", json!(4));
+        db.set("y", json!(5));
+        db.set("s", json!("hello"));
+        db.set("sa", json!(["a", "b", "c", "d"]));
+        db.set("t", table_data());
+        db.set("n", Json::Null);
+        db.set("orders", orders_val());
+        db.set("people", people_val());
+        db.set("events", events_val());
+    }
+
+    #[test]
+    fn priority_keys_served_while_preloading() {
+        let path = TempDir::new("preload");
+        let ondisk_db = OnDiskDb::open(&path).unwrap();
+        for i in 0..100 {
+            ondisk_db.set(&format!("k{}", i), &json!(i)).unwrap();
+        }
+        let keys = ["k7".to_string()];
+        let mut memson = Memson::from_disk_with_priority(ondisk_db, &keys).unwrap();
+        assert_eq!(Ok(json!(7)), memson.eval(Cmd::Key("k7".to_string())));
+        assert_eq!(Ok(json!(99)), memson.eval(Cmd::Key("k99".to_string())));
+        memson.eval(Cmd::Delete("k50".to_string())).unwrap();
+        assert_eq!(Ok(json!(99)), memson.eval(Cmd::Len(None)));
+        assert!(memson.preload.is_none());
+    }
+
+    #[test]
+    fn disabled_cmds_refused() {
+        let path = TempDir::new("caps");
+        let ondisk_db = OnDiskDb::open(&path).unwrap();
+        ondisk_db.set("a", &json!([1, 2])).unwrap();
+        let mut memson = Memson::from_disk(ondisk_db).unwrap();
+        memson.set_capabilities(Capabilities::disabling("write,admin").unwrap());
+        let disabled = Err(Error::Disabled("write".to_string()));
+        assert_eq!(disabled, memson.eval(Cmd::Delete("a".to_string())));
+        let qry = serde_json::from_value(json!({"from": "a"})).unwrap();
+        let res = memson.query_within(qry, Cancel::default(), None);
+        assert_eq!(Ok(json!([1, 2])), res);
+        let cmd = Cmd::parse(json!({"hotKeys": 3})).unwrap();
+        assert_eq!(Err(Error::Disabled("admin".to_string())), memson.eval(cmd));
+        assert_eq!(Ok(json!([1, 2])), memson.eval(Cmd::Key("a".to_string())));
+    }
+
+    #[test]
+    fn dump_restored_to_disk() {
+        let path = TempDir::new("dump");
+        let ondisk_db = OnDiskDb::open(&path).unwrap();
+        ondisk_db.set("a", &json!([{"x": 1}, {"x": 2.5}])).unwrap();
+        ondisk_db.set("b", &json!("b")).unwrap();
+        let mut memson = Memson::from_disk(ondisk_db).unwrap();
+        let keys = ["a".to_string(), "missing".to_string()];
+        let data = memson.dump(Some(&keys), Format::Cbor, None).unwrap();
+        let all = memson.dump(None, Format::MsgPack, None).unwrap();
+        memson.eval(Cmd::Delete("a".to_string())).unwrap();
+        let set = Cmd::Set("b".to_string(), Box::new(Cmd::Json(json!("c"))), None);
+        memson.eval(set).unwrap();
+        assert_eq!(Ok(json!(1)), memson.restore(&data, Format::Cbor, None));
+        assert_eq!(Ok(Some(json!("c"))), memson.disk_db.get("b"));
+        assert_eq!(Ok(json!(2)), memson.restore(&all, Format::MsgPack, None));
+        assert_eq!(Ok(Some(json!("b"))), memson.disk_db.get("b"));
+        let val = memson.eval(Cmd::Key("a".to_string()));
+        assert_eq!(Ok(json!([{"x": 1}, {"x": 2.5}])), val);
+        assert_eq!(
+            Err(Error::Serialize),
+            memson.restore(&all, Format::Cbor, None)
+        );
+    }
+
+    #[test]
+    fn backup_restored_through_cmds() {
+        let path = TempDir::new("backup");
+        let ondisk_db = OnDiskDb::open(path.join("db")).unwrap();
+        ondisk_db.set("a", &json!([{"x": 1}, {"x": 2}])).unwrap();
+        let mut memson = Memson::from_disk(ondisk_db).unwrap();
+        memson.mem_db.write().compress(0).unwrap();
+        let backup = json!({"backup": {"path": "memson.bak", "compress": "gzip"}});
+        let backup = Cmd::parse(backup).unwrap();
+        let disabled = Err(Error::Disabled("backup".to_string()));
+        assert_eq!(disabled, memson.eval(backup.clone()));
+        memson.set_backup_dir(path.join("backups"));
+        let res = memson.eval(backup).unwrap();
+        assert_eq!(json!(1), res["keys"]);
+        let set = Cmd::Set("a".to_string(), Box::new(Cmd::Json(json!(0))), None);
+        memson.eval(set).unwrap();
+        let restore = Cmd::parse(json!({"restore": "memson.bak"})).unwrap();
+        assert_eq!(Ok(json!(1)), memson.eval(restore));
+        assert_eq!(
+            Ok(Some(json!([{"x": 1}, {"x": 2}]))),
+            memson.disk_db.get("a")
+        );
+        let missing = Cmd::Restore("missing".to_string());
+        assert_eq!(Err(Error::BadIO), memson.eval(missing));
+        let outside = path.join("db").join("conf");
+        let outside = outside.to_str().unwrap();
+        for name in [outside, "../db/conf", "..\\db\\conf"] {
+            let bad = Err(Error::BadArg(json!(name)));
+            assert_eq!(bad, memson.eval(Cmd::Restore(name.to_string())));
+            let backup = json!({"backup": {"path": name}});
+            assert_eq!(bad, memson.eval(Cmd::parse(backup).unwrap()));
+        }
+        assert!(path.join("db").join("conf").exists());
+    }
+
+    #[test]
+    fn metrics_history_of_client_cmds() {
+        let path = TempDir::new("metrics");
+        let mut memson = Memson::open(&path).unwrap();
+        let history = Cmd::parse(json!({"metricsHistory": {"minutes": 5}})).unwrap();
+        assert_eq!(Ok(json!([])), memson.eval(history.clone()));
+        let insert = Cmd::parse(json!({"insert": ["k", [{"a": 1}]]})).unwrap();
+        memson.eval_within(insert, Cancel::default(), None).unwrap();
+        let missing = Cmd::Key("missing".to_string());
+        assert!(memson
+            .eval_within(missing, Cancel::default(), None)
+            .is_err());
+        let cmd = serde_json::from_value(json!({"from": "k"})).unwrap();
+        memson.query_within(cmd, Cancel::default(), None).unwrap();
+        let res = memson.eval(history).unwrap();
+        // the cmds may straddle a minute
+        let minutes = res.as_array().unwrap();
+        let sum = |field: &str| {
+            minutes
+                .iter()
+                .map(|x| x[field].as_u64().unwrap())
+                .sum::<u64>()
+        };
+        assert_eq!(3, sum("ops"));
+        assert_eq!(1, sum("errors"));
+        let last = minutes.last().unwrap();
+        assert!(last["opsPerSec"].as_f64().unwrap() > 0.0);
+        assert!(last["p99Ms"].as_f64().unwrap() > 0.0);
+        assert_eq!(json!(memson.mem_db.read().size()), last["bytes"]);
+        let bad = json!({"metricsHistory": {"minutes": -1}});
+        assert_eq!(Err(Error::BadCmd), Cmd::parse(bad));
+    }
+
+    #[test]
+    fn grants_enforced_before_evaluation() {
+        let path = TempDir::new("acls");
+        let mut memson = Memson::open(&path).unwrap();
+        memson.set_acls(Acls::parse("ci:read, t1:write:t1_").unwrap());
+        let (ci, t1) = (User("ci".to_string()), User("t1".to_string()));
+        let mut eval = |cmd: Json, user: &User| {
+            let cmd = Cmd::parse(cmd).unwrap();
+            memson.eval_within(cmd, Cancel::default(), Some(user))
+        };
+        let insert = json!({"insert": ["t1_rows", [{"a": 1}]]});
+        let forbidden = |msg: &str| Err(Error::Forbidden(msg.to_string()));
+        assert_eq!(forbidden("write cmds for ci"), eval(insert.clone(), &ci));
+        assert_eq!(Ok(json!(1)), eval(insert, &t1));
+        let other = json!({"insert": ["t2_rows", [{"a": 1}]]});
+        assert_eq!(forbidden("key t2_rows for t1"), eval(other, &t1));
+        let qry: QueryCmd = serde_json::from_value(json!({"from": "t1_rows"})).unwrap();
+        let res = memson.query_within(qry.clone(), Cancel::default(), Some(&ci));
+        assert_eq!(Ok(json!([{"a": 1}])), res);
+        let sub = Cmd::Subscribe(vec!["t1_rows".to_string()], None);
+        let id = memson.eval_within(sub, Cancel::default(), Some(&t1));
+        let id = id.unwrap().as_u64().unwrap();
+        let events = memson.eval_within(Cmd::Events(id), Cancel::default(), Some(&t1));
+        assert!(events.is_ok());
+        let events = memson.eval_within(Cmd::Events(id), Cancel::default(), Some(&ci));
+        assert!(events.is_ok());
+        let data = memson.dump(None, Format::Cbor, Some(&ci)).unwrap();
+        let res = memson.dump(None, Format::Cbor, Some(&t1));
+        let every_key = Error::Forbidden("every key for t1".to_string());
+        assert_eq!(Some(every_key), res.err());
+        let res = memson.restore(&data, Format::Cbor, Some(&ci));
+        assert_eq!(forbidden("write cmds for ci"), res);
+        assert_eq!(Ok(json!(1)), memson.restore(&data, Format::Cbor, Some(&t1)));
+        assert_eq!(Ok(json!(1)), memson.restore(&data, Format::Cbor, None));
+    }
+
+    #[test]
+    fn sets_published_to_subscribers() {
+        let path = TempDir::new("sets");
+        let mut memson = Memson::open(&path).unwrap();
+        let mut eval = |cmd: Json| memson.eval(Cmd::parse(cmd).unwrap());
+        let id = eval(json!({"subscribe": ["a", "b"]})).unwrap();
+        assert_eq!(Ok(Json::Null), eval(json!({"set": ["a", 1]})));
+        assert_eq!(Ok(json!(true)), eval(json!({"set": ["b", 2, "nx"]})));
+        assert_eq!(Ok(json!(1)), eval(json!({"getSet": ["a", 4]})));
+        let exp = json!({"events": [
+            {"seq": 1, "key": "a", "val": 1},
+            {"seq": 2, "key": "b", "val": 2},
+            {"seq": 3, "key": "a", "val": 4},
+        ], "missed": 0});
+        assert_eq!(Ok(exp), eval(json!({ "events": id })));
+        assert_eq!(Ok(Some(json!(4))), memson.disk_db.get("a"));
+        assert_eq!(Ok(Some(json!(2))), memson.disk_db.get("b"));
+    }
+
+    #[test]
+    fn reads_shared_by_readers() {
+        let path = TempDir::new("reader");
+        let ondisk_db = OnDiskDb::open(&path).unwrap();
+        ondisk_db.set("a", &json!([1, 2])).unwrap();
+        ondisk_db.set("b", &json!(1)).unwrap();
+        let keys = ["a".to_string()];
+        let mut memson = Memson::from_disk_with_priority(ondisk_db, &keys).unwrap();
+        memson.set_capabilities(Capabilities::disabling("admin").unwrap());
+        memson.set_acls(Acls::parse("ci:read:a").unwrap());
+        let reader = memson.reader();
+        let (key, cancel) = (|key: &str| Cmd::Key(key.to_string()), Cancel::default());
+        // keys may still be on disk until every entry is loaded
+        let res = reader.eval_within(key("a"), &cancel, None);
+        assert_eq!(Err(Box::new(key("a"))), res);
+        assert_eq!(Ok(json!(2)), memson.eval(Cmd::Len(None)));
+        let guard = memson.mem_db.read();
+        let res = std::thread::scope(|s| {
+            s.spawn(|| reader.eval_within(key("a"), &cancel, None))
+                .join()
+                .unwrap()
+        });
+        assert_eq!(Ok(Ok(json!([1, 2]))), res);
+        drop(guard);
+        let ci = User("ci".to_string());
+        assert_eq!(
+            Ok(Ok(json!(1))),
+            reader.eval_within(key("b"), &cancel, None)
+        );
+        let res = reader.eval_within(key("b"), &cancel, Some(&ci));
+        assert_eq!(Err(Box::new(key("b"))), res);
+        let del = Cmd::Delete("a".to_string());
+        assert_eq!(
+            Err(Box::new(del.clone())),
+            reader.eval_within(del, &cancel, None)
+        );
+        let hot_keys = Cmd::parse(json!({"hotKeys": 3})).unwrap();
+        let res = reader.eval_within(hot_keys.clone(), &cancel, None);
+        assert_eq!(Err(Box::new(hot_keys)), res);
+        let history = memson.metrics.lock().unwrap().history(1, now_secs(), 0);
+        assert_eq!(json!(2), history[0]["ops"]);
+    }
+
+    #[test]
+    fn backend_fronted_by_memson() {
+        let dir = TempDir::new("fronted");
+        let backend = OnDiskDb::open(dir.join("backend")).unwrap();
+        backend.set("x", &json!(1)).unwrap();
+        let mut memson = Memson::open(dir.join("db")).unwrap();
+        let sled = backend.sled.clone();
+        memson.set_backend(Box::new(SingleFlight::new(OnDiskDb { sled })));
+        let mut eval = |cmd: Json| memson.eval(Cmd::parse(cmd).unwrap());
+        assert_eq!(Ok(json!(1)), eval(json!({"key": "x"})));
+        eval(json!({"set": ["y", [1]]})).unwrap();
+        eval(json!({"push": ["y", 2]})).unwrap();
+        eval(json!({"del": "x"})).unwrap();
+        assert_eq!(Ok(Some(json!([1, 2]))), backend.get("y"));
+        assert_eq!(Ok(None), backend.get("x"));
+    }
+
+    #[test]
+    fn indexes_built_outside_memson() {
+        let path = TempDir::new("index");
+        let mut memson = Memson::open(&path).unwrap();
+        let set = json!({"set": ["orders", [{"qty": 1}, {"qty": 2}]]});
+        memson.eval(Cmd::parse(set).unwrap()).unwrap();
+        memson.set_acls(Acls::parse("ci:read").unwrap());
+        let ci = User("ci".to_string());
+        let res = memson.begin_index("orders", "qty", Some(&ci));
+        let forbidden = Error::Forbidden("admin cmds for ci".to_string());
+        assert_eq!(Err(forbidden), res);
+        let (id, val) = memson.begin_index("orders", "qty", None).unwrap();
+        let insert = json!({"insert": ["orders", [{"qty": 2}]]});
+        memson.eval(Cmd::parse(insert).unwrap()).unwrap();
+        memson.finish_index(id, Index::build(&val, "qty")).unwrap();
+        let eq = Cmd::parse(json!({"==": [{"key": "qty"}, 2]})).unwrap();
+        let index = memson
+            .mem_db
+            .read()
+            .index("orders", "qty")
+            .unwrap()
+            .candidates(&eq);
+        assert_eq!(Some(vec![1, 2]), index);
+        let res = memson.begin_index("missing", "qty", None);
+        assert_eq!(Err(Error::BadKey("missing".to_string())), res);
+        memson.set_capabilities(Capabilities::disabling("admin").unwrap());
+        let res = memson.begin_index("orders", "qty", None);
+        assert_eq!(Err(Error::Disabled("admin".to_string())), res);
+    }
+
+    #[test]
+    fn expired_entries_deleted_from_disk() {
+        let path = TempDir::new("expiry");
+        let ondisk_db = OnDiskDb::open(&path).unwrap();
+        ondisk_db.set("session", &json!({"user": 1})).unwrap();
+        let mut memson = Memson::from_disk(ondisk_db).unwrap();
+        let cmd = Cmd::parse(json!({"expire": ["session", 0.01]})).unwrap();
+        assert_eq!(Ok(json!(true)), memson.eval(cmd));
+        std::thread::sleep(Duration::from_millis(20));
+        assert_eq!(
+            Ok(json!(false)),
+            memson.eval(Cmd::Has("session".to_string()))
+        );
+        assert_eq!(Ok(None), memson.disk_db.get("session"));
+    }
+
+    #[test]
+    fn evicted_entries_loaded_from_disk() {
+        let path = TempDir::new("evict");
+        let ondisk_db = OnDiskDb::open(&path).unwrap();
+        ondisk_db.set("a", &json!([1, 2, 3])).unwrap();
+        ondisk_db.set("b", &json!([4, 5, 6])).unwrap();
+        let mut memson = Memson::from_disk(ondisk_db).unwrap();
+        memson.set_max_memory(30, Box::new(Lru));
+        assert_eq!(24, memson.mem_db.read().size());
+        memson
+            .eval(Cmd::parse(json!({"push": ["b", 7]})).unwrap())
+            .unwrap();
+        assert!(!memson.mem_db.read().has("a"));
+        let val = memson.eval(Cmd::parse(json!({"sum": {"key": "a"}})).unwrap());
+        assert_eq!(Ok(json!(6)), val);
+        assert!(!memson.mem_db.read().has("b"));
+        let val = memson.eval(Cmd::parse(json!({"key": "b"})).unwrap());
+        assert_eq!(Ok(json!([4, 5, 6, 7])), val);
+        memson.eval(Cmd::Delete("a".to_string())).unwrap();
+        let val = memson.eval(Cmd::parse(json!({"has": "a"})).unwrap());
+        assert_eq!(Ok(json!(false)), val);
+    }
+
+    #[test]
+    fn unsaved_entries_saved_before_eviction() {
+        let dir = TempDir::new("evict-wal");
+        let wal_path = dir.join("wal");
+        let ondisk_db = OnDiskDb::open(dir.join("db")).unwrap();
+        ondisk_db.set("a", &json!([1, 2, 3])).unwrap();
+        ondisk_db.set("b", &json!([4, 5, 6])).unwrap();
+        let mut memson = Memson::from_disk(ondisk_db).unwrap();
+        memson.open_wal(&wal_path, false).unwrap();
+        memson.set_max_memory(30, Box::new(Lru));
+        let mut eval = |cmd: Json| memson.eval(Cmd::parse(cmd).unwrap());
+        eval(json!({"push": ["b", 7]})).unwrap();
+        // b is changed only in the log, so it's saved and the log emptied to evict it
+        assert!(std::fs::read(&wal_path).unwrap().is_empty());
+        assert_eq!(Ok(json!(6)), eval(json!({"sum": {"key": "a"}})));
+        assert_eq!(Ok(json!([4, 5, 6, 7])), eval(json!({"key": "b"})));
+        eval(json!({"del": "a"})).unwrap();
+        assert_eq!(Ok(json!(false)), eval(json!({"has": "a"})));
+        eval(json!({"push": ["b", 8]})).unwrap();
+        assert_eq!(Ok(json!(false)), eval(json!({"has": "a"})));
+        assert_eq!(Ok(json!([4, 5, 6, 7, 8])), eval(json!({"key": "b"})));
+    }
+
+    #[test]
+    fn changes_replayed_from_wal_on_startup() {
+        let dir = TempDir::new("wal-db");
+        let wal_path = dir.join("wal");
+        let open = || {
+            let ondisk_db = OnDiskDb::open(dir.join("db")).unwrap();
+            let mut memson = Memson::from_disk(ondisk_db).unwrap();
+            memson.open_wal(&wal_path, false).unwrap();
+            memson
+        };
+        let mut memson = open();
+        let mut eval = |cmd: Json| memson.eval(Cmd::parse(cmd).unwrap());
+        eval(json!({"set": ["a", [1, 2]]})).unwrap();
+        eval(json!({"append": ["a", 3]})).unwrap();
+        eval(json!({"set": ["b", {"key": "a"}]})).unwrap();
+        eval(json!({"batch": [{"pop": "b"}, {"append": ["missing", 1]}]})).unwrap();
+        eval(json!({"set": ["c", 1]})).unwrap();
+        eval(json!({"del": "c"})).unwrap();
+        assert!(eval(json!({"append": ["missing", 1]})).is_err());
+        eval(json!({"set": ["d", 1]})).unwrap();
+        eval(json!({"expire": ["d", 0.05]})).unwrap();
+        eval(json!({"expire": ["a", 30]})).unwrap();
+        let template = json!({"n": {"$int": [0, 1000000]}});
+        eval(json!({"generate": {"table": "g", "n": 20, "template": template}})).unwrap();
+        let rows = eval(json!({"key": "g"})).unwrap();
+        assert!(!std::fs::read(&wal_path).unwrap().is_empty());
+        drop(memson);
+        std::thread::sleep(Duration::from_millis(60));
+        for _ in 0..2 {
+            let mut memson = open();
+            let mut eval = |cmd: Json| memson.eval(Cmd::parse(cmd).unwrap());
+            assert_eq!(Ok(json!([1, 2, 3])), eval(json!({"key": "a"})));
+            assert_eq!(Ok(json!([1, 2])), eval(json!({"key": "b"})));
+            assert_eq!(Ok(json!(false)), eval(json!({"has": "c"})));
+            assert_eq!(Ok(json!(false)), eval(json!({"has": "missing"})));
+            // rows generated without a seed are replayed with the seed they were made with
+            assert_eq!(Ok(rows.clone()), eval(json!({"key": "g"})));
+            // the deadlines replayed are those logged, not counted again from startup
+            assert_eq!(Ok(json!(false)), eval(json!({"has": "d"})));
+            let ttl = eval(json!({"ttl": "a"})).unwrap().as_f64().unwrap();
+            assert!(ttl > 29.0 && ttl < 30.0);
+        }
+    }
+
+    #[test]
+    fn wal_checkpointed_every_so_many_cmds() {
+        let dir = TempDir::new("checkpoint");
+        let wal_path = dir.join("wal");
+        let ondisk_db = OnDiskDb::open(dir.join("db")).unwrap();
+        let mut memson = Memson::from_disk(ondisk_db).unwrap();
+        memson.open_wal(&wal_path, false).unwrap();
+        memson.checkpoint_every = 3;
+        let mut eval = |cmd: Json| memson.eval(Cmd::parse(cmd).unwrap()).unwrap();
+        eval(json!({"set": ["a", [1]]}));
+        eval(json!({"push": ["a", 2]}));
+        assert_eq!(
+            2,
+            std::fs::read_to_string(&wal_path).unwrap().lines().count()
+        );
+        eval(json!({"delPattern": "a*"}));
+        eval(json!({"set": ["b", [1]]}));
+        eval(json!({"push": ["b", 2]}));
+        eval(json!({"push": ["b", 3]}));
+        assert!(std::fs::read(&wal_path).unwrap().is_empty());
+        assert_eq!(Ok(None), memson.disk_db.get("a"));
+        assert_eq!(Ok(Some(json!([1, 2, 3]))), memson.disk_db.get("b"));
+        let push = Cmd::parse(json!({"push": ["b", 4]})).unwrap();
+        memson.eval(push).unwrap();
+        drop(memson);
+        let ondisk_db = OnDiskDb::open(dir.join("db")).unwrap();
+        let mut memson = Memson::from_disk(ondisk_db).unwrap();
+        memson.open_wal(&wal_path, false).unwrap();
+        let val = memson.eval(Cmd::Key("b".to_string()));
+        assert_eq!(Ok(json!([1, 2, 3, 4])), val);
+    }
+
+    /// an indexed table whose second row was added behind the back of its index
+    fn stale_index_db() -> InMemDb {
+        let mut db = InMemDb::new();
+        let mut eval = |cmd: Json| db.eval(Cmd::parse(cmd).unwrap()).unwrap();
+        eval(json!({"insert": ["t", [{"a": 1}]]}));
+        eval(json!({"createIndex": {"key": "t", "field": "a"}}));
+        if let Json::Array(rows) = db.entry("t") {
+            rows.push(json!({"a": 2}));
+        }
+        db
+    }
+
+    #[test]
+    fn invariants_checked_without_backend() {
+        let mut db = stale_index_db();
+        let writes: BTreeSet<String> = vec!["t".to_string()].into_iter().collect();
+        let exp = vec!["t has a stale index on a".to_string()];
+        assert_eq!(exp, db.invariant_violations(&writes));
+    }
+
+    #[cfg(feature = "invariants")]
+    #[test]
+    #[should_panic(expected = "t has a stale index on a")]
+    fn invariants_asserted_without_backend() {
+        let mut db = stale_index_db();
+        let insert = json!({"insert": ["t", [{"a": 3}]]});
+        let _ = db.eval(Cmd::parse(insert).unwrap());
+    }
+
+    #[cfg(feature = "invariants")]
+    #[test]
+    #[should_panic(expected = "t has a stale index on a")]
+    fn invariants_asserted_after_failed_cmds() {
+        let mut db = stale_index_db();
+        let append = json!({"append": ["t", {"/": [1, 0]}]});
+        assert!(db.eval(Cmd::parse(append).unwrap()).is_err());
+    }
+
+    #[test]
+    fn load_data() {
+        let path = TempDir::new("load");
+        let data = json!([
+                { "time": 0, "customer": "james", "qty": 2, "price": 9.0, "discount": 10 },
+                { "time": 1, "customer": "ania", "qty": 2, "price": 2.0 },
+                { "time": 2, "customer": "misha", "qty": 4, "price": 1.0 },
+                { "time": 3, "customer": "james", "qty": 10, "price": 16.0, "discount": 20 },
+                { "time": 4, "customer": "james", "qty": 1, "price": 16.0 },
+        ]);
+        {
+            let ondisk_db = OnDiskDb::open(&path).unwrap();
+            ondisk_db.set("customers", &data).unwrap();
+        }
+        let mut memson = Memson::open(&path).unwrap();
+        assert_eq!(Ok(data), memson.eval(Cmd::Key("customers".to_string())));
+    }
+
+    #[test]
+    fn select_all_from_orders() {
+        let exp = json!([
+            { "time": 0, "customer": "james", "qty": 2, "price": 9.0, "discount": 10 },
+            { "time": 1, "customer": "ania", "qty": 2, "price": 2.0 },
+            { "time": 2, "customer": "misha", "qty": 4, "price": 1.0 },
+            { "time": 3, "customer": "james", "qty": 10, "price": 16.0, "discount": 20 },
+            { "time": 4, "customer": "james", "qty": 1, "price": 16.0 },
+        ]);
+        assert_eq!(Ok(exp), query(json!({"from": "orders"})));
+    }
+
+    #[test]
+    fn select_customer_from_orders() {
+        let exp = json!({"name":["james", "ania", "misha", "james", "james"]});
+        assert_eq!(
+            Ok(exp),
+            query(json!({
+                "select": {"name":{"key":"customer"}},
+                "from": "orders"
+            }))
+        );
+    }
+
+    #[test]
+    fn select_customer_qty_from_orders() {
+        let exp =
+            json!({"name": ["james", "ania","misha","james","james"], "quantity": [2,2,4,10,1]});
+        assert_eq!(
+            Ok(exp),
+            query(json!({
+                "select": {
+                    "name":{"key":"customer"},
+                    "quantity":{"key": "qty"},
+                },
+                "from": "orders"
+            }))
+        );
+    }
+
+    #[test]
+    fn select_customer_qty_discount_from_orders() {
+        let exp = json!({
+            "name": ["james", "ania", "misha", "james", "james"],
+            "quantity": [2, 2, 4, 10, 1],
+            "discount": [10,20]
+        });
+        assert_eq!(
+            Ok(exp),
+            query(json!({
+                "select": {
+                    "name":{"key":"customer"},
+                    "quantity":{"key": "qty"},
+                    "discount":{"key": "discount"}
+                },
+                "from": "orders"
+            }))
+        );
+    }
+
+    #[test]
+    fn select_unique_customer_from_orders() {
+        let exp = json!({ "uniqueNames": ["james", "ania", "misha"] });
+        assert_eq!(
+            Ok(exp),
+            query(json!({
+                "select": {
+                    "uniqueNames":{"unique": {"key":"customer"}},
+                },
+                "from": "orders"
+            }))
+        );
+    }
+
+    #[test]
+    fn select_count_discount_count_quantity_from_orders() {
+        let exp = json!(
+            { "countDiscount": 2, "countCustomer": 5}
+        );
+        assert_eq!(
+            Ok(exp),
+            query(json!({
+                "select": {
+                    "countDiscount":{ "len": {"key": "discount"} },
+                    "countCustomer":{ "len": {"key": "customer"} },
+                },
+                "from": "orders"
+            }))
+        );
+    }
+
+    #[test]
+    fn select_mul_price_quantity_from_orders() {
+        let exp: Json = json!({ "value": [18.0, 4.0, 4.0, 160.0, 16.0] });
+        assert_eq!(
+            Ok(exp),
+            query(json!({
+                "select": {
+                    "value":{ "*": [{"key": "qty"}, {"key":"price"}] },
+                },
+                "from": "orders"
+            }))
+        );
+    }
+
+    #[test]
+    fn select_sum_mul_price_quantity_from_orders() {
+        let exp = json!({ "totalValue": 202.0 });
+        assert_eq!(
+            Ok(exp),
+            query(json!({
+                "select": {
+                    "totalValue": {"sum": { "*": [{"key": "qty"}, {"key":"price"}]} },
+                },
+                "from": "orders"
+            }))
+        );
+    }
+
+    #[test]
+    fn select_computed_with_missing_fields_from_orders() {
+        let exp = json!({
+            "discounted": [-1.0, null, null, -4.0, null],
+        });
+        assert_eq!(
+            Ok(exp),
+            query(json!({
+                "select": {
+                    "discounted": {"-": [{"key": "price"}, {"key": "discount"}]},
+                },
+                "from": "orders"
+            }))
+        );
+    }
+
+    #[test]
+    fn select_aggregate_and_computed_without_by_from_orders() {
+        assert_eq!(
+            Err(Error::BadSelect),
+            query(json!({
+                "select": {
+                    "discounted": {"-": [{"key": "price"}, {"key": "discount"}]},
+                    "totalDiscount": {"sum": {"*": [{"key": "qty"}, {"key": "discount"}]}},
+                },
+                "from": "orders"
+            }))
+        );
+    }
+
+    #[test]
+    fn select_computed_by_customer_from_orders() {
+        let exp = json!({
+            "james": { "total": [20, 200, null] },
+            "ania": { "total": [null] },
+            "misha": { "total": [null] },
+        });
+        assert_eq!(
+            query(json!({
+                "select": {"total": {"*": [{"key": "qty"}, {"key": "discount"}]}},
+                "by": {"key": "customer"},
+                "from": "orders"
+            })),
+            Ok(exp)
+        );
+    }
+
+    #[test]
+    fn select_by_customer_from_orders() {
+        let exp = json!({
+                "james": [{ "time": 0, "customer": "james", "qty": 2, "price": 9.0, "discount": 10 },{ "time": 3, "customer": "james", "qty": 10, "price": 16.0, "discount": 20 },{ "time": 4, "customer": "james", "qty": 1, "price": 16.0 }],
+                "ania": [{ "time": 1, "customer": "ania", "qty": 2, "price": 2.0 }],
+                "misha": [{ "time": 2, "customer": "misha", "qty": 4, "price": 1.0 }],
+        });
+        assert_eq!(
+            Ok(exp),
+            query(json!({
+                "by": {"key":"customer"},
+                "from": "orders"
+            }))
+        );
+    }
+
+    #[test]
+    fn select_max_qty_by_customer_from_orders() {
+        let exp = json!({
+                "james": { "maxQty": 10 },
+                "ania": { "maxQty": 2 },
+                "misha": { "maxQty": 4 },
+        });
+        assert_eq!(
+            query(json!({
+                "select": {"maxQty":{"max":{"key":"qty"}}},
+                "by": {"key":"customer"},
+                "from": "orders"
+            })),
+            Ok(exp)
+        );
+    }
+
+    #[test]
+    fn select_max_unique_qty_by_customer_from_orders() {
+        let exp = json!({
+                "james": { "maxUniqueQty": 10 },
+                "ania": { "maxUniqueQty": 2 },
+                "misha": { "maxUniqueQty": 4 },
+        });
+        assert_eq!(
+            query(json!({
+                "select": {"maxUniqueQty":{"max":{"unique":{"key":"qty"}}}},
+                "by": {"key":"customer"},
+                "from": "orders"
+            })),
+            Ok(exp)
+        );
+    }
+
+    #[test]
+    fn select_first_time_last_time_from_orders() {
+        let exp = json!({
+                "start": 0,
+                "end": 4,
+        });
+        assert_eq!(
+            query(json!({
+                "select": {
+                    "start": {"first":{"key":"time"}},
+                    "end": {"last":{"key":"time"}},
+                },
+                "from": "orders"
+            })),
+            Ok(exp)
+        );
+    }
+
+    #[test]
+    fn select_first_time_last_time_by_customer_from_orders() {
+        let exp = json!({
+                "james": { "startQty":2, "endQty":1 },
+                "ania": { "startQty":2, "endQty":2 },
+                "misha": { "startQty": 4, "endQty": 4},
+        });
+        assert_eq!(
+            query(json!({
+                "select": {
+                    "startQty":{"first":{"key":"qty"}},
+                    "endQty":{"last":{"key":"qty"}},
+                },
+                "by": {"key":"customer"},
+                "from": "orders"
+            })),
+            Ok(exp)
+        );
+    }
+
+    #[test]
+    fn select_order_volume_by_customer_from_orders() {
+        let exp = json!({
+                "james": { "orderVolume": 194.0 },
+                "ania": { "orderVolume": 4.0 },
+                "misha": { "orderVolume": 4.0 },
+        });
+        assert_eq!(
+            query(json!({
+                "select": {
+                    "orderVolume":{"sum":{"*": [{"key":"qty"},{"key":"price"}]}},
+                },
+                "by": {"key":"customer"},
+                "from": "orders"
+            })),
+            Ok(exp)
+        );
+    }
+
+    #[test]
+    fn select_order_value_by_customer_from_orders() {
+        let exp = json!({
+                "james":{ "orderVal": [18.0, 160.0, 16.0] },
+                "ania": { "orderVal": [4.0] },
                 "misha": { "orderVal": [4.0] },
         });
         assert_eq!(
-            query(json!({
-                "select": {
-                    "orderVal":{"*": [{"key":"qty"},{"key":"price"}]},
-                },
-                "by": {"key":"customer"},
-                "from": "orders"
-            })),
-            Ok(exp)
+            query(json!({
+                "select": {
+                    "orderVal":{"*": [{"key":"qty"},{"key":"price"}]},
+                },
+                "by": {"key":"customer"},
+                "from": "orders"
+            })),
+            Ok(exp)
+        );
+    }
+
+    #[test]
+    fn test_len() {
+        assert_eq!(Ok(json!(17)), eval(Cmd::parse(json!("len")).unwrap()));
+        assert_eq!(
+            Ok(json!(5)),
+            eval(Cmd::parse(json!({"len": {"key": "a"}})).unwrap())
+        );
+        assert_eq!(
+            Ok(json!(5)),
+            eval(Cmd::parse(json!({"len": {"key": "s"}})).unwrap())
+        );
+        let cmd = Cmd::parse(json!({"len": {"key": "people.address"}})).unwrap();
+        assert_eq!(Ok(json!(3)), eval(cmd));
+    }
+
+    #[test]
+    fn dotted_key_reads() {
+        let key = |key: &str| eval(Cmd::Key(key.to_string()));
+        let cities = json!(["London", "Paris", "London"]);
+        assert_eq!(Ok(cities), key("people.address.city"));
+        assert_eq!(Ok(json!(["N1"])), key("people.address.zip"));
+        let street = Err(Error::BadPath("address.street".to_string()));
+        assert_eq!(street, key("people.address.street"));
+        assert_eq!(Ok(json!("N1")), key("people.0.address.zip"));
+        assert_eq!(Ok(json!(["james", "ania", "misha", "ania"])), key("t.name"));
+        assert_eq!(Err(Error::BadPath("x".to_string())), key("i.x"));
+    }
+
+    #[test]
+    fn lookup_tells_missing_from_null() {
+        let mut db = test_db();
+        db.set("n", Json::Null);
+        db.set("nested", json!({"a": {"b": null}}));
+        let mut lookup = |key: &str| db.eval(Cmd::Lookup(key.to_string())).unwrap();
+        assert_eq!(json!({"status": "found", "val": 2}), lookup("i"));
+        assert_eq!(json!({"status": "null"}), lookup("n"));
+        assert_eq!(json!({"status": "null"}), lookup("nested.a.b"));
+        assert_eq!(json!({"status": "missingKey"}), lookup("missing"));
+        assert_eq!(json!({"status": "missingKey"}), lookup("missing.a"));
+        assert_eq!(json!({"status": "missingPath"}), lookup("nested.a.c"));
+        assert_eq!(json!({"status": "missingPath"}), lookup("people.9"));
+        assert_eq!(
+            json!({"status": "missingPath"}),
+            lookup("people.address.street")
+        );
+        let zips = json!({"status": "found", "val": ["N1"]});
+        assert_eq!(zips, lookup("people.address.zip"));
+    }
+
+    #[test]
+    fn delete_many_and_by_pattern() {
+        let mut db = test_db();
+        for i in 0..3 {
+            db.set(format!("session:{}", i), json!(i));
+        }
+        let id = db
+            .eval(Cmd::parse(json!({"subscribe": []})).unwrap())
+            .unwrap();
+        let mut eval = |cmd: Json| db.eval(Cmd::parse(cmd).unwrap());
+        assert_eq!(
+            Ok(json!(2)),
+            eval(json!({"delMany": ["a", "b", "missing"]}))
+        );
+        assert_eq!(Ok(json!(3)), eval(json!({"delPattern": "session:*"})));
+        assert_eq!(Ok(json!(0)), eval(json!({"delPattern": "session:*"})));
+        assert_eq!(Ok(json!(15)), eval(json!("len")));
+        let events = eval(json!({ "events": id })).unwrap();
+        assert_eq!(6, events["events"].as_array().unwrap().len());
+    }
+
+    #[test]
+    fn expire_and_ttl() {
+        let mut db = test_db();
+        let id = db.pubsub_mut().subscribe(vec!["s".to_string()], None);
+        let mut eval = |cmd: Json| db.eval(Cmd::parse(cmd).unwrap());
+        assert_eq!(Ok(Json::Null), eval(json!({"ttl": "s"})));
+        assert_eq!(Ok(json!(false)), eval(json!({"expire": ["missing", 1]})));
+        assert_eq!(Ok(json!(true)), eval(json!({"expire": ["s", 0.05]})));
+        assert_eq!(Ok(json!(true)), eval(json!({"expire": ["i", 30]})));
+        let ttl = eval(json!({"ttl": "s"})).unwrap().as_f64().unwrap();
+        assert!(ttl > 0.0 && ttl <= 0.05);
+        let summary = eval(json!("summary")).unwrap();
+        let expiring: Vec<&Json> = summary["expiring"]
+            .as_array()
+            .unwrap()
+            .iter()
+            .map(|x| &x["key"])
+            .collect();
+        assert_eq!(vec![&json!("s"), &json!("i")], expiring);
+        assert_eq!(Ok(json!(2)), eval(json!({"set": ["i", 3]})));
+        assert_eq!(Ok(Json::Null), eval(json!({"ttl": "i"})));
+        std::thread::sleep(Duration::from_millis(60));
+        assert_eq!(Ok(json!(false)), eval(json!({"has": "s"})));
+        assert_eq!(
+            Err(Error::BadKey("s".to_string())),
+            eval(json!({"ttl": "s"}))
+        );
+        let events = eval(json!({ "events": id })).unwrap();
+        assert_eq!(json!(null), events["events"][0]["val"]);
+    }
+
+    #[test]
+    fn evicts_least_recently_used() {
+        let mut db = InMemDb::new();
+        for (i, key) in ["a", "b", "c"].iter().enumerate() {
+            db.set(*key, json!([i]));
+            db.eval(Cmd::Key(key.to_string())).unwrap();
+            std::thread::sleep(Duration::from_millis(2));
+        }
+        db.eval(Cmd::Key("a".to_string())).unwrap();
+        assert_eq!(24, db.size());
+        db.set_max_memory(16, Box::new(Lru));
+        assert!(db.has("a") && !db.has("b") && db.has("c"));
+        let cmd = Cmd::parse(json!({"append": ["c", {"key": "a"}]})).unwrap();
+        db.eval(cmd).unwrap();
+        assert_eq!(Ok(&json!([2, [0]])), db.get("c"));
+        assert_eq!(24, db.size());
+        db.eval(Cmd::parse(json!({"set": ["d", "abcdefgh"]})).unwrap())
+            .unwrap();
+        assert!(!db.has("a") && !db.has("c") && db.has("d"));
+        assert_eq!(8, db.size());
+        assert!(db.invariant_violations(&BTreeSet::new()).is_empty());
+    }
+
+    #[test]
+    fn writes_rejected_above_soft_limit() {
+        let mut db = InMemDb::new();
+        db.set("a", json!("0123456789"));
+        db.set_soft_limit(16);
+        db.set_max_memory(24, Box::new(Lru));
+        let mut eval = |cmd: Json| db.eval(Cmd::parse(cmd).unwrap());
+        assert_eq!(Ok(Json::Null), eval(json!({"set": ["b", "01234567"]})));
+        let err = Err(Error::MemoryPressure);
+        assert_eq!(err, eval(json!({"set": ["c", "0"]})));
+        assert_eq!(err, eval(json!({"tx": [{"del": "a"}, {"push": ["b", 1]}]})));
+        assert_eq!(Ok(json!("01234567")), eval(json!({"key": "b"})));
+        assert_eq!(Ok(json!("0123456789")), eval(json!({"del": "a"})));
+        assert_eq!(
+            Ok(Json::Null),
+            eval(json!({"set": ["c", "0123456789abcdefghij"]}))
+        );
+        assert!(!db.has("b") && db.has("c"));
+        assert!(db.under_pressure());
+    }
+
+    #[test]
+    fn mget_keys_and_paths() {
+        let cmd = Cmd::parse(json!({"mget": ["i", "people.0.name", "t.age", "missing"]}));
+        let exp = json!({
+            "i": 2,
+            "people.0.name": "james",
+            "t.age": [35, 28, 10, 20],
+            "missing": null,
+        });
+        assert_eq!(Ok(exp), eval(cmd.unwrap()));
+    }
+
+    #[test]
+    fn test_size_of() {
+        let cmd = Cmd::parse(json!({"sizeOf": "people"})).unwrap();
+        let val = eval(cmd).unwrap();
+        assert_eq!(json!(3), val["depth"]);
+        assert_eq!(json!([1, 4, 11, 4]), val["levels"]);
+        let cmd = Cmd::parse(json!({"sizeOf": "people.0.address"})).unwrap();
+        assert_eq!(json!(1), eval(cmd).unwrap()["depth"]);
+        let cmd = Cmd::parse(json!({"sizeOf": "people.9"})).unwrap();
+        assert_eq!(Err(Error::BadPath("people.9".to_string())), eval(cmd));
+    }
+
+    #[test]
+    fn test_insert() {
+        let mut db = test_db();
+        let cmd = json!({"insert": ["orders", [{"time": 5, "qty": 1}, {"time": 6, "qty": 3}]]});
+        assert_eq!(Ok(json!(2)), db.eval(Cmd::parse(cmd).unwrap()));
+        assert_eq!(7, db.get("orders").unwrap().as_array().unwrap().len());
+        let cmd = json!({"insert": ["new", [{"a": 1}]]});
+        assert_eq!(Ok(json!(1)), db.eval(Cmd::parse(cmd).unwrap()));
+        assert_eq!(Ok(&json!([{"a": 1}])), db.get("new"));
+    }
+
+    #[test]
+    fn test_first() {
+        assert_eq!(Ok(Json::Bool(true)), eval(first(key("b"))));
+        assert_eq!(Ok(Json::Bool(true)), eval(first(key("b"))));
+        assert_eq!(Ok(Json::from(3.3)), eval(first(key("f"))));
+        assert_eq!(Ok(Json::from(2)), eval(first(key("i"))));
+        assert_eq!(Ok(Json::from(1.1)), eval(first(key("fa"))));
+        assert_eq!(Ok(Json::from(1)), eval(first(key("ia"))));
+    }
+
+    #[test]
+    fn test_last() {
+        assert_eq!(Ok(Json::from(true)), eval(last(key("b"))));
+        assert_eq!(Ok(Json::from(3.3)), eval(last(key("f"))));
+        assert_eq!(Ok(Json::from(2)), eval(last(key("i"))));
+        assert_eq!(Ok(Json::from(5.5)), eval(last(key("fa"))));
+        assert_eq!(Ok(Json::from(5)), eval(last(key("ia"))));
+    }
+
+    #[test]
+    fn test_max() {
+        assert_eq!(Ok(Json::Bool(true)), eval(max(key("b"))));
+        assert_eq!(Ok(Json::from(2)), eval(max(key("i"))));
+        assert_eq!(Ok(Json::from(3.3)), eval(max(key("f"))));
+        assert_eq!(Ok(Json::from(5)), eval(max(key("ia"))));
+        assert_eq!(Ok(Json::from(5.5)), eval(max(key("fa"))));
+    }
+
+    #[test]
+    fn test_min() {
+        assert_eq!(Ok(Json::Bool(true)), eval(min(key("b"))));
+        assert_eq!(Ok(Json::from(2)), eval(min(key("i"))));
+        assert_eq!(Ok(Json::from(3.3)), eval(min(key("f"))));
+        assert_eq!(Ok(Json::from(1.1)), eval(min(key("fa"))));
+        assert_eq!(Ok(Json::from(1)), eval(min(key("ia"))));
+    }
+
+    #[test]
+    fn test_avg() {
+        assert_eq!(Ok(Json::from(3.3)), eval(avg(key("f"))));
+        assert_eq!(Ok(Json::from(2)), eval(avg(key("i"))));
+        assert_eq!(Ok(Json::from(3.3)), eval(avg(key("fa"))));
+        assert_eq!(Ok(Json::from(3.0)), eval(avg(key("ia"))));
+    }
+
+    #[test]
+    fn test_var() {
+        assert_eq!(Ok(Json::from(0)), eval(var(key("f"))));
+        assert_eq!(Ok(Json::from(0)), eval(var(key("i"))));
+        let val = eval(var(key("fa"))).unwrap().as_f64().unwrap();
+        assert_approx_eq!(3.10, val, 0.0249f64);
+        let val = eval(var(key("ia"))).unwrap().as_f64().unwrap();
+        assert_approx_eq!(2.56, val, 0.0249f64);
+    }
+
+    #[test]
+    fn test_dev() {
+        assert_eq!(Ok(Json::from(0)), eval(dev(key("f"))));
+        assert_eq!(Ok(Json::from(0)), eval(dev(key("i"))));
+        let val = eval(dev(key("fa"))).unwrap().as_f64().unwrap();
+        assert_approx_eq!(1.55, val, 0.03f64);
+        let val = eval(dev(key("ia"))).unwrap().as_f64().unwrap();
+        assert_approx_eq!(1.414, val, 0.03f64);
+    }
+
+    #[test]
+    fn test_add() {
+        assert_eq!(Ok(Json::from(9)), eval(add(key("x"), key("y"))));
+        assert_eq!(
+            Ok(Json::from(vec![
+                Json::from(5),
+                Json::from(6),
+                Json::from(7),
+                Json::from(8),
+                Json::from(9),
+            ])),
+            eval(add(key("x"), key("ia")))
+        );
+
+        assert_eq!(
+            Ok(Json::from(vec![
+                Json::from(3),
+                Json::from(4),
+                Json::from(5),
+                Json::from(6),
+                Json::from(7),
+            ])),
+            eval(add(key("ia"), key("i")))
+        );
+
+        assert_eq!(
+            Ok(Json::from(vec![
+                Json::Null,
+                Json::from(4),
+                Json::Null,
+                Json::from(6),
+                Json::from(7),
+            ])),
+            eval(add(key("nia"), key("i")))
+        );
+
+        assert_eq!(
+            Ok(Json::from(vec![
+                Json::Null,
+                Json::Null,
+                Json::Null,
+                Json::Null,
+                Json::from(10),
+            ])),
+            eval(add(key("nia"), key("nfa")))
+        );
+
+        assert_eq!(
+            Ok(Json::Array(vec![
+                Json::from("ahello"),
+                Json::from("bhello"),
+                Json::from("chello"),
+                Json::from("dhello"),
+            ])),
+            eval(add(key("sa"), key("s")))
+        );
+        assert_eq!(
+            Ok(Json::Array(vec![
+                Json::from("helloa"),
+                Json::from("hellob"),
+                Json::from("helloc"),
+                Json::from("hellod"),
+            ])),
+            eval(add(key("s"), key("sa")))
+        );
+
+        assert_eq!(Ok(Json::from("hellohello")), eval(add(key("s"), key("s"))));
+        assert_eq!(Ok(json!("hello3.3")), eval(add(key("s"), key("f"))));
+        assert_eq!(Ok(json!("3.3hello")), eval(add(key("f"), key("s"))));
+        assert_eq!(Ok(json!("2hello")), eval(add(key("i"), key("s"))));
+        assert_eq!(Ok(json!("hello2")), eval(add(key("s"), key("i"))));
+    }
+
+    #[test]
+    fn test_sub() {
+        assert_eq!(Ok(Json::from(-1)), eval(sub(key("x"), key("y"))));
+        assert_eq!(Ok(Json::from(1)), eval(sub(key("y"), key("x"))));
+        assert_eq!(
+            Ok(Json::from(vec![
+                Json::from(3),
+                Json::from(2),
+                Json::from(1),
+                Json::from(0),
+                Json::from(-1),
+            ])),
+            eval(sub(key("x"), key("ia")))
+        );
+
+        assert_eq!(
+            Ok(Json::from(vec![
+                Json::from(-4),
+                Json::from(-3),
+                Json::from(-2),
+                Json::from(-1),
+                Json::from(0),
+            ])),
+            eval(sub(key("ia"), key("y")))
+        );
+
+        assert_eq!(
+            Ok(Json::from(vec![
+                Json::from(0),
+                Json::from(0),
+                Json::from(0),
+                Json::from(0),
+                Json::from(0),
+            ])),
+            eval(sub(key("ia"), key("ia")))
+        );
+
+        assert_eq!(bad_type(), eval(sub(key("s"), key("s"))));
+        assert_eq!(bad_type(), eval(sub(key("sa"), key("s"))));
+        assert_eq!(bad_type(), eval(sub(key("s"), key("sa"))));
+        assert_eq!(bad_type(), eval(sub(key("i"), key("s"))));
+        assert_eq!(bad_type(), eval(sub(key("s"), key("i"))));
+    }
+
+    #[test]
+    fn json_mul() {
+        assert_eq!(Ok(Json::from(20)), eval(mul(key("x"), key("y"))));
+        assert_eq!(Ok(Json::from(16)), eval(mul(key("x"), key("x"))));
+        let arr = vec![
+            Json::from(5),
+            Json::from(10),
+            Json::from(15),
+            Json::from(20),
+            Json::from(25),
+        ];
+        assert_eq!(Ok(Json::from(arr.clone())), eval(mul(key("ia"), key("y"))));
+        assert_eq!(Ok(Json::from(arr)), eval(mul(key("y"), key("ia"))));
+        assert_eq!(
+            Ok(Json::Array(vec![
+                Json::from(1),
+                Json::from(4),
+                Json::from(9),
+                Json::from(16),
+                Json::from(25),
+            ])),
+            eval(mul(key("ia"), key("ia")))
+        );
+        assert_eq!(bad_type(), eval(mul(key("s"), key("s"))));
+        assert_eq!(bad_type(), eval(mul(key("sa"), key("s"))));
+        assert_eq!(bad_type(), eval(mul(key("s"), key("sa"))));
+        assert_eq!(bad_type(), eval(mul(key("i"), key("s"))));
+        assert_eq!(bad_type(), eval(mul(key("s"), key("i"))));
+    }
+
+    #[test]
+    fn json_div() {
+        assert_eq!(Ok(Json::from(1.0)), eval(div(key("x"), key("x"))));
+        assert_eq!(Ok(Json::from(1.0)), eval(div(key("y"), key("y"))));
+        assert_eq!(
+            Ok(Json::from(vec![
+                Json::from(1.0),
+                Json::from(1.0),
+                Json::from(1.0),
+                Json::from(1.0),
+                Json::from(1.0),
+            ])),
+            eval(div(key("ia"), key("ia")))
+        );
+        assert_eq!(
+            Ok(Json::from(vec![
+                Json::from(0.5),
+                Json::from(1.0),
+                Json::from(1.5),
+                Json::from(2.0),
+                Json::from(2.5),
+            ])),
+            eval(div(key("ia"), key("i")))
+        );
+        assert_eq!(
+            Ok(Json::from(vec![
+                Json::from(2.0),
+                Json::from(1.0),
+                Json::from(0.6666666666666666),
+                Json::from(0.5),
+                Json::from(0.4),
+            ])),
+            eval(div(key("i"), key("ia")))
+        );
+
+        assert_eq!(bad_type(), eval(div(key("s"), key("s"))));
+        assert_eq!(bad_type(), eval(div(key("sa"), key("s"))));
+        assert_eq!(bad_type(), eval(div(key("s"), key("sa"))));
+        assert_eq!(bad_type(), eval(div(key("i"), key("s"))));
+        assert_eq!(bad_type(), eval(div(key("s"), key("i"))));
+    }
+
+    #[test]
+    fn eval_cmds() {
+        assert_eq!(Ok(Json::Bool(true)), eval(key("b")));
+        assert_eq!(
+            eval(key("ia")),
+            Ok(Json::Array(vec![
+                Json::from(1),
+                Json::from(2),
+                Json::from(3),
+                Json::from(4),
+                Json::from(5),
+            ]))
+        );
+        assert_eq!(eval(key("i")), Ok(Json::from(2)));
+        assert_eq!(eval(key("f")), Ok(Json::from(3.3)));
+        assert_eq!(
+            eval(key("fa")),
+            Ok(Json::Array(vec![
+                Json::from(1.1),
+                Json::from(2.2),
+                Json::from(3.3),
+                Json::from(4.4),
+                Json::from(5.5),
+            ]))
+        );
+        assert_eq!(eval(key("f")), Ok(Json::from(3.3)));
+        assert_eq!(eval(key("s")), Ok(Json::from("hello")));
+        assert_eq!(
+            eval(key("sa")),
+            Ok(Json::Array(vec![
+                Json::from("a"),
+                Json::from("b"),
+                Json::from("c"),
+                Json::from("d"),
+            ]))
         );
     }
 
     #[test]
-    fn test_first() {
-        assert_eq!(Ok(Json::Bool(true)), eval(first(key("b"))));
-        assert_eq!(Ok(Json::Bool(true)), eval(first(key("b"))));
-        assert_eq!(Ok(Json::from(3.3)), eval(first(key("f"))));
-        assert_eq!(Ok(Json::from(2)), eval(first(key("i"))));
-        assert_eq!(Ok(Json::from(1.1)), eval(first(key("fa"))));
-        assert_eq!(Ok(Json::from(1)), eval(first(key("ia"))));
+    fn test_get() {
+        assert_eq!(Ok(Json::Bool(true)), eval(key("b")));
+        assert_eq!(Ok(Json::Bool(true)), eval(key("b")));
+        assert_eq!(
+            Ok(Json::Array(vec![
+                Json::from(1),
+                Json::from(2),
+                Json::from(3),
+                Json::from(4),
+                Json::from(5)
+            ])),
+            eval(key("ia"))
+        );
+        assert_eq!(Ok(Json::from(2)), eval(key("i")));
+    }
+
+    #[test]
+    fn eval_set_get_ok() {
+        let vec = vec![
+            Json::from(1),
+            Json::from(2),
+            Json::from(3),
+            Json::from(4),
+            Json::from(5),
+        ];
+        let val = Json::from(vec.clone());
+        let mut db = test_db();
+        assert_eq!(Ok(Json::Null), db.eval(set("nums", Cmd::Json(val.clone()))));
+        assert_eq!(Ok(val), db.eval(key("nums")));
+    }
+
+    #[test]
+    fn eval_get_string_err_not_found() {
+        assert_eq!(Err(Error::BadKey("ania".to_string())), eval(key("ania")));
+    }
+
+    fn key_range(range: Json) -> Result<Json, Error> {
+        eval(Cmd::parse(json!({ "keyRange": range })).unwrap())
+    }
+
+    #[test]
+    fn eval_key_range() {
+        let exp = json!(["f", "fa", "i", "ia"]);
+        assert_eq!(Ok(exp), key_range(json!({"from": "f", "to": "n"})));
+        let exp = json!(["ia", "i"]);
+        let range = json!({"from": "f", "to": "n", "reverse": true, "limit": 2});
+        assert_eq!(Ok(exp), key_range(range));
+        let exp = json!([{"key": "x", "val": 4}, {"key": "y", "val": 5}]);
+        assert_eq!(Ok(exp), key_range(json!({"from": "x", "values": true})));
+        assert_eq!(Ok(json!([])), key_range(json!({"from": "n", "to": "f"})));
+        let range = json!({"from": "f", "to": "n", "start": -3, "step": 2});
+        assert_eq!(Ok(json!(["fa", "ia"])), key_range(range));
+    }
+
+    #[test]
+    fn eval_generate_fills_table() {
+        let mut db = test_db();
+        let gen = json!({"generate": {
+            "table": "sales",
+            "n": 20,
+            "template": {"id": {"$seq": 0}, "qty": {"$int": [1, 1]}},
+            "seed": 3,
+        }});
+        assert_eq!(Ok(json!(20)), db.eval(Cmd::parse(gen).unwrap()));
+        let qry = json!({"query": {"select": {"n": {"sum": {"key": "qty"}}}, "from": "sales"}});
+        assert_eq!(Ok(json!({"n": 20})), db.eval(Cmd::parse(qry).unwrap()));
+        let bad = json!({"generate": {"table": "sales", "n": 1, "template": {"$int": "x"}}});
+        assert!(db.eval(Cmd::parse(bad).unwrap()).is_err());
+        let len = json!({"len": {"key": "sales"}});
+        assert_eq!(Ok(json!(20)), db.eval(Cmd::parse(len).unwrap()));
+    }
+
+    #[test]
+    fn eval_scan_pages_through_matching_keys() {
+        let scan = |val: Json| eval(Cmd::parse(json!({ "scan": val })).unwrap());
+        let exp = json!({"keys": ["a", "b"], "cursor": "b"});
+        assert_eq!(Ok(exp), scan(json!({"pattern": "*", "count": 2})));
+        let page = json!({"pattern": "n*a", "cursor": "nfa", "count": 1});
+        assert_eq!(Ok(json!({"keys": ["nia"], "cursor": null})), scan(page));
+        let exp = json!({"keys": ["a", "fa", "ia", "nfa", "nia", "sa"], "cursor": null});
+        assert_eq!(Ok(exp), scan(json!("*a")));
+        let exp = json!({"keys": ["i", "ia"], "cursor": null});
+        assert_eq!(Ok(exp), scan(json!("i*")));
+        assert_eq!(Ok(json!({"keys": [], "cursor": null})), scan(json!("zz*")));
+    }
+
+    #[test]
+    fn eval_keys_filtered_by_type_and_size() {
+        let cmd = Cmd::parse(json!({"keys": {"type": "array", "maxSize": 30}})).unwrap();
+        assert_eq!(Ok(json!(["nfa", "nia", "sa"])), eval(cmd));
+        let cmd = Cmd::parse(json!({"keys": {"type": "number", "start": 1, "size": 2}})).unwrap();
+        assert_eq!(Ok(json!({"keys": ["i", "x"], "total": 4})), eval(cmd));
+        let cmd = Cmd::parse(json!({"keys": {"start": 100}})).unwrap();
+        assert_eq!(Ok(json!({"keys": [], "total": 17})), eval(cmd));
+        let cmd = Cmd::parse(json!({"keys": {"type": "number", "start": -2}})).unwrap();
+        assert_eq!(Ok(json!({"keys": ["x", "y"], "total": 4})), eval(cmd));
+        let cmd = Cmd::parse(json!({"summary": {"type": "string"}})).unwrap();
+        let exp = json!({"no_entries": 17, "keys": ["s"], "expiring": []});
+        assert_eq!(Ok(exp), eval(cmd));
+    }
+
+    #[test]
+    fn eval_hot_keys() {
+        let mut db = test_db();
+        for val in [
+            json!({"key": "a"}),
+            json!({"sum": {"key": "a"}}),
+            json!({"+": [{"key": "x"}, {"key": "a"}]}),
+            json!({"key": "x"}),
+            json!({"set": ["z", 1]}),
+            json!({"query": {"from": "orders"}}),
+        ] {
+            db.eval(Cmd::parse(val).unwrap()).unwrap();
+        }
+        assert_eq!(3, db.stats().get("a").unwrap().reads);
+        let hot = db.eval(Cmd::parse(json!({"hotKeys": 2})).unwrap()).unwrap();
+        let keys = |x: &Json| -> Vec<Json> {
+            x.as_array()
+                .unwrap()
+                .iter()
+                .map(|x| x["key"].clone())
+                .collect()
+        };
+        assert_eq!(vec![json!("a"), json!("x")], keys(&hot["reads"]));
+        assert_eq!(vec![json!("z")], keys(&hot["writes"]));
+        assert_eq!(json!(1), hot["writes"][0]["count"]);
+        // missing and deleted keys have no stats
+        assert!(db.eval(Cmd::Key("missing".to_string())).is_err());
+        db.eval(Cmd::Delete("z".to_string())).unwrap();
+        assert_eq!(None, db.stats().get("missing"));
+        let hot = db.eval(Cmd::parse(json!({"hotKeys": 2})).unwrap()).unwrap();
+        assert_eq!(json!([]), hot["writes"]);
+    }
+
+    #[test]
+    fn nested_get() {
+        let act = eval(get("name", key("t"))).unwrap();
+        assert_eq!(json!(["james", "ania", "misha", "ania",]), act);
+    }
+
+    fn test_db() -> InMemDb {
+        let mut db = InMemDb::new();
+        insert_data(&mut db);
+        db
+    }
+
+    fn query(json: Json) -> Result<Json, Error> {
+        let cmd = serde_json::from_value(json).unwrap();
+        let db = test_db();
+        let qry = Query::from(&db, cmd);
+        qry.exec()
+    }
+
+    #[test]
+    fn select_all_query() {
+        let qry = query(json!({"from": "t"}));
+        assert_eq!(Ok(table_data()), qry);
+    }
+
+    fn table_data() -> Json {
+        json!([
+            {"name": "james", "age": 35},
+            {"name": "ania", "age": 28, "job": "english teacher"},
+            {"name": "misha", "age": 10},
+            {"name": "ania", "age": 20},
+        ])
+    }
+
+    #[test]
+    fn select_1_prop_query() {
+        let qry = query(json!({"select": {"name": {"key": "name"}}, "from": "t"}));
+        let val = json!({"name": ["james", "ania", "misha", "ania"]});
+        assert_eq!(Ok(val), qry);
+    }
+
+    #[test]
+    fn select_3_prop_query() {
+        let qry = query(json!({
+            "select": {
+                "name": {"key": "name"},
+                "age": {"key": "age"},
+                "job": {"key": "job"}
+            },
+            "from": "t"
+        }));
+        let val = json!({
+            "name": ["james", "ania", "misha", "ania"],
+            "age": [35, 28, 10, 20],
+            "job": ["english teacher"],
+        });
+        assert_eq!(Ok(val), qry);
+    }
+
+    #[test]
+    fn select_all_where_eq_query() {
+        let qry = query(json!({"from": "t", "where": {"==": [{"key": "name"}, "james"]}}));
+        let val = json!([{"name": "james", "age": 35}]);
+        assert_eq!(Ok(val), qry);
+    }
+
+    #[test]
+    fn select_all_where_neq_query_ok() {
+        let qry = query(json!({"from": "t", "where": {"!=": [{"key":"name"}, "james"]}}));
+        let val = json!([
+            {"name": "ania", "age": 28, "job": "english teacher"},
+            {"name": "misha", "age": 10},
+            {"name": "ania", "age": 20},
+        ]);
+        assert_eq!(Ok(val), qry);
+    }
+
+    #[test]
+    fn select_all_where_gt_query_ok() {
+        let qry = query(json!({"from": "t", "where": {">": [{"key": "age"}, 20]}}));
+        let val = json!([
+            {"name": "james", "age": 35},
+            {"name": "ania", "age": 28, "job": "english teacher"},
+        ]);
+        assert_eq!(Ok(val), qry);
+    }
+
+    #[test]
+    fn select_all_where_lt_query_ok() {
+        let qry = query(json!({"from": "t", "where": {"<": [{"key": "age"}, 20]}}));
+        let val = json!([{"name": "misha", "age": 10}]);
+        assert_eq!(Ok(val), qry);
+    }
+
+    #[test]
+    fn select_all_where_lte_query_ok() {
+        let qry = query(json!({"from": "t", "where": {"<=": [{"key": "age"}, 28]}}));
+        let val = json!([
+            {"name": "ania", "age": 28, "job": "english teacher"},
+            {"name": "misha", "age": 10},
+            {"name": "ania", "age": 20},
+        ]);
+        assert_eq!(Ok(val), qry);
+    }
+
+    #[test]
+    fn select_all_where_gte_query_ok() {
+        let qry = query(json!({"from": "t", "where": {">=": [{"key":"age"}, 28]}}));
+        let val = json!([
+            {"name": "james", "age": 35},
+            {"name": "ania", "age": 28, "job": "english teacher"}
+        ]);
+
+        assert_eq!(Ok(val), qry);
+    }
+
+    #[test]
+    fn select_all_where_and_gate_ok() {
+        let qry = query(json!({
+            "from": "t",
+            "where": {"&&": [
+                {">": [{"key": "age"}, 20]},
+                {"==": [{"key":"name"}, "ania"]}
+            ]}
+        }));
+        let val = json!([{"name": "ania", "age": 28, "job": "english teacher"}]);
+        assert_eq!(Ok(val), qry);
+    }
+
+    #[test]
+    fn select_sum_ok() {
+        let qry = query(json!({
+            "select": {"totalAge": {"sum": {"key": "age"}}},
+            "from": "t"
+        }));
+        let val = json!({"totalAge": 93});
+        assert_eq!(Ok(val), qry);
+    }
+
+    #[test]
+    fn select_max_num_ok() {
+        let qry = query(json!({
+            "select": {
+                "maxAge": {"max": {"key": "age"}}
+            },
+            "from": "t"
+        }));
+        let val = json!({"maxAge": 35});
+        assert_eq!(Ok(val), qry);
+    }
+
+    #[test]
+    fn select_max_str_ok() {
+        let qry = query(json!({
+            "select": {
+                "maxName": {"max":  {"key": "name"}}
+            },
+            "from": "t"
+        }));
+        let val = json!({"maxName": "misha"});
+        assert_eq!(Ok(val), qry);
+    }
+
+    #[test]
+    fn select_min_num_ok() {
+        let qry = query(json!({
+            "select": {
+                "minAge": {"min": {"key": "age"}}
+            },
+            "from": "t"
+        }));
+        let val = json!({"minAge": 10});
+        assert_eq!(Ok(val), qry);
     }
 
     #[test]
-    fn test_last() {
-        assert_eq!(Ok(Json::from(true)), eval(last(key("b"))));
-        assert_eq!(Ok(Json::from(3.3)), eval(last(key("f"))));
-        assert_eq!(Ok(Json::from(2)), eval(last(key("i"))));
-        assert_eq!(Ok(Json::from(5.5)), eval(last(key("fa"))));
-        assert_eq!(Ok(Json::from(5)), eval(last(key("ia"))));
+    fn select_avg_num_ok() {
+        let qry = query(json!({
+            "select": {
+                "avgAge": {"avg": {"key": "age"}}
+            },
+            "from": "t"
+        }));
+        assert_eq!(Ok(json!({"avgAge": 23.25})), qry);
     }
 
     #[test]
-    fn test_max() {
-        assert_eq!(Ok(Json::Bool(true)), eval(max(key("b"))));
-        assert_eq!(Ok(Json::from(2)), eval(max(key("i"))));
-        assert_eq!(Ok(Json::from(3.3)), eval(max(key("f"))));
-        assert_eq!(Ok(Json::from(5)), eval(max(key("ia"))));
-        assert_eq!(Ok(Json::from(5.5)), eval(max(key("fa"))));
+    fn select_first_ok() {
+        let qry = query(json!({
+            "select": {
+                "firstAge": {"first": {"key": "age"}}
+            },
+            "from": "t"
+        }));
+        assert_eq!(Ok(json!({"firstAge": 35})), qry);
     }
 
     #[test]
-    fn test_min() {
-        assert_eq!(Ok(Json::Bool(true)), eval(min(key("b"))));
-        assert_eq!(Ok(Json::from(2)), eval(min(key("i"))));
-        assert_eq!(Ok(Json::from(3.3)), eval(min(key("f"))));
-        assert_eq!(Ok(Json::from(1.1)), eval(min(key("fa"))));
-        assert_eq!(Ok(Json::from(1)), eval(min(key("ia"))));
+    fn select_last_ok() {
+        let qry = query(json!({
+            "select": {
+                "lastAge": {"last": {"key": "age"}}
+            },
+            "from": "t"
+        }));
+        assert_eq!(Ok(json!({"lastAge": 20})), qry);
     }
 
     #[test]
-    fn test_avg() {
-        assert_eq!(Ok(Json::from(3.3)), eval(avg(key("f"))));
-        assert_eq!(Ok(Json::from(2)), eval(avg(key("i"))));
-        assert_eq!(Ok(Json::from(3.3)), eval(avg(key("fa"))));
-        assert_eq!(Ok(Json::from(3.0)), eval(avg(key("ia"))));
+    fn select_var_num_ok() {
+        let qry = query(json!({
+            "select": {
+                "varAge": {"var": {"key": "age"}}
+            },
+            "from": "t"
+        }));
+        assert_eq!(Ok(json!({"varAge": 146.75})), qry);
     }
 
     #[test]
-    fn test_var() {
-        assert_eq!(Ok(Json::from(0)), eval(var(key("f"))));
-        assert_eq!(Ok(Json::from(0)), eval(var(key("i"))));
-        let val = eval(var(key("fa"))).unwrap().as_f64().unwrap();
-        assert_approx_eq!(3.10, val, 0.0249f64);
-        let val = eval(var(key("ia"))).unwrap().as_f64().unwrap();
-        assert_approx_eq!(2.56, val, 0.0249f64);
+    fn select_dev_num_ok() {
+        let qry = query(json!({
+            "select": {
+                "devAge": {"dev": {"key": "age"}}
+            },
+            "from": "t"
+        }));
+        assert_eq!(Ok(json!({"devAge": 9.310612224768036})), qry);
     }
 
     #[test]
-    fn test_dev() {
-        assert_eq!(Ok(Json::from(0)), eval(dev(key("f"))));
-        assert_eq!(Ok(Json::from(0)), eval(dev(key("i"))));
-        let val = eval(dev(key("fa"))).unwrap().as_f64().unwrap();
-        assert_approx_eq!(1.55, val, 0.03f64);
-        let val = eval(dev(key("ia"))).unwrap().as_f64().unwrap();
-        assert_approx_eq!(1.414, val, 0.03f64);
+    fn select_max_age_where_age_gt_20() {
+        let qry = query(json!({
+            "select": {
+                "maxAge": {"max": {"key": "age"}}
+            },
+            "from": "t",
+            "where": {">": [{"key":"age"}, 20]}
+        }));
+        assert_eq!(Ok(json!({"maxAge": 35})), qry);
     }
 
     #[test]
-    fn test_add() {
-        assert_eq!(Ok(Json::from(9)), eval(add(key("x"), key("y"))));
-        assert_eq!(
-            Ok(Json::from(vec![
-                Json::from(5),
-                Json::from(6),
-                Json::from(7),
-                Json::from(8),
-                Json::from(9),
-            ])),
-            eval(add(key("x"), key("ia")))
-        );
-
-        assert_eq!(
-            Ok(Json::from(vec![
-                Json::from(3),
-                Json::from(4),
-                Json::from(5),
-                Json::from(6),
-                Json::from(7),
-            ])),
-            eval(add(key("ia"), key("i")))
-        );
-
-        assert_eq!(
-            Ok(Json::from(vec![
-                Json::Null,
-                Json::from(4),
-                Json::Null,
-                Json::from(6),
-                Json::from(7),
-            ])),
-            eval(add(key("nia"), key("i")))
-        );
-
-        assert_eq!(
-            Ok(Json::from(vec![
-                Json::Null,
-                Json::Null,
-                Json::Null,
-                Json::Null,
-                Json::from(10),
-            ])),
-            eval(add(key("nia"), key("nfa")))
-        );
-
-        assert_eq!(
-            Ok(Json::Array(vec![
-                Json::from("ahello"),
-                Json::from("bhello"),
-                Json::from("chello"),
-                Json::from("dhello"),
-            ])),
-            eval(add(key("sa"), key("s")))
-        );
-        assert_eq!(
-            Ok(Json::Array(vec![
-                Json::from("helloa"),
-                Json::from("hellob"),
-                Json::from("helloc"),
-                Json::from("hellod"),
-            ])),
-            eval(add(key("s"), key("sa")))
-        );
-
-        assert_eq!(Ok(Json::from("hellohello")), eval(add(key("s"), key("s"))));
-        assert_eq!(Ok(json!("hello3.3")), eval(add(key("s"), key("f"))));
-        assert_eq!(Ok(json!("3.3hello")), eval(add(key("f"), key("s"))));
-        assert_eq!(Ok(json!("2hello")), eval(add(key("i"), key("s"))));
-        assert_eq!(Ok(json!("hello2")), eval(add(key("s"), key("i"))));
+    fn select_get_age_where_age_gt_20() {
+        let qry = query(json!({
+            "select": {
+                "age": {"key": "age"}
+            },
+            "from": "t",
+            "where": {">": [{"key":"age"}, 20]}
+        }));
+        assert_eq!(Ok(json!({"age":[35, 28]})), qry);
     }
 
     #[test]
-    fn test_sub() {
-        assert_eq!(Ok(Json::from(-1)), eval(sub(key("x"), key("y"))));
-        assert_eq!(Ok(Json::from(1)), eval(sub(key("y"), key("x"))));
-        assert_eq!(
-            Ok(Json::from(vec![
-                Json::from(3),
-                Json::from(2),
-                Json::from(1),
-                Json::from(0),
-                Json::from(-1),
-            ])),
-            eval(sub(key("x"), key("ia")))
-        );
-
-        assert_eq!(
-            Ok(Json::from(vec![
-                Json::from(-4),
-                Json::from(-3),
-                Json::from(-2),
-                Json::from(-1),
-                Json::from(0),
-            ])),
-            eval(sub(key("ia"), key("y")))
-        );
-
-        assert_eq!(
-            Ok(Json::from(vec![
-                Json::from(0),
-                Json::from(0),
-                Json::from(0),
-                Json::from(0),
-                Json::from(0),
-            ])),
-            eval(sub(key("ia"), key("ia")))
-        );
-
-        assert_eq!(bad_type(), eval(sub(key("s"), key("s"))));
-        assert_eq!(bad_type(), eval(sub(key("sa"), key("s"))));
-        assert_eq!(bad_type(), eval(sub(key("s"), key("sa"))));
-        assert_eq!(bad_type(), eval(sub(key("i"), key("s"))));
-        assert_eq!(bad_type(), eval(sub(key("s"), key("i"))));
+    fn select_min_age_where_age_gt_20() {
+        let qry = query(json!({
+            "select": {
+                "minAge": {"min":  {"key": "age"}}
+            },
+            "from": "t",
+            "where": {">": [{"key":"age"}, 20]}
+        }));
+        assert_eq!(Ok(json!({"minAge": 28})), qry);
     }
 
     #[test]
-    fn json_mul() {
-        assert_eq!(Ok(Json::from(20)), eval(mul(key("x"), key("y"))));
-        assert_eq!(Ok(Json::from(16)), eval(mul(key("x"), key("x"))));
-        let arr = vec![
-            Json::from(5),
-            Json::from(10),
-            Json::from(15),
-            Json::from(20),
-            Json::from(25),
-        ];
-        assert_eq!(Ok(Json::from(arr.clone())), eval(mul(key("ia"), key("y"))));
-        assert_eq!(Ok(Json::from(arr)), eval(mul(key("y"), key("ia"))));
-        assert_eq!(
-            Ok(Json::Array(vec![
-                Json::from(1),
-                Json::from(4),
-                Json::from(9),
-                Json::from(16),
-                Json::from(25),
-            ])),
-            eval(mul(key("ia"), key("ia")))
-        );
-        assert_eq!(bad_type(), eval(mul(key("s"), key("s"))));
-        assert_eq!(bad_type(), eval(mul(key("sa"), key("s"))));
-        assert_eq!(bad_type(), eval(mul(key("s"), key("sa"))));
-        assert_eq!(bad_type(), eval(mul(key("i"), key("s"))));
-        assert_eq!(bad_type(), eval(mul(key("s"), key("i"))));
+    fn select_min_max_age_where_age_gt_20() {
+        let qry = query(json!({
+            "select": {
+                "youngestAge": {"min": {"key": "age"}},
+                "oldestAge": {"max": {"key": "age"}},
+            },
+            "from": "t",
+            "where": {">": [{"key":"age"}, 20]}
+        }));
+        assert_eq!(Ok(json!({"youngestAge": 28, "oldestAge": 35})), qry);
     }
 
     #[test]
-    fn json_div() {
-        assert_eq!(Ok(Json::from(1.0)), eval(div(key("x"), key("x"))));
-        assert_eq!(Ok(Json::from(1.0)), eval(div(key("y"), key("y"))));
-        assert_eq!(
-            Ok(Json::from(vec![
-                Json::from(1.0),
-                Json::from(1.0),
-                Json::from(1.0),
-                Json::from(1.0),
-                Json::from(1.0),
-            ])),
-            eval(div(key("ia"), key("ia")))
-        );
+    fn select_empty_obj() {
+        let qry = query(json!({
+            "select": {},
+            "from": "t",
+        }));
         assert_eq!(
-            Ok(Json::from(vec![
-                Json::from(0.5),
-                Json::from(1.0),
-                Json::from(1.5),
-                Json::from(2.0),
-                Json::from(2.5),
+            Ok(json!([
+                {"name": "james", "age": 35},
+                {"name": "ania", "age": 28, "job": "english teacher"},
+                {"name": "misha", "age": 10},
+                {"name": "ania", "age": 20},
             ])),
-            eval(div(key("ia"), key("i")))
+            qry
         );
+    }
+
+    #[test]
+    fn select_empty_obj_where_age_gt_20() {
+        let qry = query(json!({
+            "select": {},
+            "from": "t",
+            "where": {">": [{"key":"age"}, 20]}
+        }));
         assert_eq!(
-            Ok(Json::from(vec![
-                Json::from(2.0),
-                Json::from(1.0),
-                Json::from(0.6666666666666666),
-                Json::from(0.5),
-                Json::from(0.4),
+            Ok(json!([
+                {"name": "james", "age": 35},
+                {"name": "ania", "age": 28, "job": "english teacher"},
             ])),
-            eval(div(key("i"), key("ia")))
+            qry
         );
-
-        assert_eq!(bad_type(), eval(div(key("s"), key("s"))));
-        assert_eq!(bad_type(), eval(div(key("sa"), key("s"))));
-        assert_eq!(bad_type(), eval(div(key("s"), key("sa"))));
-        assert_eq!(bad_type(), eval(div(key("i"), key("s"))));
-        assert_eq!(bad_type(), eval(div(key("s"), key("i"))));
     }
 
     #[test]
-    fn eval_cmds() {
-        assert_eq!(Ok(Json::Bool(true)), eval(key("b")));
-        assert_eq!(
-            eval(key("ia")),
-            Ok(Json::Array(vec![
-                Json::from(1),
-                Json::from(2),
-                Json::from(3),
-                Json::from(4),
-                Json::from(5),
-            ]))
-        );
-        assert_eq!(eval(key("i")), Ok(Json::from(2)));
-        assert_eq!(eval(key("f")), Ok(Json::from(3.3)));
-        assert_eq!(
-            eval(key("fa")),
-            Ok(Json::Array(vec![
-                Json::from(1.1),
-                Json::from(2.2),
-                Json::from(3.3),
-                Json::from(4.4),
-                Json::from(5.5),
-            ]))
-        );
-        assert_eq!(eval(key("f")), Ok(Json::from(3.3)));
-        assert_eq!(eval(key("s")), Ok(Json::from("hello")));
+    fn select_age_by_name() {
+        let qry = query(json!({
+            "select": {
+                "age": {"key": "age"},
+            },
+            "from": "t",
+            "by": {"key": "name"},
+        }));
         assert_eq!(
-            eval(key("sa")),
-            Ok(Json::Array(vec![
-                Json::from("a"),
-                Json::from("b"),
-                Json::from("c"),
-                Json::from("d"),
-            ]))
+            Ok(json!({
+                "misha": {"age": [10]},
+                "ania": {"age": [28, 20]},
+                "james": {"age":[35]},
+            })),
+            qry
         );
     }
 
     #[test]
-    fn test_get() {
-        assert_eq!(Ok(Json::Bool(true)), eval(key("b")));
-        assert_eq!(Ok(Json::Bool(true)), eval(key("b")));
+    fn select_first_age_by_name() {
+        let qry = query(json!({
+            "select": {
+                "age": {"first": {"key": "age"}},
+            },
+            "from": "t",
+            "by": {"key": "name"},
+        }));
         assert_eq!(
-            Ok(Json::Array(vec![
-                Json::from(1),
-                Json::from(2),
-                Json::from(3),
-                Json::from(4),
-                Json::from(5)
-            ])),
-            eval(key("ia"))
+            Ok(json!({
+                "misha": {"age": 10},
+                "ania": {"age": 28},
+                "james": {"age": 35},
+            })),
+            qry
         );
-        assert_eq!(Ok(Json::from(2)), eval(key("i")));
     }
 
     #[test]
-    fn eval_set_get_ok() {
-        let vec = vec![
-            Json::from(1),
-            Json::from(2),
-            Json::from(3),
-            Json::from(4),
-            Json::from(5),
-        ];
-        let val = Json::from(vec.clone());
-        let mut db = test_db();
-        assert_eq!(Ok(Json::Null), db.eval(set("nums", Cmd::Json(val.clone()))));
-        assert_eq!(Ok(Json::from(val)), db.eval(key("nums")));
+    fn select_last_age_by_name() {
+        let qry = query(json!({
+            "select": {
+                "age": {"last": {"key":"age"}},
+            },
+            "from": "t",
+            "by": {"key": "name"},
+        }));
+        assert_eq!(
+            Ok(json!({
+                "misha":{"age": 10},
+                "ania":{"age": 20},
+                "james":{"age": 35},
+            })),
+            qry
+        );
     }
 
     #[test]
-    fn eval_get_string_err_not_found() {
-        assert_eq!(Err(Error::BadKey("ania".to_string())), eval(key("ania")));
+    fn select_count_age_by_name() {
+        let qry = query(json!({
+            "select": {
+                "age": {"len": {"key": "age"}},
+            },
+            "from": "t",
+            "by": {"key": "name"},
+        }));
+        assert_eq!(
+            Ok(json!({
+                "misha": {"age": 1},
+                "ania": {"age": 2},
+                "james": {"age": 1},
+            })),
+            qry
+        );
     }
 
     #[test]
-    fn nested_get() {
-        let act = eval(get("name", key("t"))).unwrap();
-        assert_eq!(json!(["james", "ania", "misha", "ania",]), act);
-    }
-
-    fn test_db() -> InMemDb {
-        let mut db = InMemDb::new();
-        insert_data(&mut db);
-        db
+    fn select_min_age_by_name() {
+        let qry = query(json!({
+            "select": {
+                "age": {"min": {"key": "age"}},
+            },
+            "from": "t",
+            "by": {"key":"name"},
+        }));
+        assert_eq!(
+            Ok(json!({
+                "misha":{"age": 10},
+                "ania":{"age": 20},
+                "james":{"age": 35},
+            })),
+            qry
+        );
     }
 
-    fn query(json: Json) -> Result<Json, Error> {
-        let cmd = serde_json::from_value(json).unwrap();
-        let db = test_db();
-        let qry = Query::from(&db, cmd);
-        qry.exec()
+    #[test]
+    fn select_max_age_by_name() {
+        let qry = query(json!({
+            "select": {
+                "age": {"max": {"key": "age"}},
+            },
+            "from": "t",
+            "by": {"key": "name"},
+        }));
+        assert_eq!(
+            Ok(json!({
+                "misha":{"age": 10},
+                "ania":{"age": 28},
+                "james":{"age": 35},
+            })),
+            qry
+        );
     }
 
     #[test]
-    fn select_all_query() {
-        let qry = query(json!({"from": "t"}));
-        assert_eq!(Ok(table_data()), qry);
+    fn select_name_by_age() {
+        let qry = query(json!({
+            "select": {
+                "name": {"key": "name"},
+            },
+            "from": "t",
+            "by": {"key": "age"},
+        }));
+        assert_eq!(
+            Ok(json!({
+                "10": {"name": ["misha"]},
+                "20": {"name": ["ania"]},
+                "28": {"name": ["ania"]},
+                "35": {"name": ["james"]},
+            })),
+            qry
+        );
     }
 
-    fn table_data() -> Json {
-        json!([
-            {"name": "james", "age": 35},
-            {"name": "ania", "age": 28, "job": "english teacher"},
-            {"name": "misha", "age": 10},
-            {"name": "ania", "age": 20},
-        ])
+    #[test]
+    fn select_age_by_name_where_age_gt_20() {
+        let qry = query(json!({
+            "select": {
+                "age": {"key": "age"},
+            },
+            "from": "t",
+            "by": {"key": "name"},
+            "where": {">": [{"key": "age"}, 20]}
+        }));
+        assert_eq!(
+            Ok(json!({
+                "ania": {"age": [28]},
+                "james": {"age": [35]}
+            })),
+            qry
+        );
     }
 
     #[test]
-    fn select_1_prop_query() {
-        let qry = query(json!({"select": {"name": {"key": "name"}}, "from": "t"}));
-        let val = json!({"name": ["james", "ania", "misha", "ania"]});
-        assert_eq!(Ok(val), qry);
+    fn select_empty_keys_by_name() {
+        let qry = query(json!({
+            "select": {
+                "qty": {"key": "qty"},
+                "price": {"key": "price"},
+            },
+            "from": "t",
+            "by": {"key":"name"}
+        }));
+        assert_eq!(
+            Ok(json!({
+                "ania": {"qty":[], "price":[]},
+                "james": {"qty":[], "price":[]},
+                "misha": {"qty":[], "price":[]},
+            })),
+            qry
+        );
     }
 
     #[test]
-    fn select_3_prop_query() {
+    fn select_age_job_by_name_where_age_gt_20() {
         let qry = query(json!({
             "select": {
-                "name": {"key": "name"},
                 "age": {"key": "age"},
-                "job": {"key": "job"}
+                "job": {"key": "job"},
             },
-            "from": "t"
+            "from": "t",
+            "by": {"key": "name"},
+            "where": {">": [{"key": "age"}, 20]}
         }));
-        let val = json!({
-            "name": ["james", "ania", "misha", "ania"],
-            "age": [35, 28, 10, 20],
-            "job": ["english teacher"],
-        });
-        assert_eq!(Ok(val), qry);
+        assert_eq!(
+            Ok(json!({
+                "ania": {"age": [28], "job": ["english teacher"]},
+                "james": {"age": [35], "job": []},
+            })),
+            qry
+        );
     }
 
     #[test]
-    fn select_all_where_eq_query() {
-        let qry = query(json!({"from": "t", "where": {"==": [{"key": "name"}, "james"]}}));
-        let val = json!([{"name": "james", "age": 35}]);
-        assert_eq!(Ok(val), qry);
+    fn select_all_by_name_where_age_gt_20() {
+        let qry = query(json!({
+            "from": "t",
+            "by": {"key": "name"},
+            "where": {">": [{"key":"age"}, 20]}
+        }));
+        assert_eq!(
+            Ok(json!({
+                "ania": [{"age": 28, "name": "ania", "job": "english teacher"}],
+                "james": [{"age": 35, "name": "james"}],
+            })),
+            qry
+        );
     }
 
     #[test]
-    fn select_all_where_neq_query_ok() {
-        let qry = query(json!({"from": "t", "where": {"!=": [{"key":"name"}, "james"]}}));
-        let val = json!([
-            {"name": "ania", "age": 28, "job": "english teacher"},
-            {"name": "misha", "age": 10},
-            {"name": "ania", "age": 20},
-        ]);
-        assert_eq!(Ok(val), qry);
+    fn select_count_max_min_age_by_name() {
+        let qry = query(json!({
+            "select": {
+                "maxAge": {"max": {"key": "age"}},
+                "minAge": {"min": {"key": "age"}},
+                "countAge": {"len": {"key": "age"}},
+            },
+            "from": "t",
+            "by": {"key": "name"},
+        }));
+        assert_eq!(
+            Ok(json!({
+                "ania": {"maxAge": 28, "countAge": 2, "minAge": 20},
+                "james": {"maxAge": 35, "countAge": 1, "minAge": 35},
+                "misha": {"maxAge": 10, "countAge": 1, "minAge": 10}
+            })),
+            qry
+        );
     }
 
     #[test]
-    fn select_all_where_gt_query_ok() {
-        let qry = query(json!({"from": "t", "where": {">": [{"key": "age"}, 20]}}));
-        let val = json!([
-            {"name": "james", "age": 35},
-            {"name": "ania", "age": 28, "job": "english teacher"},
-        ]);
-        assert_eq!(Ok(val), qry);
+    fn select_all_sort_by_name() {
+        let qry = query(json!({
+            "from": "orders",
+            "sort": "customer",
+        }));
+        assert_eq!(
+            Ok(json!([
+                { "time": 1, "customer": "ania", "qty": 2, "price": 2.0 },
+                { "time": 0, "customer": "james", "qty": 2, "price": 9.0, "discount": 10 },
+                { "time": 3, "customer": "james", "qty": 10, "price": 16.0, "discount": 20 },
+                { "time": 4, "customer": "james", "qty": 1, "price": 16.0 },
+                { "time": 2, "customer": "misha", "qty": 4, "price": 1.0 },
+            ])),
+            qry
+        );
     }
 
     #[test]
-    fn select_all_where_lt_query_ok() {
-        let qry = query(json!({"from": "t", "where": {"<": [{"key": "age"}, 20]}}));
-        let val = json!([{"name": "misha", "age": 10}]);
-        assert_eq!(Ok(val), qry);
+    fn select_all_sort_by_qty() {
+        let qry = query(json!({
+            "from": "orders",
+            "sort": "qty",
+        }));
+        assert_eq!(
+            Ok(json!([
+                    { "time": 4, "customer": "james", "qty": 1, "price": 16.0 },
+                    { "time": 0, "customer": "james", "qty": 2, "price": 9.0, "discount": 10 },
+                    { "time": 1, "customer": "ania", "qty": 2, "price": 2.0 },
+                    { "time": 2, "customer": "misha", "qty": 4, "price": 1.0 },
+                    { "time": 3, "customer": "james", "qty": 10, "price": 16.0, "discount": 20 },
+            ])),
+            qry
+        );
     }
 
     #[test]
-    fn select_all_where_lte_query_ok() {
-        let qry = query(json!({"from": "t", "where": {"<=": [{"key": "age"}, 28]}}));
-        let val = json!([
-            {"name": "ania", "age": 28, "job": "english teacher"},
-            {"name": "misha", "age": 10},
-            {"name": "ania", "age": 20},
-        ]);
-        assert_eq!(Ok(val), qry);
+    fn select_all_sort_by_time() {
+        let qry = query(json!({
+            "from": "orders",
+            "sortBy": "time",
+        }));
+        assert_eq!(
+            Ok(json!([
+                    { "time": 0, "customer": "james", "qty": 2, "price": 9.0, "discount": 10 },
+                    { "time": 1, "customer": "ania", "qty": 2, "price": 2.0 },
+                    { "time": 2, "customer": "misha", "qty": 4, "price": 1.0 },
+                    { "time": 3, "customer": "james", "qty": 10, "price": 16.0, "discount": 20 },
+                    { "time": 4, "customer": "james", "qty": 1, "price": 16.0 },
+            ])),
+            qry
+        );
     }
 
     #[test]
-    fn select_all_where_gte_query_ok() {
-        let qry = query(json!({"from": "t", "where": {">=": [{"key":"age"}, 28]}}));
-        let val = json!([
-            {"name": "james", "age": 35},
-            {"name": "ania", "age": 28, "job": "english teacher"}
-        ]);
-
-        assert_eq!(Ok(val), qry);
+    fn select_all_sort_by_time_desc() {
+        let qry = query(json!({
+            "from": "orders",
+            "sort": "time",
+            "descend": true,
+        }));
+        assert_eq!(
+            Ok(json!([
+                    { "time": 4, "customer": "james", "qty": 1, "price": 16.0 },
+                    { "time": 3, "customer": "james", "qty": 10, "price": 16.0, "discount": 20 },
+                    { "time": 2, "customer": "misha", "qty": 4, "price": 1.0 },
+                    { "time": 1, "customer": "ania", "qty": 2, "price": 2.0 },
+                    { "time": 0, "customer": "james", "qty": 2, "price": 9.0, "discount": 10 },
+            ])),
+            qry
+        );
     }
 
     #[test]
-    fn select_all_where_and_gate_ok() {
+    fn select_all_sort_by_price() {
         let qry = query(json!({
-            "from": "t",
-            "where": {"&&": [
-                {">": [{"key": "age"}, 20]},
-                {"==": [{"key":"name"}, "ania"]}
-            ]}
+            "from": "orders",
+            "sort": "price",
         }));
-        let val = json!([{"name": "ania", "age": 28, "job": "english teacher"}]);
-        assert_eq!(Ok(val), qry);
+        assert_eq!(
+            Ok(json!([
+                    { "time": 2, "customer": "misha", "qty": 4, "price": 1.0 },
+                    { "time": 1, "customer": "ania", "qty": 2, "price": 2.0 },
+                    { "time": 0, "customer": "james", "qty": 2, "price": 9.0, "discount": 10 },
+                    { "time": 3, "customer": "james", "qty": 10, "price": 16.0, "discount": 20 },
+                    { "time": 4, "customer": "james", "qty": 1, "price": 16.0 },
+            ])),
+            qry
+        );
     }
 
     #[test]
-    fn select_sum_ok() {
+    fn select_all_sort_by_discount() {
         let qry = query(json!({
-            "select": {"totalAge": {"sum": {"key": "age"}}},
-            "from": "t"
+            "from": "orders",
+            "sort": "discount",
         }));
-        let val = json!({"totalAge": 93});
-        assert_eq!(Ok(val), qry);
+        assert_eq!(
+            Ok(json!([
+                    { "time": 0, "customer": "james", "qty": 2, "price": 9.0, "discount": 10 },
+                    { "time": 3, "customer": "james", "qty": 10, "price": 16.0, "discount": 20 },
+                    { "time": 1, "customer": "ania", "qty": 2, "price": 2.0 },
+                    { "time": 2, "customer": "misha", "qty": 4, "price": 1.0 },
+                    { "time": 4, "customer": "james", "qty": 1, "price": 16.0 },
+            ])),
+            qry
+        );
     }
 
     #[test]
-    fn select_max_num_ok() {
+    fn select_from_orders_where_key_eq_discount() {
         let qry = query(json!({
-            "select": {
-                "maxAge": {"max": {"key": "age"}}
-            },
-            "from": "t"
+            "from": "orders",
+            "where": {"has": "discount"},
         }));
-        let val = json!({"maxAge": 35});
-        assert_eq!(Ok(val), qry);
+        assert_eq!(
+            Ok(json!([
+                { "time": 0, "customer": "james", "qty": 2, "price": 9.0, "discount": 10 },
+                { "time": 3, "customer": "james", "qty": 10, "price": 16.0, "discount": 20 },
+            ])),
+            qry
+        );
     }
 
     #[test]
-    fn select_max_str_ok() {
+    fn select_sum_qty_from_subquery() {
         let qry = query(json!({
-            "select": {
-                "maxName": {"max":  {"key": "name"}}
-            },
-            "from": "t"
+            "select": {"totalQty": {"sum": {"key": "qty"}}},
+            "from": {"from": "orders", "where": {"==": [{"key": "customer"}, "james"]}},
         }));
-        let val = json!({"maxName": "misha"});
-        assert_eq!(Ok(val), qry);
+        assert_eq!(Ok(json!({"totalQty": 13})), qry);
     }
 
     #[test]
-    fn select_min_num_ok() {
-        let qry = query(json!({
-            "select": {
-                "minAge": {"min": {"key": "age"}}
-            },
-            "from": "t"
+    fn select_from_subquery_not_rows() {
+        let qry = query(json!({
+            "from": {"select": {"n": {"len": {"key": "qty"}}}, "from": "orders"},
         }));
-        let val = json!({"minAge": 10});
-        assert_eq!(Ok(val), qry);
+        assert_eq!(Err(Error::BadFrom), qry);
     }
 
     #[test]
-    fn select_avg_num_ok() {
+    fn select_counts_from_orders() {
         let qry = query(json!({
             "select": {
-                "avgAge": {"avg": {"key": "age"}}
+                "n": {"count": "*"},
+                "discounted": {"count": {"key": "discount"}},
+                "customers": {"count_distinct": {"key": "customer"}},
             },
-            "from": "t"
+            "from": "orders",
         }));
-        assert_eq!(Ok(json!({"avgAge": 23.25})), qry);
+        assert_eq!(Ok(json!({"n": 5, "discounted": 2, "customers": 3})), qry);
     }
 
     #[test]
-    fn select_first_ok() {
+    fn select_heavy_hitters_from_orders() {
         let qry = query(json!({
-            "select": {
-                "firstAge": {"first": {"key": "age"}}
-            },
-            "from": "t"
+            "select": {"top": {"heavy_hitters": {"key": "customer", "k": 1}}},
+            "from": "orders",
         }));
-        assert_eq!(Ok(json!({"firstAge": 35})), qry);
+        let exp = json!({"top": [{"val": "james", "count": 3, "error": 0}]});
+        assert_eq!(Ok(exp), qry);
     }
 
     #[test]
-    fn select_last_ok() {
+    fn select_collect_and_join_by_customer_from_orders() {
         let qry = query(json!({
             "select": {
-                "lastAge": {"last": {"key": "age"}}
+                "qtys": {"collect": {"key": "qty"}},
+                "prices": {"join": [{"key": "price"}, ","]},
             },
-            "from": "t"
+            "by": {"key": "customer"},
+            "from": "orders",
         }));
-        assert_eq!(Ok(json!({"lastAge": 20})), qry);
+        let exp = json!({
+            "james": {"qtys": [2, 10, 1], "prices": "9.0,16.0,16.0"},
+            "ania": {"qtys": [2], "prices": "2.0"},
+            "misha": {"qtys": [4], "prices": "1.0"},
+        });
+        assert_eq!(Ok(exp), qry);
     }
 
     #[test]
-    fn select_var_num_ok() {
+    fn select_name_from_people_where_nested_city() {
         let qry = query(json!({
-            "select": {
-                "varAge": {"var": {"key": "age"}}
-            },
-            "from": "t"
+            "select": {"name": {"key": "name"}, "city": {"key": "address.city"}},
+            "from": "people",
+            "where": {"==": [{"key": "address.city"}, "London"]},
         }));
-        assert_eq!(Ok(json!({"varAge": 146.75})), qry);
+        let exp = json!({"name": ["james", "misha"], "city": ["London", "London"]});
+        assert_eq!(Ok(exp), qry);
     }
 
     #[test]
-    fn select_dev_num_ok() {
+    fn select_max_age_by_nested_city_from_people() {
         let qry = query(json!({
-            "select": {
-                "devAge": {"dev": {"key": "age"}}
-            },
-            "from": "t"
+            "select": {"age": {"max": {"key": "age"}}},
+            "by": {"key": "address.city"},
+            "from": "people",
         }));
-        assert_eq!(Ok(json!({"devAge": 9.310612224768036})), qry);
+        let exp = json!({"London": {"age": 35}, "Paris": {"age": 28}});
+        assert_eq!(Ok(exp), qry);
     }
 
     #[test]
-    fn select_max_age_where_age_gt_20() {
+    fn select_id_from_events_sorted_with_limit() {
         let qry = query(json!({
-            "select": {
-                "maxAge": {"max": {"key": "age"}}
-            },
-            "from": "t",
-            "where": {">": [{"key":"age"}, 20]}
+            "select": {"id": {"key": "_id"}},
+            "from": "events",
+            "sort": "time",
+            "limit": 3,
         }));
-        assert_eq!(Ok(json!({"maxAge": 35})), qry);
+        assert_eq!(Ok(json!({"id": [2, 1, 3]})), qry);
     }
 
     #[test]
-    fn select_get_age_where_age_gt_20() {
+    fn select_id_from_events_sorted_with_offset() {
         let qry = query(json!({
-            "select": {
-                "age": {"key": "age"}
-            },
-            "from": "t",
-            "where": {">": [{"key":"age"}, 20]}
+            "select": {"id": {"key": "_id"}},
+            "from": "events",
+            "sort": "time",
+            "offset": 1,
+            "limit": 2,
         }));
-        assert_eq!(Ok(json!({"age":[35, 28]})), qry);
+        assert_eq!(Ok(json!({"id": [1, 3]})), qry);
+        let qry = query(json!({
+            "select": {"id": {"key": "_id"}},
+            "from": "events",
+            "sort": "time",
+            "offset": -2,
+        }));
+        assert_eq!(Ok(json!({"id": [4, 5]})), qry);
     }
 
     #[test]
-    fn select_min_age_where_age_gt_20() {
+    fn select_id_from_events_sorted_after_last_row() {
         let qry = query(json!({
-            "select": {
-                "minAge": {"min":  {"key": "age"}}
-            },
-            "from": "t",
-            "where": {">": [{"key":"age"}, 20]}
+            "select": {"id": {"key": "_id"}},
+            "from": "events",
+            "sort": "time",
+            "after": {"time": 1, "_id": 3},
+            "limit": 3,
         }));
-        assert_eq!(Ok(json!({"minAge": 28})), qry);
+        assert_eq!(Ok(json!({"id": [4, 5]})), qry);
     }
 
     #[test]
-    fn select_min_max_age_where_age_gt_20() {
+    fn select_id_from_events_descending_after_last_row() {
         let qry = query(json!({
-            "select": {
-                "youngestAge": {"min": {"key": "age"}},
-                "oldestAge": {"max": {"key": "age"}},
-            },
-            "from": "t",
-            "where": {">": [{"key":"age"}, 20]}
+            "select": {"id": {"key": "_id"}},
+            "from": "events",
+            "sort": "time",
+            "descend": true,
+            "after": {"time": 1, "_id": 1},
         }));
-        assert_eq!(Ok(json!({"youngestAge": 28, "oldestAge": 35})), qry);
+        assert_eq!(Ok(json!({"id": [3, 4, 2]})), qry);
     }
 
     #[test]
-    fn select_empty_obj() {
+    fn select_all_from_events_after_id() {
+        let qry = query(json!({"from": "events", "after": {"_id": 3}}));
+        let exp = json!([
+            { "_id": 4, "time": 1, "kind": "buy" },
+            { "_id": 5, "time": 2, "kind": "click" },
+        ]);
+        assert_eq!(Ok(exp), qry);
+        // pages cut by a limit follow the ids, not the order the rows were inserted in
+        let page = |after: Json| {
+            let qry = query(json!({"from": "events", "after": after, "limit": 1}));
+            qry.unwrap()[0]["_id"].clone()
+        };
+        assert_eq!(json!(4), page(json!({"_id": 3})));
+        assert_eq!(json!(5), page(json!({"_id": 4})));
+        let qry = query(json!({"select": {"id": {"key": "_id"}}, "from": "events", "limit": 2}));
+        assert_eq!(Ok(json!({"id": [1, 2]})), qry);
+    }
+
+    #[test]
+    fn select_name_from_people_where_name_like() {
         let qry = query(json!({
-            "select": {},
-            "from": "t",
+            "select": {"name": {"key": "name"}},
+            "from": "people",
+            "where": {"like": [{"key": "name"}, "an%"]},
         }));
-        assert_eq!(
-            Ok(json!([
-                {"name": "james", "age": 35},
-                {"name": "ania", "age": 28, "job": "english teacher"},
-                {"name": "misha", "age": 10},
-                {"name": "ania", "age": 20},
-            ])),
-            qry
-        );
+        assert_eq!(Ok(json!({"name": ["ania", "anna"]})), qry);
     }
 
     #[test]
-    fn select_empty_obj_where_age_gt_20() {
+    fn select_name_from_people_where_city_regex() {
         let qry = query(json!({
-            "select": {},
-            "from": "t",
-            "where": {">": [{"key":"age"}, 20]}
+            "select": {"name": {"key": "name"}},
+            "from": "people",
+            "where": {"regex": [{"key": "address.city"}, "^L"]},
         }));
-        assert_eq!(
-            Ok(json!([
-                {"name": "james", "age": 35},
-                {"name": "ania", "age": 28, "job": "english teacher"},
-            ])),
-            qry
-        );
+        assert_eq!(Ok(json!({"name": ["james", "misha"]})), qry);
     }
 
     #[test]
-    fn select_age_by_name() {
+    fn select_name_from_people_where_age_between() {
         let qry = query(json!({
-            "select": {
-                "age": {"key": "age"},
-            },
-            "from": "t",
-            "by": {"key": "name"},
+            "select": {"name": {"key": "name"}},
+            "from": "people",
+            "where": {"between": [{"key": "age"}, 20, 35]},
         }));
-        assert_eq!(
-            Ok(json!({
-                "misha": {"age": [10]},
-                "ania": {"age": [28, 20]},
-                "james": {"age":[35]},
-            })),
-            qry
-        );
+        assert_eq!(Ok(json!({"name": ["james", "ania"]})), qry);
     }
 
     #[test]
-    fn select_first_age_by_name() {
+    fn select_name_from_people_where_has_and_is_null() {
         let qry = query(json!({
-            "select": {
-                "age": {"first": {"key": "age"}},
-            },
-            "from": "t",
-            "by": {"key": "name"},
+            "select": {"name": {"key": "name"}},
+            "from": "people",
+            "where": {"has": "address.zip"},
         }));
-        assert_eq!(
-            Ok(json!({
-                "misha": {"age": 10},
-                "ania": {"age": 28},
-                "james": {"age": 35},
-            })),
-            qry
-        );
+        assert_eq!(Ok(json!({"name": ["james"]})), qry);
+        let qry = query(json!({
+            "select": {"name": {"key": "name"}},
+            "from": "people",
+            "where": {"is_null": "address"},
+        }));
+        assert_eq!(Ok(json!({"name": ["anna"]})), qry);
     }
 
     #[test]
-    fn select_last_age_by_name() {
+    fn select_qty_from_orders_where_discount_is_null() {
         let qry = query(json!({
-            "select": {
-                "age": {"last": {"key":"age"}},
-            },
-            "from": "t",
-            "by": {"key": "name"},
+            "select": {"qty": {"key": "qty"}},
+            "from": "orders",
+            "where": {"is_null": "discount"},
         }));
-        assert_eq!(
-            Ok(json!({
-                "misha":{"age": 10},
-                "ania":{"age": 20},
-                "james":{"age": 35},
-            })),
-            qry
-        );
+        assert_eq!(Ok(json!({"qty": [2, 4, 1]})), qry);
     }
 
     #[test]
-    fn select_count_age_by_name() {
+    fn post_cmds_shape_query_result() {
         let qry = query(json!({
-            "select": {
-                "age": {"len": {"key": "age"}},
-            },
-            "from": "t",
-            "by": {"key": "name"},
+            "from": "orders",
+            "where": {">": [{"key": "qty"}, 1]},
+            "post": [
+                {"get": ["customer", "$result"]},
+                {"unique": "$result"},
+                {"sort": ["$result", true]},
+                {"str": "$result"},
+            ],
         }));
+        assert_eq!(Ok(json!("[\"misha\",\"james\",\"ania\"]")), qry);
+        let qry = query(json!({
+            "select": {"qty": {"sum": {"key": "qty"}}},
+            "from": "orders",
+            "post": [{"get": ["qty", "$result"]}, {"*": ["$result", 2]}],
+        }));
+        assert_eq!(Ok(json!(38)), qry);
+        let bad = query(json!({"from": "orders", "post": [{"pop": "orders"}]}));
+        assert_eq!(Err(Error::BadCmd), bad);
+    }
+
+    #[test]
+    fn explain_grouped_query_from_subquery() {
+        let cmd = Cmd::parse(json!({"explain": {
+            "select": {"n": {"count": "*"}},
+            "by": {"key": "customer"},
+            "from": {"from": "orders", "where": {">": [{"key": "qty"}, 1]}},
+            "sort": "time",
+        }}))
+        .unwrap();
+        let exp = json!({
+            "from": {"query": {
+                "from": "orders",
+                "unnest": null,
+                "where": {">": [{"key": "qty"}, 1]},
+                "sort": null,
+                "after": null,
+                "limit": null,
+                "defaultLimit": null,
+                "path": "select_all",
+                "index": false,
+                "plan": [
+                    {"stage": "scan", "from": "orders"},
+                    {"stage": "filter", "index": false},
+                    {"stage": "selectAll", "limit": null},
+                ],
+                "rowsScanned": 5,
+                "warnings": [],
+            }},
+            "unnest": null,
+            "where": null,
+            "sort": {"key": "time", "descend": false},
+            "after": null,
+            "limit": null,
+            "defaultLimit": 50,
+            "path": "group_by",
+            "index": false,
+            "plan": [
+                {"stage": "scan", "from": "query"},
+                {"stage": "sort", "key": "time", "descend": false},
+                {"stage": "project", "fields": ["customer"]},
+                {"stage": "group", "selects": ["n"]},
+            ],
+            "rowsScanned": 5,
+            "warnings": [],
+        });
+        assert_eq!(Ok(exp), eval(cmd));
+    }
+
+    #[test]
+    fn select_all_capped_at_default_limit() {
+        let mut db = test_db();
+        let rows: Vec<Json> = (0..60).map(|i| json!({ "i": i })).collect();
+        db.set("big", Json::from(rows));
+        let count = |db: &InMemDb, qry: Json| {
+            let cmd: QueryCmd = serde_json::from_value(qry).unwrap();
+            match Query::from(db, cmd).exec().unwrap() {
+                Json::Array(rows) => rows.len(),
+                val => panic!("expected rows: {}", val),
+            }
+        };
+        assert_eq!(DEFAULT_LIMIT, count(&db, json!({"from": "big"})));
+        assert_eq!(55, count(&db, json!({"from": "big", "limit": 55})));
         assert_eq!(
-            Ok(json!({
-                "misha": {"age": 1},
-                "ania": {"age": 2},
-                "james": {"age": 1},
-            })),
-            qry
+            60,
+            count(&db, json!({"from": {"from": "big"}, "limit": 100}))
         );
+        db.set_default_limit(None);
+        assert_eq!(60, count(&db, json!({"from": "big"})));
+        db.set_default_limit(Some(10));
+        assert_eq!(10, count(&db, json!({"from": "big"})));
     }
 
     #[test]
-    fn select_min_age_by_name() {
-        let qry = query(json!({
-            "select": {
-                "age": {"min": {"key": "age"}},
-            },
-            "from": "t",
-            "by": {"key":"name"},
-        }));
+    fn estimate_from_sampled_scan() {
+        let mut db = test_db();
+        db.set_default_limit(None);
+        let rows: Vec<Json> = (0..10_000).map(|i| json!({ "i": i, "g": i % 3 })).collect();
+        db.set("big", Json::from(rows));
+        let estimate = |db: &InMemDb, qry: Json| {
+            let cmd: QueryCmd = serde_json::from_value(qry).unwrap();
+            Query::from(db, cmd).estimate().unwrap()
+        };
+        let qry = json!({"from": "big", "where": {"<": [{"key": "i"}, 2500]}});
+        let est = estimate(&db, qry.clone());
+        assert_eq!(json!("sample"), est["method"]);
+        assert_eq!(json!(ESTIMATE_SAMPLE), est["sampled"]);
+        assert_eq!(json!(2500), est["rows"]);
+        let cmd: QueryCmd = serde_json::from_value(qry).unwrap();
+        let exact = json_size(&Query::from(&db, cmd).exec().unwrap()) as f64;
+        assert!((est["bytes"].as_f64().unwrap() - exact).abs() / exact < 0.1);
+        let by = estimate(
+            &db,
+            json!({"select": {"n": {"count": "*"}}, "by": {"key": "g"}, "from": "big"}),
+        );
+        assert_eq!(json!(3), by["rows"]);
+        let agg = estimate(&db, json!({"select": {"n": {"count": "*"}}, "from": "big"}));
+        assert_eq!(json!(1), agg["rows"]);
+        let limit = estimate(&db, json!({"from": {"from": "big"}, "limit": 10}));
+        assert_eq!(json!("subquery"), limit["method"]);
+        assert_eq!(json!(10), limit["rows"]);
+        let small = estimate(
+            &db,
+            json!({"from": "orders", "where": {">": [{"key": "qty"}, 2]}}),
+        );
+        assert_eq!(json!("scan"), small["method"]);
+        assert_eq!(json!(2), small["rows"]);
+    }
+
+    #[test]
+    fn estimate_filter_from_index_histogram() {
+        let mut db = test_db();
+        db.create_index("orders", "qty").unwrap();
+        let cmd = json!({"estimate": {"from": "orders", "where": {"==": [{"key": "qty"}, 2]}}});
+        let est = db.eval(Cmd::parse(cmd).unwrap()).unwrap();
+        assert_eq!(json!("index"), est["method"]);
+        assert_eq!(json!(2), est["rows"]);
+    }
+
+    #[test]
+    fn indexed_where_matches_scan() {
+        let filters = vec![
+            json!({"==": [{"key": "customer"}, "james"]}),
+            json!({">": [{"key": "qty"}, 2]}),
+            json!({">=": [{"key": "qty"}, 2]}),
+            json!({"<": [{"key": "qty"}, 4]}),
+            json!({"<=": [4, {"key": "qty"}]}),
+            json!({"==": [{"key": "discount"}, null]}),
+        ];
+        let mut db = test_db();
+        let scans: Vec<Result<Json, Error>> = filters
+            .iter()
+            .map(|filter| query(json!({"from": "orders", "where": filter})))
+            .collect();
+        for field in &["customer", "qty", "discount"] {
+            let cmd = json!({"createIndex": {"key": "orders", "field": field}});
+            assert_eq!(Ok(Json::Null), db.eval(Cmd::parse(cmd).unwrap()));
+        }
+        for (filter, scan) in filters.into_iter().zip(scans) {
+            let cmd = json!({"query": {"from": "orders", "where": filter}});
+            assert_eq!(scan, db.eval(Cmd::parse(cmd).unwrap()));
+        }
+        let cmd = json!({"explain": {"from": "orders", "where": {">": [{"key": "qty"}, 2]}}});
         assert_eq!(
-            Ok(json!({
-                "misha":{"age": 10},
-                "ania":{"age": 20},
-                "james":{"age": 35},
-            })),
-            qry
+            json!(true),
+            db.eval(Cmd::parse(cmd).unwrap()).unwrap()["index"]
         );
     }
 
     #[test]
-    fn select_max_age_by_name() {
-        let qry = query(json!({
+    fn index_kept_up_to_date_on_writes() {
+        let mut db = test_db();
+        let mut eval = |cmd: Json| db.eval(Cmd::parse(cmd).unwrap());
+        eval(json!({"createIndex": {"key": "orders", "field": "qty"}})).unwrap();
+        let qry = json!({"query": {"select": {"t": {"key": "time"}}, "from": "orders", "where": {"==": [{"key": "qty"}, 2]}}});
+        assert_eq!(Ok(json!({"t": [0, 1]})), eval(qry.clone()));
+        eval(json!({"insert": ["orders", [{"time": 5, "qty": 2}]]})).unwrap();
+        eval(json!({"append": ["orders", {"time": 6, "qty": 2}]})).unwrap();
+        assert_eq!(Ok(json!({"t": [0, 1, 5, 6]})), eval(qry.clone()));
+        eval(json!({"pop": "orders"})).unwrap();
+        assert_eq!(Ok(json!({"t": [0, 1, 5]})), eval(qry.clone()));
+        eval(json!({"set": ["orders", [{"time": 7, "qty": 2}]]})).unwrap();
+        assert_eq!(Ok(json!({"t": [7]})), eval(qry.clone()));
+        eval(json!({"del": "orders"})).unwrap();
+        let missing = json!({"createIndex": {"key": "orders", "field": "qty"}});
+        assert_eq!(Err(Error::BadKey("orders".to_string())), eval(missing));
+    }
+
+    #[test]
+    fn batch_evals_in_order() {
+        let mut db = test_db();
+        let cmd = Cmd::parse(json!({"batch": [
+            {"set": ["k", [1, 2]]},
+            {"append": ["k", 3]},
+            {"key": "missing"},
+            {"sum": {"key": "k"}},
+        ]}));
+        let exp = json!([null, null, "bad key: missing", 6]);
+        assert_eq!(Ok(exp), db.eval(cmd.unwrap()));
+    }
+
+    #[test]
+    fn set_if_absent_or_present() {
+        let mut db = test_db();
+        let mut eval = |cmd: Json| db.eval(Cmd::parse(cmd).unwrap());
+        assert_eq!(Ok(json!(false)), eval(json!({"set": ["i", 1, "nx"]})));
+        assert_eq!(Ok(json!(false)), eval(json!({"set": ["k", 1, "xx"]})));
+        assert_eq!(Ok(json!(false)), eval(json!({"has": "k"})));
+        assert_eq!(Ok(json!(true)), eval(json!({"set": ["k", 1, "nx"]})));
+        assert_eq!(Ok(json!(true)), eval(json!({"set": ["i", 3, "xx"]})));
+        assert_eq!(Ok(json!(3)), eval(json!({"getSet": ["i", 4]})));
+        assert_eq!(Ok(json!(null)), eval(json!({"getSet": ["j", 5]})));
+        let keys = json!({"batch": [{"key": "i"}, {"key": "k"}, {"key": "j"}]});
+        assert_eq!(Ok(json!([4, 1, 5])), eval(keys));
+    }
+
+    #[test]
+    fn merge_patches_documents() {
+        let mut db = test_db();
+        db.create_index("people", "name").unwrap();
+        let mut eval = |cmd: Json| db.eval(Cmd::parse(cmd).unwrap());
+        let doc = json!({"name": "anna", "address": {"city": "London", "zip": "N1"}});
+        assert_eq!(Ok(doc.clone()), eval(json!({"merge": ["doc", doc]})));
+        let patch = json!({"address": {"zip": null, "country": "UK"}, "age": 28});
+        let exp =
+            json!({"name": "anna", "address": {"city": "London", "country": "UK"}, "age": 28});
+        assert_eq!(Ok(exp.clone()), eval(json!({"merge": ["doc", patch]})));
+        assert_eq!(Ok(exp), eval(json!({"key": "doc"})));
+        assert_eq!(Ok(json!(7)), eval(json!({"merge": ["doc", 7]})));
+        let rows = json!([{"name": "misha"}]);
+        eval(json!({"merge": ["people", rows]})).unwrap();
+        let qry = json!({"query": {"from": "people", "where": {"==": [{"key": "name"}, "misha"]}}});
+        assert_eq!(Ok(json!([{"name": "misha"}])), eval(qry));
+    }
+
+    #[test]
+    fn rows_moved_between_tables() {
+        let dir = TempDir::new("move");
+        let ondisk_db = OnDiskDb::open(&dir).unwrap();
+        let orders = json!([
+            {"id": 1, "status": "done"},
+            {"id": 2, "status": "open"},
+            {"id": 3, "status": "done"},
+        ]);
+        ondisk_db.set("active", &orders).unwrap();
+        let archived = json!([{"id": 0, "status": "done"}]);
+        ondisk_db.set("archived", &archived).unwrap();
+        ondisk_db.set("n", &json!(1)).unwrap();
+        let mut memson = Memson::from_disk(ondisk_db).unwrap();
+        let mut eval = |cmd: Json| memson.eval(Cmd::parse(cmd).unwrap());
+        eval(json!({"createIndex": {"key": "archived", "field": "id"}})).unwrap();
+        let done = json!({"==": [{"key": "status"}, "done"]});
+        let mv = json!({"moveRows": {"from": "active", "to": "archived", "where": done}});
+        assert_eq!(Ok(json!(2)), eval(mv.clone()));
+        assert_eq!(Ok(json!(0)), eval(mv));
+        let qry = json!({"query": {
+            "select": {"id": {"key": "id"}},
+            "from": "archived",
+            "where": {">": [{"key": "id"}, 0]},
+        }});
+        assert_eq!(Ok(json!({"id": [1, 3]})), eval(qry));
+        let open_rows = json!({"==": [{"key": "status"}, "open"]});
+        let mv = json!({"moveRows": {"from": "active", "to": "new", "where": open_rows}});
+        assert_eq!(Ok(json!(1)), eval(mv));
+        let mv = json!({"moveRows": {"from": "archived", "to": "n", "where": true}});
+        assert_eq!(Err(Error::ExpectedArr), eval(mv));
+        let saved = |key: &str| memson.disk_db.get(key).unwrap();
+        assert_eq!(Some(json!([])), saved("active"));
+        assert_eq!(3, saved("archived").unwrap().as_array().unwrap().len());
+        assert_eq!(Some(json!([{"id": 2, "status": "open"}])), saved("new"));
+    }
+
+    #[test]
+    fn diff_of_keys_and_values() {
+        let mut db = test_db();
+        let mut eval = |cmd: Json| db.eval(Cmd::parse(cmd).unwrap());
+        eval(json!({"set": ["v1", {"name": "anna", "tags": ["a"]}]})).unwrap();
+        eval(json!({"set": ["v2", {"name": "ania", "tags": ["a", "b"]}]})).unwrap();
+        let exp = json!([
+            {"op": "replace", "path": "/name", "value": "ania"},
+            {"op": "add", "path": "/tags/1", "value": "b"},
+        ]);
+        let diff = json!({"diff": [{"key": "v1"}, {"key": "v2"}]});
+        assert_eq!(Ok(exp), eval(diff));
+        let exp = json!([{"op": "replace", "path": "", "value": 2}]);
+        assert_eq!(Ok(exp), eval(json!({"diff": [1, 2]})));
+    }
+
+    #[test]
+    fn script_binds_results_to_later_cmds() {
+        let mut db = test_db();
+        let mut eval = |cmd: Json| db.eval(Cmd::parse(cmd).unwrap());
+        let script = json!({"script": [
+            {"let": "most", "cmd": {"max": {"key": "ia"}}},
+            {"let": "who", "cmd": ["james", "$most"]},
+            {"set": ["best", "$who"]},
+            {"query": {
+                "from": "orders",
+                "select": {"n": {"count": {"key": "qty"}}},
+                "where": {">": [{"key": "qty"}, "$most"]},
+            }},
+        ]});
+        assert_eq!(Ok(json!({"n": 1})), eval(script));
+        assert_eq!(Ok(json!(["james", 5])), eval(json!({"key": "best"})));
+        let failing = json!({"script": [
+            {"let": "x", "cmd": {"pop": "ia"}},
+            {"set": ["best", "$x"]},
+            {"append": ["missing", "$x"]},
+        ]});
+        assert!(eval(failing).is_err());
+        assert_eq!(Ok(json!(["james", 5])), eval(json!({"key": "best"})));
+        assert_eq!(Ok(json!([1, 2, 3, 4, 5])), eval(json!({"key": "ia"})));
+    }
+
+    #[test]
+    fn tx_commits_all_or_nothing() {
+        let mut db = test_db();
+        db.create_index("orders", "qty").unwrap();
+        let mut eval = |cmd: Json| db.eval(Cmd::parse(cmd).unwrap());
+        let tx = json!({"tx": [
+            {"append": ["ia", 6]},
+            {"del": "orders"},
+            {"set": ["k", 1]},
+            {"key": "missing"},
+        ]});
+        assert_eq!(Err(Error::BadKey("missing".to_string())), eval(tx));
+        assert_eq!(Ok(json!([1, 2, 3, 4, 5])), eval(json!({"key": "ia"})));
+        assert_eq!(Ok(json!(false)), eval(json!({"has": "k"})));
+        let qry = json!({"query": {"select": {"t": {"key": "time"}}, "from": "orders", "where": {"==": [{"key": "qty"}, 2]}}});
+        assert_eq!(Ok(json!({"t": [0, 1]})), eval(qry.clone()));
+        let tx = json!({"tx": [{"append": ["ia", 6]}, {"set": ["k", 1]}, {"sum": {"key": "ia"}}]});
+        assert_eq!(Ok(json!([null, null, 21])), eval(tx));
+        assert_eq!(Ok(json!(1)), eval(json!({"key": "k"})));
+        let tx = json!({"tx": [{"delPattern": "i*"}, {"append": ["missing", 1]}]});
+        assert!(eval(tx).is_err());
+        assert_eq!(Ok(json!([1, 2, 3, 4, 5, 6])), eval(json!({"key": "ia"})));
+        let script = json!({"script": [{"delPattern": "k*"}, {"append": ["missing", 1]}]});
+        assert!(eval(script).is_err());
+        assert_eq!(Ok(json!(1)), eval(json!({"key": "k"})));
+    }
+
+    #[test]
+    fn tx_deletions_by_pattern_saved() {
+        let path = TempDir::new("tx");
+        let mut memson = Memson::open(&path).unwrap();
+        let mut eval = |cmd: Json| memson.eval(Cmd::parse(cmd).unwrap());
+        eval(json!({"set": ["t1", 1]})).unwrap();
+        eval(json!({"set": ["t2", 2]})).unwrap();
+        eval(json!({"tx": [{"delPattern": "t*"}, {"set": ["u", 3]}]})).unwrap();
+        assert_eq!(Ok(None), memson.disk_db.get("t1"));
+        assert_eq!(Ok(None), memson.disk_db.get("t2"));
+        assert_eq!(Ok(Some(json!(3))), memson.disk_db.get("u"));
+    }
+
+    #[test]
+    fn subscribers_replay_unacked_changes() {
+        let mut db = test_db();
+        let mut eval = |cmd: Json| db.eval(Cmd::parse(cmd).unwrap());
+        let id = eval(json!({"subscribe": ["ia", "k"]})).unwrap();
+        eval(json!({"append": ["ia", 6]})).unwrap();
+        eval(json!({"set": ["x", 1]})).unwrap();
+        eval(json!({"tx": [{"set": ["k", 1]}, {"key": "missing"}]})).unwrap_err();
+        eval(json!({"batch": [{"set": ["k", 2]}, {"del": "k"}]})).unwrap();
+        let exp = json!({"events": [
+            {"seq": 1, "key": "ia", "val": [1, 2, 3, 4, 5, 6]},
+            {"seq": 2, "key": "k", "val": 2},
+            {"seq": 3, "key": "k", "val": null},
+        ], "missed": 0});
+        assert_eq!(Ok(exp), eval(json!({ "events": id })));
+        eval(json!({"ack": [id, 2]})).unwrap();
+        let exp = json!({"events": [{"seq": 3, "key": "k", "val": null}], "missed": 0});
+        assert_eq!(Ok(exp), eval(json!({ "events": id })));
+        eval(json!({ "unsubscribe": id })).unwrap();
+        let bad = Err(Error::BadSubscriber(id.as_u64().unwrap()));
+        assert_eq!(bad, eval(json!({ "events": id })));
+    }
+
+    #[test]
+    fn filtered_subscribers_get_appended_rows() {
+        let mut db = test_db();
+        let mut eval = |cmd: Json| db.eval(Cmd::parse(cmd).unwrap());
+        let sub = json!({"subscribe": {"keys": ["orders"], "where": {">": [{"key": "qty"}, 5]}}});
+        let id = eval(sub).unwrap();
+        let rows = json!([{"time": 5, "qty": 6}, {"time": 6, "qty": 1}]);
+        eval(json!({"insert": ["orders", rows]})).unwrap();
+        eval(json!({"append": ["orders", {"time": 7, "qty": 2}]})).unwrap();
+        let exp = json!({"events": [
+            {"seq": 1, "key": "orders", "val": [{"time": 5, "qty": 6}]},
+        ], "missed": 0});
+        assert_eq!(Ok(exp), eval(json!({ "events": id })));
+    }
+
+    #[test]
+    fn query_subscribers_get_changed_results() {
+        let mut db = test_db();
+        let mut eval = |cmd: Json| db.eval(Cmd::parse(cmd).unwrap());
+        let qry = json!({"select": {"q": {"max": {"key": "qty"}}}, "from": "orders"});
+        let res = eval(json!({ "subscribeQuery": qry })).unwrap();
+        assert_eq!(json!({"q": 10}), res["val"]);
+        eval(json!({"append": ["orders", {"time": 5, "qty": 3}]})).unwrap();
+        eval(json!({"append": ["orders", {"time": 6, "qty": 12}]})).unwrap();
+        eval(json!({"set": ["x", 1]})).unwrap();
+        let exp = json!({"events": [
+            {"seq": 3, "key": "orders", "val": {"q": 12}},
+        ], "missed": 0});
+        assert_eq!(Ok(exp), eval(json!({ "events": res["id"] })));
+    }
+
+    #[test]
+    fn compressed_values_decompressed_on_access() {
+        let mut db = test_db();
+        let len = db.len();
+        db.create_index("orders", "qty").unwrap();
+        let log: Vec<Json> = (0..20)
+            .map(|i| json!({ "level": if i % 3 == 0 { "warn" } else { "info" } }))
+            .collect();
+        db.set("log", Json::from(log.clone()));
+        let mut eval = |cmd: Json| db.eval(Cmd::parse(cmd).unwrap());
+        let res = eval(json!({"compress": 100})).unwrap();
+        assert!(res["keys"].as_u64().unwrap() > 0);
+        assert!(res["compressed"].as_u64() < res["bytes"].as_u64());
+        assert_eq!(Some(1), res["dictEncoded"].as_u64());
+        assert_eq!(Ok(Json::from(log)), eval(json!({"key": "log"})));
+        eval(json!({"del": "log"})).unwrap();
+        assert_eq!(Ok(json!(len)), eval(json!("len")));
+        assert_eq!(Ok(json!(true)), eval(json!({"has": "people"})));
+        assert_eq!(Ok(people_val()), eval(json!({"key": "people"})));
+        let qry = json!({"query": {"select": {"q": {"max": {"key": "qty"}}}, "from": "orders"}});
+        assert_eq!(Ok(json!({"q": 10})), eval(qry));
+        eval(json!({"compress": 1})).unwrap();
+        assert_eq!(Ok(json!(5)), eval(json!({"len": {"key": "s"}})));
+        let keys = eval(json!({"keys": null})).unwrap();
+        assert_eq!(len, keys.as_array().unwrap().len());
+        eval(json!({"compress": 1})).unwrap();
+        assert_eq!(Ok(json!("hello")), eval(json!({"set": ["s", "bye"]})));
+        assert!(db.invariant_violations(&BTreeSet::new()).is_empty());
+    }
+
+    #[test]
+    fn annotated_dates_and_decimals_queried() {
+        let mut db = test_db();
+        db.set(
+            "payments",
+            json!([
+                { "id": 1, "day": { "$date": "2021-03-04" }, "amount": { "$decimal": "0.10" } },
+                { "id": 2, "day": { "$date": "2021-01-15" }, "amount": { "$decimal": "0.20" } },
+                { "id": 3, "day": { "$date": "2021-02-01T12:00:00Z" }, "amount": 1 },
+            ]),
+        );
+        let qry = |qry: Json| Query::from(&db, serde_json::from_value(qry).unwrap()).exec();
+        let sorted = qry(json!({
+            "select": { "id": { "key": "id" } },
+            "from": "payments",
+            "where": { ">": [{ "key": "day" }, "2021-01-31"] },
+            "sort": "day",
+        }));
+        assert_eq!(Ok(json!({ "id": [3, 1] })), sorted);
+        let totals = qry(json!({
             "select": {
-                "age": {"max": {"key": "age"}},
+                "total": { "sum": { "key": "amount" } },
+                "max": { "max": { "key": "amount" } },
             },
-            "from": "t",
-            "by": {"key": "name"},
+            "from": "payments",
         }));
-        assert_eq!(
-            Ok(json!({
-                "misha":{"age": 10},
-                "ania":{"age": 28},
-                "james":{"age": 35},
-            })),
-            qry
-        );
+        let exp = json!({ "total": { "$decimal": "1.30" }, "max": 1 });
+        assert_eq!(Ok(exp), totals);
+        let matched = qry(json!({
+            "select": { "id": { "key": "id" } },
+            "from": "payments",
+            "where": { "==": [{ "key": "amount" }, 0.1] },
+        }));
+        assert_eq!(Ok(json!({ "id": [1] })), matched);
     }
 
     #[test]
-    fn select_name_by_age() {
-        let qry = query(json!({
-            "select": {
-                "name": {"key": "name"},
-            },
-            "from": "t",
-            "by": {"key": "age"},
-        }));
-        assert_eq!(
-            Ok(json!({
-                "10": {"name": ["misha"]},
-                "20": {"name": ["ania"]},
-                "28": {"name": ["ania"]},
-                "35": {"name": ["james"]},
-            })),
-            qry
+    fn unnest_line_items() {
+        let mut db = test_db();
+        db.set(
+            "baskets",
+            json!([
+                { "id": 1, "items": [{ "sku": "a", "qty": 2 }, { "sku": "b", "qty": 1 }] },
+                { "id": 2, "items": [] },
+                { "id": 3, "items": [{ "sku": "a", "qty": 5 }] },
+                { "id": 4 },
+            ]),
         );
+        let qry = |qry: Json| Query::from(&db, serde_json::from_value(qry).unwrap()).exec();
+        let exp = json!([
+            { "id": 1, "items": { "sku": "a", "qty": 2 } },
+            { "id": 1, "items": { "sku": "b", "qty": 1 } },
+            { "id": 3, "items": { "sku": "a", "qty": 5 } },
+        ]);
+        assert_eq!(Ok(exp), qry(json!({"from": "baskets", "unnest": "items"})));
+        let exp = json!({ "a": { "qty": 5 }, "b": { "qty": 1 } });
+        let grouped = json!({
+            "select": { "qty": { "max": { "key": "items.qty" } } },
+            "by": { "key": "items.sku" },
+            "from": "baskets",
+            "unnest": "items",
+        });
+        assert_eq!(Ok(exp), qry(grouped));
+        let filtered = json!({
+            "select": { "id": { "key": "id" } },
+            "from": "baskets",
+            "unnest": "items",
+            "where": { "==": [{ "key": "items.sku" }, "a"] },
+        });
+        assert_eq!(Ok(json!({ "id": [1, 3] })), qry(filtered));
     }
 
     #[test]
-    fn select_age_by_name_where_age_gt_20() {
-        let qry = query(json!({
-            "select": {
-                "age": {"key": "age"},
-            },
-            "from": "t",
-            "by": {"key": "name"},
-            "where": {">": [{"key": "age"}, 20]}
-        }));
-        assert_eq!(
-            Ok(json!({
-                "ania": {"age": [28]},
-                "james": {"age": [35]}
-            })),
-            qry
+    fn knn_nearest_embeddings() {
+        let mut db = test_db();
+        db.set(
+            "docs",
+            json!([
+                { "id": 1, "topic": "cats", "embedding": [0.9, 0.1] },
+                { "id": 2, "topic": "dogs", "embedding": [0.1, 0.9] },
+                { "id": 3, "topic": "cats", "embedding": [0.7, 0.3] },
+                { "id": 4, "topic": "cats", "embedding": "none" },
+            ]),
         );
+        let qry = |qry: Json| Query::from(&db, serde_json::from_value(qry).unwrap()).exec();
+        let nearest = json!({
+            "select": { "id": { "key": "id" } },
+            "from": "docs",
+            "knn": { "key": "embedding", "vector": [0.0, 1.0], "k": 2, "metric": "cosine" },
+        });
+        assert_eq!(Ok(json!({ "id": [2, 3] })), qry(nearest));
+        let filtered = json!({
+            "select": { "id": { "key": "id" } },
+            "from": "docs",
+            "where": { "==": [{ "key": "topic" }, "cats"] },
+            "knn": { "key": "embedding", "vector": [1, 0], "k": 10 },
+        });
+        assert_eq!(Ok(json!({ "id": [1, 3] })), qry(filtered));
     }
 
     #[test]
-    fn select_empty_keys_by_name() {
-        let qry = query(json!({
-            "select": {
-                "qty": {"key": "qty"},
-                "price": {"key": "price"},
-            },
-            "from": "t",
-            "by": {"key":"name"}
-        }));
-        assert_eq!(
-            Ok(json!({
-                "ania": {"qty":[], "price":[]},
-                "james": {"qty":[], "price":[]},
-                "misha": {"qty":[], "price":[]},
-            })),
-            qry
-        );
+    fn select_from_several_keys() {
+        let mut db = test_db();
+        db.set("day1", json!([{ "qty": 1 }, { "qty": 2 }]));
+        db.set("day2", json!([{ "qty": 5 }]));
+        let qry = |qry: Json| Query::from(&db, serde_json::from_value(qry).unwrap()).exec();
+        let exp = json!([
+            { "qty": 1, "_src": "day1" },
+            { "qty": 2, "_src": "day1" },
+            { "qty": 5, "_src": "day2" },
+        ]);
+        assert_eq!(Ok(exp), qry(json!({"from": ["day1", "day2"]})));
+        let grouped = json!({
+            "select": { "qty": { "max": { "key": "qty" } } },
+            "by": { "key": "_src" },
+            "from": ["day1", "day2"],
+        });
+        let exp = json!({ "day1": { "qty": 2 }, "day2": { "qty": 5 } });
+        assert_eq!(Ok(exp), qry(grouped));
+        let filtered = json!({
+            "select": { "qty": { "key": "qty" }, "src": { "key": "_src" } },
+            "from": ["day1", "day2"],
+            "where": { ">": [{ "key": "qty" }, 1] },
+        });
+        let exp = json!({ "qty": [2, 5], "src": ["day1", "day2"] });
+        assert_eq!(Ok(exp), qry(filtered));
+        let by_src = json!({
+            "from": ["day1", "day2"],
+            "where": { "==": [{ "key": "_src" }, "day2"] },
+            "limit": 1,
+        });
+        assert_eq!(Ok(json!([{ "qty": 5, "_src": "day2" }])), qry(by_src));
+        let missing = json!({"from": ["day1", "day3"]});
+        assert_eq!(Err(Error::BadKey("day3".to_string())), qry(missing));
     }
 
     #[test]
-    fn select_age_job_by_name_where_age_gt_20() {
-        let qry = query(json!({
-            "select": {
-                "age": {"key": "age"},
-                "job": {"key": "job"},
-            },
-            "from": "t",
-            "by": {"key": "name"},
-            "where": {">": [{"key": "age"}, 20]}
-        }));
-        assert_eq!(
-            Ok(json!({
-                "ania": {"age": [28], "job": ["english teacher"]},
-                "james": {"age": [35], "job": []},
-            })),
-            qry
-        );
+    fn explain_aggregate_query() {
+        let cmd = Cmd::parse(json!({"explain": {
+            "select": {"n": {"sum": {"key": "qty"}}},
+            "from": "orders",
+        }}))
+        .unwrap();
+        let plan = eval(cmd).unwrap();
+        assert_eq!(json!("aggregate"), plan["path"]);
+        assert_eq!(json!(5), plan["rowsScanned"]);
     }
 
     #[test]
-    fn select_all_by_name_where_age_gt_20() {
-        let qry = query(json!({
-            "from": "t",
-            "by": {"key": "name"},
-            "where": {">": [{"key":"age"}, 20]}
-        }));
-        assert_eq!(
-            Ok(json!({
-                "ania": [{"age": 28, "name": "ania", "job": "english teacher"}],
-                "james": [{"age": 35, "name": "james"}],
-            })),
-            qry
-        );
+    fn aggregates_over_empty_inputs() {
+        let selects = json!({
+            "sum": {"sum": {"key": "qty"}},
+            "count": {"count": {"key": "qty"}},
+            "avg": {"avg": {"key": "qty"}},
+            "min": {"min": {"key": "qty"}},
+            "max": {"max": {"key": "qty"}},
+            "var": {"var": {"key": "qty"}},
+        });
+        let empty =
+            json!({"sum": 0, "count": 0, "avg": null, "min": null, "max": null, "var": null});
+        for threshold in [usize::MAX, 0] {
+            let mut db = test_db();
+            db.set_par_threshold(threshold);
+            db.set("none", json!([]));
+            db.set("some", json!([{"k": "a"}, {"k": "b", "qty": 2}]));
+            let qry = |qry: Json| Query::from(&db, serde_json::from_value(qry).unwrap()).exec();
+            let flat = qry(json!({"select": selects, "from": "none"}));
+            assert_eq!(Ok(empty.clone()), flat);
+            let grouped = qry(json!({"select": selects, "from": "some", "by": {"key": "k"}}));
+            let b = json!({"sum": 2, "count": 1, "avg": 2.0, "min": 2, "max": 2, "var": 0});
+            assert_eq!(Ok(json!({"a": empty, "b": b})), grouped);
+        }
+        for agg in ["avg", "min", "max", "var", "dev", "first", "last"] {
+            assert_eq!(Ok(Json::Null), eval(Cmd::parse(json!({agg: []})).unwrap()));
+        }
+        assert_eq!(Ok(json!(0)), eval(Cmd::parse(json!({"sum": []})).unwrap()));
     }
 
     #[test]
-    fn select_count_max_min_age_by_name() {
-        let qry = query(json!({
-            "select": {
-                "maxAge": {"max": {"key": "age"}},
-                "minAge": {"min": {"key": "age"}},
-                "countAge": {"len": {"key": "age"}},
-            },
-            "from": "t",
-            "by": {"key": "name"},
-        }));
+    fn queries_run_on_their_own_pool() {
+        let mut db = test_db();
+        db.set_par_threshold(0);
+        let qry = json!({"select": {"n": {"max": {"key": "qty"}}}, "by": {"key": "customer"}, "from": "orders"});
+        let exp = query(qry.clone());
+        db.set_query_threads(2).unwrap();
+        assert_eq!(2, db.in_query_pool(rayon::current_num_threads));
+        let name = db.in_query_pool(|| std::thread::current().name().map(String::from));
+        assert!(name.unwrap().starts_with("memson-query-"));
         assert_eq!(
-            Ok(json!({
-                "ania": {"maxAge": 28, "countAge": 2, "minAge": 20},
-                "james": {"maxAge": 35, "countAge": 1, "minAge": 35},
-                "misha": {"maxAge": 10, "countAge": 1, "minAge": 10}
-            })),
-            qry
+            exp,
+            db.eval(Cmd::Query(serde_json::from_value(qry).unwrap()))
         );
     }
 
     #[test]
-    fn select_all_sort_by_name() {
-        let qry = query(json!({
-            "from": "orders",
-            "sort": "customer",
-        }));
-        assert_eq!(
-            Ok(json!([
-                { "time": 1, "customer": "ania", "qty": 2, "price": 2.0 },
-                { "time": 0, "customer": "james", "qty": 2, "price": 9.0, "discount": 10 },
-                { "time": 3, "customer": "james", "qty": 10, "price": 16.0, "discount": 20 },
-                { "time": 4, "customer": "james", "qty": 1, "price": 16.0 },
-                { "time": 2, "customer": "misha", "qty": 4, "price": 1.0 },
-            ])),
-            qry
-        );
+    fn parallelism_tuned_at_runtime() {
+        let mut db = test_db();
+        let mut eval = |cmd: Json| db.eval(Cmd::parse(cmd).unwrap());
+        let exp = json!({"rows": crate::inmem::PAR_THRESHOLD, "bytes": crate::inmem::PAR_BYTES});
+        assert_eq!(Ok(exp), eval(json!({"parallelism": {}})));
+        let exp = json!({"rows": crate::inmem::PAR_THRESHOLD, "bytes": 64});
+        assert_eq!(Ok(exp), eval(json!({"parallelism": {"bytes": 64}})));
+        let exp = json!({"rows": 10, "bytes": 64});
+        assert_eq!(Ok(exp), eval(json!({"parallelism": {"rows": 10}})));
+        let bad = json!({"parallelism": {"rows": -1}});
+        assert_eq!(Err(Error::BadArg(json!({"rows": -1}))), Cmd::parse(bad));
+        let qry = json!({"select": {"n": {"max": {"key": "qty"}}}, "by": {"key": "customer"}, "from": "orders"});
+        let cmd = Cmd::Query(serde_json::from_value(qry.clone()).unwrap());
+        assert_eq!(query(qry), db.eval(cmd));
     }
 
     #[test]
-    fn select_all_sort_by_qty() {
-        let qry = query(json!({
-            "from": "orders",
-            "sort": "qty",
-        }));
-        assert_eq!(
-            Ok(json!([
-                    { "time": 4, "customer": "james", "qty": 1, "price": 16.0 },
-                    { "time": 0, "customer": "james", "qty": 2, "price": 9.0, "discount": 10 },
-                    { "time": 1, "customer": "ania", "qty": 2, "price": 2.0 },
-                    { "time": 2, "customer": "misha", "qty": 4, "price": 1.0 },
-                    { "time": 3, "customer": "james", "qty": 10, "price": 16.0, "discount": 20 },
-            ])),
-            qry
-        );
+    fn parallel_query_matches_serial() {
+        let qrys = vec![
+            json!({"select": {"qty": {"sum": {"key": "qty"}}, "n": {"count": "*"}}, "from": "orders"}),
+            json!({"select": {"n": {"max": {"key": "qty"}}}, "by": {"key": "customer"}, "from": "orders"}),
+            json!({"from": "orders", "where": {">": [{"key": "qty"}, 1]}}),
+        ];
+        let mut par_db = test_db();
+        par_db.set_par_threshold(0);
+        for qry in qrys {
+            let cmd: QueryCmd = serde_json::from_value(qry.clone()).unwrap();
+            let exp = query(qry);
+            assert_eq!(exp, Query::from(&par_db, cmd).exec());
+        }
     }
 
     #[test]
-    fn select_all_sort_by_time() {
-        let qry = query(json!({
+    fn query_past_timeout_errors() {
+        let qry = json!({
+            "select": {"n": {"count": "*"}},
             "from": "orders",
-            "sortBy": "time",
-        }));
-        assert_eq!(
-            Ok(json!([
-                    { "time": 0, "customer": "james", "qty": 2, "price": 9.0, "discount": 10 },
-                    { "time": 1, "customer": "ania", "qty": 2, "price": 2.0 },
-                    { "time": 2, "customer": "misha", "qty": 4, "price": 1.0 },
-                    { "time": 3, "customer": "james", "qty": 10, "price": 16.0, "discount": 20 },
-                    { "time": 4, "customer": "james", "qty": 1, "price": 16.0 },
-            ])),
-            qry
-        );
+            "where": {">": [{"key": "qty"}, 1]},
+            "timeout_ms": 0,
+        });
+        assert_eq!(Err(Error::Timeout), query(qry));
+        let qry = json!({
+            "select": {"n": {"max": {"key": "qty"}}},
+            "by": {"key": "customer"},
+            "from": {"from": "orders", "timeout_ms": 0},
+            "timeout_ms": 60000,
+        });
+        assert_eq!(Err(Error::Timeout), query(qry));
     }
 
     #[test]
-    fn select_all_sort_by_time_desc() {
-        let qry = query(json!({
-            "from": "orders",
-            "sort": "time",
-            "descend": true,
-        }));
+    fn query_within_timeout_evaluates() {
+        let qry = json!({"select": {"n": {"count": "*"}}, "from": "orders", "timeout_ms": 60000});
+        assert_eq!(Ok(json!({"n": 5})), query(qry));
+    }
+
+    #[test]
+    fn cancelled_query_errors() {
+        let db = test_db();
+        let cmd: QueryCmd = serde_json::from_value(json!({"from": "orders"})).unwrap();
+        let qry = Query::from(&db, cmd);
+        qry.cancel().cancel();
+        assert_eq!(Err(Error::Timeout), qry.exec());
+    }
+
+    #[test]
+    fn cmds_time_out_with_their_caller() {
+        let mut db = test_db();
+        let qry = json!({"query": {"from": {"from": "orders"}, "timeout_ms": 60000}});
+        let batch = json!({"batch": [{"key": "s"}, qry.clone()]});
+        db.set_cancel(Cancel::after(Duration::ZERO));
         assert_eq!(
-            Ok(json!([
-                    { "time": 4, "customer": "james", "qty": 1, "price": 16.0 },
-                    { "time": 3, "customer": "james", "qty": 10, "price": 16.0, "discount": 20 },
-                    { "time": 2, "customer": "misha", "qty": 4, "price": 1.0 },
-                    { "time": 1, "customer": "ania", "qty": 2, "price": 2.0 },
-                    { "time": 0, "customer": "james", "qty": 2, "price": 9.0, "discount": 10 },
-            ])),
-            qry
+            Err(Error::Timeout),
+            db.eval(Cmd::parse(qry.clone()).unwrap())
         );
+        let exp = json!(["query timed out", "query timed out"]);
+        assert_eq!(Ok(exp), db.eval(Cmd::parse(batch).unwrap()));
+        let caller = db.set_cancel(Cancel::default());
+        assert!(caller.is_cancelled());
+        assert!(db.eval(Cmd::parse(qry).unwrap()).is_ok());
+        let parent = Cancel::default();
+        let child = parent.child(None);
+        child.cancel();
+        assert!(!parent.is_cancelled());
+        let child = parent.child(Some(Duration::from_secs(60)));
+        parent.cancel();
+        assert!(child.is_cancelled());
     }
 
     #[test]
-    fn select_all_sort_by_price() {
-        let qry = query(json!({
+    fn execute_prepared_query_with_params() {
+        let mut db = test_db();
+        let prepare = Cmd::parse(json!({"prepare": ["big_orders", {
+            "select": {"n": {"count": "*"}, "qty": {"sum": {"key": "qty"}}},
             "from": "orders",
-            "sort": "price",
-        }));
+            "where": {">=": [{"key": "qty"}, {"param": "min"}]},
+        }]}))
+        .unwrap();
+        assert_eq!(Ok(Json::Null), db.eval(prepare));
+        let execute = |db: &mut InMemDb, params: Json| {
+            db.eval(Cmd::parse(json!({"execute": ["big_orders", params]})).unwrap())
+        };
         assert_eq!(
-            Ok(json!([
-                    { "time": 2, "customer": "misha", "qty": 4, "price": 1.0 },
-                    { "time": 1, "customer": "ania", "qty": 2, "price": 2.0 },
-                    { "time": 0, "customer": "james", "qty": 2, "price": 9.0, "discount": 10 },
-                    { "time": 3, "customer": "james", "qty": 10, "price": 16.0, "discount": 20 },
-                    { "time": 4, "customer": "james", "qty": 1, "price": 16.0 },
-            ])),
-            qry
+            Ok(json!({"n": 2, "qty": 14})),
+            execute(&mut db, json!({"min": 4}))
+        );
+        assert_eq!(
+            Ok(json!({"n": 4, "qty": 18})),
+            execute(&mut db, json!({"min": 2}))
         );
+        let err = Err(Error::BadParam("min".to_string()));
+        assert_eq!(err, execute(&mut db, json!({})));
+        let missing = Cmd::parse(json!({"execute": "missing"})).unwrap();
+        assert_eq!(Err(Error::BadKey("missing".to_string())), db.eval(missing));
     }
 
     #[test]
-    fn select_all_sort_by_discount() {
-        let qry = query(json!({
+    fn row_refs_point_into_source_rows() {
+        let db = test_db();
+        let cmd: QueryCmd = serde_json::from_value(json!({
             "from": "orders",
-            "sort": "discount",
-        }));
-        assert_eq!(
-            Ok(json!([
-                    { "time": 0, "customer": "james", "qty": 2, "price": 9.0, "discount": 10 },
-                    { "time": 3, "customer": "james", "qty": 10, "price": 16.0, "discount": 20 },
-                    { "time": 1, "customer": "ania", "qty": 2, "price": 2.0 },
-                    { "time": 2, "customer": "misha", "qty": 4, "price": 1.0 },
-                    { "time": 4, "customer": "james", "qty": 1, "price": 16.0 },
-            ])),
-            qry
-        );
+            "where": {">": [{"key": "qty"}, 1]},
+            "sort": "qty",
+            "limit": 2,
+        }))
+        .unwrap();
+        let rows = db.get("orders").unwrap().as_array().unwrap();
+        let qry = Query::from(&db, cmd);
+        let refs = match qry.eval_stream(&qry.plan().unwrap()) {
+            Ok(Stream::Refs(refs)) => refs,
+            _ => panic!("expected row refs"),
+        };
+        assert_eq!(2, refs.len());
+        assert!(std::ptr::eq(refs[0], &rows[0]));
+        assert!(std::ptr::eq(refs[1], &rows[1]));
     }
 
     #[test]
-    fn select_from_orders_where_key_eq_discount() {
+    fn filtered_rows_projected_to_selected_fields() {
+        let db = test_db();
+        let cmd: QueryCmd = serde_json::from_value(json!({
+            "select": {"city": {"key": "address.city"}, "n": {"count": "*"}},
+            "by": {"key": "name"},
+            "from": "people",
+            "where": {">": [{"key": "age"}, 30]},
+        }))
+        .unwrap();
+        let qry = Query::from(&db, cmd);
+        let rows = qry.eval_rows(&qry.plan().unwrap()).unwrap();
+        let exp = json!([
+            { "name": "james", "address": { "city": "London", "zip": "N1" } },
+            { "name": "anna" },
+        ]);
+        assert_eq!(exp.as_array().unwrap().as_slice(), rows.as_slice());
+        let exp = json!({"james": {"city": ["London"], "n": 1}, "anna": {"city": [], "n": 1}});
+        assert_eq!(Ok(exp), qry.exec());
+    }
+
+    #[test]
+    fn select_count_by_customer_from_orders() {
         let qry = query(json!({
+            "select": {"n": {"count": "*"}},
+            "by": {"key": "customer"},
             "from": "orders",
-            "where": {"has": "discount"},
         }));
-        assert_eq!(
-            Ok(json!([
-                { "time": 0, "customer": "james", "qty": 2, "price": 9.0, "discount": 10 },
-                { "time": 3, "customer": "james", "qty": 10, "price": 16.0, "discount": 20 },
-            ])),
-            qry
-        );
+        let exp = json!({
+            "james": {"n": 3},
+            "ania": {"n": 1},
+            "misha": {"n": 1},
+        });
+        assert_eq!(Ok(exp), qry);
     }
 
     #[test]
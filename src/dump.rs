@@ -0,0 +1,202 @@
+use crate::err::Error;
+use crate::json_ops::Json;
+use flate2::read::GzDecoder;
+use flate2::write::GzEncoder;
+use flate2::Compression;
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+use std::fs::{self, File};
+use std::io::{BufReader, BufWriter, Read, Write};
+use std::path::{Path, PathBuf};
+
+/// the first bytes of a gzip stream
+const GZIP_MAGIC: [u8; 2] = [0x1f, 0x8b];
+/// the first bytes of a zstd frame
+const ZSTD_MAGIC: [u8; 4] = [0x28, 0xb5, 0x2f, 0xfd];
+
+/// The binary formats entries are dumped in; both are far smaller and faster to read and
+/// write than json text for large arrays of rows
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Format {
+    MsgPack,
+    Cbor,
+}
+
+impl Format {
+    /// the format of a name, `msgpack` or `cbor`
+    pub fn parse(name: &str) -> Option<Self> {
+        match name {
+            "msgpack" => Some(Format::MsgPack),
+            "cbor" => Some(Format::Cbor),
+            _ => None,
+        }
+    }
+
+    /// the media type of a dump in the format
+    pub fn content_type(&self) -> &'static str {
+        match self {
+            Format::MsgPack => "application/msgpack",
+            Format::Cbor => "application/cbor",
+        }
+    }
+}
+
+/// The codecs backups are compressed with
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Codec {
+    #[default]
+    Zstd,
+    Gzip,
+}
+
+/// encodes entries as a map of their keys to their values
+pub fn dump<'a, I>(entries: I, format: Format) -> Result<Vec<u8>, Error>
+where
+    I: IntoIterator<Item = (&'a str, &'a Json)>,
+{
+    let mut buf = Vec::new();
+    dump_to(entries, format, &mut buf)?;
+    Ok(buf)
+}
+
+/// encodes entries as a map of their keys to their values into a writer
+pub fn dump_to<'a, I, W>(entries: I, format: Format, mut writer: W) -> Result<(), Error>
+where
+    I: IntoIterator<Item = (&'a str, &'a Json)>,
+    W: Write,
+{
+    let entries: BTreeMap<&str, &Json> = entries.into_iter().collect();
+    match format {
+        Format::MsgPack => {
+            rmp_serde::encode::write(&mut writer, &entries).map_err(|_| Error::Serialize)
+        }
+        Format::Cbor => ciborium::into_writer(&entries, writer).map_err(|_| Error::Serialize),
+    }
+}
+
+/// decodes the entries of a dump
+pub fn restore(data: &[u8], format: Format) -> Result<BTreeMap<String, Json>, Error> {
+    restore_from(data, format)
+}
+
+/// decodes the entries of a dump from a reader
+pub fn restore_from<R: Read>(reader: R, format: Format) -> Result<BTreeMap<String, Json>, Error> {
+    match format {
+        Format::MsgPack => rmp_serde::from_read(reader).map_err(|_| Error::Serialize),
+        Format::Cbor => ciborium::from_reader(reader).map_err(|_| Error::Serialize),
+    }
+}
+
+/// the path of a backup named by a client, which must be a plain file name so backups
+/// stay inside the dir
+pub fn backup_path(dir: &Path, name: &str) -> Result<PathBuf, Error> {
+    if name.is_empty() || name.contains(['/', '\\']) || name.contains("..") {
+        return Err(Error::BadArg(name.into()));
+    }
+    Ok(dir.join(name))
+}
+
+/// writes a snapshot of entries to a file as compressed msgpack; the file is only
+/// replaced once the snapshot is complete. Returns the bytes written
+pub fn write_backup<'a, I>(path: &Path, codec: Codec, entries: I) -> Result<u64, Error>
+where
+    I: IntoIterator<Item = (&'a str, &'a Json)>,
+{
+    let mut tmp = path.as_os_str().to_owned();
+    tmp.push(".tmp");
+    let file = BufWriter::new(File::create(&tmp).map_err(|_| Error::BadIO)?);
+    let file = match codec {
+        Codec::Zstd => {
+            let mut encoder = zstd::Encoder::new(file, 0).map_err(|_| Error::BadIO)?;
+            dump_to(entries, Format::MsgPack, &mut encoder)?;
+            encoder.finish().map_err(|_| Error::BadIO)?
+        }
+        Codec::Gzip => {
+            let mut encoder = GzEncoder::new(file, Compression::default());
+            dump_to(entries, Format::MsgPack, &mut encoder)?;
+            encoder.finish().map_err(|_| Error::BadIO)?
+        }
+    };
+    let file = file.into_inner().map_err(|_| Error::BadIO)?;
+    file.sync_all().map_err(|_| Error::BadIO)?;
+    fs::rename(&tmp, path).map_err(|_| Error::BadIO)?;
+    fs::metadata(path)
+        .map(|x| x.len())
+        .map_err(|_| Error::BadIO)
+}
+
+/// reads the entries of a backup, telling its codec from its first bytes
+pub fn read_backup(path: &Path) -> Result<BTreeMap<String, Json>, Error> {
+    let mut file = BufReader::new(File::open(path).map_err(|_| Error::BadIO)?);
+    let mut magic = [0; 4];
+    file.read_exact(&mut magic).map_err(|_| Error::BadIO)?;
+    let file = std::io::Cursor::new(magic).chain(file);
+    if magic == ZSTD_MAGIC {
+        let decoder = zstd::Decoder::new(file).map_err(|_| Error::BadIO)?;
+        restore_from(decoder, Format::MsgPack)
+    } else if magic[..2] == GZIP_MAGIC {
+        restore_from(GzDecoder::new(file), Format::MsgPack)
+    } else {
+        Err(Error::Serialize)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::tempdir::TempDir;
+    use serde_json::json;
+
+    #[test]
+    fn entries_round_trip() {
+        let rows: Json = (0..100)
+            .map(|i| json!({"id": i, "price": i as f64 / 4.0, "name": "a", "tags": [null, true]}))
+            .collect();
+        let entries = [("orders", &rows), ("n", &json!(-3))];
+        for format in [Format::MsgPack, Format::Cbor] {
+            let data = dump(entries, format).unwrap();
+            assert!(data.len() < serde_json::to_vec(&rows).unwrap().len());
+            let restored = restore(&data, format).unwrap();
+            assert_eq!(Some(&rows), restored.get("orders"));
+            assert_eq!(Some(&json!(-3)), restored.get("n"));
+        }
+        assert_eq!(Err(Error::Serialize), restore(b"{}", Format::Cbor));
+        assert_eq!(None, Format::parse("json"));
+    }
+
+    #[test]
+    fn backups_round_trip() {
+        let rows: Json = (0..1000).map(|i| json!({"id": i, "name": "a"})).collect();
+        let entries = [("orders", &rows), ("s", &json!("s"))];
+        for codec in [Codec::Zstd, Codec::Gzip] {
+            let dir = TempDir::new(&format!("backup-{:?}", codec));
+            let path = dir.join("backup");
+            let path = path.as_path();
+            let bytes = write_backup(path, codec, entries).unwrap();
+            assert!(bytes < dump(entries, Format::MsgPack).unwrap().len() as u64);
+            let restored = read_backup(path).unwrap();
+            assert_eq!(Some(&rows), restored.get("orders"));
+            assert_eq!(2, restored.len());
+            std::fs::write(path, b"not a backup").unwrap();
+            assert_eq!(Err(Error::Serialize), read_backup(path));
+        }
+    }
+
+    #[test]
+    fn backup_names_confined_to_dir() {
+        let dir = Path::new("/var/lib/memson/backups");
+        assert_eq!(Ok(dir.join("daily.bak")), backup_path(dir, "daily.bak"));
+        for name in [
+            "",
+            "..",
+            "../daily.bak",
+            "/etc/passwd",
+            "a/b",
+            "a\\b",
+            "a..b",
+        ] {
+            assert_eq!(Err(Error::BadArg(json!(name))), backup_path(dir, name));
+        }
+    }
+}
@@ -1,19 +1,36 @@
-use crate::Json;
+use crate::json_ops::Json;
 use std::fmt;
 
-#[derive(Debug, PartialEq)]
+#[derive(Clone, Debug, PartialEq)]
 pub enum Error {
     BadType,
     BadCmd,
     BadKey(String),
+    /// a path missing inside the value of a key that exists
+    BadPath(String),
     ExpectedArr,
     BadFrom,
     Serialize,
     BadGroupBy,
+    BadSelect,
     BadIO,
     BadArg(Json),
+    BadParam(String),
+    BadSubscriber(u64),
+    BadFormat(u32),
+    Disabled(String),
+    MemoryPressure,
+    Overflow,
+    DivByZero,
     IndexOutOfBounds,
     FloatCmp,
+    Chaos,
+    Timeout,
+    /// a request made before the client authenticated
+    Unauthenticated,
+    BadCredentials,
+    /// a cmd outside the grant of the user
+    Forbidden(String),
 }
 
 impl fmt::Display for Error {
@@ -22,14 +39,28 @@ impl fmt::Display for Error {
             Error::BadCmd => write!(f, "bad cmd"),
             Error::BadType => write!(f, "incorrect type"),
             Error::BadKey(key) => write!(f, "bad key: {}", &key),
+            Error::BadPath(path) => write!(f, "bad path: {}", &path),
             Error::ExpectedArr => write!(f, "expected json array"),
             Error::BadFrom => write!(f, "bad from"),
             Error::Serialize => write!(f, "bad serialization"),
             Error::BadGroupBy => write!(f, "bad group by"),
+            Error::BadSelect => write!(f, "bad select: aggregates and row values need a by"),
             Error::BadIO => write!(f, "bad io"),
             Error::BadArg(msg) => write!(f, "{} is a bad argument", msg),
+            Error::BadParam(name) => write!(f, "no value for param: {}", name),
+            Error::BadSubscriber(id) => write!(f, "no subscriber: {}", id),
+            Error::BadFormat(version) => write!(f, "unsupported data format version: {}", version),
+            Error::Disabled(family) => write!(f, "{} cmds are disabled", family),
+            Error::MemoryPressure => write!(f, "memory above soft limit: writes are rejected"),
+            Error::Overflow => write!(f, "numeric overflow"),
+            Error::DivByZero => write!(f, "division by zero"),
             Error::IndexOutOfBounds => write!(f, "index out of bounds"),
             Error::FloatCmp => write!(f, "float comparison"),
+            Error::Chaos => write!(f, "injected failure"),
+            Error::Timeout => write!(f, "query timed out"),
+            Error::Unauthenticated => write!(f, "unauthenticated"),
+            Error::BadCredentials => write!(f, "bad credentials"),
+            Error::Forbidden(what) => write!(f, "forbidden: {}", what),
         }
     }
 }
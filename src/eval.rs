@@ -1,50 +1,124 @@
 use crate::apply::apply;
 use crate::cmd::{Cmd, QueryCmd};
-use crate::db::Query;
+use crate::db::{Cancel, Query};
+use crate::expiry::unix_secs;
+use crate::generate::generate_rows;
+use crate::graph::traverse;
 use crate::inmem::InMemDb;
-use crate::json::*;
+use crate::json_ops::*;
+use crate::lint::lint;
 use crate::Error;
 use crate::Res;
 use core::option::Option::Some;
+use serde_json::json;
+use std::mem;
+use std::time::Duration;
 
 /// evaluate the key command
-fn eval_key(db: &InMemDb, key: String) -> Res {
-    let mut it = key.split('.');
-    let key = it.next().ok_or_else(|| Error::BadKey(key.clone()))?;
-    let mut ref_val = db.get(key)?;
-    let mut fat_val = None;
-    for key in it {
-        match ref_val {
-            Json::Array(ref arr) => {
-                let mut out = Vec::new();
-                for val in arr {
-                    if let Some(val) = val.get(key) {
-                        out.push(val.clone());
-                    }
-                }
-                fat_val = Some(out);
-            }
-            val => {
-                ref_val = val
-                    .get(&key)
-                    .ok_or_else(|| Error::BadKey(key.to_string()))?;
+pub fn eval_key(db: &InMemDb, key: String) -> Res {
+    match key.find('.') {
+        Some(i) => eval_path(db.get(&key[..i])?, &key[i + 1..]),
+        None => Ok(db.get(&key)?.clone()),
+    }
+}
+
+/// the values of several keys, which may be dotted paths, by key; null for missing keys
+fn eval_mget(db: &InMemDb, keys: Vec<String>) -> Json {
+    let vals = keys.into_iter().map(|key| {
+        let val = eval_key(db, key.clone()).unwrap_or(Json::Null);
+        (key, val)
+    });
+    Json::Object(vals.collect())
+}
+
+/// looks up a key, which may be a dotted path, in an envelope telling apart a missing key,
+/// a path missing inside its value and a null value
+fn eval_lookup(db: &InMemDb, key: String) -> Res {
+    match eval_key(db, key) {
+        Ok(Json::Null) => Ok(json!({"status": "null"})),
+        Ok(val) => Ok(json!({"status": "found", "val": val})),
+        Err(Error::BadKey(_)) => Ok(json!({"status": "missingKey"})),
+        Err(Error::BadPath(_)) | Err(Error::IndexOutOfBounds) => {
+            Ok(json!({"status": "missingPath"}))
+        }
+        Err(err) => Err(err),
+    }
+}
+
+/// follows a dotted path through a value; over an array the rest of the path is followed
+/// through each row, cloning only the values found and skipping rows without them, unless
+/// the path indexes the array. The path is missing if no row has it
+fn eval_path(val: &Json, path: &str) -> Res {
+    let (key, rest) = match path.find('.') {
+        Some(i) => (&path[..i], Some(&path[i + 1..])),
+        None => (path, None),
+    };
+    let val = match (val, key.parse::<usize>()) {
+        (Json::Array(rows), Ok(i)) => rows.get(i).ok_or(Error::IndexOutOfBounds)?,
+        (Json::Array(rows), Err(_)) => {
+            let vals: Vec<Json> = rows
+                .iter()
+                .filter_map(|row| json_path(row, path))
+                .cloned()
+                .collect();
+            if vals.is_empty() {
+                return Err(Error::BadPath(path.to_string()));
             }
+            return Ok(Json::Array(vals));
         }
+        (val, _) => val
+            .get(key)
+            .ok_or_else(|| Error::BadPath(key.to_string()))?,
+    };
+    match rest {
+        Some(rest) => eval_path(val, rest),
+        None => Ok(val.clone()),
     }
-    Ok(if let Some(val) = fat_val {
-        Json::Array(val)
-    } else {
-        ref_val.clone()
-    })
 }
 
 fn eval_append(db: &mut InMemDb, key: &str, arg: Cmd) -> Res {
     let elem = eval_cmd(db, arg)?;
-    let val = db.get_mut(&key)?;
+    let val = db.get_mut(key)?;
     json_append(val, elem);
     Ok(Json::Null)
 }
 
+/// patches the value of a key, or null if it's missing, returning the patched value
+fn eval_merge(db: &mut InMemDb, key: String, patch: Json) -> Res {
+    if !db.has(&key) {
+        let mut val = Json::Null;
+        json_merge_patch(&mut val, patch);
+        db.set(key, val.clone());
+        return Ok(val);
+    }
+    let val = db.get_mut(&key)?;
+    json_merge_patch(val, patch);
+    Ok(val.clone())
+}
+
+/// moves the rows of a table passing a filter to the end of another, creating it if
+/// missing; returns the number of rows moved. Both tables are checked before either
+/// changes, so a failed move changes neither
+fn eval_move_rows(db: &mut InMemDb, from: String, to: String, filter: Cmd) -> Res {
+    if !db.get(&from)?.is_array() || db.get(&to).is_ok_and(|x| !x.is_array()) {
+        return Err(Error::ExpectedArr);
+    }
+    let rows = mem::take(db.get_mut(&from)?);
+    let (moved, kept): (Vec<Json>, Vec<Json>) = match rows {
+        Json::Array(rows) => rows
+            .into_iter()
+            .partition(|row| row.is_object() && eval_filter(filter.clone(), row) == Some(true)),
+        _ => unreachable!("checked to be an array"),
+    };
+    *db.get_mut(&from)? = Json::Array(kept);
+    let n = moved.len();
+    match db.entry(to) {
+        Json::Array(rows) => rows.extend(moved),
+        val => *val = Json::Array(moved),
+    }
+    Ok(Json::from(n))
+}
+
 /// evaluate the sort command
 fn eval_sort_cmd(db: &mut InMemDb, arg: Cmd) -> Res {
     let mut val = eval_cmd(db, arg)?;
@@ -53,16 +127,13 @@ fn eval_sort_cmd(db: &mut InMemDb, arg: Cmd) -> Res {
 }
 
 /// evaluate the insert command
-fn eval_insert(db: &mut InMemDb, key: &str, arg: Vec<JsonObj>) -> Res {
-    let val = db.get_mut(&key)?;
-    let n = arg.len();
-    json_insert(val, arg);
-    Ok(Json::from(n))
+fn eval_insert(db: &mut InMemDb, key: String, arg: Vec<JsonObj>) -> Res {
+    Ok(Json::from(db.insert(key, arg)))
 }
 
 fn eval_push(db: &mut InMemDb, key: &str, arg: Cmd) -> Res {
     let val = eval_cmd(db, arg)?;
-    let kv = db.get_mut(&key)?;
+    let kv = db.get_mut(key)?;
     json_push(kv, val);
     Ok(Json::Null)
 }
@@ -78,6 +149,15 @@ fn eval_evals(db: &mut InMemDb, cmds: Vec<Cmd>) -> Res {
     Ok(Json::Array(vals?))
 }
 
+/// evaluates each cmd of a batch in order; a failed cmd's result is its error message and
+/// doesn't stop the cmds after it
+pub fn eval_batch<F: FnMut(Cmd) -> Res>(cmds: Vec<Cmd>, mut eval: F) -> Json {
+    let vals = cmds
+        .into_iter()
+        .map(|cmd| eval(cmd).unwrap_or_else(|err| Json::from(err.to_string())));
+    Json::Array(vals.collect())
+}
+
 fn eval_map(db: &mut InMemDb, arg: Cmd, key: String) -> Res {
     let val = eval_cmd(db, arg)?;
     json_map(&val, key)
@@ -145,22 +225,66 @@ pub fn eval_cmd(db: &mut InMemDb, cmd: Cmd) -> Res {
         Cmd::Append(key, arg) => eval_append(db, &key, *arg),
         Cmd::Avg(arg) => eval_unr_fn(db, *arg, json_avg),
         Cmd::Bar(lhs, rhs) => eval_bin_fn(db, *lhs, *rhs, json_bar),
-        Cmd::Len(arg) => eval_unr_fn(db, *arg, &count),
+        Cmd::Len(Some(arg)) => eval_unr_fn(db, *arg, |x| Ok(json_len(x))),
+        Cmd::Len(None) => Ok(Json::from(db.len())),
+        Cmd::Between(arg, lo, hi) => {
+            let lo = eval_cmd(db, *lo)?;
+            let hi = eval_cmd(db, *hi)?;
+            eval_unr_fn(db, *arg, |x| Ok(json_between(x, &lo, &hi)))
+        }
+        Cmd::Collect(arg) => eval_unr_fn(db, *arg, |x| Ok(json_collect(x))),
+        Cmd::Like(arg, pat) | Cmd::Regex(arg, pat) => {
+            eval_unr_fn(db, *arg, |x| Ok(json_matches(x, |s| pat.is_match(s))))
+        }
+        Cmd::Join(arg, sep) => eval_unr_fn(db, *arg, |x| Ok(json_join(x, &sep))),
+        Cmd::Count(Some(arg)) => eval_unr_fn(db, *arg, |x| Ok(json_count_vals(x))),
+        Cmd::Count(None) => Ok(Json::from(db.len())),
+        Cmd::CountDistinct(arg) => eval_unr_fn(db, *arg, |x| Ok(json_count_distinct(x))),
+        Cmd::HeavyHitters(arg, k) => eval_unr_fn(db, *arg, |x| Ok(json_heavy_hitters(x, k))),
+        Cmd::CreateIndex { key, field } => {
+            db.create_index(key, field)?;
+            Ok(Json::Null)
+        }
         Cmd::Delete(key) => Ok(db.delete(&key).unwrap_or(Json::Null)),
+        Cmd::DeleteMany(keys) => {
+            let n = keys.iter().filter(|key| db.delete(key).is_some()).count();
+            Ok(Json::from(n))
+        }
+        Cmd::DeletePattern(pat) => {
+            let keys = db.matching_keys(&pat);
+            let n = keys.iter().filter(|key| db.delete(key).is_some()).count();
+            Ok(Json::from(n))
+        }
         Cmd::Div(lhs, rhs) => eval_bin_fn(db, *lhs, *rhs, json_div),
         Cmd::First(arg) => eval_unr_fn(db, *arg, |x| Ok(json_first(x))),
         Cmd::Get(key, arg) => {
             let val = eval_cmd(db, *arg)?;
             Ok(json_get(&key, &val).unwrap_or(Json::Null))
         }
-        Cmd::Insert(key, arg) => eval_insert(db, &key, arg),
+        Cmd::Insert(key, arg) => eval_insert(db, key, arg),
+        Cmd::Merge(key, patch) => eval_merge(db, key, patch),
+        Cmd::MoveRows { from, to, filter } => eval_move_rows(db, from, to, *filter),
+        Cmd::Backup(backup) => db.backup(&backup),
+        // restores are evaluated as a tx of their entries, rather than within one
+        Cmd::Restore(_) => Err(Error::BadCmd),
+        // the metrics are kept by memson, around the evaluation of each cmd
+        Cmd::MetricsHistory { .. } => Err(Error::BadCmd),
+        Cmd::Generate(gen) => {
+            let rows = generate_rows(&gen.template, gen.n, gen.seed)?;
+            db.set(gen.table, Json::Array(rows));
+            Ok(Json::from(gen.n))
+        }
         Cmd::Json(val) => Ok(val),
-        Cmd::Keys(page) => Ok(Json::Array(db.keys(page))),
+        Cmd::Keys(page, filter) => db.keys(page, filter.as_ref()),
+        Cmd::KeyRange(range) => db.key_range(&range).map(Json::Array),
+        Cmd::Scan(scan) => Ok(db.scan(&scan)),
+        Cmd::Traverse(t) => traverse(db, &t),
         Cmd::Last(arg) => eval_unr_fn(db, *arg, |x| Ok(json_last(x))),
         Cmd::Max(arg) => Ok(json_max(&eval_cmd(db, *arg)?)
             .cloned()
             .unwrap_or(Json::Null)),
         Cmd::In(lhs, rhs) => eval_bin_fn(db, *lhs, *rhs, |x, y| Ok(json_in(x, y))),
+        Cmd::Diff(lhs, rhs) => eval_bin_fn(db, *lhs, *rhs, |x, y| Ok(json_diff(x, y))),
         Cmd::Min(arg) => Ok(json_min(&eval_cmd(db, *arg)?)
             .cloned()
             .unwrap_or(Json::Null)),
@@ -168,24 +292,66 @@ pub fn eval_cmd(db: &mut InMemDb, cmd: Cmd) -> Res {
         Cmd::Push(key, arg) => eval_push(db, &key, *arg),
         Cmd::Pop(key) => Ok(pop(db, key)?.unwrap_or(Json::Null)),
         Cmd::Query(cmd) => eval_query(db, cmd),
-        Cmd::Set(key, arg) => {
+        Cmd::Validate(cmd) => Ok(Json::from(lint(db, &cmd))),
+        Cmd::Explain(cmd) => Ok(Query::from(db, cmd).explain()),
+        Cmd::Estimate(cmd) => Query::from(db, cmd).estimate(),
+        Cmd::Prepare(name, cmd) => {
+            db.prepare(name, cmd)?;
+            Ok(Json::Null)
+        }
+        Cmd::Execute(name, params) => db.execute(&name, &params),
+        Cmd::Param(name) => Err(Error::BadParam(name)),
+        Cmd::Set(key, arg, None) | Cmd::GetSet(key, arg) => {
             let val = eval_cmd(db, *arg)?;
             Ok(db.set(key, val).unwrap_or(Json::Null))
         }
-        Cmd::Slice(arg, range) => json_slice(eval_cmd(db, *arg)?, range),
+        Cmd::Set(key, arg, Some(cond)) => {
+            if !cond.holds(db.has(&key)) {
+                return Ok(Json::Bool(false));
+            }
+            let val = eval_cmd(db, *arg)?;
+            db.set(key, val);
+            Ok(Json::Bool(true))
+        }
+        Cmd::Slice(arg, range) => range.slice(eval_cmd(db, *arg)?),
         Cmd::Sort(arg, _) => eval_sort_cmd(db, *arg),
         Cmd::Dev(arg) => eval_unr_fn(db, *arg, json_dev),
         Cmd::Sub(lhs, rhs) => eval_bin_fn(db, *lhs, *rhs, json_sub),
         Cmd::Sum(arg) => eval_unr_fn(db, *arg, |x| Ok(json_sum(x))),
-        Cmd::Summary => Ok(db.summary()),
+        Cmd::Summary(filter) => Ok(db.summary(filter.as_ref())),
         Cmd::Unique(arg) => eval_unr_fn(db, *arg, unique),
         Cmd::Var(arg) => eval_unr_fn(db, *arg, json_var),
         Cmd::ToString(arg) => Ok(eval_cmd(db, *arg)?),
         Cmd::Key(key) => eval_key(db, key),
+        Cmd::MGet(keys) => Ok(eval_mget(db, keys)),
+        Cmd::Lookup(key) => eval_lookup(db, key),
         Cmd::Reverse(arg) => eval_reverse(db, *arg),
         Cmd::Median(arg) => eval_median(db, *arg),
         Cmd::SortBy(arg, key) => eval_sortby(db, *arg, key),
         Cmd::Eval(cmds) => eval_evals(db, cmds),
+        Cmd::Batch(cmds) => Ok(eval_batch(cmds, |cmd| eval_cmd(db, cmd))),
+        Cmd::Tx(cmds) => db.tx(cmds),
+        Cmd::Script(stmts) => db.script(stmts),
+        Cmd::Expire(key, secs) => Ok(Json::Bool(db.expire(&key, Duration::from_secs_f64(secs)))),
+        Cmd::ExpireAt(key, at) => {
+            let ttl = (at - unix_secs()).max(0.0);
+            Ok(Json::Bool(db.expire(&key, Duration::from_secs_f64(ttl))))
+        }
+        Cmd::Ttl(key) => Ok(db.ttl(&key)?.map_or(Json::Null, |x| x.as_secs_f64().into())),
+        Cmd::Subscribe(keys, filter) => {
+            let id = db.pubsub_mut().subscribe(keys, filter.map(|x| *x));
+            Ok(Json::from(id))
+        }
+        Cmd::SubscribeQuery(qry) => db.subscribe_query(qry),
+        Cmd::Events(id) => db.pubsub_mut().events(id),
+        Cmd::Ack(id, seq) => {
+            db.pubsub_mut().ack(id, seq)?;
+            Ok(Json::Null)
+        }
+        Cmd::Unsubscribe(id) => {
+            db.pubsub_mut().unsubscribe(id)?;
+            Ok(Json::Null)
+        }
         Cmd::Eq(lhs, rhs) => eval_eq(db, *lhs, *rhs),
         Cmd::NotEq(lhs, rhs) => eval_not_eq(db, *lhs, *rhs),
         Cmd::Gt(lhs, rhs) => eval_gt(db, *lhs, *rhs),
@@ -198,13 +364,91 @@ pub fn eval_cmd(db: &mut InMemDb, cmd: Cmd) -> Res {
         Cmd::Flat(arg) => eval_flat(db, *arg),
         Cmd::NumSort(arg, descend) => eval_numsort(db, *arg, descend),
         Cmd::Has(key) => Ok(Json::Bool(db.has(&key))),
+        Cmd::HotKeys(n) => Ok(db.stats().hot_keys(n)),
+        Cmd::Parallelism(tuning) => Ok(db.tune_parallelism(&tuning)),
+        Cmd::Compress(min_bytes) => db.compress(min_bytes),
+        Cmd::Intern(min_bytes) => Ok(db.duplicates(min_bytes)),
+        Cmd::SizeOf(key) => eval_size_of(db, &key),
+        #[cfg(feature = "chaos")]
+        Cmd::Chaos(rule) => {
+            db.chaos_mut().set(rule);
+            Ok(Json::Null)
+        }
+        #[cfg(not(feature = "chaos"))]
+        Cmd::Chaos(_) => Err(Error::BadCmd),
+        Cmd::IsNull(key) => Ok(Json::Bool(db.get(&key).map_or(true, Json::is_null))),
     }
 }
 
+/// checks if a cmd only reads entries, so it can be evaluated through a shared db
+pub fn reads_only(cmd: &Cmd) -> bool {
+    matches!(
+        cmd,
+        Cmd::Key(_)
+            | Cmd::MGet(_)
+            | Cmd::Lookup(_)
+            | Cmd::Has(_)
+            | Cmd::IsNull(_)
+            | Cmd::SizeOf(_)
+            | Cmd::Ttl(_)
+            | Cmd::Len(None)
+            | Cmd::Count(None)
+            | Cmd::Keys(_, _)
+            | Cmd::KeyRange(_)
+            | Cmd::Scan(_)
+            | Cmd::Traverse(_)
+            | Cmd::Summary(_)
+            | Cmd::Query(_)
+            | Cmd::Estimate(_)
+            | Cmd::Json(_)
+    )
+}
+
+/// evaluates a cmd that only reads entries through a shared db; its queries time out once
+/// the token is cancelled
+pub fn eval_shared(db: &InMemDb, cmd: Cmd, cancel: &Cancel) -> Res {
+    match cmd {
+        Cmd::Key(key) => eval_key(db, key),
+        Cmd::MGet(keys) => Ok(eval_mget(db, keys)),
+        Cmd::Lookup(key) => eval_lookup(db, key),
+        Cmd::Has(key) => Ok(Json::Bool(db.has(&key))),
+        Cmd::IsNull(key) => Ok(Json::Bool(db.get(&key).map_or(true, Json::is_null))),
+        Cmd::SizeOf(key) => eval_size_of(db, &key),
+        Cmd::Ttl(key) => Ok(db.ttl(&key)?.map_or(Json::Null, |x| x.as_secs_f64().into())),
+        Cmd::Len(None) | Cmd::Count(None) => Ok(Json::from(db.len())),
+        Cmd::Keys(page, filter) => db.keys(page, filter.as_ref()),
+        Cmd::KeyRange(range) => db.key_range(&range).map(Json::Array),
+        Cmd::Scan(scan) => Ok(db.scan(&scan)),
+        Cmd::Traverse(t) => traverse(db, &t),
+        Cmd::Summary(filter) => Ok(db.summary(filter.as_ref())),
+        Cmd::Query(cmd) => {
+            let qry = Query::within(db, cmd, cancel);
+            db.in_query_pool(|| qry.exec())
+        }
+        Cmd::Estimate(cmd) => Query::within(db, cmd, cancel).estimate(),
+        Cmd::Json(val) => Ok(val),
+        _ => Err(Error::BadCmd),
+    }
+}
+
+/// evaluate the size of a value without copying it; the key may be a dot separated path
+fn eval_size_of(db: &InMemDb, key: &str) -> Res {
+    let (root, path) = match key.find('.') {
+        Some(i) => (&key[..i], Some(&key[i + 1..])),
+        None => (key, None),
+    };
+    let val = db.get(root)?;
+    let val = match path {
+        Some(path) => json_path(val, path).ok_or_else(|| Error::BadPath(key.to_string()))?,
+        None => val,
+    };
+    Ok(json_size_of(val))
+}
+
 // evaluate the query command
 fn eval_query(db: &InMemDb, cmd: QueryCmd) -> Res {
     let qry = Query::from(db, cmd);
-    qry.exec()
+    db.in_query_pool(|| qry.exec())
 }
 
 // evaluation of the pop command
@@ -0,0 +1,110 @@
+use crate::err::Error;
+use crate::stats::AccessStats;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+/// Chooses the entries evicted once memson holds more than its memory cap
+pub trait Eviction: Send + Sync {
+    /// the position of the key to evict among the candidates; none to evict nothing
+    fn victim(&mut self, keys: &[&str], stats: &AccessStats) -> Option<usize>;
+}
+
+/// Evicts the least recently read or written key
+#[derive(Debug, Default)]
+pub struct Lru;
+
+impl Eviction for Lru {
+    fn victim(&mut self, keys: &[&str], stats: &AccessStats) -> Option<usize> {
+        let last_access = |key: &str| stats.get(key).map_or(0, |x| x.last_access);
+        (0..keys.len()).min_by_key(|i| last_access(keys[*i]))
+    }
+}
+
+/// Evicts the least frequently read or written key
+#[derive(Debug, Default)]
+pub struct Lfu;
+
+impl Eviction for Lfu {
+    fn victim(&mut self, keys: &[&str], stats: &AccessStats) -> Option<usize> {
+        let accesses = |key: &str| stats.get(key).map_or(0, |x| x.reads + x.writes);
+        (0..keys.len()).min_by_key(|i| accesses(keys[*i]))
+    }
+}
+
+/// Evicts a key at random
+#[derive(Debug)]
+pub struct RandomEviction {
+    state: u64,
+}
+
+impl RandomEviction {
+    pub fn new(seed: u64) -> Self {
+        // xorshift never leaves the zero state
+        RandomEviction { state: seed | 1 }
+    }
+}
+
+impl Default for RandomEviction {
+    fn default() -> Self {
+        let nanos = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map(|d| d.subsec_nanos())
+            .unwrap_or(0);
+        RandomEviction::new(nanos.into())
+    }
+}
+
+impl Eviction for RandomEviction {
+    fn victim(&mut self, keys: &[&str], _: &AccessStats) -> Option<usize> {
+        if keys.is_empty() {
+            return None;
+        }
+        self.state ^= self.state << 13;
+        self.state ^= self.state >> 7;
+        self.state ^= self.state << 17;
+        Some((self.state % keys.len() as u64) as usize)
+    }
+}
+
+/// the eviction policy of a name: `lru`, `lfu` or `random`
+pub fn eviction_policy(name: &str) -> Result<Box<dyn Eviction>, Error> {
+    match name {
+        "lru" => Ok(Box::new(Lru)),
+        "lfu" => Ok(Box::new(Lfu)),
+        "random" => Ok(Box::new(RandomEviction::default())),
+        _ => Err(Error::BadArg(name.into())),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::collections::BTreeSet;
+
+    fn keys(keys: &[&str]) -> BTreeSet<String> {
+        keys.iter().map(|x| x.to_string()).collect()
+    }
+
+    #[test]
+    fn lru_and_lfu_victims() {
+        let mut stats = AccessStats::default();
+        stats.record(&keys(&["a", "b"]), &BTreeSet::new());
+        std::thread::sleep(std::time::Duration::from_millis(2));
+        stats.record(&keys(&["a"]), &keys(&["a"]));
+        let candidates = ["a", "b"];
+        assert_eq!(Some(1), Lru.victim(&candidates, &stats));
+        assert_eq!(Some(1), Lfu.victim(&candidates, &stats));
+        assert_eq!(Some(2), Lru.victim(&["a", "b", "never"], &stats));
+        assert_eq!(None, Lfu.victim(&[], &stats));
+    }
+
+    #[test]
+    fn random_victims_in_range() {
+        let mut random = RandomEviction::new(7);
+        let stats = AccessStats::default();
+        for _ in 0..100 {
+            assert!(random.victim(&["a", "b", "c"], &stats).unwrap() < 3);
+        }
+        assert_eq!(None, random.victim(&[], &stats));
+        assert!(eviction_policy("mru").is_err());
+    }
+}
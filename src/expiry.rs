@@ -0,0 +1,114 @@
+use std::collections::{BTreeSet, HashMap};
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
+/// how soon keys must expire to be reported by the summary
+pub const EXPIRING_SOON: Duration = Duration::from_secs(60);
+
+/// the current unix time in seconds, which deadlines outliving memson are given in
+pub fn unix_secs() -> f64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map_or(0.0, |x| x.as_secs_f64())
+}
+
+/// The deadlines of the keys that expire, ordered so the due keys are found without
+/// visiting the others
+#[derive(Debug, Default)]
+pub struct Expiry {
+    deadlines: HashMap<String, Instant>,
+    queue: BTreeSet<(Instant, String)>,
+}
+
+impl Expiry {
+    /// sets when a key expires, replacing any earlier deadline
+    pub fn set(&mut self, key: &str, at: Instant) {
+        self.clear(key);
+        self.deadlines.insert(key.to_string(), at);
+        self.queue.insert((at, key.to_string()));
+    }
+
+    /// stops a key from expiring
+    pub fn clear(&mut self, key: &str) {
+        if let Some(at) = self.deadlines.remove(key) {
+            self.queue.remove(&(at, key.to_string()));
+        }
+    }
+
+    /// the time left before a key expires; none if it doesn't expire
+    pub fn remaining(&self, key: &str, now: Instant) -> Option<Duration> {
+        self.deadlines
+            .get(key)
+            .map(|at| at.saturating_duration_since(now))
+    }
+
+    /// checks if any keys are due to expire
+    pub fn is_due(&self, now: Instant) -> bool {
+        self.queue.iter().next().is_some_and(|(at, _)| *at <= now)
+    }
+
+    /// removes and returns the keys due to expire
+    pub fn due(&mut self, now: Instant) -> Vec<String> {
+        let mut keys = Vec::new();
+        while let Some((at, _)) = self.queue.iter().next() {
+            if *at > now {
+                break;
+            }
+            let (_, key) = self.queue.pop_first().unwrap();
+            self.deadlines.remove(&key);
+            keys.push(key);
+        }
+        keys
+    }
+
+    /// the keys expiring within a duration, soonest first, with the time they have left
+    pub fn expiring_within(&self, now: Instant, within: Duration) -> Vec<(&str, Duration)> {
+        self.queue
+            .iter()
+            .take_while(|(at, _)| *at <= now + within)
+            .map(|(at, key)| (key.as_str(), at.saturating_duration_since(now)))
+            .collect()
+    }
+
+    /// every key that expires, soonest first, with the time it has left
+    pub fn pending(&self, now: Instant) -> Vec<(&str, Duration)> {
+        self.queue
+            .iter()
+            .map(|(at, key)| (key.as_str(), at.saturating_duration_since(now)))
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn due_keys_removed_in_deadline_order() {
+        let now = Instant::now();
+        let mut expiry = Expiry::default();
+        expiry.set("a", now + Duration::from_secs(10));
+        expiry.set("b", now + Duration::from_secs(5));
+        expiry.set("c", now + Duration::from_secs(100));
+        expiry.set("c", now + Duration::from_secs(1));
+        expiry.set("d", now + Duration::from_secs(2));
+        expiry.clear("d");
+        assert_eq!(Some(Duration::from_secs(10)), expiry.remaining("a", now));
+        assert_eq!(None, expiry.remaining("d", now));
+        let soon = expiry.expiring_within(now, Duration::from_secs(5));
+        assert_eq!(
+            vec![("c", Duration::from_secs(1)), ("b", Duration::from_secs(5))],
+            soon
+        );
+        assert_eq!(3, expiry.pending(now).len());
+        assert!(!expiry.is_due(now));
+        assert!(expiry.due(now).is_empty());
+        assert!(expiry.is_due(now + Duration::from_secs(6)));
+        let keys = expiry.due(now + Duration::from_secs(6));
+        assert_eq!(vec!["c".to_string(), "b".to_string()], keys);
+        assert_eq!(None, expiry.remaining("b", now));
+        assert_eq!(
+            Some(Duration::ZERO),
+            expiry.remaining("a", now + Duration::from_secs(20))
+        );
+    }
+}
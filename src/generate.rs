@@ -0,0 +1,215 @@
+use crate::err::Error;
+use crate::json_ops::{format_date, parse_date, Json, JsonObj};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+/// the most rows a single generate cmd makes
+pub const MAX_GENERATED_ROWS: usize = 1_000_000;
+
+const NAMES: &[&str] = &[
+    "james", "ania", "misha", "anna", "olivia", "noah", "amelia", "oliver", "isla", "george",
+    "ava", "arthur", "mia", "leo", "ivy", "harry", "freya", "oscar", "lily", "jack",
+];
+
+/// How a field of the generated rows gets its value
+#[derive(Clone, Debug, PartialEq)]
+enum Gen {
+    /// an integer within an inclusive range, as `{"$int": [1, 10]}`
+    Int(i64, i64),
+    /// a float within a range, as `{"$float": [0, 1]}`
+    Float(f64, f64),
+    /// a first name, as `{"$name": null}`
+    Name,
+    /// a `yyyy-mm-dd` date within an inclusive range, as `{"$date": ["2020-01-01", "2021-01-01"]}`
+    Date(i64, i64),
+    /// one of the values, as `{"$choice": ["a", "b"]}`
+    Choice(Vec<Json>),
+    /// the number of the row counting from a start, as `{"$seq": 1}`
+    Seq(i64),
+    Obj(Vec<(String, Gen)>),
+    Arr(Vec<Gen>),
+    Lit(Json),
+}
+
+impl Gen {
+    fn parse(val: &Json) -> Result<Self, Error> {
+        let obj = match val {
+            Json::Object(obj) => obj,
+            Json::Array(vals) => {
+                return vals
+                    .iter()
+                    .map(Gen::parse)
+                    .collect::<Result<_, _>>()
+                    .map(Gen::Arr)
+            }
+            val => return Ok(Gen::Lit(val.clone())),
+        };
+        let spec = obj
+            .iter()
+            .next()
+            .filter(|(name, _)| obj.len() == 1 && name.starts_with('$'));
+        let (name, arg) = match spec {
+            Some(spec) => spec,
+            None => {
+                let fields = obj
+                    .iter()
+                    .map(|(key, val)| Ok((key.clone(), Gen::parse(val)?)));
+                return fields.collect::<Result<_, _>>().map(Gen::Obj);
+            }
+        };
+        let bad_arg = || Error::BadArg(val.clone());
+        let bounds = || match arg.as_array().map(Vec::as_slice) {
+            Some([lo, hi]) => Ok((lo, hi)),
+            _ => Err(bad_arg()),
+        };
+        let gen = match name.as_str() {
+            "$int" => {
+                let (lo, hi) = bounds()?;
+                Gen::Int(
+                    lo.as_i64().ok_or_else(bad_arg)?,
+                    hi.as_i64().ok_or_else(bad_arg)?,
+                )
+            }
+            "$float" => {
+                let (lo, hi) = bounds()?;
+                Gen::Float(
+                    lo.as_f64().ok_or_else(bad_arg)?,
+                    hi.as_f64().ok_or_else(bad_arg)?,
+                )
+            }
+            "$name" => Gen::Name,
+            "$date" => {
+                let (lo, hi) = bounds()?;
+                let day = |x: &Json| x.as_str().and_then(parse_date).ok_or_else(bad_arg);
+                Gen::Date(day(lo)?, day(hi)?)
+            }
+            "$choice" => match arg {
+                Json::Array(vals) if !vals.is_empty() => Gen::Choice(vals.clone()),
+                _ => return Err(bad_arg()),
+            },
+            "$seq" => Gen::Seq(arg.as_i64().unwrap_or(0)),
+            _ => return Err(bad_arg()),
+        };
+        match gen {
+            Gen::Int(lo, hi) | Gen::Date(lo, hi) if lo > hi => Err(bad_arg()),
+            Gen::Float(lo, hi) if lo > hi => Err(bad_arg()),
+            gen => Ok(gen),
+        }
+    }
+
+    fn gen(&self, row: usize, rng: &mut Rng) -> Json {
+        match self {
+            Gen::Int(lo, hi) => Json::from(rng.between(*lo, *hi)),
+            Gen::Float(lo, hi) => Json::from(lo + rng.next_f64() * (hi - lo)),
+            Gen::Name => Json::from(NAMES[rng.below(NAMES.len())]),
+            Gen::Date(lo, hi) => Json::from(format_date(rng.between(*lo, *hi))),
+            Gen::Choice(vals) => vals[rng.below(vals.len())].clone(),
+            Gen::Seq(start) => Json::from(start + row as i64),
+            Gen::Obj(fields) => Json::Object(
+                fields
+                    .iter()
+                    .map(|(key, gen)| (key.clone(), gen.gen(row, rng)))
+                    .collect::<JsonObj>(),
+            ),
+            Gen::Arr(gens) => gens.iter().map(|gen| gen.gen(row, rng)).collect(),
+            Gen::Lit(val) => val.clone(),
+        }
+    }
+}
+
+/// A xorshift generator; good enough for test data, not for anything secret
+struct Rng(u64);
+
+impl Rng {
+    fn next_u64(&mut self) -> u64 {
+        self.0 ^= self.0 << 13;
+        self.0 ^= self.0 >> 7;
+        self.0 ^= self.0 << 17;
+        self.0
+    }
+
+    fn next_f64(&mut self) -> f64 {
+        (self.next_u64() >> 11) as f64 / (1u64 << 53) as f64
+    }
+
+    fn below(&mut self, n: usize) -> usize {
+        (self.next_u64() % n as u64) as usize
+    }
+
+    fn between(&mut self, lo: i64, hi: i64) -> i64 {
+        let span = (hi as i128 - lo as i128 + 1) as u128;
+        (lo as i128 + (self.next_u64() as u128 % span) as i128) as i64
+    }
+}
+
+/// a seed taken from the clock, for rows generated without one
+pub fn clock_seed() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_nanos() as u64)
+        .unwrap_or(0)
+}
+
+/// n rows made from a template, whose `{"$int": ..}`, `{"$float": ..}`, `{"$name": ..}`,
+/// `{"$date": ..}`, `{"$choice": ..}` and `{"$seq": ..}` values are generated for each
+/// row; any other value is copied. The same seed makes the same rows
+pub fn generate_rows(template: &Json, n: usize, seed: Option<u64>) -> Result<Vec<Json>, Error> {
+    if n > MAX_GENERATED_ROWS {
+        return Err(Error::BadArg(Json::from(n)));
+    }
+    let gen = Gen::parse(template)?;
+    let seed = seed.unwrap_or_else(clock_seed);
+    // xorshift never leaves the zero state
+    let mut rng = Rng(seed | 1);
+    Ok((0..n).map(|row| gen.gen(row, &mut rng)).collect())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    #[test]
+    fn rows_follow_template() {
+        let template = json!({
+            "id": {"$seq": 1},
+            "name": {"$name": null},
+            "qty": {"$int": [1, 3]},
+            "price": {"$float": [0.5, 2.5]},
+            "day": {"$date": ["2020-02-28", "2020-03-01"]},
+            "tier": {"$choice": ["gold", "silver"]},
+            "tags": [{"$choice": ["a"]}, "fixed"],
+            "shop": {"city": "London"},
+        });
+        let rows = generate_rows(&template, 100, Some(7)).unwrap();
+        assert_eq!(rows, generate_rows(&template, 100, Some(7)).unwrap());
+        for (i, row) in rows.iter().enumerate() {
+            assert_eq!(json!(i + 1), row["id"]);
+            assert!(NAMES.contains(&row["name"].as_str().unwrap()));
+            assert!((1..=3).contains(&row["qty"].as_i64().unwrap()));
+            assert!((0.5..2.5).contains(&row["price"].as_f64().unwrap()));
+            let days = ["2020-02-28", "2020-02-29", "2020-03-01"];
+            assert!(days.contains(&row["day"].as_str().unwrap()));
+            assert_eq!(json!(["a", "fixed"]), row["tags"]);
+            assert_eq!(json!({"city": "London"}), row["shop"]);
+        }
+    }
+
+    #[test]
+    fn bad_templates_rejected() {
+        for template in [
+            json!({"$int": [3, 1]}),
+            json!({"$int": [1]}),
+            json!({"$choice": []}),
+            json!({"$date": ["2020-01-01", "soon"]}),
+            json!({"a": {"$uuid": null}}),
+        ] {
+            assert!(
+                generate_rows(&template, 1, Some(1)).is_err(),
+                "{}",
+                template
+            );
+        }
+        let too_many = MAX_GENERATED_ROWS + 1;
+        assert!(generate_rows(&json!(1), too_many, None).is_err());
+    }
+}
@@ -0,0 +1,106 @@
+//! Golden-file tests of the query DSL
+//!
+//! Each `tests/golden/fixtures/<key>.json` is loaded under `<key>`, then every
+//! `tests/golden/queries/<name>.query.json` cmd is evaluated and compared with
+//! `<name>.expected.json`. Failed cmds are expected as `{"error": "<message>"}`.
+//! Run with `MEMSON_BLESS=1` to rewrite the expected files from the current output.
+
+use crate::cmd::Cmd;
+use crate::inmem::InMemDb;
+use crate::json_ops::Json;
+use serde_json::json;
+use std::env;
+use std::fs;
+use std::path::{Path, PathBuf};
+
+const QUERY_EXT: &str = ".query.json";
+const EXPECTED_EXT: &str = ".expected.json";
+
+fn golden_dir() -> PathBuf {
+    Path::new(env!("CARGO_MANIFEST_DIR"))
+        .join("tests")
+        .join("golden")
+}
+
+fn read_json(path: &Path) -> Json {
+    let text = fs::read_to_string(path).unwrap_or_else(|e| panic!("{}: {}", path.display(), e));
+    serde_json::from_str(&text).unwrap_or_else(|e| panic!("{}: {}", path.display(), e))
+}
+
+/// the json files of a directory sorted by name
+fn json_files(dir: &Path) -> Vec<PathBuf> {
+    let mut paths: Vec<PathBuf> = fs::read_dir(dir)
+        .unwrap_or_else(|e| panic!("{}: {}", dir.display(), e))
+        .map(|entry| entry.unwrap().path())
+        .filter(|path| path.extension().is_some_and(|ext| ext == "json"))
+        .collect();
+    paths.sort();
+    paths
+}
+
+/// an in-memory db holding every fixture dataset
+fn load_fixtures(dir: &Path) -> InMemDb {
+    let mut db = InMemDb::new();
+    for path in json_files(dir) {
+        let key = path.file_stem().unwrap().to_string_lossy().to_string();
+        db.set(key, read_json(&path));
+    }
+    db
+}
+
+/// evaluates a query file against a fresh copy of the fixtures
+fn run(fixtures: &Path, path: &Path) -> Json {
+    let cmd = match Cmd::parse(read_json(path)) {
+        Ok(cmd) => cmd,
+        Err(err) => return json!({ "error": err.to_string() }),
+    };
+    let mut db = load_fixtures(fixtures);
+    match db.eval(cmd) {
+        Ok(val) => val,
+        Err(err) => json!({ "error": err.to_string() }),
+    }
+}
+
+#[test]
+fn golden_queries() {
+    let dir = golden_dir();
+    let fixtures = dir.join("fixtures");
+    let bless = env::var_os("MEMSON_BLESS").is_some();
+    let mut failures = Vec::new();
+    let queries = json_files(&dir.join("queries"));
+    for path in &queries {
+        let name = path.file_name().unwrap().to_string_lossy();
+        let name = match name.strip_suffix(QUERY_EXT) {
+            Some(name) => name.to_string(),
+            None => continue,
+        };
+        let expected_path = path.with_file_name(format!("{}{}", name, EXPECTED_EXT));
+        let actual = run(&fixtures, path);
+        if bless {
+            let text = serde_json::to_string_pretty(&actual).unwrap() + "\n";
+            fs::write(&expected_path, text).unwrap();
+            continue;
+        }
+        if !expected_path.exists() {
+            failures.push(format!("{}: missing {}", name, expected_path.display()));
+            continue;
+        }
+        let expected = read_json(&expected_path);
+        if expected != actual {
+            failures.push(format!(
+                "{}:\n  expected: {}\n  actual:   {}",
+                name, expected, actual
+            ));
+        }
+    }
+    assert!(
+        !queries.is_empty(),
+        "no golden queries in {}",
+        dir.display()
+    );
+    assert!(
+        failures.is_empty(),
+        "golden queries failed:\n{}",
+        failures.join("\n")
+    );
+}
@@ -0,0 +1,169 @@
+use crate::cmd::Traverse;
+use crate::db::{tag_row, ID_KEY};
+use crate::err::Error;
+use crate::inmem::InMemDb;
+use crate::json_ops::{json_path, Json};
+use crate::Res;
+use serde_json::json;
+use std::collections::{HashMap, HashSet};
+
+/// A row linked by the edge field of another row, as its key and id
+type Link<'a> = (&'a str, &'a Json);
+
+/// The rows of the keys visited by a traversal by their ids, indexed as each key is
+/// first linked to
+struct Rows<'a> {
+    db: &'a InMemDb,
+    by_id: HashMap<&'a str, HashMap<String, &'a Json>>,
+}
+
+impl<'a> Rows<'a> {
+    /// the row of a key with an id; none if the key isn't an array or lacks the row
+    fn get(&mut self, (key, id): Link<'a>) -> Option<&'a Json> {
+        let db = self.db;
+        let rows = self.by_id.entry(key).or_insert_with(|| {
+            let rows = db.get(key).ok().and_then(Json::as_array);
+            rows.into_iter()
+                .flatten()
+                .filter_map(|row| Some((row.get(ID_KEY)?.to_string(), row)))
+                .collect()
+        });
+        rows.get(&id.to_string()).copied()
+    }
+}
+
+/// the links held by the edge field of a row of a key
+fn links<'a>(key: &'a str, row: &'a Json, edge: &str) -> Vec<Link<'a>> {
+    let link = |val: &'a Json| match val {
+        Json::Null => None,
+        Json::Object(obj) => match (obj.get("key"), obj.get("id")) {
+            (Some(Json::String(key)), Some(id)) => Some((key.as_str(), id)),
+            _ => None,
+        },
+        id => Some((key, id)),
+    };
+    match json_path(row, edge) {
+        Some(Json::Array(vals)) => vals.iter().filter_map(link).collect(),
+        Some(val) => link(val).into_iter().collect(),
+        None => Vec::new(),
+    }
+}
+
+/// the subgraph reached by following the links of the rows of a key, breadth first out
+/// to the depth of the traversal. Nodes are the rows reached, each once, tagged with their
+/// key in `_src`; edges are the links followed, as `{"from": .., "to": ..}` of
+/// `{"key": .., "id": ..}`. Links to missing rows are skipped
+pub fn traverse(db: &InMemDb, t: &Traverse) -> Res {
+    let from = db.get(&t.from)?.as_array().ok_or(Error::ExpectedArr)?;
+    let mut rows = Rows {
+        db,
+        by_id: HashMap::new(),
+    };
+    let mut frontier: Vec<(Link, &Json)> = match &t.start {
+        Some(ids) => ids
+            .iter()
+            .filter_map(|id| Some(((t.from.as_str(), id), rows.get((&t.from, id))?)))
+            .collect(),
+        None => from
+            .iter()
+            .filter_map(|row| Some(((t.from.as_str(), row.get(ID_KEY)?), row)))
+            .collect(),
+    };
+    let mut seen = HashSet::new();
+    frontier.retain(|((key, id), _)| seen.insert((*key, id.to_string())));
+    let node = |(key, id): Link| json!({"key": key, "id": id});
+    let (mut nodes, mut edges) = (Vec::new(), Vec::new());
+    for depth in 0..=t.depth {
+        let mut next = Vec::new();
+        for (at, row) in frontier {
+            nodes.push(tag_row(at.0, row));
+            if depth == t.depth {
+                continue;
+            }
+            for to in links(at.0, row, &t.edge) {
+                let linked = match rows.get(to) {
+                    Some(linked) => linked,
+                    None => continue,
+                };
+                edges.push(json!({"from": node(at), "to": node(to)}));
+                if seen.insert((to.0, to.1.to_string())) {
+                    next.push((to, linked));
+                }
+            }
+        }
+        frontier = next;
+    }
+    Ok(json!({"nodes": nodes, "edges": edges}))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn social() -> InMemDb {
+        let mut db = InMemDb::new();
+        db.set(
+            "users",
+            json!([
+                {"_id": 1, "name": "ann", "friends": [2, 3], "team": {"key": "teams", "id": "a"}},
+                {"_id": 2, "name": "bob", "friends": [1, 4]},
+                {"_id": 3, "name": "cat", "friends": 9},
+                {"_id": 4, "name": "dan", "friends": [1]},
+            ]),
+        );
+        db.set("teams", json!([{"_id": "a", "name": "core"}]));
+        db
+    }
+
+    fn names(graph: &Json) -> Vec<&str> {
+        let nodes = graph["nodes"].as_array().unwrap();
+        nodes.iter().map(|x| x["name"].as_str().unwrap()).collect()
+    }
+
+    #[test]
+    fn links_followed_breadth_first_to_depth() {
+        let db = social();
+        let t = |depth, start| Traverse {
+            from: "users".to_string(),
+            edge: "friends".to_string(),
+            depth,
+            start: Some(vec![start]),
+        };
+        let graph = traverse(&db, &t(1, json!(1))).unwrap();
+        assert_eq!(vec!["ann", "bob", "cat"], names(&graph));
+        assert_eq!(json!("users"), graph["nodes"][1]["_src"]);
+        let exp = json!([
+            {"from": {"key": "users", "id": 1}, "to": {"key": "users", "id": 2}},
+            {"from": {"key": "users", "id": 1}, "to": {"key": "users", "id": 3}},
+        ]);
+        assert_eq!(exp, graph["edges"]);
+        let graph = traverse(&db, &t(2, json!(1))).unwrap();
+        assert_eq!(vec!["ann", "bob", "cat", "dan"], names(&graph));
+        assert_eq!(4, graph["edges"].as_array().unwrap().len());
+        let graph = traverse(&db, &t(0, json!(3))).unwrap();
+        assert_eq!(vec!["cat"], names(&graph));
+        assert_eq!(json!([]), graph["edges"]);
+    }
+
+    #[test]
+    fn links_to_other_keys_followed() {
+        let db = social();
+        let t = Traverse {
+            from: "users".to_string(),
+            edge: "team".to_string(),
+            depth: 3,
+            start: None,
+        };
+        let graph = traverse(&db, &t).unwrap();
+        assert_eq!(vec!["ann", "bob", "cat", "dan", "core"], names(&graph));
+        assert_eq!(json!("teams"), graph["nodes"][4]["_src"]);
+        let missing = Traverse {
+            from: "groups".to_string(),
+            ..t
+        };
+        assert_eq!(
+            Err(Error::BadKey("groups".to_string())),
+            traverse(&db, &missing)
+        );
+    }
+}
@@ -0,0 +1,172 @@
+use crate::index::IndexKey;
+use std::cmp::Ordering;
+
+/// the buckets of the histogram of an indexed field
+pub const HISTOGRAM_BUCKETS: usize = 16;
+/// how many times the rows of an even bucket a bucket may grow to through appended rows
+/// before the histogram is rebuilt
+const MAX_SKEW: usize = 2;
+
+/// A bucket of a histogram: the rows whose values are at most its bound, and above the
+/// bound of the bucket before it
+#[derive(Clone, Debug, PartialEq)]
+struct Bucket {
+    upper: IndexKey,
+    rows: usize,
+    distinct: usize,
+}
+
+/// An equi-depth histogram of the values of an indexed field, where each bucket holds
+/// about as many rows as the others, so the rows passing a filter can be estimated
+/// without reading the index
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct Histogram {
+    buckets: Vec<Bucket>,
+    rows: usize,
+}
+
+impl Histogram {
+    /// the histogram of the values of an index, in order, with the rows holding each
+    pub fn build<'a, I>(values: I) -> Self
+    where
+        I: IntoIterator<Item = (&'a IndexKey, usize)>,
+        I::IntoIter: Clone,
+    {
+        let values = values.into_iter();
+        let rows: usize = values.clone().map(|(_, n)| n).sum();
+        let depth = (rows / HISTOGRAM_BUCKETS).max(1);
+        let mut buckets: Vec<Bucket> = Vec::new();
+        for (key, n) in values {
+            match buckets.last_mut() {
+                // values as frequent as a whole bucket get their own, so they don't skew
+                // the estimates of the values sharing it
+                Some(last) if last.rows < depth && n < depth => {
+                    last.upper = key.clone();
+                    last.rows += n;
+                    last.distinct += 1;
+                }
+                _ => buckets.push(Bucket {
+                    upper: key.clone(),
+                    rows: n,
+                    distinct: 1,
+                }),
+            }
+        }
+        Histogram { buckets, rows }
+    }
+
+    /// the rows counted
+    pub fn rows(&self) -> usize {
+        self.rows
+    }
+
+    /// counts a row appended with a value, whether or not other rows hold it. False once
+    /// the buckets are too uneven to estimate well, so the histogram must be rebuilt
+    pub fn add(&mut self, key: &IndexKey, new_value: bool) -> bool {
+        self.rows += 1;
+        let i = self
+            .buckets
+            .iter()
+            .position(|x| *key <= x.upper)
+            .unwrap_or_else(|| self.buckets.len().saturating_sub(1));
+        let bucket = match self.buckets.get_mut(i) {
+            Some(bucket) => bucket,
+            None => {
+                self.buckets.push(Bucket {
+                    upper: key.clone(),
+                    rows: 1,
+                    distinct: 1,
+                });
+                return true;
+            }
+        };
+        if *key > bucket.upper {
+            bucket.upper = key.clone();
+        }
+        bucket.rows += 1;
+        bucket.distinct += usize::from(new_value);
+        let depth = (self.rows / HISTOGRAM_BUCKETS).max(1);
+        bucket.rows <= depth * MAX_SKEW || self.buckets.len() < HISTOGRAM_BUCKETS
+    }
+
+    /// the estimated fraction of the rows counted equal to a value, if the ordering is
+    /// equal, or at least or at most it, if greater or less
+    pub fn selectivity(&self, op: Ordering, key: &IndexKey) -> f64 {
+        if self.rows == 0 {
+            return 0.0;
+        }
+        let i = self.buckets.iter().position(|x| *key <= x.upper);
+        let rows = match (op, i) {
+            (_, None) if op != Ordering::Less => 0.0,
+            (_, None) => self.rows as f64,
+            (Ordering::Equal, Some(i)) => {
+                let x = &self.buckets[i];
+                x.rows as f64 / x.distinct.max(1) as f64
+            }
+            (Ordering::Greater, Some(i)) => {
+                let above: usize = self.buckets[i + 1..].iter().map(|x| x.rows).sum();
+                above as f64 + self.within(i, key, op)
+            }
+            (Ordering::Less, Some(i)) => {
+                let below: usize = self.buckets[..i].iter().map(|x| x.rows).sum();
+                below as f64 + self.within(i, key, op)
+            }
+        };
+        (rows / self.rows as f64).min(1.0)
+    }
+
+    /// the rows of the bucket of a value estimated within the range of the ordering;
+    /// half, as values spread evenly within a bucket, unless the value is the bound or
+    /// below the only value of the bucket
+    fn within(&self, i: usize, key: &IndexKey, op: Ordering) -> f64 {
+        let x = &self.buckets[i];
+        match (x.upper == *key, x.distinct, op) {
+            (true, _, Ordering::Greater) | (true, _, Ordering::Equal) => {
+                x.rows as f64 / x.distinct.max(1) as f64
+            }
+            (true, _, Ordering::Less) => x.rows as f64,
+            (false, 1, Ordering::Greater) => x.rows as f64,
+            (false, 1, _) => 0.0,
+            (false, _, _) => x.rows as f64 / 2.0,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn num(x: usize) -> IndexKey {
+        IndexKey::Num(x as f64)
+    }
+
+    #[test]
+    fn buckets_hold_even_rows() {
+        let keys: Vec<IndexKey> = (0..100).map(num).collect();
+        let hist = Histogram::build(keys.iter().map(|x| (x, 2)));
+        assert_eq!(200, hist.rows());
+        assert_eq!(HISTOGRAM_BUCKETS + 1, hist.buckets.len());
+        assert!(hist.buckets.iter().all(|x| x.rows <= 14));
+        let close = |x: f64, y: f64| (x - y).abs() < 0.06;
+        assert!(close(0.01, hist.selectivity(Ordering::Equal, &num(42))));
+        assert!(close(0.5, hist.selectivity(Ordering::Greater, &num(50))));
+        assert!(close(0.1, hist.selectivity(Ordering::Less, &num(10))));
+        assert_eq!(0.0, hist.selectivity(Ordering::Greater, &num(500)));
+        assert_eq!(1.0, hist.selectivity(Ordering::Less, &num(500)));
+    }
+
+    #[test]
+    fn appended_rows_counted_until_skewed() {
+        let keys: Vec<IndexKey> = (0..32).map(num).collect();
+        let mut hist = Histogram::build(keys.iter().map(|x| (x, 1)));
+        assert!(hist.add(&num(40), true));
+        assert_eq!(Some(&num(40)), hist.buckets.last().map(|x| &x.upper));
+        let even = (0..10).all(|_| hist.add(&num(41), false));
+        assert!(!even);
+        assert_eq!(Some(&num(41)), hist.buckets.last().map(|x| &x.upper));
+        assert_eq!(
+            0.0,
+            Histogram::default().selectivity(Ordering::Equal, &num(1))
+        );
+    }
+}
@@ -0,0 +1,370 @@
+use crate::cmd::Cmd;
+use crate::histogram::Histogram;
+use crate::json_ops::{json_path, typed, Json};
+use std::cmp::Ordering;
+use std::collections::BTreeMap;
+use std::ops::Bound;
+
+/// A scalar json value that can be ordered; values of different types are ordered by type
+#[derive(Clone, Debug)]
+pub enum IndexKey {
+    Null,
+    Bool(bool),
+    Num(f64),
+    Str(String),
+}
+
+impl IndexKey {
+    /// the key of a json value; arrays and objects aren't indexed
+    pub fn from_json(val: &Json) -> Option<Self> {
+        match val {
+            Json::Null => Some(IndexKey::Null),
+            Json::Bool(b) => Some(IndexKey::Bool(*b)),
+            Json::Number(n) => n.as_f64().map(IndexKey::Num),
+            Json::String(s) => Some(IndexKey::Str(s.clone())),
+            Json::Array(_) | Json::Object(_) => None,
+        }
+    }
+
+    fn rank(&self) -> u8 {
+        match self {
+            IndexKey::Null => 0,
+            IndexKey::Bool(_) => 1,
+            IndexKey::Num(_) => 2,
+            IndexKey::Str(_) => 3,
+        }
+    }
+
+    /// checks if both keys are of the same json type
+    fn same_type(&self, other: &IndexKey) -> bool {
+        self.rank() == other.rank()
+    }
+}
+
+impl Ord for IndexKey {
+    fn cmp(&self, other: &Self) -> Ordering {
+        match (self, other) {
+            (IndexKey::Bool(x), IndexKey::Bool(y)) => x.cmp(y),
+            (IndexKey::Num(x), IndexKey::Num(y)) => x.total_cmp(y),
+            (IndexKey::Str(x), IndexKey::Str(y)) => x.cmp(y),
+            (x, y) => x.rank().cmp(&y.rank()),
+        }
+    }
+}
+
+impl PartialOrd for IndexKey {
+    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl PartialEq for IndexKey {
+    fn eq(&self, other: &Self) -> bool {
+        self.cmp(other) == Ordering::Equal
+    }
+}
+
+impl Eq for IndexKey {}
+
+/// A secondary index of the positions of the rows of a key by the value of a field
+#[derive(Clone, Debug)]
+pub struct Index {
+    field: String,
+    entries: BTreeMap<IndexKey, Vec<usize>>,
+    /// whether any row holds an annotated value, as `{"$date": ..}`, which compares with
+    /// plain values but isn't indexed
+    typed: bool,
+    /// the distribution of the indexed values, to estimate the rows passing filters
+    histogram: Histogram,
+}
+
+impl Index {
+    /// indexes the rows of a json array; any other value has no rows
+    pub fn build<F: Into<String>>(val: &Json, field: F) -> Self {
+        let mut index = Index {
+            field: field.into(),
+            entries: BTreeMap::new(),
+            typed: false,
+            histogram: Histogram::default(),
+        };
+        index.extend(val, 0);
+        index.rebuild_histogram();
+        index
+    }
+
+    /// the field of the rows indexed
+    pub fn field(&self) -> &str {
+        &self.field
+    }
+
+    /// indexes the rows from the start position onwards, after rows are appended
+    pub fn extend(&mut self, val: &Json, start: usize) {
+        let rows = match val {
+            Json::Array(rows) => rows,
+            _ => return,
+        };
+        let mut skewed = false;
+        for (i, row) in rows.iter().enumerate().skip(start) {
+            let val = json_path(row, &self.field);
+            if let Some(key) = val.and_then(IndexKey::from_json) {
+                let new_value = !self.entries.contains_key(&key);
+                if !self.histogram.add(&key, new_value) {
+                    skewed = true;
+                }
+                self.entries.entry(key).or_default().push(i);
+            } else if val.and_then(typed).is_some() {
+                self.typed = true;
+            }
+        }
+        if skewed {
+            self.rebuild_histogram();
+        }
+    }
+
+    /// checks if both indexes hold the same positions for the same values
+    #[cfg(any(test, feature = "invariants"))]
+    pub fn same_entries(&self, other: &Index) -> bool {
+        self.entries == other.entries
+    }
+
+    fn rebuild_histogram(&mut self) {
+        let values = self.entries.iter().map(|(key, rows)| (key, rows.len()));
+        self.histogram = Histogram::build(values);
+    }
+
+    /// the estimated fraction of the indexed rows passing the filter; none if the filter
+    /// cannot be answered by the index
+    pub fn selectivity(&self, filter: &Cmd) -> Option<f64> {
+        let (op, key) = self.indexed_range(filter)?;
+        Some(self.histogram.selectivity(op, &key))
+    }
+
+    /// the positions of the rows that may pass the filter, in row order. None if the
+    /// filter cannot be answered by the index. Candidates are a superset of the matching
+    /// rows, so the filter must still be evaluated against them
+    pub fn candidates(&self, filter: &Cmd) -> Option<Vec<usize>> {
+        let (op, key) = self.indexed_range(filter)?;
+        let range = match op {
+            Ordering::Equal => (Bound::Included(&key), Bound::Included(&key)),
+            Ordering::Greater => (Bound::Included(&key), Bound::Unbounded),
+            Ordering::Less => (Bound::Unbounded, Bound::Included(&key)),
+        };
+        let mut positions: Vec<usize> = self
+            .entries
+            .range::<IndexKey, _>(range)
+            .filter(|(k, _)| k.same_type(&key))
+            .flat_map(|(_, positions)| positions.iter().copied())
+            .collect();
+        positions.sort_unstable();
+        Some(positions)
+    }
+
+    /// the value a filter compares the field to, and whether it keeps the rows equal to
+    /// it, or at least or at most it; none if the filter cannot be answered by the index
+    fn indexed_range(&self, filter: &Cmd) -> Option<(Ordering, IndexKey)> {
+        if self.typed {
+            return None;
+        }
+        let field = self.field.as_str();
+        let (op, val) = match filter {
+            Cmd::Eq(x, y) => indexed_cmp(x, y, field).map(|(val, _)| (Ordering::Equal, val))?,
+            Cmd::Gt(x, y) | Cmd::Gte(x, y) => indexed_cmp(x, y, field)
+                .map(|(val, flip)| (flip_if(Ordering::Greater, flip), val))?,
+            Cmd::Lt(x, y) | Cmd::Lte(x, y) => {
+                indexed_cmp(x, y, field).map(|(val, flip)| (flip_if(Ordering::Less, flip), val))?
+            }
+            _ => return None,
+        };
+        // rows missing the field compare as null, but aren't indexed
+        if val.is_null() {
+            return None;
+        }
+        Some((op, IndexKey::from_json(val)?))
+    }
+}
+
+/// An index built online: the rows of a key are scanned from a snapshot without holding
+/// the db, while the writes to the key since the snapshot are tracked, to bring the index
+/// up to date once it's swapped in
+#[derive(Clone, Debug)]
+pub struct IndexBuild {
+    key: String,
+    field: String,
+    /// the position from which rows were appended since the snapshot; none once the rows
+    /// were otherwise changed
+    appended_from: Option<usize>,
+}
+
+impl IndexBuild {
+    /// a build of the index of a key by a field, from a snapshot of the given value
+    pub fn new<K: Into<String>, F: Into<String>>(key: K, field: F, val: &Json) -> Self {
+        let rows = match val {
+            Json::Array(rows) => Some(rows.len()),
+            _ => None,
+        };
+        IndexBuild {
+            key: key.into(),
+            field: field.into(),
+            appended_from: rows,
+        }
+    }
+
+    /// the key of the rows indexed
+    pub fn key(&self) -> &str {
+        &self.key
+    }
+
+    /// the field of the rows indexed
+    pub fn field(&self) -> &str {
+        &self.field
+    }
+
+    /// tracks a write to the key that appended rows from the given position, or none if
+    /// it changed the rows otherwise
+    pub fn track(&mut self, appended_from: Option<usize>) {
+        self.appended_from = self.appended_from.zip(appended_from).map(|(x, y)| x.min(y));
+    }
+
+    /// brings the index scanned from the snapshot up to date with the current value of
+    /// the key; rows appended since are indexed, and any other change rebuilds the index
+    pub fn finish(&self, mut index: Index, val: &Json) -> Index {
+        match (val, self.appended_from) {
+            (Json::Array(rows), Some(n)) if rows.len() >= n => {
+                index.extend(val, n);
+                index
+            }
+            _ => Index::build(val, self.field.as_str()),
+        }
+    }
+}
+
+fn flip_if(ord: Ordering, flip: bool) -> Ordering {
+    if flip {
+        ord.reverse()
+    } else {
+        ord
+    }
+}
+
+/// the literal a field is compared to, and whether the literal is on the left hand side
+fn indexed_cmp<'a>(x: &'a Cmd, y: &'a Cmd, field: &str) -> Option<(&'a Json, bool)> {
+    match (x, y) {
+        (Cmd::Key(key), Cmd::Json(val)) if key == field => Some((val, false)),
+        (Cmd::Json(val), Cmd::Key(key)) if key == field => Some((val, true)),
+        _ => None,
+    }
+}
+
+/// the field of the rows compared to a literal by a filter
+pub fn filter_field(filter: &Cmd) -> Option<&str> {
+    match filter {
+        Cmd::Eq(x, y) | Cmd::Gt(x, y) | Cmd::Gte(x, y) | Cmd::Lt(x, y) | Cmd::Lte(x, y) => {
+            match (x.as_ref(), y.as_ref()) {
+                (Cmd::Key(key), Cmd::Json(_)) | (Cmd::Json(_), Cmd::Key(key)) => Some(key),
+                _ => None,
+            }
+        }
+        _ => None,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    fn rows() -> Json {
+        json!([
+            { "qty": 2, "name": "a" },
+            { "qty": 10, "name": "b" },
+            { "qty": "ten" },
+            { "name": "c" },
+            { "qty": 4.0 },
+            { "qty": 2 },
+        ])
+    }
+
+    fn parse(val: Json) -> Cmd {
+        Cmd::parse(val).unwrap()
+    }
+
+    #[test]
+    fn numbers_order_before_strings() {
+        assert!(IndexKey::Num(100.0) < IndexKey::Str("1".to_string()));
+        assert!(IndexKey::Null < IndexKey::Bool(false));
+        assert_eq!(IndexKey::Num(1.0), IndexKey::from_json(&json!(1)).unwrap());
+    }
+
+    #[test]
+    fn candidates_of_eq_gt_lt() {
+        let index = Index::build(&rows(), "qty");
+        let eq = parse(json!({"==": [{"key": "qty"}, 2]}));
+        assert_eq!(Some(vec![0, 5]), index.candidates(&eq));
+        let gt = parse(json!({">": [{"key": "qty"}, 4]}));
+        assert_eq!(Some(vec![1, 4]), index.candidates(&gt));
+        let lt = parse(json!({"<": [4, {"key": "qty"}]}));
+        assert_eq!(Some(vec![1, 4]), index.candidates(&lt));
+        let lt = parse(json!({"<": [{"key": "qty"}, 4]}));
+        assert_eq!(Some(vec![0, 4, 5]), index.candidates(&lt));
+        let s = parse(json!({"==": [{"key": "qty"}, "ten"]}));
+        assert_eq!(Some(vec![2]), index.candidates(&s));
+        let other = parse(json!({"==": [{"key": "name"}, "a"]}));
+        assert_eq!(None, index.candidates(&other));
+        let null = parse(json!({"==": [{"key": "qty"}, null]}));
+        assert_eq!(None, index.candidates(&null));
+    }
+
+    #[test]
+    fn annotated_rows_not_answered_by_index() {
+        let mut rows = rows();
+        rows.as_array_mut()
+            .unwrap()
+            .push(json!({ "qty": { "$decimal": "2.0" } }));
+        let eq = parse(json!({"==": [{"key": "qty"}, 2]}));
+        assert_eq!(None, Index::build(&rows, "qty").candidates(&eq));
+    }
+
+    #[test]
+    fn selectivity_estimated_from_histogram() {
+        let rows: Json = (0..100).map(|x| json!({ "qty": x % 50 })).collect();
+        let mut index = Index::build(&rows, "qty");
+        let eq = parse(json!({"==": [{"key": "qty"}, 7]}));
+        assert_eq!(Some(0.02), index.selectivity(&eq));
+        let gt = parse(json!({">=": [{"key": "qty"}, 25]}));
+        assert!((index.selectivity(&gt).unwrap() - 0.5).abs() < 0.05);
+        let other = parse(json!({"==": [{"key": "name"}, "a"]}));
+        assert_eq!(None, index.selectivity(&other));
+        let mut rows = rows.as_array().unwrap().clone();
+        rows.extend((0..100).map(|_| json!({ "qty": 99 })));
+        index.extend(&Json::from(rows), 100);
+        let hot = parse(json!({"==": [{"key": "qty"}, 99]}));
+        assert_eq!(Some(0.5), index.selectivity(&hot));
+    }
+
+    #[test]
+    fn online_build_catches_up_with_writes() {
+        let mut rows = rows();
+        let mut build = IndexBuild::new("orders", "qty", &rows);
+        let index = Index::build(&rows, "qty");
+        rows.as_array_mut().unwrap().push(json!({ "qty": 2 }));
+        build.track(Some(6));
+        let eq = parse(json!({"==": [{"key": "qty"}, 2]}));
+        let index = build.finish(index, &rows);
+        assert_eq!(Some(vec![0, 5, 6]), index.candidates(&eq));
+        rows.as_array_mut().unwrap().remove(0);
+        build.track(None);
+        build.track(Some(6));
+        let index = build.finish(index, &rows);
+        assert_eq!(Some(vec![4, 5]), index.candidates(&eq));
+    }
+
+    #[test]
+    fn extend_indexes_appended_rows() {
+        let mut rows = rows();
+        let mut index = Index::build(&rows, "qty");
+        rows.as_array_mut().unwrap().push(json!({ "qty": 2 }));
+        index.extend(&rows, 6);
+        let eq = parse(json!({"==": [{"key": "qty"}, 2]}));
+        assert_eq!(Some(vec![0, 5, 6]), index.candidates(&eq));
+    }
+}
@@ -1,14 +1,38 @@
-use crate::cmd::{Cmd, QueryCmd, Range};
-use crate::db::{Query, PAGE_SIZE};
+use crate::backend::{query_read_keys, read_keys, written_keys, Backend, SoftTtl};
+#[cfg(feature = "chaos")]
+use crate::chaos::Chaos;
+use crate::cmd::{
+    Backup, Cmd, KeyFilter, KeyRange, KeyScan, Parallelism, Pattern, QueryCmd, Range, Stmt,
+};
+use crate::compress::{compress, decompress, is_compressible, is_dict_encoded};
+use crate::db::{Cancel, Prepared, Query, DEFAULT_LIMIT, PAGE_SIZE};
+use crate::dump::{backup_path, dump, read_backup, write_backup, Format};
 use crate::err::Error;
-use crate::eval::eval_cmd;
-use crate::json::{json_get, Json};
+use crate::eval::{eval_batch, eval_cmd, eval_key, reads_only};
+use crate::eviction::Eviction;
+use crate::expiry::{Expiry, EXPIRING_SOON};
+use crate::index::{Index, IndexBuild};
+use crate::json_ops::{json_duplicates, json_insert, json_size, Json, JsonObj};
 use crate::ondisk::{ivec_to_json, OnDiskDb};
+use crate::pubsub::PubSub;
+use crate::stats::AccessStats;
+use crate::storage::{Cache, Storage};
 use crate::Res;
+use rayon::{ThreadPool, ThreadPoolBuilder};
 use serde_json::json;
-use std::collections::BTreeMap;
+use std::collections::{BTreeMap, BTreeSet, HashMap};
+use std::fmt;
+use std::ops::Bound;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
 
-pub type Cache = BTreeMap<String, Json>;
+/// the default number of rows from which queries are evaluated across threads
+pub const PAR_THRESHOLD: usize = 4096;
+/// the default approximate bytes of rows from which queries are evaluated across threads
+pub const PAR_BYTES: usize = 1 << 24;
+/// the most keys remembered as missing from the backend
+pub const MAX_MISSES: usize = 10_000;
 
 pub fn load_cache(db: &sled::Db) -> Result<Cache, Error> {
     let mut cache = Cache::new();
@@ -16,23 +40,73 @@ pub fn load_cache(db: &sled::Db) -> Result<Cache, Error> {
         let (key, val) = kv.map_err(|_| Error::BadIO)?;
         let key: String = bincode::deserialize(key.as_ref()).map_err(|_| Error::BadIO)?;
         let val: Json = bincode::deserialize(val.as_ref()).map_err(|_| Error::BadIO)?;
-        cache.insert(key, val);
+        cache.insert(key, Arc::new(val));
     }
     Ok(cache)
 }
 
 /// The in-memory database of memson
-#[derive(Debug)]
 pub struct InMemDb {
-    cache: Cache,
+    /// the entries in memory, decompressed
+    cache: Box<dyn Storage>,
+    /// entries compressed at rest, decompressed into the cache when next accessed
+    cold: BTreeMap<String, Vec<u8>>,
+    backend: Option<Box<dyn Backend>>,
+    /// how long a key missing from the backend is remembered as missing
+    negative_ttl: Option<Duration>,
+    /// keys missing from the backend and when they were found missing
+    misses: HashMap<String, Instant>,
+    soft_ttl: Option<SoftTtl>,
+    /// the number of rows from which queries are evaluated across threads
+    par_threshold: usize,
+    /// the approximate bytes of rows from which queries are evaluated across threads
+    par_bytes: usize,
+    /// the threads queries are evaluated on, apart from the global pool; none to share it
+    query_pool: Option<Arc<ThreadPool>>,
+    /// the number of rows returned by queries without selects or a limit
+    default_limit: Option<usize>,
+    stats: AccessStats,
+    /// queries prepared by name
+    prepared: HashMap<String, Prepared>,
+    /// the secondary indexes of the rows of each key
+    indexes: HashMap<String, Vec<Index>>,
+    /// the indexes being built online, by id, tracking the writes to their keys
+    builds: HashMap<u64, IndexBuild>,
+    next_build: u64,
+    /// subscriptions to the changes of entries
+    pubsub: PubSub,
+    /// the deadlines of the keys that expire
+    expiry: Expiry,
+    /// the most bytes the entries may take, and the policy choosing the entries evicted
+    /// beyond it
+    max_memory: Option<(usize, Box<dyn Eviction>)>,
+    /// the bytes beyond which writes growing entries are rejected
+    soft_limit: Option<usize>,
+    /// the keys whose changes are only in memory, which aren't evicted until saved
+    unsaved: BTreeSet<String>,
+    /// the approximate size of each entry, tracked while memory is limited
+    sizes: HashMap<String, usize>,
+    size: usize,
+    /// cancelled once the caller of the cmd being evaluated runs out of time
+    cancel: Cancel,
+    /// the dir backups are written to and restored from; none to refuse backups
+    backup_dir: Option<PathBuf>,
+    #[cfg(feature = "chaos")]
+    chaos: Chaos,
 }
 
-impl InMemDb {
-    /// retrieves the val of the ke/val entry
-    fn key(&self, key: String) -> Result<Json, Error> {
-        self.cache.get(&key).cloned().ok_or(Error::BadKey(key))
+impl fmt::Debug for InMemDb {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("InMemDb")
+            .field("cache", &self.cache)
+            .field("cold", &self.cold.len())
+            .field("backend", &self.backend.is_some())
+            .field("negative_ttl", &self.negative_ttl)
+            .finish()
     }
+}
 
+impl InMemDb {
     /// populate an in memory database from a on disk db
     ///
     pub fn load(on_disk_db: &OnDiskDb) -> Result<Self, Error> {
@@ -45,106 +119,1213 @@ impl InMemDb {
         Ok(inmem_db)
     }
 
-    /// the paginated keys of entried in memson
-    pub fn keys(&self, range: Option<Range>) -> Vec<Json> {
-        if let Some(range) = range {
-            let start = range.start.unwrap_or(0);
-            let size = range.size.unwrap_or(PAGE_SIZE);
-            self.cache
-                .keys()
-                .skip(start)
-                .take(size)
-                .cloned()
-                .map(Json::from)
-                .collect()
+    /// the first page of keys of entries in memson; a requested page comes with the total
+    /// number of keys, as `{"keys": [...], "total": n}`
+    pub fn keys(&self, range: Option<Range>, filter: Option<&KeyFilter>) -> Result<Json, Error> {
+        let keys = self.filtered_keys(filter);
+        match range {
+            Some(range) => {
+                let total = self.filtered_keys(filter).count();
+                let at = range.or_size(PAGE_SIZE).positions(total)?;
+                let page: Vec<Json> = at.pick(keys).map(Json::from).collect();
+                Ok(json!({"keys": page, "total": total}))
+            }
+            None => Ok(keys.take(PAGE_SIZE).map(Json::from).collect()),
+        }
+    }
+
+    /// the keys of entries matching a pattern
+    pub fn matching_keys(&self, pat: &Pattern) -> Vec<String> {
+        self.cache
+            .iter()
+            .map(|(key, _)| key)
+            .filter(|key| pat.is_match(key))
+            .map(String::from)
+            .collect()
+    }
+
+    /// collects the keys a cmd deletes by pattern
+    pub(crate) fn pattern_writes(&self, cmd: &Cmd, out: &mut BTreeSet<String>) {
+        if let Cmd::DeletePattern(pat) = cmd {
+            out.extend(self.matching_keys(pat));
+        }
+        for child in cmd.children() {
+            self.pattern_writes(child, out);
+        }
+    }
+
+    /// the keys of entries whose values pass the filter
+    fn filtered_keys<'a>(&'a self, filter: Option<&'a KeyFilter>) -> impl Iterator<Item = &'a str> {
+        self.cache
+            .iter()
+            .filter(move |(_, val)| filter.is_none_or(|f| f.matches(val)))
+            .map(|(key, _)| key)
+    }
+
+    /// the keys, and optionally values, of entries within a lexicographic range
+    pub fn key_range(&self, range: &KeyRange) -> Result<Vec<Json>, Error> {
+        if let (Some(from), Some(to)) = (&range.from, &range.to) {
+            if from >= to {
+                return Ok(Vec::new());
+            }
+        }
+        let from = range
+            .from
+            .as_deref()
+            .map_or(Bound::Unbounded, Bound::Included);
+        let to = range
+            .to
+            .as_deref()
+            .map_or(Bound::Unbounded, Bound::Excluded);
+        let page = Range {
+            size: range.page.size.or(range.limit),
+            ..range.page.clone()
+        };
+        // the keys within the range are only counted when the page counts from their end
+        let from_end = [page.start, page.end].iter().flatten().any(|i| *i < 0);
+        let len = match from_end {
+            true => self.cache.range(from, to).count(),
+            false => usize::MAX,
+        };
+        let at = page.or_size(PAGE_SIZE).positions(len)?;
+        let entries = self.cache.range(from, to);
+        let f = |(key, val): (&str, &Arc<Json>)| {
+            if range.values.unwrap_or(false) {
+                json!({"key": key, "val": val.as_ref()})
+            } else {
+                Json::from(key)
+            }
+        };
+        if range.reverse.unwrap_or(false) {
+            Ok(at.pick(entries.rev()).map(f).collect())
         } else {
-            self.cache
-                .keys()
-                .take(PAGE_SIZE)
-                .cloned()
-                .map(Json::from)
-                .collect()
+            Ok(at.pick(entries).map(f).collect())
         }
     }
 
+    /// a page of the keys matching a glob pattern after the cursor, and the cursor of the
+    /// next page; null once no keys are left. Only the keys starting with the literal
+    /// prefix of the pattern are read
+    pub fn scan(&self, scan: &KeyScan) -> Json {
+        let prefix = scan.prefix();
+        let from = match &scan.cursor {
+            Some(cursor) if cursor.as_str() >= prefix => Bound::Excluded(cursor.as_str()),
+            _ => Bound::Included(prefix),
+        };
+        let count = scan.count.unwrap_or(PAGE_SIZE);
+        let mut keys = self
+            .cache
+            .range(from, Bound::Unbounded)
+            .map(|(key, _)| key)
+            .take_while(|key| key.starts_with(prefix))
+            .filter(|key| scan.pattern.is_match(key));
+        let page: Vec<&str> = keys.by_ref().take(count).collect();
+        let more = page.len() == count && keys.next().is_some();
+        let cursor = page.last().filter(|_| more);
+        json!({"keys": page, "cursor": cursor})
+    }
+
     /// delete an entry by key and return the previous value if exists
     pub fn delete(&mut self, key: &str) -> Option<Json> {
-        self.cache.remove(key)
+        let _ = self.thaw(key);
+        self.expiry.clear(key);
+        let val = self.cache.remove(key);
+        self.reindex(key);
+        self.resize(key);
+        val.map(unshare)
+    }
+
+    /// indexes the rows of a key by a field, replacing any index of the field
+    pub fn create_index<K: Into<String>, F: Into<String>>(
+        &mut self,
+        key: K,
+        field: F,
+    ) -> Result<(), Error> {
+        let key = key.into();
+        let index = Index::build(self.get(&key)?, field);
+        let indexes = self.indexes.entry(key).or_default();
+        indexes.retain(|x| x.field() != index.field());
+        indexes.push(index);
+        Ok(())
+    }
+
+    /// begins building the index of the rows of a key by a field online; returns the id
+    /// of the build and a snapshot of the value to scan without holding the db. Writes to
+    /// the key are tracked until the build is finished
+    pub fn begin_index<K: Into<String>, F: Into<String>>(
+        &mut self,
+        key: K,
+        field: F,
+    ) -> Result<(u64, Arc<Json>), Error> {
+        let key = key.into();
+        let val = self.get_shared(&key)?;
+        let id = self.next_build;
+        self.next_build += 1;
+        self.builds.insert(id, IndexBuild::new(key, field, &val));
+        Ok((id, val))
+    }
+
+    /// swaps in the index scanned from the snapshot of a build, after bringing it up to
+    /// date with the writes to its key since, replacing any index of the field
+    pub fn finish_index(&mut self, id: u64, index: Index) -> Result<(), Error> {
+        let build = self.builds.remove(&id).ok_or(Error::BadCmd)?;
+        let val = self
+            .cache
+            .get(build.key())
+            .map(Arc::as_ref)
+            .unwrap_or(&Json::Null);
+        let index = build.finish(index, val);
+        let indexes = self.indexes.entry(build.key().to_string()).or_default();
+        indexes.retain(|x| x.field() != index.field());
+        indexes.push(index);
+        Ok(())
+    }
+
+    /// checks if an index of the rows of a key is being built online
+    fn is_building(&self, key: &str) -> bool {
+        self.builds.values().any(|build| build.key() == key)
+    }
+
+    /// tracks a write to a key in the builds of its indexes
+    fn track_builds(&mut self, key: &str, appended_from: Option<usize>) {
+        for build in self.builds.values_mut().filter(|x| x.key() == key) {
+            build.track(appended_from);
+        }
+    }
+
+    /// the index of the rows of a key by a field
+    pub fn index(&self, key: &str, field: &str) -> Option<&Index> {
+        self.indexes
+            .get(key)?
+            .iter()
+            .find(|index| index.field() == field)
+    }
+
+    /// rebuilds the indexes of a key after its value changed
+    fn reindex(&mut self, key: &str) {
+        self.track_builds(key, None);
+        if let Some(indexes) = self.indexes.get_mut(key) {
+            let val = self.cache.get(key).map(Arc::as_ref).unwrap_or(&Json::Null);
+            for index in indexes.iter_mut() {
+                *index = Index::build(val, index.field());
+            }
+        }
+    }
+
+    /// the number of rows of the indexed or subscribed keys about to have rows appended by
+    /// a cmd
+    fn appended_from(&self, cmd: &Cmd, writes: &BTreeSet<String>) -> HashMap<String, usize> {
+        let appended: Vec<&String> = match cmd {
+            Cmd::Insert(_, _) | Cmd::Append(_, _) => writes.iter().collect(),
+            // the rows left in the table moved from are rewritten rather than appended
+            Cmd::MoveRows { from, to, .. } if from != to => vec![to],
+            _ => return HashMap::new(),
+        };
+        appended
+            .into_iter()
+            .filter(|key| {
+                self.indexes.contains_key(*key)
+                    || self.is_building(key)
+                    || self.pubsub.is_subscribed(key)
+            })
+            .filter_map(|key| match self.cache.get(key).map(Arc::as_ref) {
+                Some(Json::Array(rows)) => Some((key.clone(), rows.len())),
+                _ => None,
+            })
+            .collect()
+    }
+
+    /// brings the indexes of the written keys up to date; appended rows are indexed
+    /// without rebuilding
+    fn update_indexes(
+        &mut self,
+        writes: &BTreeSet<String>,
+        appended_from: &HashMap<String, usize>,
+    ) {
+        for key in writes {
+            let appended = match (self.cache.get(key).map(Arc::as_ref), appended_from.get(key)) {
+                (Some(Json::Array(rows)), Some(&n)) if rows.len() >= n => Some(n),
+                _ => None,
+            };
+            self.track_builds(key, appended);
+            let indexes = match self.indexes.get_mut(key) {
+                Some(indexes) => indexes,
+                None => continue,
+            };
+            match (self.cache.get(key).map(Arc::as_ref), appended) {
+                (Some(val), Some(n)) => {
+                    for index in indexes.iter_mut() {
+                        index.extend(val, n);
+                    }
+                }
+                _ => self.reindex(key),
+            }
+        }
     }
 
-    //TODO remove allocations
     /// eval key command that supports nesting
     pub fn eval_key(&self, key: String) -> Res {
-        let mut it = key.split('.');
-        let key = it.next().ok_or_else(|| Error::BadKey(key.clone()))?;
-        let mut val = self.key(key.to_string())?;
-        for key in it {
-            if let Some(v) = json_get(key, &val) {
-                val = v;
-            } else {
-                return Err(Error::BadKey(key.to_string()));
-            }
+        eval_key(self, key)
+    }
+
+    /// the number of rows from which queries are evaluated across threads
+    pub fn par_threshold(&self) -> usize {
+        self.par_threshold
+    }
+
+    /// sets the number of rows from which queries are evaluated across threads
+    pub fn set_par_threshold(&mut self, n: usize) {
+        self.par_threshold = n;
+    }
+
+    /// the approximate bytes of rows from which queries are evaluated across threads
+    pub fn par_bytes(&self) -> usize {
+        self.par_bytes
+    }
+
+    /// sets the approximate bytes of rows from which queries are evaluated across threads
+    pub fn set_par_bytes(&mut self, n: usize) {
+        self.par_bytes = n;
+    }
+
+    /// sets the thresholds given from which queries are evaluated across threads, and
+    /// returns the thresholds in force
+    pub fn tune_parallelism(&mut self, tuning: &Parallelism) -> Json {
+        if let Some(n) = tuning.rows {
+            self.par_threshold = n;
         }
-        Ok(val)
+        if let Some(n) = tuning.bytes {
+            self.par_bytes = n;
+        }
+        json!({"rows": self.par_threshold, "bytes": self.par_bytes})
+    }
+
+    /// evaluates queries on a pool of their own with n threads, so a burst of heavy
+    /// queries leaves the global pool to every other cmd
+    pub fn set_query_threads(&mut self, n: usize) -> Result<(), Error> {
+        let pool = ThreadPoolBuilder::new()
+            .num_threads(n)
+            .thread_name(|i| format!("memson-query-{}", i))
+            .build()
+            .map_err(|_| Error::BadArg(Json::from(n)))?;
+        self.query_pool = Some(Arc::new(pool));
+        Ok(())
+    }
+
+    /// runs f on the query pool, if there is one, so the work it spreads across threads
+    /// stays on the pool
+    pub fn in_query_pool<T, F>(&self, f: F) -> T
+    where
+        T: Send,
+        F: FnOnce() -> T + Send,
+    {
+        match &self.query_pool {
+            Some(pool) => pool.install(f),
+            None => f(),
+        }
+    }
+
+    /// the number of rows returned by queries without selects or a limit; none for all rows
+    pub fn default_limit(&self) -> Option<usize> {
+        self.default_limit
+    }
+
+    /// the token cancelled once the caller of the cmd being evaluated runs out of time
+    pub fn cancel(&self) -> &Cancel {
+        &self.cancel
+    }
+
+    /// sets the token of the caller of the cmds evaluated next, returning the previous one
+    pub fn set_cancel(&mut self, cancel: Cancel) -> Cancel {
+        std::mem::replace(&mut self.cancel, cancel)
+    }
+
+    /// sets the number of rows returned by queries without selects or a limit
+    pub fn set_default_limit(&mut self, limit: Option<usize>) {
+        self.default_limit = limit;
+    }
+
+    /// caps the approximate bytes taken by the entries, evicting entries chosen by the
+    /// policy beyond it. Entries are evicted after the cmd that exceeded the cap, other than
+    /// those it read or wrote or left unsaved, and are read through again if memson has a
+    /// backend
+    pub fn set_max_memory(&mut self, max_bytes: usize, eviction: Box<dyn Eviction>) {
+        self.max_memory = Some((max_bytes, eviction));
+        self.track_sizes();
+        self.evict(&BTreeSet::new());
+    }
+
+    /// keeps the entries of keys whose changes are only in memory from being evicted, as
+    /// they couldn't be loaded again
+    pub fn keep_unsaved<I: IntoIterator<Item = String>>(&mut self, keys: I) {
+        self.unsaved.extend(keys);
+    }
+
+    /// lets every entry be evicted again once the changes in memory are saved, evicting
+    /// those beyond the cap
+    pub fn saved(&mut self) {
+        self.unsaved.clear();
+        self.evict(&BTreeSet::new());
+    }
+
+    /// checks if the entries take more bytes than the cap, as unsaved entries can't be
+    /// evicted
+    pub fn over_max_memory(&self) -> bool {
+        self.max_memory()
+            .is_some_and(|max_bytes| self.size > max_bytes)
+    }
+
+    /// rejects the cmds that can grow entries once their approximate bytes exceed the soft
+    /// limit, while still serving reads and deletes; set below the max memory so writes
+    /// are turned away before entries are evicted
+    pub fn set_soft_limit(&mut self, bytes: usize) {
+        self.soft_limit = Some(bytes);
+        self.track_sizes();
+    }
+
+    /// sets the dir backups are written to and restored from, by file name
+    pub fn set_backup_dir<P: AsRef<Path>>(&mut self, dir: P) {
+        self.backup_dir = Some(dir.as_ref().to_path_buf());
+    }
+
+    /// the path of the backup of a name, inside the backup dir
+    pub fn backup_path(&self, name: &str) -> Result<PathBuf, Error> {
+        match &self.backup_dir {
+            Some(dir) => backup_path(dir, name),
+            None => Err(Error::Disabled("backup".to_string())),
+        }
+    }
+
+    /// checks if the entries take more bytes than the soft limit
+    pub fn under_pressure(&self) -> bool {
+        self.soft_limit.is_some_and(|bytes| self.size > bytes)
+    }
+
+    fn tracks_sizes(&self) -> bool {
+        self.max_memory.is_some() || self.soft_limit.is_some()
+    }
+
+    /// recomputes the size of every entry
+    fn track_sizes(&mut self) {
+        self.sizes.clear();
+        self.size = 0;
+        let keys: Vec<String> = self
+            .cache
+            .iter()
+            .map(|(key, _)| key)
+            .chain(self.cold.keys().map(String::as_str))
+            .map(String::from)
+            .collect();
+        for key in keys {
+            self.resize(&key);
+        }
+    }
+
+    /// the most bytes the entries may take; none if uncapped
+    pub fn max_memory(&self) -> Option<usize> {
+        self.max_memory.as_ref().map(|(max_bytes, _)| *max_bytes)
+    }
+
+    /// the approximate bytes taken by the entries, where compressed entries count their
+    /// compressed bytes
+    pub fn size(&self) -> usize {
+        if self.tracks_sizes() {
+            self.size
+        } else {
+            self.computed_size()
+        }
+    }
+
+    fn computed_size(&self) -> usize {
+        let cached: usize = self.cache.iter().map(|(_, x)| json_size(x)).sum();
+        cached + self.cold.values().map(Vec::len).sum::<usize>()
+    }
+
+    /// updates the tracked size of an entry after it changed
+    fn resize(&mut self, key: &str) {
+        if !self.tracks_sizes() {
+            return;
+        }
+        let size = match (self.cache.get(key).map(Arc::as_ref), self.cold.get(key)) {
+            (Some(val), _) => Some(json_size(val)),
+            (None, Some(data)) => Some(data.len()),
+            (None, None) => None,
+        };
+        let prev = match size {
+            Some(size) => self.sizes.insert(key.to_string(), size),
+            None => self.sizes.remove(key),
+        };
+        self.size = self.size + size.unwrap_or(0) - prev.unwrap_or(0);
+    }
+
+    /// evicts entries other than the kept and unsaved keys until their size is within
+    /// the cap
+    fn evict(&mut self, keep: &BTreeSet<String>) {
+        loop {
+            let unsaved = &self.unsaved;
+            let key = match &mut self.max_memory {
+                Some((max_bytes, eviction)) if self.size > *max_bytes => {
+                    let keys: Vec<&str> = self
+                        .cache
+                        .iter()
+                        .map(|(key, _)| key)
+                        .chain(self.cold.keys().map(String::as_str))
+                        .filter(|key| !keep.contains(*key) && !unsaved.contains(*key))
+                        .collect();
+                    match eviction.victim(&keys, &self.stats) {
+                        Some(i) => keys[i].to_string(),
+                        None => return,
+                    }
+                }
+                _ => return,
+            };
+            self.cache.remove(&key);
+            self.cold.remove(&key);
+            self.reindex(&key);
+            self.resize(&key);
+        }
+    }
+
+    /// the number of entries in memson
+    pub fn len(&self) -> usize {
+        self.cache.len() + self.cold.len()
+    }
+
+    /// checks if memson has no entries
+    pub fn is_empty(&self) -> bool {
+        self.cache.is_empty() && self.cold.is_empty()
     }
 
     /// has checks if the key is contained in memson or not
     pub fn has(&self, key: &str) -> bool {
-        self.cache.contains_key(key)
+        self.cache.contains_key(key) || self.cold.contains_key(key)
     }
 
     /// get a key/val entry; similar to key but takes a reference to a string
     pub fn get(&self, key: &str) -> Result<&Json, Error> {
         self.cache
             .get(key)
+            .map(Arc::as_ref)
             .ok_or_else(|| Error::BadKey(key.to_string()))
     }
 
-    /// get a key/val entry; similar to key but takes a reference to a string
+    /// a shared reference to the value of a key, without copying it
+    pub fn get_shared(&self, key: &str) -> Result<Arc<Json>, Error> {
+        self.cache
+            .get(key)
+            .cloned()
+            .ok_or_else(|| Error::BadKey(key.to_string()))
+    }
+
+    /// get a key/val entry; similar to key but takes a reference to a string. A value
+    /// still shared is copied first
     pub fn get_mut(&mut self, key: &str) -> Result<&mut Json, Error> {
         self.cache
             .get_mut(key)
+            .map(Arc::make_mut)
             .ok_or_else(|| Error::BadKey(key.to_string()))
     }
 
     /// inserts a new key/val entry
     pub fn set<K: Into<String>>(&mut self, key: K, val: Json) -> Option<Json> {
-        self.cache.insert(key.into(), val)
+        let key = key.into();
+        let _ = self.thaw(&key);
+        self.expiry.clear(&key);
+        let prev = self.cache.set(key.clone(), Arc::new(val));
+        self.reindex(&key);
+        self.resize(&key);
+        prev.map(unshare)
+    }
+
+    /// expires a key after a duration, replacing any earlier deadline; false if the key is
+    /// missing. Deadlines are kept in memory only
+    pub fn expire(&mut self, key: &str, ttl: Duration) -> bool {
+        if !self.has(key) {
+            return false;
+        }
+        self.expiry.set(key, Instant::now() + ttl);
+        true
+    }
+
+    /// the time left before a key expires; none if it doesn't expire
+    pub fn ttl(&self, key: &str) -> Result<Option<Duration>, Error> {
+        if !self.has(key) {
+            return Err(Error::BadKey(key.to_string()));
+        }
+        Ok(self.expiry.remaining(key, Instant::now()))
+    }
+
+    /// deletes the entries whose deadline passed, notifying their subscribers; returns the
+    /// keys deleted
+    pub fn expire_due(&mut self) -> Result<Vec<String>, Error> {
+        let keys: BTreeSet<String> = self.expiry.due(Instant::now()).into_iter().collect();
+        if keys.is_empty() {
+            return Ok(Vec::new());
+        }
+        for key in &keys {
+            self.cold.remove(key);
+            self.cache.remove(key);
+            self.stats.remove(key);
+            self.reindex(key);
+            self.resize(key);
+        }
+        self.publish(&keys, &HashMap::new());
+        if self.backend.is_some() {
+            for key in &keys {
+                self.write_through(key)?;
+            }
+        }
+        Ok(keys.into_iter().collect())
     }
 
     /// evaluate a command
     pub fn eval(&mut self, cmd: Cmd) -> Res {
-        eval_cmd(self, cmd)
+        if let Cmd::Batch(cmds) = cmd {
+            return Ok(eval_batch(cmds, |cmd| self.eval(cmd)));
+        }
+        if let Cmd::Restore(path) = cmd {
+            return self.restore_backup(&path);
+        }
+        self.cancel.check()?;
+        self.expire_due()?;
+        if self.under_pressure() && grows_entries(&cmd) {
+            return Err(Error::MemoryPressure);
+        }
+        let mut reads = BTreeSet::new();
+        read_keys(&cmd, &mut reads);
+        let mut writes = BTreeSet::new();
+        written_keys(&cmd, &mut writes);
+        #[cfg(feature = "chaos")]
+        {
+            let keys = reads.union(&writes).cloned().collect();
+            self.chaos.inject(&cmd, &keys)?;
+        }
+        if scans_keys(&cmd) {
+            self.thaw_all()?;
+            self.pattern_writes(&cmd, &mut writes);
+        } else {
+            for key in reads.union(&writes) {
+                self.thaw(key)?;
+            }
+        }
+        self.stats.record(&reads, &writes);
+        if self.backend.is_some() {
+            self.apply_refreshes();
+            for key in &reads {
+                self.read_through(key)?;
+            }
+        }
+        let appended_from = self.appended_from(&cmd, &writes);
+        let res = eval_cmd(self, cmd);
+        // keys missing once the cmd is evaluated have no stats, so hot keys lists only
+        // the entries there are
+        for key in reads.union(&writes) {
+            if !self.has(key) {
+                self.stats.remove(key);
+            }
+        }
+        self.update_indexes(&writes, &appended_from);
+        for key in &writes {
+            self.resize(key);
+        }
+        let res = res.and_then(|val| self.settle(val, &reads, &writes, &appended_from));
+        #[cfg(feature = "invariants")]
+        self.assert_invariants(&writes);
+        res
+    }
+
+    /// publishes, evicts and writes through after a cmd wrote the given keys
+    fn settle(
+        &mut self,
+        val: Json,
+        reads: &BTreeSet<String>,
+        writes: &BTreeSet<String>,
+        appended_from: &HashMap<String, usize>,
+    ) -> Res {
+        self.publish(writes, appended_from);
+        if self.max_memory.is_some() {
+            let keep = reads.union(writes).cloned().collect();
+            self.evict(&keep);
+        }
+        if self.backend.is_some() {
+            for key in writes {
+                self.write_through(key)?;
+            }
+        }
+        Ok(val)
+    }
+
+    /// evaluates the cmds in order, keeping their writes only if every cmd succeeds; on
+    /// failure the written entries are restored and the error returned
+    pub fn tx(&mut self, cmds: Vec<Cmd>) -> Res {
+        let staged = self.stage(&cmds);
+        let mut vals = Vec::with_capacity(cmds.len());
+        for cmd in cmds {
+            match eval_cmd(self, cmd) {
+                Ok(val) => vals.push(val),
+                Err(err) => {
+                    self.restore(staged);
+                    return Err(err);
+                }
+            }
+        }
+        Ok(Json::Array(vals))
+    }
+
+    /// evaluates the statements of a script in order, as a tx, binding the result of each
+    /// named statement for the statements after it; returns the result of the last
+    pub fn script(&mut self, stmts: Vec<Stmt>) -> Res {
+        let cmds: Vec<&Cmd> = stmts.iter().map(|x| &x.cmd).collect();
+        let staged = self.stage(cmds);
+        let mut vars = JsonObj::new();
+        let mut last = Json::Null;
+        for Stmt { name, mut cmd } in stmts {
+            cmd.bind_vars(&vars);
+            let res = self.cancel.check().and_then(|_| eval_cmd(self, cmd));
+            match res {
+                Ok(val) => {
+                    if let Some(name) = name {
+                        vars.insert(name, val.clone());
+                    }
+                    last = val;
+                }
+                Err(err) => {
+                    self.restore(staged);
+                    return Err(err);
+                }
+            }
+        }
+        Ok(last)
+    }
+
+    /// the values of the entries written by the cmds, including those deleted by pattern,
+    /// to restore them if the cmds fail
+    fn stage<'c>(
+        &self,
+        cmds: impl IntoIterator<Item = &'c Cmd>,
+    ) -> Vec<(String, Option<Arc<Json>>)> {
+        let mut writes = BTreeSet::new();
+        for cmd in cmds {
+            written_keys(cmd, &mut writes);
+            self.pattern_writes(cmd, &mut writes);
+        }
+        writes
+            .into_iter()
+            .map(|key| {
+                let val = self.cache.get(&key).cloned();
+                (key, val)
+            })
+            .collect()
+    }
+
+    fn restore(&mut self, staged: Vec<(String, Option<Arc<Json>>)>) {
+        for (key, val) in staged {
+            match val {
+                Some(val) => self.cache.set(key, val),
+                None => self.cache.remove(&key),
+            };
+        }
+    }
+
+    /// aborts if a mutation left memson inconsistent; only built for soak tests
+    #[cfg(feature = "invariants")]
+    fn assert_invariants(&mut self, writes: &BTreeSet<String>) {
+        if writes.is_empty() {
+            return;
+        }
+        let violations = self.invariant_violations(writes);
+        if !violations.is_empty() {
+            panic!("memson invariants violated: {}", violations.join("; "));
+        }
+    }
+
+    /// checks the invariants that must hold after the given keys are written
+    #[cfg(any(test, feature = "invariants"))]
+    pub(crate) fn invariant_violations(&mut self, writes: &BTreeSet<String>) -> Vec<String> {
+        let mut violations = Vec::new();
+        for key in self.misses.keys() {
+            if self.cache.contains_key(key) {
+                violations.push(format!("{} is cached and remembered as missing", key));
+            }
+        }
+        for key in self.cold.keys() {
+            if self.cache.contains_key(key) {
+                violations.push(format!("{} is both cached and compressed", key));
+            }
+        }
+        if self.tracks_sizes() {
+            let size = self.computed_size();
+            if size != self.size {
+                violations.push(format!("size is {} but tracked as {}", size, self.size));
+            }
+        }
+        for key in writes {
+            if self.has(key) && self.stats.get(key).is_none_or(|stats| stats.writes == 0) {
+                violations.push(format!("{} was written without a recorded write", key));
+            }
+            let val = self.cache.get(key).map_or(&Json::Null, Arc::as_ref);
+            for index in self.indexes.get(key).into_iter().flatten() {
+                if !index.same_entries(&Index::build(val, index.field())) {
+                    violations.push(format!("{} has a stale index on {}", key, index.field()));
+                }
+            }
+        }
+        if let Some(backend) = &mut self.backend {
+            for key in writes {
+                match backend.load(key) {
+                    Ok(val) if val.as_ref() == self.cache.get(key).map(Arc::as_ref) => (),
+                    Ok(_) => violations.push(format!("{} differs from the backend", key)),
+                    Err(err) => violations.push(format!("{} failed to load: {}", key, err)),
+                }
+            }
+        }
+        violations
+    }
+
+    /// the faults injected into cmds
+    #[cfg(feature = "chaos")]
+    pub fn chaos_mut(&mut self) -> &mut Chaos {
+        &mut self.chaos
+    }
+
+    /// the subscriptions to the changes of entries
+    pub fn pubsub_mut(&mut self) -> &mut PubSub {
+        &mut self.pubsub
+    }
+
+    /// subscribes to the results of a query, which is evaluated again whenever a key it
+    /// reads is written; responds with the id of the subscriber and the current result
+    pub fn subscribe_query(&mut self, qry: QueryCmd) -> Res {
+        let mut keys = BTreeSet::new();
+        query_read_keys(&qry, &mut keys);
+        let val = Query::from(self, qry.clone()).exec()?;
+        let id = self.pubsub.subscribe_query(qry, keys, val.clone());
+        Ok(json!({"id": id, "val": val}))
+    }
+
+    /// notifies the subscribers of the written keys of their current values, and the
+    /// subscribers of queries reading them of their new results
+    fn publish(&mut self, writes: &BTreeSet<String>, appended_from: &HashMap<String, usize>) {
+        for key in writes {
+            if self.pubsub.is_subscribed(key) {
+                let val = self.cache.get(key).map(Arc::as_ref).unwrap_or(&Json::Null);
+                self.pubsub
+                    .publish(key, val, appended_from.get(key).copied());
+            }
+        }
+        for (id, key, qry) in self.pubsub.queries(writes) {
+            let mut reads = BTreeSet::new();
+            query_read_keys(&qry, &mut reads);
+            for key in &reads {
+                let _ = self.thaw(key);
+            }
+            let val = Query::from(self, qry)
+                .exec()
+                .unwrap_or_else(|err| Json::from(err.to_string()));
+            self.pubsub.publish_result(id, &key, val);
+        }
+    }
+
+    /// checks if a cmd can be evaluated without changing the db: it only reads entries
+    /// already decompressed in memory, and no entries are due to expire or be refreshed
+    pub fn can_share(&self, cmd: &Cmd) -> bool {
+        if !reads_only(cmd) || self.backend.is_some() || self.expiry.is_due(Instant::now()) {
+            return false;
+        }
+        #[cfg(feature = "chaos")]
+        if self.chaos.is_active() {
+            return false;
+        }
+        if scans_keys(cmd) {
+            return self.cold.is_empty();
+        }
+        let mut reads = BTreeSet::new();
+        read_keys(cmd, &mut reads);
+        reads.iter().all(|key| !self.cold.contains_key(key))
+    }
+
+    /// records the keys read by cmds evaluated through a shared db
+    pub fn record_reads(&mut self, reads: &BTreeSet<String>) {
+        let reads = reads.iter().filter(|key| self.has(key)).cloned().collect();
+        self.stats.record(&reads, &BTreeSet::new());
+    }
+
+    /// the access statistics of the keys of memson
+    pub fn stats(&self) -> &AccessStats {
+        &self.stats
+    }
+
+    /// replaces stale entries with the values reloaded in the background
+    fn apply_refreshes(&mut self) {
+        let vals = match &mut self.soft_ttl {
+            Some(soft_ttl) => soft_ttl.drain(),
+            None => return,
+        };
+        for (key, res) in vals {
+            match res {
+                Ok(Some(val)) => {
+                    self.cold.remove(&key);
+                    self.cache.set(key.clone(), Arc::new(val));
+                    self.resize(&key);
+                }
+                Ok(None) => {
+                    self.cold.remove(&key);
+                    self.cache.remove(&key);
+                    self.resize(&key);
+                }
+                Err(_) => (),
+            }
+        }
+    }
+
+    /// loads a missing entry from the backend
+    fn read_through(&mut self, key: &str) -> Result<(), Error> {
+        if self.cache.contains_key(key) {
+            if let Some(soft_ttl) = &mut self.soft_ttl {
+                soft_ttl.refresh_if_stale(key);
+            }
+            return Ok(());
+        }
+        if self.is_known_miss(key) {
+            return Ok(());
+        }
+        if let Some(backend) = &mut self.backend {
+            match backend.load(key)? {
+                Some(val) => {
+                    if let Some(soft_ttl) = &mut self.soft_ttl {
+                        soft_ttl.touch(key);
+                    }
+                    self.cache.set(key.to_string(), Arc::new(val));
+                    self.resize(key);
+                }
+                None if self.negative_ttl.is_some() => self.remember_miss(key),
+                None => (),
+            }
+        }
+        Ok(())
+    }
+
+    /// remembers a key found missing from the backend. Beyond the most misses, the expired
+    /// ones are forgotten, then the oldest, so reads of random missing keys don't grow them
+    fn remember_miss(&mut self, key: &str) {
+        if self.misses.len() >= MAX_MISSES {
+            let ttl = self.negative_ttl.unwrap_or_default();
+            self.misses.retain(|_, at| at.elapsed() < ttl);
+        }
+        if self.misses.len() >= MAX_MISSES {
+            let oldest = self.misses.iter().min_by_key(|(_, at)| **at);
+            if let Some(oldest) = oldest.map(|(key, _)| key.clone()) {
+                self.misses.remove(&oldest);
+            }
+        }
+        self.misses.insert(key.to_string(), Instant::now());
+    }
+
+    /// the number of keys remembered as missing from the backend
+    pub fn known_misses(&self) -> usize {
+        self.misses.len()
+    }
+
+    /// checks if the key was recently found missing from the backend
+    fn is_known_miss(&mut self, key: &str) -> bool {
+        match (self.negative_ttl, self.misses.get(key)) {
+            (Some(ttl), Some(at)) if at.elapsed() < ttl => true,
+            (_, Some(_)) => {
+                self.misses.remove(key);
+                false
+            }
+            _ => false,
+        }
+    }
+
+    /// serve entries loaded from the backend for the ttl, then serve them stale while
+    /// the given backend reloads them in the background
+    pub fn set_soft_ttl(&mut self, ttl: Duration, refresher: Box<dyn Backend>) {
+        self.soft_ttl = Some(SoftTtl::spawn(ttl, refresher));
+    }
+
+    /// caches the entries of a backend, reading missing keys through from it and writing
+    /// changes through to it
+    pub fn set_backend(&mut self, backend: Box<dyn Backend>) {
+        self.backend = Some(backend);
+        self.misses.clear();
+    }
+
+    /// remember keys missing from the backend for the ttl rather than asking it again
+    pub fn set_negative_ttl(&mut self, ttl: Option<Duration>) {
+        self.negative_ttl = ttl;
+        self.misses.clear();
+    }
+
+    /// propagates the current state of an entry to the backend
+    fn write_through(&mut self, key: &str) -> Result<(), Error> {
+        self.misses.remove(key);
+        if let Some(soft_ttl) = &mut self.soft_ttl {
+            soft_ttl.touch(key);
+        }
+        if let Some(backend) = &mut self.backend {
+            match self.cache.get(key).map(Arc::as_ref) {
+                Some(val) => backend.store(key, val)?,
+                None => backend.remove(key)?,
+            }
+        }
+        Ok(())
     }
 
     /// create a new instance of the in-memory database with no entries
     pub fn new() -> Self {
         Self {
-            cache: Cache::new(),
+            cache: Box::new(Cache::new()),
+            cold: BTreeMap::new(),
+            backend: None,
+            negative_ttl: None,
+            misses: HashMap::new(),
+            soft_ttl: None,
+            par_threshold: PAR_THRESHOLD,
+            par_bytes: PAR_BYTES,
+            query_pool: None,
+            default_limit: Some(DEFAULT_LIMIT),
+            stats: AccessStats::default(),
+            prepared: HashMap::new(),
+            indexes: HashMap::new(),
+            builds: HashMap::new(),
+            next_build: 0,
+            pubsub: PubSub::default(),
+            expiry: Expiry::default(),
+            max_memory: None,
+            soft_limit: None,
+            unsaved: BTreeSet::new(),
+            sizes: HashMap::new(),
+            size: 0,
+            cancel: Cancel::default(),
+            backup_dir: None,
+            #[cfg(feature = "chaos")]
+            chaos: Chaos::default(),
+        }
+    }
+
+    /// create an in-memory database over the entries of a storage engine
+    pub fn with_storage(storage: Box<dyn Storage>) -> Self {
+        Self {
+            cache: storage,
+            ..Self::new()
+        }
+    }
+
+    /// create an empty in-memory database that caches the entries of a backend
+    pub fn with_backend(backend: Box<dyn Backend>) -> Self {
+        Self {
+            backend: Some(backend),
+            ..Self::new()
         }
     }
 
+    /// appends rows to the array of a key, creating the key if missing; returns the number
+    /// of rows inserted
+    pub fn insert<K: Into<String>>(&mut self, key: K, rows: Vec<JsonObj>) -> usize {
+        let n = rows.len();
+        let key = key.into();
+        if !self.cache.contains_key(&key) {
+            self.cache
+                .set(key.clone(), Arc::new(Json::Array(Vec::new())));
+        }
+        json_insert(Arc::make_mut(self.cache.get_mut(&key).unwrap()), rows);
+        self.resize(&key);
+        n
+    }
+
     /// retrieves a key/val entry and if not present, it inserts an entry
     pub fn entry<K: Into<String>>(&mut self, key: K) -> &mut Json {
-        self.cache.entry(key.into()).or_insert_with(|| Json::Null)
+        let key = key.into();
+        if !self.cache.contains_key(&key) {
+            self.cache.set(key.clone(), Arc::default());
+        }
+        Arc::make_mut(self.cache.get_mut(&key).unwrap())
+    }
+
+    /// the keys that expire, soonest first, with the time they have left
+    pub fn expiring(&self) -> Vec<(&str, Duration)> {
+        self.expiry.pending(Instant::now())
     }
 
-    /// summary of keys stored and no. of entries
-    pub fn summary(&self) -> Json {
-        let no_entries = Json::from(self.cache.len());
-        let keys: Vec<Json> = self
+    /// summary of keys stored and no. of entries, and the keys expiring soon
+    pub fn summary(&self, filter: Option<&KeyFilter>) -> Json {
+        let no_entries = Json::from(self.len());
+        let keys: Vec<Json> = self.filtered_keys(filter).map(Json::from).collect();
+        let expiring: Vec<Json> = self
+            .expiry
+            .expiring_within(Instant::now(), EXPIRING_SOON)
+            .into_iter()
+            .map(|(key, ttl)| json!({"key": key, "ttl": ttl.as_secs_f64()}))
+            .collect();
+        json!({"no_entries": no_entries, "keys": keys, "expiring": expiring})
+    }
+
+    /// compresses the string and array values of at least the given approximate size,
+    /// other than those of indexed keys; they're decompressed when next accessed. Tables
+    /// have their low cardinality string columns dictionary encoded first
+    pub fn compress(&mut self, min_bytes: usize) -> Result<Json, Error> {
+        let keys: Vec<String> = self
             .cache
-            .keys()
-            .map(|x| Json::String(x.to_string()))
+            .iter()
+            .filter(|(key, val)| {
+                !self.indexes.contains_key(*key) && is_compressible(val, min_bytes)
+            })
+            .map(|(key, _)| key.to_string())
+            .collect();
+        let mut bytes = 0;
+        let mut compressed = 0;
+        let mut dict_encoded = 0;
+        for key in &keys {
+            let val = self.cache.remove(key).unwrap();
+            let data = compress(&val)?;
+            bytes += json_size(&val);
+            compressed += data.len();
+            dict_encoded += usize::from(is_dict_encoded(&data));
+            self.cold.insert(key.clone(), data);
+            self.resize(key);
+        }
+        Ok(json!({
+            "keys": keys.len(),
+            "bytes": bytes,
+            "compressed": compressed,
+            "dictEncoded": dict_encoded,
+        }))
+    }
+
+    /// encodes the entries of the given keys, or of every key, in a binary format; missing
+    /// keys are left out
+    pub fn dump(&mut self, keys: Option<&[String]>, format: Format) -> Result<Vec<u8>, Error> {
+        match keys {
+            Some(keys) => {
+                for key in keys {
+                    self.thaw(key)?;
+                    if self.backend.is_some() {
+                        self.read_through(key)?;
+                    }
+                }
+            }
+            None => self.thaw_all()?,
+        }
+        let entries: Vec<(&str, &Json)> = match keys {
+            Some(keys) => keys
+                .iter()
+                .filter_map(|key| Some((key.as_str(), self.cache.get(key)?.as_ref())))
+                .collect(),
+            None => self
+                .cache
+                .iter()
+                .map(|(key, val)| (key, val.as_ref()))
+                .collect(),
+        };
+        dump(entries, format)
+    }
+
+    /// writes a compressed snapshot of every entry in memory to a file; returns the number
+    /// of entries and the bytes written
+    pub fn backup(&mut self, backup: &Backup) -> Res {
+        let path = self.backup_path(&backup.path)?;
+        if let Some(dir) = &self.backup_dir {
+            std::fs::create_dir_all(dir).map_err(|_| Error::BadIO)?;
+        }
+        self.thaw_all()?;
+        let entries = self.cache.iter().map(|(key, val)| (key, val.as_ref()));
+        let bytes = write_backup(&path, backup.compress, entries)?;
+        Ok(json!({"keys": self.cache.len(), "bytes": bytes}))
+    }
+
+    /// sets the entries of a backup in one tx, as set cmds so they're indexed, published
+    /// and written through; returns the number of entries restored
+    fn restore_backup(&mut self, name: &str) -> Res {
+        let entries = read_backup(&self.backup_path(name)?)?;
+        let n = entries.len();
+        let sets = entries
+            .into_iter()
+            .map(|(key, val)| Cmd::Set(key, Box::new(Cmd::Json(val)), None))
             .collect();
-        json!({"no_entries": no_entries, "keys": keys})
+        self.eval(Cmd::Tx(sets))?;
+        Ok(Json::from(n))
+    }
+
+    /// reports the subtrees repeated across the cached values; compressed values are
+    /// skipped, as compression already removes repetition within them
+    pub fn duplicates(&self, min_bytes: usize) -> Json {
+        json_duplicates(self.cache.iter().map(|(_, x)| x.as_ref()), min_bytes)
+    }
+
+    /// decompresses an entry into the cache
+    fn thaw(&mut self, key: &str) -> Result<(), Error> {
+        if let Some(data) = self.cold.remove(key) {
+            self.cache
+                .set(key.to_string(), Arc::new(decompress(&data)?));
+            self.resize(key);
+        }
+        Ok(())
+    }
+
+    /// decompresses every entry into the cache
+    fn thaw_all(&mut self) -> Result<(), Error> {
+        while let Some(key) = self.cold.keys().next().cloned() {
+            self.thaw(&key)?;
+        }
+        Ok(())
     }
 
     /// execute query
     pub fn query(&self, cmd: QueryCmd) -> Res {
-        let qry = Query::from(&self, cmd);
+        let qry = Query::from(self, cmd);
         qry.exec()
     }
+
+    /// prepares a query under a name, replacing any query prepared under the name
+    pub fn prepare<K: Into<String>>(&mut self, name: K, cmd: QueryCmd) -> Result<(), Error> {
+        self.prepared.insert(name.into(), Prepared::new(cmd)?);
+        Ok(())
+    }
+
+    /// executes a prepared query with the given params
+    pub fn execute(&self, name: &str, params: &JsonObj) -> Res {
+        let prepared = self
+            .prepared
+            .get(name)
+            .ok_or_else(|| Error::BadKey(name.to_string()))?;
+        prepared.bind(self, params)?.exec()
+    }
+}
+
+/// checks if a cmd can grow the entries it writes
+fn grows_entries(cmd: &Cmd) -> bool {
+    match cmd {
+        Cmd::Set(_, _, _)
+        | Cmd::GetSet(_, _)
+        | Cmd::Append(_, _)
+        | Cmd::Push(_, _)
+        | Cmd::Insert(_, _)
+        | Cmd::Merge(_, _)
+        | Cmd::MoveRows { .. }
+        | Cmd::Generate(_)
+        | Cmd::Restore(_) => true,
+        cmd => cmd.children().into_iter().any(grows_entries),
+    }
+}
+
+/// takes a value out of its arc, copying it only if it's still shared
+fn unshare(val: Arc<Json>) -> Json {
+    Arc::try_unwrap(val).unwrap_or_else(|val| (*val).clone())
+}
+
+/// checks if a cmd reads entries beyond those it names, so every entry must be decompressed
+pub(crate) fn scans_keys(cmd: &Cmd) -> bool {
+    match cmd {
+        Cmd::Keys(_, _)
+        | Cmd::KeyRange(_)
+        | Cmd::Scan(_)
+        | Cmd::Traverse(_)
+        | Cmd::Summary(_)
+        | Cmd::Execute(_, _)
+        | Cmd::DeletePattern(_) => true,
+        cmd => cmd.children().into_iter().any(scans_keys),
+    }
 }
 
 impl Default for InMemDb {
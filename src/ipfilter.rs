@@ -0,0 +1,184 @@
+use crate::err::Error;
+use std::collections::HashMap;
+use std::net::IpAddr;
+use std::str::FromStr;
+use std::sync::{Arc, Mutex};
+
+/// An ip address or a CIDR block of addresses, such as `10.0.0.0/8`
+#[derive(Clone, Debug, PartialEq)]
+pub struct IpBlock {
+    addr: IpAddr,
+    prefix: u32,
+}
+
+impl IpBlock {
+    pub fn contains(&self, ip: &IpAddr) -> bool {
+        match (self.addr, ip) {
+            (IpAddr::V4(x), IpAddr::V4(y)) => {
+                let (x, y) = (u32::from(x).into(), u32::from(*y).into());
+                masked(x, 32, self.prefix) == masked(y, 32, self.prefix)
+            }
+            (IpAddr::V6(x), IpAddr::V6(y)) => {
+                let (x, y) = (u128::from(x), u128::from(*y));
+                masked(x, 128, self.prefix) == masked(y, 128, self.prefix)
+            }
+            _ => false,
+        }
+    }
+}
+
+/// the leading prefix bits of an address of the given width
+fn masked(addr: u128, width: u32, prefix: u32) -> u128 {
+    if prefix == 0 {
+        0
+    } else {
+        addr >> (width - prefix)
+    }
+}
+
+impl FromStr for IpBlock {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self, Error> {
+        let bad = || Error::BadArg(s.into());
+        let (addr, prefix) = match s.split_once('/') {
+            Some((addr, prefix)) => (addr, Some(prefix)),
+            None => (s, None),
+        };
+        let addr: IpAddr = addr.parse().map_err(|_| bad())?;
+        let width = if addr.is_ipv4() { 32 } else { 128 };
+        let prefix = match prefix {
+            Some(prefix) => prefix.parse().map_err(|_| bad())?,
+            None => width,
+        };
+        if prefix > width {
+            return Err(bad());
+        }
+        Ok(IpBlock { addr, prefix })
+    }
+}
+
+/// parses a comma separated list of ip blocks
+pub fn parse_blocks(s: &str) -> Result<Vec<IpBlock>, Error> {
+    s.split(',')
+        .map(str::trim)
+        .filter(|x| !x.is_empty())
+        .map(IpBlock::from_str)
+        .collect()
+}
+
+/// Why a client was refused
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Refusal {
+    /// the client's address is denied, or missing from the allowlist
+    Denied,
+    /// the client already has the most requests in flight allowed
+    Busy,
+}
+
+/// Admits the clients allowed by address, up to a number of requests in flight from each
+/// address. Denied addresses are refused even when allowed
+#[derive(Clone, Debug, Default)]
+pub struct IpFilter {
+    /// the blocks clients must be in; any address if empty
+    allow: Vec<IpBlock>,
+    deny: Vec<IpBlock>,
+    max_per_ip: Option<usize>,
+    active: Arc<Mutex<HashMap<IpAddr, usize>>>,
+}
+
+impl IpFilter {
+    pub fn new(allow: Vec<IpBlock>, deny: Vec<IpBlock>, max_per_ip: Option<usize>) -> Self {
+        IpFilter {
+            allow,
+            deny,
+            max_per_ip,
+            active: Arc::default(),
+        }
+    }
+
+    /// checks if the filter can refuse anyone
+    pub fn is_open(&self) -> bool {
+        self.allow.is_empty() && self.deny.is_empty() && self.max_per_ip.is_none()
+    }
+
+    /// admits a client, returning a permit that frees its slot once dropped
+    pub fn admit(&self, ip: IpAddr) -> Result<Permit, Refusal> {
+        let allowed = self.allow.is_empty() || self.allow.iter().any(|x| x.contains(&ip));
+        if !allowed || self.deny.iter().any(|x| x.contains(&ip)) {
+            return Err(Refusal::Denied);
+        }
+        let mut active = self.active.lock().unwrap();
+        let n = active.get(&ip).copied().unwrap_or(0);
+        if self.max_per_ip.is_some_and(|max| n >= max) {
+            return Err(Refusal::Busy);
+        }
+        active.insert(ip, n + 1);
+        Ok(Permit {
+            ip,
+            active: self.active.clone(),
+        })
+    }
+}
+
+/// A slot of a client admitted by an ip filter
+#[derive(Debug)]
+pub struct Permit {
+    ip: IpAddr,
+    active: Arc<Mutex<HashMap<IpAddr, usize>>>,
+}
+
+impl Drop for Permit {
+    fn drop(&mut self) {
+        let mut active = self.active.lock().unwrap();
+        if let Some(n) = active.get_mut(&self.ip) {
+            *n -= 1;
+            if *n == 0 {
+                active.remove(&self.ip);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn ip(s: &str) -> IpAddr {
+        s.parse().unwrap()
+    }
+
+    #[test]
+    fn blocks_contain_addresses() {
+        let block: IpBlock = "10.1.0.0/16".parse().unwrap();
+        assert!(block.contains(&ip("10.1.200.3")));
+        assert!(!block.contains(&ip("10.2.0.1")));
+        assert!(!block.contains(&ip("::1")));
+        let any: IpBlock = "0.0.0.0/0".parse().unwrap();
+        assert!(any.contains(&ip("192.168.1.1")));
+        let v6: IpBlock = "fe80::/10".parse().unwrap();
+        assert!(v6.contains(&ip("fe80::1")));
+        assert!("10.0.0.1"
+            .parse::<IpBlock>()
+            .unwrap()
+            .contains(&ip("10.0.0.1")));
+        assert!("10.0.0.1/33".parse::<IpBlock>().is_err());
+        assert!(parse_blocks("10.0.0.1, nonsense").is_err());
+    }
+
+    #[test]
+    fn allow_deny_and_limit() {
+        let allow = parse_blocks("10.0.0.0/8").unwrap();
+        let deny = parse_blocks("10.0.0.13").unwrap();
+        let filter = IpFilter::new(allow, deny, Some(2));
+        assert_eq!(Refusal::Denied, filter.admit(ip("11.0.0.1")).unwrap_err());
+        assert_eq!(Refusal::Denied, filter.admit(ip("10.0.0.13")).unwrap_err());
+        let first = filter.admit(ip("10.0.0.1")).unwrap();
+        let _second = filter.admit(ip("10.0.0.1")).unwrap();
+        assert_eq!(Refusal::Busy, filter.admit(ip("10.0.0.1")).unwrap_err());
+        assert!(filter.admit(ip("10.0.0.2")).is_ok());
+        drop(first);
+        assert!(filter.admit(ip("10.0.0.1")).is_ok());
+        assert!(IpFilter::default().is_open());
+    }
+}
@@ -0,0 +1,84 @@
+use super::Json;
+use serde_json::json;
+
+/// the json patch (RFC 6902) operations turning one value into another, in the order they
+/// must be applied. Array elements are compared by position; any extra elements are
+/// removed from the end or added after the last
+pub fn json_diff(from: &Json, to: &Json) -> Json {
+    let mut ops = Vec::new();
+    diff_at(&mut String::new(), from, to, &mut ops);
+    Json::Array(ops)
+}
+
+fn diff_at(path: &mut String, from: &Json, to: &Json, ops: &mut Vec<Json>) {
+    match (from, to) {
+        (Json::Object(x), Json::Object(y)) => {
+            for (key, val) in x {
+                with_token(path, key, |path| match y.get(key) {
+                    Some(to) => diff_at(path, val, to, ops),
+                    None => ops.push(json!({"op": "remove", "path": path})),
+                });
+            }
+            for (key, val) in y.iter().filter(|(key, _)| !x.contains_key(*key)) {
+                with_token(path, key, |path| {
+                    ops.push(json!({"op": "add", "path": path, "value": val}))
+                });
+            }
+        }
+        (Json::Array(x), Json::Array(y)) => {
+            for (i, (from, to)) in x.iter().zip(y).enumerate() {
+                with_token(path, &i.to_string(), |path| diff_at(path, from, to, ops));
+            }
+            for i in (y.len()..x.len()).rev() {
+                with_token(path, &i.to_string(), |path| {
+                    ops.push(json!({"op": "remove", "path": path}))
+                });
+            }
+            for (i, val) in y.iter().enumerate().skip(x.len()) {
+                with_token(path, &i.to_string(), |path| {
+                    ops.push(json!({"op": "add", "path": path, "value": val}))
+                });
+            }
+        }
+        (from, to) if from != to => {
+            ops.push(json!({"op": "replace", "path": path, "value": to}));
+        }
+        _ => (),
+    }
+}
+
+/// calls a function with a json pointer token appended to the path, escaping `~` and `/`
+fn with_token<F: FnOnce(&mut String)>(path: &mut String, token: &str, f: F) {
+    let len = path.len();
+    path.push('/');
+    path.push_str(&token.replace('~', "~0").replace('/', "~1"));
+    f(path);
+    path.truncate(len);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn diff_of_nested_values() {
+        let from = json!({"a": 1, "b": {"c": [1, 2, 3]}, "d/e": "x", "f": "gone"});
+        let to = json!({"a": 2, "b": {"c": [1, 5]}, "d/e": "x", "g": [true]});
+        let exp = json!([
+            {"op": "replace", "path": "/a", "value": 2},
+            {"op": "replace", "path": "/b/c/1", "value": 5},
+            {"op": "remove", "path": "/b/c/2"},
+            {"op": "remove", "path": "/f"},
+            {"op": "add", "path": "/g", "value": [true]},
+        ]);
+        assert_eq!(exp, json_diff(&from, &to));
+        assert_eq!(json!([]), json_diff(&from, &from));
+        let exp = json!([
+            {"op": "add", "path": "/1", "value": "b"},
+            {"op": "add", "path": "/2", "value": "c"},
+        ]);
+        assert_eq!(exp, json_diff(&json!(["a"]), &json!(["a", "b", "c"])));
+        let exp = json!([{"op": "replace", "path": "", "value": [1]}]);
+        assert_eq!(exp, json_diff(&json!({"a": 1}), &json!([1])));
+    }
+}
@@ -1,17 +1,30 @@
-use crate::err::Error;
+//! Vectorised comparisons, arithmetic and aggregates over json values, where an array
+//! operand applies the operation to each of its elements
+
+mod diff;
+mod numeric;
+mod sketch;
+mod typed;
 
-use crate::cmd::Range;
-use crate::Res;
+use crate::err::Error;
 use rayon::prelude::*;
 use serde_json::Number;
 pub use serde_json::{json, Map};
 use std::cmp::Ordering;
+use std::collections::HashMap;
 use std::mem;
 
+pub use diff::*;
+pub use numeric::*;
+pub use sketch::*;
+pub use typed::*;
+
 pub type Json = serde_json::Value;
 pub type JsonObj = Map<String, Json>;
 pub type JsonNum = serde_json::Number;
 
+type Res = Result<Json, Error>;
+
 // wrapper around json_count to return as a Result
 pub fn count(val: &Json) -> Result<Json, Error> {
     Ok(json_count(val))
@@ -31,14 +44,16 @@ pub fn json_eq(x: &Json, y: &Json) -> Json {
             let val: Vec<Json> = x
                 .par_iter()
                 .zip(y.par_iter())
-                .map(|(x, y)| Json::from(x == y))
+                .map(|(x, y)| Json::from(json_equal(x, y)))
                 .collect();
             Json::Array(val)
         }
-        (Json::Array(x), val) | (val, Json::Array(x)) => {
-            Json::Array(x.par_iter().map(|x| Json::from(x == val)).collect())
-        }
-        (x, y) => Json::from(x == y),
+        (Json::Array(x), val) | (val, Json::Array(x)) => Json::Array(
+            x.par_iter()
+                .map(|x| Json::from(json_equal(x, val)))
+                .collect(),
+        ),
+        (x, y) => Json::from(json_equal(x, y)),
     }
 }
 
@@ -50,13 +65,13 @@ pub fn json_not_eq(x: &Json, y: &Json) -> Json {
         (Json::Array(x), Json::Array(y)) => Json::Array(
             x.par_iter()
                 .zip(y.par_iter())
-                .map(|(x, y)| Json::from(x != y))
+                .map(|(x, y)| Json::from(json_neq(x, y)))
                 .collect(),
         ),
         (Json::Array(x), val) | (val, Json::Array(x)) => {
-            Json::Array(x.par_iter().map(|x| Json::from(x != val)).collect())
+            Json::Array(x.par_iter().map(|x| Json::from(json_neq(x, val))).collect())
         }
-        (x, y) => Json::from(x != y),
+        (x, y) => Json::from(json_neq(x, y)),
     }
 }
 
@@ -136,9 +151,21 @@ pub fn json_gte(x: &Json, y: &Json) -> Json {
     }
 }
 
-/// Json equality comparison test
+/// Vectorized test if x lies within the inclusive range of lo and hi.
+/// Returns back a json value of a boolean or an array of booleans.
+///
+pub fn json_between(x: &Json, lo: &Json, hi: &Json) -> Json {
+    let f = |x: &Json| Json::from(gte(x, lo) && lte(x, hi));
+    match x {
+        Json::Array(x) => Json::Array(x.par_iter().map(f).collect()),
+        x => f(x),
+    }
+}
+
+/// Json equality comparison test; annotated values equal when they compare equal, as
+/// `{"$decimal": "1.50"}` and `1.5`
 pub fn json_equal(x: &Json, y: &Json) -> bool {
-    x == y
+    x == y || typed_cmp(x, y) == Some(Ordering::Equal)
 }
 
 // Vectorised or gate
@@ -171,11 +198,14 @@ pub fn json_and(x: &Json, y: &Json) -> Res {
 
 // not equals gate
 pub fn json_neq(x: &Json, y: &Json) -> bool {
-    x != y
+    !json_equal(x, y)
 }
 
 /// Compares two json values for order.
 fn json_cmp<'a>(x: &'a Json, y: &'a Json) -> Result<Ordering, Error> {
+    if let Some(ord) = typed_cmp(x, y) {
+        return Ok(ord);
+    }
     match (x, y) {
         (Json::String(x), Json::String(y)) => Ok(x.cmp(y)),
         (Json::Number(x), Json::Number(y)) => Ok(num_cmp(x, y)),
@@ -243,6 +273,54 @@ pub fn json_count(val: &Json) -> Json {
     }
 }
 
+// the length of an array, object or string; any other value has a length of 1
+pub fn json_len(val: &Json) -> Json {
+    match val {
+        Json::String(s) => Json::from(s.chars().count()),
+        val => json_count(val),
+    }
+}
+
+// counts the non-null values in the json value
+pub fn json_count_vals(val: &Json) -> Json {
+    match val {
+        Json::Array(ref arr) => Json::from(arr.iter().filter(|x| !x.is_null()).count()),
+        Json::Null => Json::from(0),
+        _ => Json::from(1),
+    }
+}
+
+// counts the distinct non-null values in the json value
+pub fn json_count_distinct(val: &Json) -> Json {
+    match val {
+        Json::Array(ref arr) => {
+            let vals: Vec<Json> = arr.iter().filter(|x| !x.is_null()).cloned().collect();
+            json_count(&arr_unique(&vals))
+        }
+        val => json_count_vals(val),
+    }
+}
+
+// collects the non-null values in the json value into an array
+pub fn json_collect(val: &Json) -> Json {
+    match val {
+        Json::Array(ref arr) => Json::Array(arr.iter().filter(|x| !x.is_null()).cloned().collect()),
+        Json::Null => Json::Array(Vec::new()),
+        val => Json::Array(vec![val.clone()]),
+    }
+}
+
+// joins the non-null values in the json value into a string
+pub fn json_join(val: &Json, sep: &str) -> Json {
+    match json_collect(val) {
+        Json::Array(arr) => {
+            let strs: Vec<String> = arr.iter().map(json_str).collect();
+            Json::from(strs.join(sep))
+        }
+        _ => unreachable!(),
+    }
+}
+
 // appends json to an existing json value.
 pub fn json_append(val: &mut Json, elem: Json) {
     match val {
@@ -269,11 +347,11 @@ pub fn json_append(val: &mut Json, elem: Json) {
     }
 }
 
-/// retrieves the first element in the json value.
+/// retrieves the first element in the json value; null for an empty array.
 //TODO refactor arg from ref to val
 pub fn json_first(val: &Json) -> Json {
     match val {
-        Json::Array(ref arr) if !arr.is_empty() => arr[0].clone(),
+        Json::Array(ref arr) => arr.first().cloned().unwrap_or(Json::Null),
         Json::String(s) => {
             let mut it = s.chars();
             match it.next() {
@@ -285,10 +363,10 @@ pub fn json_first(val: &Json) -> Json {
     }
 }
 
-/// retrieves the last element in the json value.
+/// retrieves the last element in the json value; null for an empty array.
 pub fn json_last(val: &Json) -> Json {
     match val {
-        Json::Array(ref arr) if !arr.is_empty() => arr[arr.len() - 1].clone(),
+        Json::Array(ref arr) => arr.last().cloned().unwrap_or(Json::Null),
         Json::String(s) => {
             let it = s.chars();
             let last = it.last();
@@ -298,11 +376,12 @@ pub fn json_last(val: &Json) -> Json {
     }
 }
 
-/// sums the json value.
+/// sums the json value; an empty array sums to 0.
 pub fn json_sum(val: &Json) -> Json {
     match val {
         Json::Number(val) => Json::Number(val.clone()),
         Json::Array(ref arr) => json_arr_sum(arr),
+        val if matches!(typed(val), Some(Typed::Decimal(_))) => val.clone(),
         _ => Json::Null,
     }
 }
@@ -315,28 +394,33 @@ pub fn json_pop(val: &mut Json) -> Result<Option<Json>, Error> {
     }
 }
 
-/// calculates the average of the json value.
+/// calculates the average of the json value; null for an empty array.
 pub fn json_avg(val: &Json) -> Result<Json, Error> {
     match val {
         Json::Number(val) => Ok(Json::Number(val.clone())),
+        Json::Array(ref arr) if arr.is_empty() => Ok(Json::Null),
         Json::Array(ref arr) => json_arr_avg(arr),
+        val if matches!(typed(val), Some(Typed::Decimal(_))) => Ok(val.clone()),
         _ => Err(Error::BadType),
     }
 }
 
-/// calculates the variance of the json value.
+/// calculates the variance of the json value; null for an empty array.
 pub fn json_var(val: &Json) -> Result<Json, Error> {
     match val {
         Json::Number(_) => Ok(Json::from(0)),
+        Json::Array(ref arr) if arr.is_empty() => Ok(Json::Null),
+        Json::Array(ref arr) if arr.len() < 2 => Ok(Json::from(0)),
         Json::Array(ref arr) => json_arr_var(arr),
         _ => Err(Error::BadType),
     }
 }
 
-/// calculates the standard deviation of the json value.
+/// calculates the standard deviation of the json value; null for an empty array.
 pub fn json_dev(val: &Json) -> Result<Json, Error> {
     match val {
         Json::Number(_) => Ok(Json::from(0)),
+        Json::Array(ref arr) if arr.is_empty() => Ok(Json::Null),
         Json::Array(ref arr) => {
             if arr.len() < 2 {
                 Ok(Json::from(0))
@@ -348,100 +432,14 @@ pub fn json_dev(val: &Json) -> Result<Json, Error> {
     }
 }
 
-/// calculates the maximum value of the json value.
+/// calculates the maximum value of the json value; none for an empty array.
 pub fn json_max(val: &Json) -> Option<&Json> {
     match val {
-        Json::Array(ref a) if !a.is_empty() => a.par_iter().reduce_with(max),
+        Json::Array(ref a) => a.par_iter().reduce_with(max),
         val => Some(val),
     }
 }
 
-//TODO(jaupe) add more cases
-/// adds two json values together.
-pub fn json_add(lhs: &Json, rhs: &Json) -> Result<Json, Error> {
-    match (lhs, rhs) {
-        (Json::Null, _) => Ok(Json::Null),
-        (_, Json::Null) => Ok(Json::Null),
-        (Json::Array(lhs), Json::Array(rhs)) => json_add_arrs(lhs, rhs),
-        (Json::Array(lhs), rhs) => json_add_arr_val(lhs, rhs),
-        (lhs, Json::Array(rhs)) => json_add_val_arr(lhs, rhs),
-        (Json::Number(lhs), Json::Number(rhs)) => Ok(Json::Number(json_add_nums(lhs, rhs))),
-        (Json::String(lhs), rhs) => json_add_str(lhs, json_tostring(rhs)),
-        (lhs, Json::String(rhs)) => json_add_str(json_tostring(lhs), rhs),
-        _ => Err(Error::BadType),
-    }
-}
-
-pub fn json_add2(x: &Json, y: &Json) -> Json {
-    match (x, y) {
-        (Json::Number(x), Json::Number(y)) => Json::Number(json_add_nums(x, y)),
-        (Json::Number(x), _) => Json::Number(x.clone()),
-        (_, Json::Number(y)) => Json::Number(y.clone()),
-        _ => Json::from(0),
-    }
-}
-
-//TODO(jaupe) add more cases
-/// bars two json values together.
-pub fn json_bar(lhs: &Json, rhs: &Json) -> Result<Json, Error> {
-    match (lhs, rhs) {
-        (Json::Array(lhs), Json::Array(rhs)) => json_bar_arrs(lhs, rhs),
-        (Json::Array(lhs), rhs) => json_bar_arr_val(lhs, rhs),
-        (Json::Number(lhs), Json::Number(rhs)) => json_bar_num_num(lhs, rhs),
-        _ => Err(Error::BadType),
-    }
-}
-
-/// vectorised bar of  two json arrays.
-fn json_bar_arrs(lhs: &[Json], rhs: &[Json]) -> Result<Json, Error> {
-    let mut out = Vec::new();
-    for (x, y) in lhs.iter().zip(rhs.iter()) {
-        let val = json_bar(x, y)?;
-        out.push(val);
-    }
-    Ok(Json::Array(out))
-}
-
-/// vectorised bar of an array and scalar.
-fn json_bar_arr_val(lhs: &[Json], rhs: &Json) -> Result<Json, Error> {
-    let mut out = Vec::new();
-    for val in lhs {
-        let val = json_bar(val, rhs)?;
-        out.push(val);
-    }
-    Ok(Json::Array(out))
-}
-
-/// bar of two json numbers
-fn json_bar_num_num(lhs: &Number, rhs: &Number) -> Result<Json, Error> {
-    match (lhs.as_i64(), rhs.as_i64()) {
-        (Some(x), Some(y)) => Ok(Json::from(x / y * y)),
-        _ => Err(Error::BadType),
-    }
-}
-
-/// subtraction of two json values
-pub fn json_sub(lhs: &Json, rhs: &Json) -> Result<Json, Error> {
-    match (lhs, rhs) {
-        (Json::Array(lhs), Json::Array(rhs)) => json_sub_arrs(lhs, rhs),
-        (Json::Array(lhs), rhs) => json_sub_arr_num(lhs, rhs),
-        (lhs, Json::Array(rhs)) => json_sub_num_arr(lhs, rhs),
-        (Json::Number(lhs), Json::Number(rhs)) => Ok(json_sub_nums(lhs, rhs)),
-        _ => Err(Error::BadType),
-    }
-}
-
-/// multiplication of two json values
-pub fn json_mul(lhs: &Json, rhs: &Json) -> Result<Json, Error> {
-    match (lhs, rhs) {
-        (Json::Array(x), Json::Array(y)) => mul_arrs(x, y),
-        (Json::Array(x), Json::Number(y)) => mul_arr_num(x, y),
-        (Json::Number(x), Json::Array(y)) => mul_arr_num(y, x),
-        (Json::Number(x), Json::Number(y)) => mul_nums(x, y),
-        _ => Err(Error::BadType),
-    }
-}
-
 /// compute the unique elements of the json value
 pub fn json_unique(val: &Json) -> Json {
     match val {
@@ -462,62 +460,6 @@ fn arr_unique(arr: &[Json]) -> Json {
     Json::Array(unique)
 }
 
-/// compute the multiplication of two json scalars
-fn mul_vals(x: &Json, y: &Json) -> Result<Json, Error> {
-    match (x, y) {
-        (Json::Number(x), Json::Number(y)) => mul_nums(x, y),
-        _ => Err(Error::BadType),
-    }
-}
-
-/// compute the multiplication of two json numbers
-fn mul_nums(x: &JsonNum, y: &JsonNum) -> Result<Json, Error> {
-    let val = match (x.is_i64(), y.is_i64()) {
-        (true, true) => Json::from(x.as_i64().unwrap() * y.as_i64().unwrap()),
-        _ => Json::from(x.as_f64().unwrap() * y.as_f64().unwrap()),
-    };
-    Ok(val)
-}
-
-/// compute the vectorized multiplication of a two json numbers
-fn mul_arr_num(x: &[Json], y: &JsonNum) -> Result<Json, Error> {
-    let mut arr = Vec::new();
-    for x in x.iter() {
-        arr.push(mul_val_num(x, y)?);
-    }
-    Ok(Json::from(arr))
-}
-
-/// compute the vectorized multiplication of a json value and json number
-fn mul_val_num(x: &Json, y: &JsonNum) -> Result<Json, Error> {
-    match x {
-        Json::Number(ref x) => mul_nums(x, y),
-        Json::Array(ref arr) => mul_arr_num(arr, y),
-        _ => Err(Error::BadType),
-    }
-}
-
-//TODO(jaupe) optimize by removing the temp allocs
-/// compute the vectorized multiplication of a json arrays
-fn mul_arrs(lhs: &[Json], rhs: &[Json]) -> Result<Json, Error> {
-    let mut arr: Vec<Json> = Vec::new();
-    for (x, y) in lhs.iter().zip(rhs.iter()) {
-        arr.push(mul_vals(x, y)?);
-    }
-    Ok(Json::from(arr))
-}
-
-/// compute the vectorized division of two json values
-pub fn json_div(lhs: &Json, rhs: &Json) -> Result<Json, Error> {
-    match (lhs, rhs) {
-        (Json::Array(ref lhs), Json::Array(ref rhs)) => div_arrs(lhs, rhs),
-        (Json::Array(ref lhs), Json::Number(ref rhs)) => div_arr_num(lhs, rhs),
-        (Json::Number(ref lhs), Json::Array(ref rhs)) => div_num_arr(lhs, rhs),
-        (Json::Number(ref lhs), Json::Number(ref rhs)) => div_nums(lhs, rhs),
-        _ => Err(Error::BadType),
-    }
-}
-
 pub fn json_sort(val: &mut Json, descend: bool) {
     if let Json::Array(ref mut arr) = val {
         if descend {
@@ -528,132 +470,9 @@ pub fn json_sort(val: &mut Json, descend: bool) {
     }
 }
 
-/// divide two json numbers
-fn div_nums(x: &JsonNum, y: &JsonNum) -> Result<Json, Error> {
-    let val = x.as_f64().unwrap() / y.as_f64().unwrap();
-    Ok(Json::from(val))
-}
-
-/// vectorised division of two json arrays
-fn div_arrs(x: &[Json], y: &[Json]) -> Result<Json, Error> {
-    let mut arr = Vec::new();
-    for (x, y) in x.iter().zip(y.iter()) {
-        arr.push(json_div(x, y)?);
-    }
-    Ok(Json::from(arr))
-}
-
-fn div_arr_num(x: &[Json], y: &JsonNum) -> Result<Json, Error> {
-    let mut arr: Vec<Json> = Vec::new();
-    for x in x {
-        arr.push(div_val_num(x, y)?);
-    }
-    Ok(Json::from(arr))
-}
-
-fn div_val_num(x: &Json, y: &JsonNum) -> Result<Json, Error> {
-    match x {
-        Json::Number(ref x) => div_nums(x, y),
-        Json::Array(ref x) => div_arr_num(x, y),
-        _ => Err(Error::BadType),
-    }
-}
-
-fn div_num_arr(x: &JsonNum, y: &[Json]) -> Result<Json, Error> {
-    let mut arr: Vec<Json> = Vec::new();
-    for y in y {
-        arr.push(div_num_val(x, y)?);
-    }
-    Ok(Json::from(arr))
-}
-
-fn div_num_val(x: &JsonNum, y: &Json) -> Result<Json, Error> {
-    match y {
-        Json::Number(ref y) => div_nums(x, y),
-        Json::Array(ref y) => div_num_arr(x, y),
-        _ => Err(Error::BadType),
-    }
-}
-
-fn json_add_str<X, Y>(x: X, y: Y) -> Result<Json, Error>
-where
-    X: Into<String>,
-    Y: Into<String>,
-{
-    let val = x.into() + &y.into();
-    Ok(Json::String(val))
-}
-
-//TODO(jaupe) add better error handlinge
-fn json_add_arr_val(x: &[Json], y: &Json) -> Result<Json, Error> {
-    let mut arr = Vec::new();
-    for x in x {
-        arr.push(json_add(x, y)?);
-    }
-    Ok(Json::Array(arr))
-}
-
-fn json_add_val_arr(x: &Json, y: &[Json]) -> Result<Json, Error> {
-    let mut arr = Vec::new();
-    for y in y {
-        arr.push(json_add(x, y)?);
-    }
-    Ok(Json::Array(arr))
-}
-
-fn json_add_arrs(lhs: &[Json], rhs: &[Json]) -> Result<Json, Error> {
-    let vec: Result<Vec<Json>, _> = lhs
-        .iter()
-        .zip(rhs.iter())
-        .map(|(x, y)| json_add(x, y))
-        .collect();
-    Ok(Json::Array(vec?))
-}
-
-pub(crate) fn json_add_nums(x: &JsonNum, y: &JsonNum) -> JsonNum {
-    match (x.is_i64(), y.is_i64()) {
-        (true, true) => JsonNum::from(x.as_i64().unwrap() + y.as_i64().unwrap()),
-        _ => JsonNum::from_f64(x.as_f64().unwrap() + y.as_f64().unwrap()).unwrap(),
-    }
-}
-
-fn json_sub_arr_num(x: &[Json], y: &Json) -> Result<Json, Error> {
-    let mut arr = Vec::new();
-    for x in x {
-        arr.push(json_sub(x, y)?);
-    }
-    Ok(Json::Array(arr))
-}
-
-fn json_sub_num_arr(x: &Json, y: &[Json]) -> Result<Json, Error> {
-    let mut out = Vec::new();
-    for y in y {
-        out.push(json_sub(x, y)?);
-    }
-    Ok(Json::Array(out))
-}
-
-fn json_sub_arrs(lhs: &[Json], rhs: &[Json]) -> Result<Json, Error> {
-    let vec = lhs
-        .iter()
-        .zip(rhs.iter())
-        .map(|(x, y)| json_sub(x, y).unwrap())
-        .collect();
-    Ok(Json::Array(vec))
-}
-
-fn json_sub_nums(x: &JsonNum, y: &JsonNum) -> Json {
-    match (x.as_i64(), y.as_i64()) {
-        (Some(x), Some(y)) => Json::from(x - y),
-        (Some(x), None) => Json::from(x as f64 - y.as_f64().unwrap()),
-        (None, Some(y)) => Json::from(x.as_f64().unwrap() - y as f64),
-        (None, None) => Json::from(x.as_f64().unwrap() - y.as_f64().unwrap()),
-    }
-}
-
 pub fn json_min(val: &Json) -> Option<&Json> {
     match val {
-        Json::Array(ref arr) if !arr.is_empty() => arr_min(arr),
+        Json::Array(ref arr) => arr_min(arr),
         val => Some(val),
     }
 }
@@ -666,6 +485,9 @@ pub fn json_tostring(val: &Json) -> String {
 }
 
 fn json_arr_sum(s: &[Json]) -> Json {
+    if let Some(sum) = typed_sum(s) {
+        return sum;
+    }
     let mut total = JsonNum::from(0);
     for val in s {
         match val {
@@ -679,6 +501,9 @@ fn json_arr_sum(s: &[Json]) -> Json {
 }
 
 fn json_arr_avg(s: &[Json]) -> Result<Json, Error> {
+    if let Some(avg) = typed_avg(s) {
+        return Ok(avg);
+    }
     let mut total = 0.0f64;
     for val in s {
         total += json_f64(val).ok_or(Error::BadType)?;
@@ -723,10 +548,8 @@ pub fn json_f64(val: &Json) -> Option<f64> {
         Json::Number(num) => {
             if let Some(x) = num.as_f64() {
                 Some(x)
-            } else if let Some(x) = num.as_i64() {
-                Some(x as f64)
             } else {
-                None
+                num.as_i64().map(|x| x as f64)
             }
         }
         _ => None,
@@ -761,6 +584,136 @@ pub fn json_string(x: &Json) -> Json {
     }
 }
 
+/// checks if json strings match a predicate; non-strings never match
+pub fn json_matches<F: Fn(&str) -> bool + Sync>(val: &Json, f: F) -> Json {
+    match val {
+        Json::Array(arr) => Json::Array(
+            arr.par_iter()
+                .map(|x| Json::Bool(x.as_str().is_some_and(&f)))
+                .collect(),
+        ),
+        Json::String(s) => Json::Bool(f(s)),
+        _ => Json::Bool(false),
+    }
+}
+
+/// the approximate number of bytes the json value occupies
+pub fn json_size(val: &Json) -> usize {
+    match val {
+        Json::Null | Json::Bool(_) => 1,
+        Json::Number(_) => 8,
+        Json::String(s) => s.len(),
+        Json::Array(arr) => arr.iter().map(json_size).sum(),
+        Json::Object(obj) => obj.iter().map(|(k, v)| k.len() + json_size(v)).sum(),
+    }
+}
+
+/// the number of largest children reported by json_size_of
+const SIZE_OF_LARGEST: usize = 10;
+
+/// describes where the bytes of a json value are: its approximate size, the number of
+/// values at each nesting level, its maximum depth and its largest children
+pub fn json_size_of(val: &Json) -> Json {
+    let mut levels = Vec::new();
+    count_levels(val, 0, &mut levels);
+    let mut largest: Vec<(String, usize)> = match val {
+        Json::Array(arr) => arr
+            .iter()
+            .enumerate()
+            .map(|(i, x)| (i.to_string(), json_size(x)))
+            .collect(),
+        Json::Object(obj) => obj.iter().map(|(k, x)| (k.clone(), json_size(x))).collect(),
+        _ => Vec::new(),
+    };
+    largest.sort_by(|x, y| y.1.cmp(&x.1).then_with(|| x.0.cmp(&y.0)));
+    largest.truncate(SIZE_OF_LARGEST);
+    let largest: Vec<Json> = largest
+        .into_iter()
+        .map(|(key, bytes)| json!({"key": key, "bytes": bytes}))
+        .collect();
+    json!({
+        "bytes": json_size(val),
+        "depth": levels.len() - 1,
+        "levels": levels,
+        "largest": largest,
+    })
+}
+
+/// counts the values at each nesting level
+fn count_levels(val: &Json, depth: usize, levels: &mut Vec<usize>) {
+    if levels.len() <= depth {
+        levels.push(0);
+    }
+    levels[depth] += 1;
+    match val {
+        Json::Array(arr) => arr.iter().for_each(|x| count_levels(x, depth + 1, levels)),
+        Json::Object(obj) => obj
+            .values()
+            .for_each(|x| count_levels(x, depth + 1, levels)),
+        _ => (),
+    }
+}
+
+/// finds the strings, arrays and objects of at least the given approximate size that occur
+/// more than once across the values, and the bytes saved by keeping one copy of each
+pub fn json_duplicates<'a, I: IntoIterator<Item = &'a Json>>(vals: I, min_bytes: usize) -> Json {
+    let mut seen = HashMap::new();
+    let mut bytes = 0;
+    for val in vals {
+        find_duplicates(val, min_bytes, &mut seen, &mut bytes);
+    }
+    let subtrees = seen.values().filter(|n| **n > 1).count();
+    let duplicates: usize = seen.values().map(|n| n - 1).sum();
+    json!({"subtrees": subtrees, "duplicates": duplicates, "bytes": bytes})
+}
+
+/// counts the occurrences of each subtree; a repeated subtree's children aren't visited,
+/// as they're shared along with it
+fn find_duplicates(
+    val: &Json,
+    min_bytes: usize,
+    seen: &mut HashMap<String, usize>,
+    bytes: &mut usize,
+) {
+    let children: Box<dyn Iterator<Item = &Json>> = match val {
+        Json::Array(arr) => Box::new(arr.iter()),
+        Json::Object(obj) => Box::new(obj.values()),
+        Json::String(_) => Box::new(std::iter::empty()),
+        _ => return,
+    };
+    let size = json_size(val);
+    if size >= min_bytes {
+        let count = seen.entry(val.to_string()).or_insert(0);
+        *count += 1;
+        if *count > 1 {
+            *bytes += size;
+            return;
+        }
+    }
+    children.for_each(|x| find_duplicates(x, min_bytes, seen, bytes));
+}
+
+/// the name of the json type of the value
+pub fn json_type(val: &Json) -> &'static str {
+    match val {
+        Json::Null => "null",
+        Json::Bool(_) => "bool",
+        Json::Number(_) => "number",
+        Json::String(_) => "string",
+        Json::Array(_) => "array",
+        Json::Object(_) => "object",
+    }
+}
+
+/// follows a dot separated path, e.g. `address.city` or `items.0`, through nested json
+/// objects and arrays
+pub fn json_path<'a>(val: &'a Json, path: &str) -> Option<&'a Json> {
+    path.split('.').try_fold(val, |val, key| match val {
+        Json::Array(arr) => key.parse::<usize>().ok().and_then(|i| arr.get(i)),
+        val => val.get(key),
+    })
+}
+
 pub fn json_get(key: &str, val: &Json) -> Option<Json> {
     match val {
         Json::Array(arr) => {
@@ -779,13 +732,7 @@ pub fn json_get(key: &str, val: &Json) -> Option<Json> {
                 Some(Json::Array(out))
             }
         }
-        Json::Object(obj) => {
-            if let Some(val) = obj.get(key) {
-                Some(val.clone())
-            } else {
-                None
-            }
-        }
+        Json::Object(obj) => obj.get(key).cloned(),
         _ => None,
     }
 }
@@ -840,7 +787,7 @@ fn map(f: &str) -> Option<fn(&Json) -> Res> {
         "min" => Some(|x| Ok(json_min(x).cloned().unwrap_or(Json::Null))),
         "sum" => Some(|x| Ok(json_sum(x))),
         "unique" => Some(|x| Ok(json_unique(x))),
-        "var" => Some(|x| json_var(x)),
+        "var" => Some(json_var),
         _ => None,
     }
 }
@@ -856,7 +803,7 @@ pub fn json_has(val: &Json, key: &str) -> Json {
 pub fn arr_has(arr: &[Json], key: &str) -> Json {
     let v = arr
         .par_iter()
-        .map(|x| x.get(key).is_some())
+        .map(|x| json_path(x, key).is_some())
         .map(Json::Bool)
         .collect();
     Json::Array(v)
@@ -874,6 +821,29 @@ pub fn json_in(lhs: &Json, rhs: &Json) -> Json {
     }
 }
 
+/// applies a json merge patch (RFC 7396) to a value: the fields of an object patch are
+/// patched in turn, a null field deleting the field, and any other patch replaces the value
+pub fn json_merge_patch(val: &mut Json, patch: Json) {
+    let patch = match patch {
+        Json::Object(patch) => patch,
+        patch => {
+            *val = patch;
+            return;
+        }
+    };
+    if !val.is_object() {
+        *val = Json::Object(JsonObj::new());
+    }
+    let obj = val.as_object_mut().unwrap();
+    for (key, patch) in patch {
+        if patch.is_null() {
+            obj.remove(&key);
+        } else {
+            json_merge_patch(obj.entry(key).or_insert(Json::Null), patch);
+        }
+    }
+}
+
 pub fn json_merge(x: &Json, y: &Json) -> Json {
     let mut out = Vec::new();
     json_arr_merge(x, &mut out);
@@ -916,6 +886,9 @@ pub fn json_flat(val: Json) -> Json {
 }
 
 pub fn json_fold_add(x: Json, y: &Json) -> Json {
+    if let Some(sum) = typed_add(&x, y) {
+        return sum;
+    }
     match y {
         Json::Number(y) => {
             if let Json::Number(x) = x {
@@ -929,6 +902,9 @@ pub fn json_fold_add(x: Json, y: &Json) -> Json {
 }
 
 pub fn json_reduce_add(x: Json, y: Json) -> Json {
+    if let Some(sum) = typed_add(&x, &y) {
+        return sum;
+    }
     match (x, y) {
         (Json::Number(x), Json::Number(y)) => Json::Number(json_add_nums(&x, &y)),
         (x, _) => x,
@@ -959,30 +935,7 @@ pub fn json_map(val: &Json, f: String) -> Result<Json, Error> {
             }
             Ok(Json::Array(v))
         }
-        val => f(&val),
-    }
-}
-
-fn arr_slice(arr: Vec<Json>, start: Option<usize>, end: Option<usize>) -> Result<Json, Error> {
-    let s = match (start, end) {
-        (Some(s), Some(e)) => {
-            if e > arr.len() || s < arr.len() {
-                return Err(Error::IndexOutOfBounds);
-            } else {
-                arr[s..e].to_vec()
-            }
-        }
-        (Some(s), None) => arr[s..].to_vec(),
-        (None, Some(e)) => arr[..e].to_vec(),
-        (None, None) => arr,
-    };
-    Ok(Json::Array(s))
-}
-
-pub fn json_slice(val: Json, range: Range) -> Res {
-    match val {
-        Json::Array(vec) => arr_slice(vec, range.start, range.size),
-        _ => Err(Error::ExpectedArr),
+        val => f(val),
     }
 }
 
@@ -997,7 +950,7 @@ pub fn json_sortby(_val: &mut Json, _key: &str) {
 }
 
 pub fn json_ord(x: &Json, y: &Json) -> Ordering {
-    if x == y {
+    if json_equal(x, y) {
         Ordering::Equal
     } else if gt(x, y) {
         Ordering::Greater
@@ -1007,7 +960,7 @@ pub fn json_ord(x: &Json, y: &Json) -> Ordering {
 }
 
 pub fn json_desc_ord(x: &Json, y: &Json) -> Ordering {
-    if x == y {
+    if json_equal(x, y) {
         Ordering::Equal
     } else if gt(x, y) {
         Ordering::Less
@@ -1017,7 +970,7 @@ pub fn json_desc_ord(x: &Json, y: &Json) -> Ordering {
 }
 
 pub fn sortby_key(key: &str, x: &Json, y: &Json) -> Ordering {
-    match (x.get(key), y.get(key)) {
+    match (json_path(x, key), json_path(y, key)) {
         (Some(x), Some(y)) => json_ord(x, y),
         (None, Some(_)) => Ordering::Greater,
         (Some(_), None) => Ordering::Less,
@@ -1026,7 +979,7 @@ pub fn sortby_key(key: &str, x: &Json, y: &Json) -> Ordering {
 }
 
 pub fn sortby_desc_key(key: &str, x: &Json, y: &Json) -> Ordering {
-    match (x.get(key), y.get(key)) {
+    match (json_path(x, key), json_path(y, key)) {
         (Some(x), Some(y)) => json_desc_ord(x, y),
         (None, Some(_)) => Ordering::Greater,
         (Some(_), None) => Ordering::Less,
@@ -1082,6 +1035,26 @@ mod tests {
         assert_eq!(Some(Json::from(28)), json_get("age", &obj));
     }
 
+    #[test]
+    fn json_between_nums_and_strs() {
+        let val = json!([1, 2.5, 3, 4, "3"]);
+        let exp = json!([false, true, true, false, false]);
+        assert_eq!(exp, json_between(&val, &json!(2), &json!(3)));
+        assert_eq!(
+            json!(true),
+            json_between(&json!("b"), &json!("a"), &json!("c"))
+        );
+    }
+
+    #[test]
+    fn json_path_nested() {
+        let val = json!({"name": "anna", "address": {"city": "London"}});
+        assert_eq!(Some(&json!("anna")), json_path(&val, "name"));
+        assert_eq!(Some(&json!("London")), json_path(&val, "address.city"));
+        assert_eq!(None, json_path(&val, "address.zip"));
+        assert_eq!(None, json_path(&val, "name.first"));
+    }
+
     #[test]
     fn json_insert_arr() {
         let f = |x: Json| x.as_object().cloned().unwrap();
@@ -1103,6 +1076,50 @@ mod tests {
         );
     }
 
+    #[test]
+    fn json_merge_patch_rfc_examples() {
+        let cases = [
+            (json!({"a": "b"}), json!({"a": "c"}), json!({"a": "c"})),
+            (
+                json!({"a": "b"}),
+                json!({"b": "c"}),
+                json!({"a": "b", "b": "c"}),
+            ),
+            (
+                json!({"a": "b", "b": "c"}),
+                json!({"a": null}),
+                json!({"b": "c"}),
+            ),
+            (json!({"a": ["b"]}), json!({"a": "c"}), json!({"a": "c"})),
+            (
+                json!({"a": {"b": "c"}}),
+                json!({"a": {"b": "d", "c": null}}),
+                json!({"a": {"b": "d"}}),
+            ),
+            (json!(["a", "b"]), json!(["c", "d"]), json!(["c", "d"])),
+            (json!({"a": "b"}), json!(["c"]), json!(["c"])),
+            (
+                json!({"e": null}),
+                json!({"a": 1}),
+                json!({"e": null, "a": 1}),
+            ),
+            (
+                json!([1, 2]),
+                json!({"a": "b", "c": null}),
+                json!({"a": "b"}),
+            ),
+            (
+                json!({}),
+                json!({"a": {"bb": {"ccc": null}}}),
+                json!({"a": {"bb": {}}}),
+            ),
+        ];
+        for (mut val, patch, exp) in cases {
+            json_merge_patch(&mut val, patch);
+            assert_eq!(exp, val);
+        }
+    }
+
     #[test]
     fn json_bar_ok() {
         let lhs = json!([0, 1, 2, 3, 4, 5, 6, 7, 8, 9]);
@@ -1123,4 +1140,35 @@ mod tests {
         json_sort(&mut val, false);
         assert_eq!(json!(['1', 1, 10, 3, 4, 5, 6, 7, 8, 9]), val);
     }
+
+    #[test]
+    fn json_size_of_ok() {
+        let val = json!({"a": [1, 2, [3]], "bb": "hello", "c": null});
+        let exp = json!({
+            "bytes": 34,
+            "depth": 3,
+            "levels": [1, 3, 3, 1],
+            "largest": [
+                {"key": "a", "bytes": 24},
+                {"key": "bb", "bytes": 5},
+                {"key": "c", "bytes": 1},
+            ],
+        });
+        assert_eq!(exp, json_size_of(&val));
+        let exp = json!({"bytes": 8, "depth": 0, "levels": [1], "largest": []});
+        assert_eq!(exp, json_size_of(&json!(1)));
+    }
+
+    #[test]
+    fn json_duplicates_ok() {
+        let addr = json!({"city": "London", "zip": "N1"});
+        let a = json!([{"name": "james", "address": addr}, {"name": "anna", "address": addr}]);
+        let b = json!({"home": addr, "name": "james"});
+        let exp = json!({"subtrees": 2, "duplicates": 3, "bytes": 2 * json_size(&addr) + 5});
+        assert_eq!(exp, json_duplicates(vec![&a, &b], 5));
+        let exp = json!({"subtrees": 1, "duplicates": 2, "bytes": 2 * json_size(&addr)});
+        assert_eq!(exp, json_duplicates(vec![&a, &b], 6));
+        let exp = json!({"subtrees": 0, "duplicates": 0, "bytes": 0});
+        assert_eq!(exp, json_duplicates(vec![&json!([1, 1, 1])], 0));
+    }
 }
@@ -0,0 +1,276 @@
+use super::{json_tostring, typed_add, Json, JsonNum};
+use crate::err::Error;
+
+/// The arithmetic of two json numbers, so the vectorised json operators can run over
+/// alternative numeric backends, such as decimals or checked integers
+pub trait NumericOps: Sync {
+    fn add(&self, x: &JsonNum, y: &JsonNum) -> Result<Json, Error>;
+    fn sub(&self, x: &JsonNum, y: &JsonNum) -> Result<Json, Error>;
+    fn mul(&self, x: &JsonNum, y: &JsonNum) -> Result<Json, Error>;
+    fn div(&self, x: &JsonNum, y: &JsonNum) -> Result<Json, Error>;
+}
+
+/// The arithmetic of memson: integers stay integers unless divided, and a float operand
+/// makes the result a float
+#[derive(Clone, Copy, Debug, Default)]
+pub struct DefaultOps;
+
+impl NumericOps for DefaultOps {
+    fn add(&self, x: &JsonNum, y: &JsonNum) -> Result<Json, Error> {
+        Ok(Json::Number(json_add_nums(x, y)))
+    }
+
+    fn sub(&self, x: &JsonNum, y: &JsonNum) -> Result<Json, Error> {
+        let val = match (x.as_i64(), y.as_i64()) {
+            (Some(x), Some(y)) => Json::from(x - y),
+            (Some(x), None) => Json::from(x as f64 - y.as_f64().unwrap()),
+            (None, Some(y)) => Json::from(x.as_f64().unwrap() - y as f64),
+            (None, None) => Json::from(x.as_f64().unwrap() - y.as_f64().unwrap()),
+        };
+        Ok(val)
+    }
+
+    fn mul(&self, x: &JsonNum, y: &JsonNum) -> Result<Json, Error> {
+        let val = match (x.is_i64(), y.is_i64()) {
+            (true, true) => Json::from(x.as_i64().unwrap() * y.as_i64().unwrap()),
+            _ => Json::from(x.as_f64().unwrap() * y.as_f64().unwrap()),
+        };
+        Ok(val)
+    }
+
+    fn div(&self, x: &JsonNum, y: &JsonNum) -> Result<Json, Error> {
+        let val = x.as_f64().unwrap() / y.as_f64().unwrap();
+        Ok(Json::from(val))
+    }
+}
+
+/// Integer arithmetic failing on overflow, and division failing on a zero divisor, rather
+/// than wrapping or dividing into infinity; floats are as in DefaultOps
+#[derive(Clone, Copy, Debug, Default)]
+pub struct CheckedOps;
+
+impl CheckedOps {
+    fn checked<F>(x: &JsonNum, y: &JsonNum, f: F) -> Option<Result<Json, Error>>
+    where
+        F: Fn(i64, i64) -> Option<i64>,
+    {
+        let (x, y) = (x.as_i64()?, y.as_i64()?);
+        Some(f(x, y).map(Json::from).ok_or(Error::Overflow))
+    }
+}
+
+impl NumericOps for CheckedOps {
+    fn add(&self, x: &JsonNum, y: &JsonNum) -> Result<Json, Error> {
+        Self::checked(x, y, i64::checked_add).unwrap_or_else(|| DefaultOps.add(x, y))
+    }
+
+    fn sub(&self, x: &JsonNum, y: &JsonNum) -> Result<Json, Error> {
+        Self::checked(x, y, i64::checked_sub).unwrap_or_else(|| DefaultOps.sub(x, y))
+    }
+
+    fn mul(&self, x: &JsonNum, y: &JsonNum) -> Result<Json, Error> {
+        Self::checked(x, y, i64::checked_mul).unwrap_or_else(|| DefaultOps.mul(x, y))
+    }
+
+    fn div(&self, x: &JsonNum, y: &JsonNum) -> Result<Json, Error> {
+        if y.as_f64() == Some(0.0) {
+            return Err(Error::DivByZero);
+        }
+        DefaultOps.div(x, y)
+    }
+}
+
+/// applies a scalar op pairwise to the elements of two arrays, or between the elements of
+/// an array and a scalar; null operands give null
+fn zip_with<F>(lhs: &Json, rhs: &Json, f: &F) -> Result<Json, Error>
+where
+    F: Fn(&Json, &Json) -> Result<Json, Error>,
+{
+    let vals: Result<Vec<Json>, Error> = match (lhs, rhs) {
+        (Json::Null, _) | (_, Json::Null) => return Ok(Json::Null),
+        (Json::Array(x), Json::Array(y)) => {
+            x.iter().zip(y).map(|(x, y)| zip_with(x, y, f)).collect()
+        }
+        (Json::Array(x), y) => x.iter().map(|x| zip_with(x, y, f)).collect(),
+        (x, Json::Array(y)) => y.iter().map(|y| zip_with(x, y, f)).collect(),
+        (x, y) => return f(x, y),
+    };
+    vals.map(Json::Array)
+}
+
+/// applies a numeric op to two json numbers
+fn num_op<F>(x: &Json, y: &Json, f: F) -> Result<Json, Error>
+where
+    F: Fn(&JsonNum, &JsonNum) -> Result<Json, Error>,
+{
+    match (x, y) {
+        (Json::Number(x), Json::Number(y)) => f(x, y),
+        _ => Err(Error::BadType),
+    }
+}
+
+/// adds two json values together; strings are concatenated
+pub fn json_add(lhs: &Json, rhs: &Json) -> Result<Json, Error> {
+    json_add_with(&DefaultOps, lhs, rhs)
+}
+
+/// adds two json values together with the given arithmetic
+pub fn json_add_with<N: NumericOps + ?Sized>(
+    ops: &N,
+    lhs: &Json,
+    rhs: &Json,
+) -> Result<Json, Error> {
+    zip_with(lhs, rhs, &|x, y| match (x, y) {
+        (Json::Number(x), Json::Number(y)) => ops.add(x, y),
+        (Json::String(x), y) => Ok(Json::String(x.clone() + &json_tostring(y))),
+        (x, Json::String(y)) => Ok(Json::String(json_tostring(x) + y)),
+        _ => Err(Error::BadType),
+    })
+}
+
+/// subtraction of two json values
+pub fn json_sub(lhs: &Json, rhs: &Json) -> Result<Json, Error> {
+    json_sub_with(&DefaultOps, lhs, rhs)
+}
+
+/// subtraction of two json values with the given arithmetic
+pub fn json_sub_with<N: NumericOps + ?Sized>(
+    ops: &N,
+    lhs: &Json,
+    rhs: &Json,
+) -> Result<Json, Error> {
+    zip_with(lhs, rhs, &|x, y| num_op(x, y, |x, y| ops.sub(x, y)))
+}
+
+/// multiplication of two json values
+pub fn json_mul(lhs: &Json, rhs: &Json) -> Result<Json, Error> {
+    json_mul_with(&DefaultOps, lhs, rhs)
+}
+
+/// multiplication of two json values with the given arithmetic
+pub fn json_mul_with<N: NumericOps + ?Sized>(
+    ops: &N,
+    lhs: &Json,
+    rhs: &Json,
+) -> Result<Json, Error> {
+    zip_with(lhs, rhs, &|x, y| num_op(x, y, |x, y| ops.mul(x, y)))
+}
+
+/// compute the vectorized division of two json values
+pub fn json_div(lhs: &Json, rhs: &Json) -> Result<Json, Error> {
+    json_div_with(&DefaultOps, lhs, rhs)
+}
+
+/// the vectorized division of two json values with the given arithmetic
+pub fn json_div_with<N: NumericOps + ?Sized>(
+    ops: &N,
+    lhs: &Json,
+    rhs: &Json,
+) -> Result<Json, Error> {
+    zip_with(lhs, rhs, &|x, y| num_op(x, y, |x, y| ops.div(x, y)))
+}
+
+pub(crate) fn json_add_nums(x: &JsonNum, y: &JsonNum) -> JsonNum {
+    match (x.is_i64(), y.is_i64()) {
+        (true, true) => JsonNum::from(x.as_i64().unwrap() + y.as_i64().unwrap()),
+        _ => JsonNum::from_f64(x.as_f64().unwrap() + y.as_f64().unwrap()).unwrap(),
+    }
+}
+
+//TODO(jaupe) add more cases
+/// bars two json values together.
+pub fn json_bar(lhs: &Json, rhs: &Json) -> Result<Json, Error> {
+    match (lhs, rhs) {
+        (Json::Array(lhs), Json::Array(rhs)) => json_bar_arrs(lhs, rhs),
+        (Json::Array(lhs), rhs) => json_bar_arr_val(lhs, rhs),
+        (Json::Number(lhs), Json::Number(rhs)) => json_bar_num_num(lhs, rhs),
+        _ => Err(Error::BadType),
+    }
+}
+
+/// vectorised bar of  two json arrays.
+fn json_bar_arrs(lhs: &[Json], rhs: &[Json]) -> Result<Json, Error> {
+    let mut out = Vec::new();
+    for (x, y) in lhs.iter().zip(rhs.iter()) {
+        let val = json_bar(x, y)?;
+        out.push(val);
+    }
+    Ok(Json::Array(out))
+}
+
+/// vectorised bar of an array and scalar.
+fn json_bar_arr_val(lhs: &[Json], rhs: &Json) -> Result<Json, Error> {
+    let mut out = Vec::new();
+    for val in lhs {
+        let val = json_bar(val, rhs)?;
+        out.push(val);
+    }
+    Ok(Json::Array(out))
+}
+
+/// bar of two json numbers
+fn json_bar_num_num(lhs: &JsonNum, rhs: &JsonNum) -> Result<Json, Error> {
+    match (lhs.as_i64(), rhs.as_i64()) {
+        (Some(x), Some(y)) => Ok(Json::from(x / y * y)),
+        _ => Err(Error::BadType),
+    }
+}
+
+pub fn json_add2(x: &Json, y: &Json) -> Json {
+    if let Some(sum) = typed_add(x, y) {
+        return sum;
+    }
+    match (x, y) {
+        (Json::Number(x), Json::Number(y)) => Json::Number(json_add_nums(x, y)),
+        (Json::Number(x), _) => Json::Number(x.clone()),
+        (_, Json::Number(y)) => Json::Number(y.clone()),
+        _ => Json::from(0),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    #[test]
+    fn vectorised_arithmetic() {
+        assert_eq!(
+            Ok(json!([2, 4.5])),
+            json_add(&json!([1, 2]), &json!([1, 2.5]))
+        );
+        assert_eq!(
+            Ok(json!(["a1", "b1"])),
+            json_add(&json!(["a", "b"]), &json!(1))
+        );
+        assert_eq!(Ok(json!([9, 8])), json_sub(&json!(10), &json!([1, 2])));
+        assert_eq!(Ok(json!([2, null])), json_mul(&json!([1, null]), &json!(2)));
+        assert_eq!(Ok(Json::Null), json_div(&Json::Null, &json!([1])));
+        assert_eq!(Err(Error::BadType), json_mul(&json!(["a"]), &json!(2)));
+    }
+
+    #[test]
+    fn checked_ops_fail_on_overflow() {
+        let max = json!(i64::MAX);
+        assert_eq!(
+            Err(Error::Overflow),
+            json_add_with(&CheckedOps, &max, &json!(1))
+        );
+        assert_eq!(
+            Err(Error::Overflow),
+            json_mul_with(&CheckedOps, &json!([1, 2]), &max)
+        );
+        assert_eq!(
+            Ok(json!(1.5)),
+            json_add_with(&CheckedOps, &json!(1), &json!(0.5))
+        );
+        assert_eq!(
+            Err(Error::DivByZero),
+            json_div_with(&CheckedOps, &json!(1), &json!(0))
+        );
+        assert_eq!(
+            Ok(json!(0.5)),
+            json_div_with(&CheckedOps, &json!(1), &json!(2))
+        );
+    }
+}
@@ -0,0 +1,113 @@
+use super::Json;
+use serde_json::json;
+use std::collections::{BTreeSet, HashMap};
+
+/// the counters kept by the heavy hitters sketch for each of the top k values asked for
+pub const COUNTERS_PER_HITTER: usize = 4;
+
+#[derive(Debug)]
+struct Counter {
+    val: Json,
+    count: u64,
+    /// the most the count overestimates the occurrences of the value
+    error: u64,
+}
+
+/// The approximate most frequent values of a stream, counted by the SpaceSaving
+/// algorithm in a fixed number of counters whatever the number of distinct values.
+/// A value missing a counter takes over the smallest one, inheriting its count as error
+#[derive(Debug)]
+pub struct SpaceSaving {
+    capacity: usize,
+    counters: HashMap<String, Counter>,
+    /// the counters ordered by count, so the smallest is found without a scan
+    by_count: BTreeSet<(u64, String)>,
+}
+
+impl SpaceSaving {
+    pub fn new(capacity: usize) -> Self {
+        SpaceSaving {
+            capacity: capacity.max(1),
+            counters: HashMap::new(),
+            by_count: BTreeSet::new(),
+        }
+    }
+
+    /// counts an occurrence of a value
+    pub fn add(&mut self, val: &Json) {
+        let id = val.to_string();
+        if let Some(counter) = self.counters.get_mut(&id) {
+            self.by_count.remove(&(counter.count, id.clone()));
+            counter.count += 1;
+            self.by_count.insert((counter.count, id));
+            return;
+        }
+        let (count, error) = if self.counters.len() < self.capacity {
+            (1, 0)
+        } else {
+            let (min, victim) = self.by_count.pop_first().unwrap();
+            self.counters.remove(&victim);
+            (min + 1, min)
+        };
+        let val = val.clone();
+        self.by_count.insert((count, id.clone()));
+        self.counters.insert(id, Counter { val, count, error });
+    }
+
+    /// the k most counted values, most counted first, with their counts and errors
+    pub fn top(&self, k: usize) -> Json {
+        self.by_count
+            .iter()
+            .rev()
+            .take(k)
+            .map(|(_, id)| {
+                let x = &self.counters[id];
+                json!({"val": x.val, "count": x.count, "error": x.error})
+            })
+            .collect()
+    }
+}
+
+/// the approximate k most frequent non-null values in the json value
+pub fn json_heavy_hitters(val: &Json, k: usize) -> Json {
+    let mut sketch = SpaceSaving::new(k.saturating_mul(COUNTERS_PER_HITTER));
+    match val {
+        Json::Array(arr) => arr
+            .iter()
+            .filter(|x| !x.is_null())
+            .for_each(|x| sketch.add(x)),
+        Json::Null => (),
+        val => sketch.add(val),
+    }
+    sketch.top(k)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn frequent_values_counted_exactly_within_capacity() {
+        let val = json!(["a", "b", "a", null, "c", "a", "b", 1]);
+        let exp = json!([
+            {"val": "a", "count": 3, "error": 0},
+            {"val": "b", "count": 2, "error": 0},
+        ]);
+        assert_eq!(exp, json_heavy_hitters(&val, 2));
+        assert_eq!(json!([]), json_heavy_hitters(&Json::Null, 2));
+    }
+
+    #[test]
+    fn skewed_stream_keeps_heavy_hitters() {
+        let mut sketch = SpaceSaving::new(4);
+        for i in 0..1000 {
+            sketch.add(&json!("hot"));
+            sketch.add(&json!(i));
+        }
+        let top = sketch.top(1);
+        assert_eq!(json!("hot"), top[0]["val"]);
+        let count = top[0]["count"].as_u64().unwrap();
+        let error = top[0]["error"].as_u64().unwrap();
+        assert!(count >= 1000 && count - error <= 1000);
+    }
+}
@@ -0,0 +1,383 @@
+use super::Json;
+use serde_json::{json, Number};
+use std::cmp::Ordering;
+use std::fmt;
+
+/// the field annotating a string as a date or timestamp
+pub const DATE_KEY: &str = "$date";
+/// the field annotating a string as an exact decimal number
+pub const DECIMAL_KEY: &str = "$decimal";
+
+const MILLIS_PER_DAY: i64 = 86_400_000;
+/// the most digits after the point a decimal keeps
+const MAX_SCALE: u32 = 30;
+
+/// A value of a type plain json lacks, stored as a single field object of a string so
+/// it survives any json round trip
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Typed {
+    /// `{"$date": "2021-03-04"}` or `{"$date": "2021-03-04T10:00:00.5+01:00"}`, as the
+    /// milliseconds since 1970-01-01 UTC
+    Date(i64),
+    /// `{"$decimal": "12.30"}`
+    Decimal(Decimal),
+}
+
+/// An exact decimal number of units of ten to the minus scale
+#[derive(Clone, Copy, Debug)]
+pub struct Decimal {
+    units: i128,
+    scale: u32,
+}
+
+impl Decimal {
+    /// parses an optionally signed number with an optional fraction, as `-12.30`
+    pub fn parse(s: &str) -> Option<Self> {
+        let digits = s.strip_prefix(['-', '+']).unwrap_or(s);
+        let (int, frac) = digits.split_once('.').unwrap_or((digits, ""));
+        let all_digits = |x: &str| x.bytes().all(|x| x.is_ascii_digit());
+        if int.is_empty() || !all_digits(int) || !all_digits(frac) {
+            return None;
+        }
+        let scale = frac.len() as u32;
+        if scale > MAX_SCALE {
+            return None;
+        }
+        let mut units: i128 = format!("{}{}", int, frac).parse().ok()?;
+        if s.starts_with('-') {
+            units = -units;
+        }
+        Some(Decimal { units, scale })
+    }
+
+    /// the exact decimal of a json number
+    pub fn from_number(num: &Number) -> Option<Self> {
+        match num.as_i64() {
+            Some(x) => Some(Decimal {
+                units: i128::from(x),
+                scale: 0,
+            }),
+            // floats display as their shortest exact decimal, without exponents
+            None => Decimal::parse(&num.as_f64()?.to_string()),
+        }
+    }
+
+    fn rescale(&self, scale: u32) -> Option<i128> {
+        self.units
+            .checked_mul(10i128.checked_pow(scale - self.scale)?)
+    }
+
+    pub fn checked_add(&self, other: &Decimal) -> Option<Decimal> {
+        let scale = self.scale.max(other.scale);
+        let units = self.rescale(scale)?.checked_add(other.rescale(scale)?)?;
+        Some(Decimal { units, scale })
+    }
+
+    /// divides by a count, rounding half away from zero to the same scale
+    pub fn div_count(&self, n: usize) -> Option<Decimal> {
+        let n = Some(n as i128).filter(|n| *n > 0)?;
+        let (quot, rem) = (self.units / n, self.units % n);
+        let units = if rem.abs() * 2 >= n {
+            quot + self.units.signum()
+        } else {
+            quot
+        };
+        Some(Decimal { units, ..*self })
+    }
+
+    pub fn to_f64(&self) -> f64 {
+        self.to_string().parse().unwrap_or(f64::NAN)
+    }
+
+    pub fn to_json(&self) -> Json {
+        json!({ DECIMAL_KEY: self.to_string() })
+    }
+}
+
+impl fmt::Display for Decimal {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let sign = if self.units < 0 { "-" } else { "" };
+        let digits = self.units.unsigned_abs().to_string();
+        let scale = self.scale as usize;
+        if scale == 0 {
+            return write!(f, "{}{}", sign, digits);
+        }
+        let digits = format!("{:0>width$}", digits, width = scale + 1);
+        let (int, frac) = digits.split_at(digits.len() - scale);
+        write!(f, "{}{}.{}", sign, int, frac)
+    }
+}
+
+impl Ord for Decimal {
+    fn cmp(&self, other: &Self) -> Ordering {
+        let scale = self.scale.max(other.scale);
+        match (self.rescale(scale), other.rescale(scale)) {
+            (Some(x), Some(y)) => x.cmp(&y),
+            _ => self.to_f64().total_cmp(&other.to_f64()),
+        }
+    }
+}
+
+impl PartialOrd for Decimal {
+    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl PartialEq for Decimal {
+    fn eq(&self, other: &Self) -> bool {
+        self.cmp(other) == Ordering::Equal
+    }
+}
+
+impl Eq for Decimal {}
+
+/// the annotated value of a json value; none if it isn't a valid annotation
+pub fn typed(val: &Json) -> Option<Typed> {
+    let obj = val.as_object().filter(|obj| obj.len() == 1)?;
+    let (key, val) = obj.iter().next()?;
+    let s = val.as_str()?;
+    match key.as_str() {
+        DATE_KEY => parse_timestamp(s).map(Typed::Date),
+        DECIMAL_KEY => Decimal::parse(s).map(Typed::Decimal),
+        _ => None,
+    }
+}
+
+/// orders two values when either is annotated; dates also compare with date strings and
+/// decimals with numbers. None if neither is annotated or they don't compare
+pub fn typed_cmp(x: &Json, y: &Json) -> Option<Ordering> {
+    if !x.is_object() && !y.is_object() {
+        return None;
+    }
+    let date = |val: &Json| match val {
+        Json::String(s) => parse_timestamp(s),
+        val => match typed(val)? {
+            Typed::Date(x) => Some(x),
+            Typed::Decimal(_) => None,
+        },
+    };
+    let decimal = |val: &Json| match val {
+        Json::Number(num) => Decimal::from_number(num),
+        val => match typed(val)? {
+            Typed::Decimal(x) => Some(x),
+            Typed::Date(_) => None,
+        },
+    };
+    match (typed(x), typed(y)) {
+        (Some(Typed::Date(x)), _) => Some(x.cmp(&date(y)?)),
+        (_, Some(Typed::Date(y))) => Some(date(x)?.cmp(&y)),
+        (Some(Typed::Decimal(x)), _) => Some(x.cmp(&decimal(y)?)),
+        (_, Some(Typed::Decimal(y))) => Some(decimal(x)?.cmp(&y)),
+        (None, None) => None,
+    }
+}
+
+/// the exact sum of two values when either is a decimal and the other a decimal or
+/// number; none otherwise. Null if the sum overflows
+pub fn typed_add(x: &Json, y: &Json) -> Option<Json> {
+    if !x.is_object() && !y.is_object() {
+        return None;
+    }
+    let (sum, _) = decimal_sum(&[x.clone(), y.clone()])?;
+    Some(sum.map_or(Json::Null, |x| x.to_json()))
+}
+
+/// the exact sum of the numbers of an array holding decimals, as a decimal; none if it
+/// holds none. Null if the sum overflows
+pub fn typed_sum(vals: &[Json]) -> Option<Json> {
+    let (sum, _) = decimal_sum(vals)?;
+    Some(sum.map_or(Json::Null, |x| x.to_json()))
+}
+
+/// the mean of the numbers of an array holding decimals, as a decimal rounded to the
+/// scale of its values; none if it holds none. Null if it has no mean
+pub fn typed_avg(vals: &[Json]) -> Option<Json> {
+    let (sum, n) = decimal_sum(vals)?;
+    Some(
+        sum.and_then(|x| x.div_count(n))
+            .map_or(Json::Null, |x| x.to_json()),
+    )
+}
+
+/// the sum and count of the decimals and numbers of an array; none unless it holds a
+/// decimal
+fn decimal_sum(vals: &[Json]) -> Option<(Option<Decimal>, usize)> {
+    let mut any_decimal = false;
+    let mut n = 0;
+    let mut sum = Some(Decimal { units: 0, scale: 0 });
+    for val in vals {
+        let x = match val {
+            Json::Number(num) => Decimal::from_number(num),
+            val => match typed(val) {
+                Some(Typed::Decimal(x)) => {
+                    any_decimal = true;
+                    Some(x)
+                }
+                _ => None,
+            },
+        };
+        if let Some(x) = x {
+            n += 1;
+            sum = sum.and_then(|sum| sum.checked_add(&x));
+        }
+    }
+    if any_decimal {
+        Some((sum, n))
+    } else {
+        None
+    }
+}
+
+/// the days since 1970-01-01 of a `yyyy-mm-dd` date
+pub fn parse_date(s: &str) -> Option<i64> {
+    let mut parts = s.splitn(3, '-').map(str::parse::<i64>);
+    let (y, m, d) = (
+        parts.next()?.ok()?,
+        parts.next()?.ok()?,
+        parts.next()?.ok()?,
+    );
+    if !(1..=12).contains(&m) || !(1..=31).contains(&d) {
+        return None;
+    }
+    // the civil calendar counted from march, so leap days end the year
+    let y = if m <= 2 { y - 1 } else { y };
+    let era = y.div_euclid(400);
+    let yoe = y - era * 400;
+    let doy = (153 * ((m + 9) % 12) + 2) / 5 + d - 1;
+    let doe = yoe * 365 + yoe / 4 - yoe / 100 + doy;
+    Some(era * 146_097 + doe - 719_468)
+}
+
+/// the `yyyy-mm-dd` date of the days since 1970-01-01
+pub fn format_date(days: i64) -> String {
+    let z = days + 719_468;
+    let era = z.div_euclid(146_097);
+    let doe = z - era * 146_097;
+    let yoe = (doe - doe / 1460 + doe / 36_524 - doe / 146_096) / 365;
+    let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+    let mp = (5 * doy + 2) / 153;
+    let d = doy - (153 * mp + 2) / 5 + 1;
+    let m = if mp < 10 { mp + 3 } else { mp - 9 };
+    let y = yoe + era * 400 + i64::from(m <= 2);
+    format!("{:04}-{:02}-{:02}", y, m, d)
+}
+
+/// the milliseconds since 1970-01-01 UTC of a `yyyy-mm-dd` date, or of an ISO 8601
+/// timestamp as `yyyy-mm-ddThh:mm:ss[.fff][Z|±hh:mm]`
+pub fn parse_timestamp(s: &str) -> Option<i64> {
+    let (date, time) = match s.split_once(['T', ' ']) {
+        Some((date, time)) => (date, Some(time)),
+        None => (s, None),
+    };
+    let days = parse_date(date)?;
+    let time = match time {
+        Some(time) => time,
+        None => return days.checked_mul(MILLIS_PER_DAY),
+    };
+    let (time, offset) = match time.find(['Z', '+', '-']) {
+        Some(i) => (&time[..i], parse_offset(&time[i..])?),
+        None => (time, 0),
+    };
+    let (hms, frac) = time.split_once('.').unwrap_or((time, ""));
+    let mut parts = hms.splitn(3, ':').map(str::parse::<i64>);
+    let (h, m, sec) = (
+        parts.next()?.ok()?,
+        parts.next()?.ok()?,
+        parts.next().unwrap_or(Ok(0)).ok()?,
+    );
+    if h > 23 || m > 59 || sec > 60 || !frac.bytes().all(|x| x.is_ascii_digit()) {
+        return None;
+    }
+    let millis: i64 = format!("{:0<3}", &frac[..frac.len().min(3)]).parse().ok()?;
+    let in_day = ((h * 60 + m) * 60 + sec) * 1000 + millis;
+    days.checked_mul(MILLIS_PER_DAY)?
+        .checked_add(in_day)?
+        .checked_sub(offset)
+}
+
+/// the milliseconds a `Z` or `±hh:mm` offset is ahead of UTC
+fn parse_offset(s: &str) -> Option<i64> {
+    if s == "Z" {
+        return Some(0);
+    }
+    let sign = if s.starts_with('-') { -1 } else { 1 };
+    let (h, m) = s[1..].split_once(':').unwrap_or((&s[1..], "0"));
+    let (h, m): (i64, i64) = (h.parse().ok()?, m.parse().ok()?);
+    Some(sign * (h * 60 + m) * 60_000)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn dates_round_trip() {
+        assert_eq!(Some(0), parse_date("1970-01-01"));
+        assert_eq!(Some(-1), parse_date("1969-12-31"));
+        for day in [-700_000, -1, 0, 59, 11_016, 18_321, 2_932_896] {
+            assert_eq!(Some(day), parse_date(&format_date(day)));
+        }
+        assert_eq!(None, parse_date("2020-13-01"));
+    }
+
+    #[test]
+    fn annotations_parsed() {
+        let day = parse_date("2021-03-04").unwrap() * MILLIS_PER_DAY;
+        assert_eq!(
+            Some(Typed::Date(day)),
+            typed(&json!({"$date": "2021-03-04"}))
+        );
+        let at = json!({"$date": "2021-03-04T10:30:00.25+01:00"});
+        let millis = day + (9 * 3600 + 30 * 60) * 1000 + 250;
+        assert_eq!(Some(Typed::Date(millis)), typed(&at));
+        assert_eq!(Some(millis), parse_timestamp("2021-03-04T09:30:00.250Z"));
+        let dec = Decimal::parse("-12.30").unwrap();
+        assert_eq!("-12.30", dec.to_string());
+        assert_eq!(
+            Some(Typed::Decimal(dec)),
+            typed(&json!({"$decimal": "-12.3"}))
+        );
+        assert_eq!("0.05", Decimal::parse("0.05").unwrap().to_string());
+        for val in [
+            json!({"$date": "2021-13-01"}),
+            json!({"$date": "2021-03-04T25:00:00"}),
+            json!({"$decimal": "1e5"}),
+            json!({"$decimal": 1.5}),
+            json!({"$decimal": "1.5", "x": 1}),
+        ] {
+            assert_eq!(None, typed(&val), "{}", val);
+        }
+    }
+
+    #[test]
+    fn annotated_values_ordered() {
+        let (x, y) = (json!({"$decimal": "0.10"}), json!({"$decimal": "0.1"}));
+        assert_eq!(Some(Ordering::Equal), typed_cmp(&x, &y));
+        assert_eq!(Some(Ordering::Less), typed_cmp(&x, &json!(0.2)));
+        assert_eq!(Some(Ordering::Greater), typed_cmp(&json!(1), &x));
+        let day = json!({"$date": "2021-03-04"});
+        assert_eq!(
+            Some(Ordering::Greater),
+            typed_cmp(&day, &json!("2021-03-03"))
+        );
+        let at = json!({"$date": "2021-03-04T00:00:00Z"});
+        assert_eq!(Some(Ordering::Equal), typed_cmp(&day, &at));
+        assert_eq!(None, typed_cmp(&day, &x));
+        assert_eq!(None, typed_cmp(&json!(1), &json!(2)));
+    }
+
+    #[test]
+    fn decimals_summed_exactly() {
+        let vals = json!([{"$decimal": "0.10"}, 0.2, {"$decimal": "0.005"}, "x"]);
+        let vals = vals.as_array().unwrap();
+        assert_eq!(Some(json!({"$decimal": "0.305"})), typed_sum(vals));
+        assert_eq!(Some(json!({"$decimal": "0.102"})), typed_avg(vals));
+        assert_eq!(None, typed_sum(&[json!(1), json!(2)]));
+        let max = json!({"$decimal": i128::MAX.to_string()});
+        assert_eq!(Some(Json::Null), typed_sum(&[max.clone(), max]));
+        let half = json!([{"$decimal": "-1"}, {"$decimal": "-2"}]);
+        let exp = json!({"$decimal": "-2"});
+        assert_eq!(Some(exp), typed_avg(half.as_array().unwrap()));
+    }
+}
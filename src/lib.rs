@@ -0,0 +1,6 @@
+//! The json utilities and trace format of memson, usable without the database
+pub mod err;
+pub mod json_ops;
+#[cfg(test)]
+mod tempdir;
+pub mod trace;
@@ -0,0 +1,237 @@
+use crate::cmd::{Cmd, QueryCmd, Source};
+use crate::db::{tag_rows, unnest, Query};
+use crate::inmem::InMemDb;
+use crate::json_ops::{json_path, json_type, Json};
+use std::collections::BTreeSet;
+
+/// the number of rows sampled when checking fields and types
+const SAMPLE_SIZE: usize = 20;
+
+/// checks a query for common mistakes and describes each one found
+pub fn lint(db: &InMemDb, qry: &QueryCmd) -> Vec<String> {
+    let mut warnings = Vec::new();
+    let mut cmds: Vec<&Cmd> = Vec::new();
+    if let Some(selects) = &qry.selects {
+        if qry.by.is_none() {
+            lint_mixed_selects(selects.iter(), &mut warnings);
+        }
+        cmds.extend(selects.values());
+    }
+    if let Some(by) = &qry.by {
+        cmds.push(by);
+    }
+    let filter = match qry.filter.clone().map(Cmd::parse) {
+        Some(Ok(filter)) => Some(filter),
+        Some(Err(_)) => {
+            warnings.push("where is not a valid cmd".to_string());
+            None
+        }
+        None => None,
+    };
+    if let Some(filter) = &filter {
+        cmds.push(filter);
+    }
+    match sample_rows(db, qry) {
+        Ok(rows) if !rows.is_empty() => {
+            lint_unknown_fields(&cmds, &rows, &mut warnings);
+            if let Some(filter) = &filter {
+                lint_filter_types(filter, &rows, &mut warnings);
+            }
+        }
+        Ok(_) => (),
+        Err(msg) => warnings.push(msg),
+    }
+    warnings
+}
+
+/// the first rows of the from statement
+fn sample_rows(db: &InMemDb, qry: &QueryCmd) -> Result<Vec<Json>, String> {
+    let rows = match &qry.from {
+        Source::Key(key) => key_rows(db, key)?
+            .iter()
+            .take(SAMPLE_SIZE)
+            .cloned()
+            .collect(),
+        Source::Keys(keys) => {
+            let mut rows = Vec::new();
+            for key in keys {
+                let key_rows = key_rows(db, key)?;
+                rows.extend(tag_rows(key, &key_rows[..SAMPLE_SIZE.min(key_rows.len())]));
+            }
+            rows
+        }
+        Source::Query(qry) => match Query::from(db, qry.as_ref().clone()).exec() {
+            Ok(Json::Array(rows)) => rows.into_iter().take(SAMPLE_SIZE).collect(),
+            Ok(_) => return Err("from does not refer to rows".to_string()),
+            Err(err) => return Err(format!("from subquery failed: {}", err)),
+        },
+    };
+    let rows = match &qry.unnest {
+        Some(field) => unnest(&rows, field),
+        None => rows,
+    };
+    Ok(rows.into_iter().take(SAMPLE_SIZE).collect())
+}
+
+/// the rows stored under a from key
+fn key_rows<'a>(db: &'a InMemDb, key: &str) -> Result<&'a [Json], String> {
+    match db.get(key) {
+        Ok(Json::Array(rows)) => Ok(rows),
+        Ok(_) => Err("from does not refer to rows".to_string()),
+        Err(_) => Err(format!("from key '{}' does not exist", key)),
+    }
+}
+
+/// flags selects that mix aggregates and row values without a by statement
+fn lint_mixed_selects<'a, I>(selects: I, warnings: &mut Vec<String>)
+where
+    I: Iterator<Item = (&'a String, &'a Cmd)>,
+{
+    let mut aggs = BTreeSet::new();
+    let mut vals = BTreeSet::new();
+    for (name, cmd) in selects {
+        if cmd.is_aggregate() {
+            aggs.insert(name);
+        } else {
+            vals.insert(name);
+        }
+    }
+    if !aggs.is_empty() && !vals.is_empty() {
+        warnings.push(format!(
+            "select mixes aggregates {:?} with row values {:?} without a by",
+            aggs, vals
+        ));
+    }
+}
+
+/// collects the row fields referenced by a cmd
+fn fields<'a>(cmd: &'a Cmd, out: &mut BTreeSet<&'a str>) {
+    if let Cmd::Key(key) = cmd {
+        out.insert(key);
+    }
+    for child in cmd.children() {
+        fields(child, out);
+    }
+}
+
+/// flags fields that are not present in any of the sampled rows
+fn lint_unknown_fields(cmds: &[&Cmd], rows: &[Json], warnings: &mut Vec<String>) {
+    let mut keys = BTreeSet::new();
+    for cmd in cmds {
+        fields(cmd, &mut keys);
+    }
+    for key in keys {
+        if rows.iter().all(|row| json_path(row, key).is_none()) {
+            warnings.push(format!("field '{}' is not in the sampled rows", key));
+        }
+    }
+}
+
+/// flags comparisons between a field and a literal of a different type
+fn lint_filter_types(filter: &Cmd, rows: &[Json], warnings: &mut Vec<String>) {
+    match filter {
+        Cmd::Eq(x, y)
+        | Cmd::NotEq(x, y)
+        | Cmd::Gt(x, y)
+        | Cmd::Gte(x, y)
+        | Cmd::Lt(x, y)
+        | Cmd::Lte(x, y) => match (x.as_ref(), y.as_ref()) {
+            (Cmd::Key(key), Cmd::Json(lit)) | (Cmd::Json(lit), Cmd::Key(key)) => {
+                lint_cmp_types(key, lit, rows, warnings)
+            }
+            _ => (),
+        },
+        cmd => {
+            for child in cmd.children() {
+                lint_filter_types(child, rows, warnings);
+            }
+        }
+    }
+}
+
+fn lint_cmp_types(key: &str, lit: &Json, rows: &[Json], warnings: &mut Vec<String>) {
+    if lit.is_null() {
+        return;
+    }
+    let types: BTreeSet<&str> = rows
+        .iter()
+        .filter_map(|row| json_path(row, key))
+        .filter(|val| !val.is_null())
+        .map(json_type)
+        .collect();
+    if !types.is_empty() && !types.contains(json_type(lit)) {
+        warnings.push(format!(
+            "where compares field '{}' of type {:?} with {}",
+            key,
+            types,
+            json_type(lit)
+        ));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    fn test_db() -> InMemDb {
+        let mut db = InMemDb::new();
+        db.set(
+            "orders",
+            json!([
+                { "customer": "james", "qty": 2, "price": 9.0 },
+                { "customer": "ania", "qty": 2, "price": 2.0 },
+            ]),
+        );
+        db
+    }
+
+    fn lint_query(qry: Json) -> Vec<String> {
+        let qry = serde_json::from_value(qry).unwrap();
+        lint(&test_db(), &qry)
+    }
+
+    #[test]
+    fn lint_ok() {
+        let warnings = lint_query(json!({
+            "select": {"qty": {"sum": {"key": "qty"}}},
+            "from": "orders",
+            "where": {">": [{"key": "price"}, 1.0]},
+        }));
+        assert!(warnings.is_empty(), "{:?}", warnings);
+    }
+
+    #[test]
+    fn lint_mixed_selects_without_by() {
+        let warnings = lint_query(json!({
+            "select": {"qty": {"sum": {"key": "qty"}}, "customer": {"key": "customer"}},
+            "from": "orders",
+        }));
+        assert_eq!(
+            vec![r#"select mixes aggregates {"qty"} with row values {"customer"} without a by"#],
+            warnings
+        );
+    }
+
+    #[test]
+    fn lint_unknown_field_and_bad_type() {
+        let warnings = lint_query(json!({
+            "select": {"n": {"count": {"key": "amount"}}},
+            "from": "orders",
+            "where": {"==": [{"key": "qty"}, "2"]},
+        }));
+        assert_eq!(
+            vec![
+                "field 'amount' is not in the sampled rows",
+                r#"where compares field 'qty' of type {"number"} with string"#,
+            ],
+            warnings
+        );
+    }
+
+    #[test]
+    fn lint_missing_from() {
+        let warnings = lint_query(json!({"from": "missing"}));
+        assert_eq!(vec!["from key 'missing' does not exist"], warnings);
+    }
+}
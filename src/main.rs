@@ -1,36 +1,263 @@
+use crate::acl::Acls;
+use crate::audit::{Audit, Rejection};
+use crate::auth::{Auth, Credentials, User};
+use crate::backend::{read_keys, written_keys, SingleFlight};
+use crate::capability::Capabilities;
+use crate::chaos::cmd_name;
 use crate::cmd::{Cmd, QueryCmd};
-use crate::db::Memson;
+use crate::db::{Cancel, Memson, Reader, DEFAULT_LIMIT};
+use crate::dump::Format;
 use crate::err::Error;
-use crate::json::Json;
+use crate::eviction::eviction_policy;
+use crate::index::Index;
+use crate::ipfilter::{parse_blocks, IpFilter, Refusal};
+use crate::json_ops::Json;
+use crate::ondisk::OnDiskDb;
+use crate::trace::Tracer;
 use actix::prelude::*;
-use actix_web::{middleware, web, App, HttpResponse, HttpServer};
-use serde::Serialize;
+use actix_web::dev::{Service, ServiceRequest, ServiceResponse};
+use actix_web::error::{BlockingError, JsonPayloadError};
+use actix_web::http::{header, HeaderName, HeaderValue, StatusCode};
+use actix_web::{middleware, web, App, HttpMessage, HttpRequest, HttpResponse, HttpServer};
+use futures::channel::oneshot;
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeSet;
 use std::env;
 use std::fmt::Debug;
+use std::future::Future;
+use std::net::SocketAddr;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
 
+pub use memson::{err, json_ops, trace};
+
+pub mod acl;
 pub mod apply;
+pub mod audit;
+pub mod auth;
+pub mod backend;
+pub mod capability;
+pub mod chaos;
 pub mod cmd;
+pub mod compress;
+pub mod concurrent;
+#[cfg(test)]
+mod conformance;
 pub mod db;
-pub mod err;
+pub mod dump;
 pub mod eval;
+pub mod eviction;
+pub mod expiry;
+pub mod generate;
+#[cfg(test)]
+mod golden;
+pub mod graph;
+pub mod histogram;
+pub mod index;
 pub mod inmem;
-pub mod json;
+pub mod ipfilter;
+pub mod lint;
+pub mod metrics;
+pub mod migrate;
 pub mod ondisk;
+pub mod plan;
+mod preload;
+pub mod pubsub;
+pub mod stats;
+pub mod storage;
+#[cfg(test)]
+mod tempdir;
+#[cfg(feature = "tls")]
+pub mod tls;
+pub mod vector;
+pub mod wal;
+mod ws;
 pub const DEFAULT_PORT: &str = "8888";
+/// the header carrying the caller's trace id; logged and echoed back in the response
+pub const TRACE_HEADER: &str = "x-trace-id";
+/// the version of the wire protocol; bumped on changes incompatible with existing clients
+pub const PROTOCOL_VERSION: &str = "1";
+/// the header carrying the protocol version in every response
+pub const PROTOCOL_HEADER: &str = "x-memson-protocol";
+/// the header carrying the default limit of the rows returned by queries; `none` if unlimited
+pub const DEFAULT_LIMIT_HEADER: &str = "x-memson-default-limit";
+/// the header carrying the milliseconds the caller has left to wait for a response; the
+/// cmd and everything it evaluates times out once they're spent
+pub const TIMEOUT_HEADER: &str = "x-memson-timeout-ms";
+/// the most bytes of a dump restored in one request
+const MAX_RESTORE_BYTES: usize = 1 << 30;
+
+/// The number of rows returned by queries without selects or a limit; none for all rows
+#[derive(Clone, Copy, Debug)]
+struct DefaultLimit(Option<usize>);
+
+impl DefaultLimit {
+    /// reads the `DEFAULT_LIMIT` env var, where `none` disables the limit
+    fn from_env() -> Self {
+        match env::var("DEFAULT_LIMIT") {
+            Ok(s) if s == "none" => DefaultLimit(None),
+            Ok(s) => DefaultLimit(Some(
+                s.parse().expect("DEFAULT_LIMIT must be a number or none"),
+            )),
+            Err(_) => DefaultLimit(Some(DEFAULT_LIMIT)),
+        }
+    }
+
+    fn header_value(&self) -> HeaderValue {
+        match self.0 {
+            Some(n) => HeaderValue::from(n),
+            None => HeaderValue::from_static("none"),
+        }
+    }
+}
 
 type Res = Result<Json, Error>;
 
 /// Define message
-#[derive(Message)]
+#[derive(Debug, Message)]
 #[rtype(result = "Result<Json, Error>")]
 enum Request {
-    Command(Cmd),
-    Query(QueryCmd),
+    Command(Cmd, Cancel, Option<User>),
+    Query(QueryCmd, Cancel, Option<User>),
+}
+
+/// Dumps the entries of the given keys, or of every entry, in a binary format
+#[derive(Message)]
+#[rtype(result = "Result<Vec<u8>, Error>")]
+struct Dump(Option<Vec<String>>, Format, Option<User>);
+
+/// Restores the entries of a dump in a binary format
+#[derive(Message)]
+#[rtype(result = "Result<Json, Error>")]
+struct Restore(web::Bytes, Format, Option<User>);
+
+/// Begins indexing the rows of a key by a field for a user, snapshotting the rows to scan
+#[derive(Message)]
+#[rtype(result = "Result<(u64, Arc<Json>), Error>")]
+struct BeginIndex(String, String, Option<User>);
+
+/// Swaps in an index scanned since it was begun
+#[derive(Message)]
+#[rtype(result = "Result<(), Error>")]
+struct FinishIndex(u64, Index);
+
+/// Checks a user may see the admin resources of memson
+#[derive(Message)]
+#[rtype(result = "Result<(), Error>")]
+struct Admit(Option<User>);
+
+impl Request {
+    /// evaluates the request through a reader if it can be shared with other readers;
+    /// otherwise hands it back
+    fn share(self, reader: &Reader, tracer: Option<&Mutex<Tracer>>) -> Result<Res, Box<Request>> {
+        let (cmd, cancel, user) = match self {
+            Request::Command(cmd, cancel, user) => (cmd, cancel, user),
+            Request::Query(qry, cancel, user) => (Cmd::Query(qry), cancel, user),
+        };
+        let traced = tracer.map(|_| trace_of(&cmd));
+        let start = Instant::now();
+        match reader.eval_within(cmd, &cancel, user.as_ref()) {
+            Ok(res) => {
+                trace(tracer, traced, start);
+                Ok(res)
+            }
+            Err(cmd) => Err(Box::new(Request::Command(*cmd, cancel, user))),
+        }
+    }
+}
+
+/// The db as seen by the handlers: the cmds only reading entries in memory are evaluated
+/// on the blocking pool alongside each other, and every other cmd by the db actor in turn
+#[derive(Clone)]
+pub(crate) struct Db {
+    actor: Addr<DbActor>,
+    reader: Reader,
+    tracer: Option<Arc<Mutex<Tracer>>>,
+}
+
+impl Db {
+    /// starts the db actor evaluating the cmds of memson that can't be shared
+    fn start(db: Memson, tracer: Option<Tracer>) -> Self {
+        let reader = db.reader();
+        let tracer = tracer.map(|x| Arc::new(Mutex::new(x)));
+        let actor = DbActor {
+            db,
+            tracer: tracer.clone(),
+        };
+        Db {
+            actor: actor.start(),
+            reader,
+            tracer,
+        }
+    }
+
+    /// evaluates a request, alongside other readers if it only reads entries in memory
+    async fn send(&self, req: Request) -> Result<Res, MailboxError> {
+        if let Request::Command(Cmd::CreateIndex { key, field }, _, user) = req {
+            return self.create_index(key, field, user).await;
+        }
+        let (reader, tracer) = (self.reader.clone(), self.tracer.clone());
+        match web::block(move || req.share(&reader, tracer.as_deref())).await {
+            Ok(res) => Ok(res),
+            Err(BlockingError::Error(req)) => self.actor.send(*req).await,
+            Err(BlockingError::Canceled) => Err(MailboxError::Closed),
+        }
+    }
+
+    /// indexes the rows of a key by a field, scanning a snapshot of them on the blocking
+    /// pool so the db actor goes on evaluating other cmds meanwhile. The build is spawned
+    /// rather than awaited here, so it's finished even if the client goes away, and the
+    /// db doesn't go on tracking writes for it
+    async fn create_index(
+        &self,
+        key: String,
+        field: String,
+        user: Option<User>,
+    ) -> Result<Res, MailboxError> {
+        let (actor, tracer) = (self.actor.clone(), self.tracer.clone());
+        let (tx, rx) = oneshot::channel();
+        actix_rt::spawn(async move {
+            let res = build_index(&actor, tracer.as_deref(), key, field, user).await;
+            let _ = tx.send(res);
+        });
+        rx.await.unwrap_or(Err(MailboxError::Closed))
+    }
+}
+
+/// begins an index through the db actor, scans its snapshot on the blocking pool, then
+/// swaps it in through the actor
+async fn build_index(
+    actor: &Addr<DbActor>,
+    tracer: Option<&Mutex<Tracer>>,
+    key: String,
+    field: String,
+    user: Option<User>,
+) -> Result<Res, MailboxError> {
+    let traced = tracer.map(|_| {
+        trace_of(&Cmd::CreateIndex {
+            key: key.clone(),
+            field: field.clone(),
+        })
+    });
+    let start = Instant::now();
+    let begin = BeginIndex(key, field.clone(), user);
+    let res = match actor.send(begin).await? {
+        Ok((id, val)) => {
+            let build = move || Ok::<_, Error>(Index::build(&val, field));
+            let index = web::block(build).await.map_err(|_| MailboxError::Closed)?;
+            actor.send(FinishIndex(id, index)).await?
+        }
+        Err(err) => Err(err),
+    };
+    trace(tracer, traced, start);
+    Ok(res.map(|_| Json::Null))
 }
 
 // Define actor
 struct DbActor {
     db: Memson,
+    /// records the cmds evaluated, if traced
+    tracer: Option<Arc<Mutex<Tracer>>>,
 }
 
 // implementation of actor for db
@@ -51,13 +278,95 @@ impl Handler<Request> for DbActor {
     type Result = Res;
 
     fn handle(&mut self, req: Request, _: &mut Context<Self>) -> Self::Result {
-        match req {
-            Request::Command(cmd) => self.db.eval(cmd),
-            Request::Query(qry) => self.db.query(qry),
+        let traced = match (&self.tracer, &req) {
+            (None, _) => None,
+            (Some(_), Request::Command(cmd, _, _)) => Some(trace_of(cmd)),
+            (Some(_), Request::Query(qry, _, _)) => Some(trace_of(&Cmd::Query(qry.clone()))),
+        };
+        let start = Instant::now();
+        let res = match req {
+            Request::Command(cmd, cancel, user) => self.db.eval_within(cmd, cancel, user.as_ref()),
+            Request::Query(qry, cancel, user) => self.db.query_within(qry, cancel, user.as_ref()),
+        };
+        trace(self.tracer.as_deref(), traced, start);
+        res
+    }
+}
+
+impl Handler<Dump> for DbActor {
+    type Result = Result<Vec<u8>, Error>;
+
+    fn handle(&mut self, Dump(keys, format, user): Dump, _: &mut Context<Self>) -> Self::Result {
+        self.db.dump(keys.as_deref(), format, user.as_ref())
+    }
+}
+
+impl Handler<Restore> for DbActor {
+    type Result = Res;
+
+    fn handle(&mut self, req: Restore, _: &mut Context<Self>) -> Self::Result {
+        let Restore(data, format, user) = req;
+        self.db.restore(&data, format, user.as_ref())
+    }
+}
+
+impl Handler<BeginIndex> for DbActor {
+    type Result = Result<(u64, Arc<Json>), Error>;
+
+    fn handle(&mut self, req: BeginIndex, _: &mut Context<Self>) -> Self::Result {
+        let BeginIndex(key, field, user) = req;
+        self.db.begin_index(&key, &field, user.as_ref())
+    }
+}
+
+impl Handler<FinishIndex> for DbActor {
+    type Result = Result<(), Error>;
+
+    fn handle(
+        &mut self,
+        FinishIndex(id, index): FinishIndex,
+        _: &mut Context<Self>,
+    ) -> Self::Result {
+        self.db.finish_index(id, index)
+    }
+}
+
+impl Handler<Admit> for DbActor {
+    type Result = Result<(), Error>;
+
+    fn handle(&mut self, Admit(user): Admit, _: &mut Context<Self>) -> Self::Result {
+        self.db.check_admin(user.as_ref())
+    }
+}
+
+/// records a cmd evaluated since the given instant, given its name, keys and size
+fn trace(
+    tracer: Option<&Mutex<Tracer>>,
+    traced: Option<(String, BTreeSet<String>, usize)>,
+    start: Instant,
+) {
+    if let (Some(tracer), Some((name, keys, size))) = (tracer, traced) {
+        let keys = keys.iter().map(String::as_str);
+        let res = tracer
+            .lock()
+            .unwrap()
+            .record(&name, keys, size, start.elapsed());
+        if let Err(err) = res {
+            println!("cannot record trace: {}", err);
         }
     }
 }
 
+/// the name, keys and size of a cmd as traced
+fn trace_of(cmd: &Cmd) -> (String, BTreeSet<String>, usize) {
+    let name = cmd_name(cmd).unwrap_or_default();
+    let mut keys = BTreeSet::new();
+    read_keys(cmd, &mut keys);
+    written_keys(cmd, &mut keys);
+    let size = serde_json::to_vec(cmd).map_or(0, |x| x.len());
+    (name, keys, size)
+}
+
 fn http_resp<T: Debug + Serialize>(r: Result<Result<T, Error>, MailboxError>) -> HttpResponse {
     match r {
         Ok(Ok(val)) => HttpResponse::Ok().json(val),
@@ -66,27 +375,311 @@ fn http_resp<T: Debug + Serialize>(r: Result<Result<T, Error>, MailboxError>) ->
     }
 }
 
-async fn summary(tx: web::Data<Addr<DbActor>>) -> HttpResponse {
-    let res = tx.send(Request::Command(Cmd::Summary)).await;
+/// records a rejected request of a client in the audit, if requests are audited
+fn audit(
+    audit: Option<&web::Data<Audit>>,
+    addr: Option<SocketAddr>,
+    rejection: Rejection,
+    detail: &str,
+) {
+    if let Some(audit) = audit {
+        let client = addr.map_or_else(|| "unknown".to_string(), |x| x.ip().to_string());
+        audit.record(&client, rejection, detail);
+    }
+}
+
+/// audits the requests rejected as disabled by memson
+fn audit_result<T>(req: &HttpRequest, r: &Result<Result<T, Error>, MailboxError>) {
+    if let Ok(Err(err @ Error::Disabled(_))) = r {
+        let detail = err.to_string();
+        audit(
+            req.app_data(),
+            req.peer_addr(),
+            Rejection::Disabled,
+            &detail,
+        );
+    }
+}
+
+/// audits bodies that aren't json, or aren't a cmd or query
+fn json_error(err: JsonPayloadError, req: &HttpRequest) -> actix_web::Error {
+    audit(
+        req.app_data(),
+        req.peer_addr(),
+        Rejection::Malformed,
+        &err.to_string(),
+    );
+    err.into()
+}
+
+/// reports the rejected requests to users whose grant allows admin cmds
+async fn audit_report(
+    req: HttpRequest,
+    db: web::Data<Db>,
+    audit: Option<web::Data<Audit>>,
+) -> HttpResponse {
+    match db.actor.send(Admit(user_of(&req))).await {
+        Ok(Ok(())) => {
+            let report = audit.map_or_else(|| Json::Array(Vec::new()), |x| x.report());
+            HttpResponse::Ok().json(report)
+        }
+        r => http_resp(r),
+    }
+}
+
+async fn summary(req: HttpRequest, tx: web::Data<Db>) -> HttpResponse {
+    let cmd = Request::Command(Cmd::Summary(None), Cancel::default(), user_of(&req));
+    let res = tx.send(cmd).await;
     http_resp(res)
 }
 
-async fn eval2(db: web::Data<Addr<DbActor>>, cmd: web::Json<Json>) -> HttpResponse {
+/// the user the request authenticated as, if any
+fn user_of(req: &HttpRequest) -> Option<User> {
+    req.extensions().get::<User>().cloned()
+}
+
+/// the token cancelled once the time left to the caller is spent, counted from the arrival
+/// of the request so time queued for the db counts too
+fn caller_deadline(req: &HttpRequest) -> Cancel {
+    req.headers()
+        .get(TIMEOUT_HEADER)
+        .and_then(|x| x.to_str().ok())
+        .and_then(|x| x.parse().ok())
+        .map(|ms| Cancel::after(Duration::from_millis(ms)))
+        .unwrap_or_default()
+}
+
+async fn eval2(req: HttpRequest, db: web::Data<Db>, cmd: web::Json<Json>) -> HttpResponse {
+    let cancel = caller_deadline(&req);
     let cmd = match Cmd::parse(cmd.0) {
         Ok(cmd) => cmd,
-        Err(err) => return HttpResponse::InternalServerError().json(err.to_string()),
+        Err(err) => {
+            let detail = err.to_string();
+            audit(
+                req.app_data(),
+                req.peer_addr(),
+                Rejection::Malformed,
+                &detail,
+            );
+            return HttpResponse::InternalServerError().json(detail);
+        }
     };
     // Send message to `DbExecutor` actor
-    let r = db.send(Request::Command(cmd)).await;
+    let r = db.send(Request::Command(cmd, cancel, user_of(&req))).await;
+    audit_result(&req, &r);
     http_resp(r)
 }
 
-async fn query2(db: web::Data<Addr<DbActor>>, cmd: web::Json<QueryCmd>) -> HttpResponse {
+async fn query2(req: HttpRequest, db: web::Data<Db>, cmd: web::Json<QueryCmd>) -> HttpResponse {
     // Send message to `DbExecutor` actor
-    let r = db.send(Request::Query(cmd.0)).await;
+    let qry = Request::Query(cmd.0, caller_deadline(&req), user_of(&req));
+    let r = db.send(qry).await;
+    audit_result(&req, &r);
+    http_resp(r)
+}
+
+/// The params of a dump or restore: the binary format, `msgpack` unless given, and the
+/// comma separated keys dumped, every key unless given
+#[derive(Debug, Deserialize)]
+struct DumpParams {
+    format: Option<String>,
+    keys: Option<String>,
+}
+
+impl DumpParams {
+    fn format(&self) -> Result<Format, HttpResponse> {
+        let name = self.format.as_deref().unwrap_or("msgpack");
+        Format::parse(name)
+            .ok_or_else(|| HttpResponse::BadRequest().json(format!("bad format: {}", name)))
+    }
+}
+
+async fn dump(req: HttpRequest, db: web::Data<Db>, params: web::Query<DumpParams>) -> HttpResponse {
+    let format = match params.format() {
+        Ok(format) => format,
+        Err(res) => return res,
+    };
+    let keys = params
+        .keys
+        .as_ref()
+        .map(|keys| keys.split(',').map(|key| key.trim().to_string()).collect());
+    match db.actor.send(Dump(keys, format, user_of(&req))).await {
+        Ok(Ok(data)) => HttpResponse::Ok()
+            .content_type(format.content_type())
+            .body(data),
+        r => http_resp(r),
+    }
+}
+
+async fn restore(
+    req: HttpRequest,
+    db: web::Data<Db>,
+    params: web::Query<DumpParams>,
+    body: web::Bytes,
+) -> HttpResponse {
+    let format = match params.format() {
+        Ok(format) => format,
+        Err(res) => return res,
+    };
+    let r = db.actor.send(Restore(body, format, user_of(&req))).await;
+    audit_result(&req, &r);
     http_resp(r)
 }
 
+/// echoes the trace id, so calls can be stitched into distributed traces, and adds the
+/// protocol version and default limit to every response
+fn headers<S>(
+    req: ServiceRequest,
+    srv: &mut S,
+) -> impl Future<Output = Result<ServiceResponse, actix_web::Error>>
+where
+    S: Service<Request = ServiceRequest, Response = ServiceResponse, Error = actix_web::Error>,
+{
+    let trace_id = req.headers().get(TRACE_HEADER).cloned();
+    let default_limit = req
+        .app_data::<web::Data<DefaultLimit>>()
+        .map(|x| x.header_value());
+    let fut = srv.call(req);
+    async move {
+        let mut res = fut.await?;
+        if let Some(trace_id) = trace_id {
+            let name = HeaderName::from_static(TRACE_HEADER);
+            res.headers_mut().insert(name, trace_id);
+        }
+        let name = HeaderName::from_static(PROTOCOL_HEADER);
+        let version = HeaderValue::from_static(PROTOCOL_VERSION);
+        res.headers_mut().insert(name, version);
+        if let Some(default_limit) = default_limit {
+            let name = HeaderName::from_static(DEFAULT_LIMIT_HEADER);
+            res.headers_mut().insert(name, default_limit);
+        }
+        Ok(res)
+    }
+}
+
+/// refuses clients denied by the ip filter, or with too many requests in flight
+fn filter_ips<S>(
+    req: ServiceRequest,
+    srv: &mut S,
+) -> impl Future<Output = Result<ServiceResponse, actix_web::Error>>
+where
+    S: Service<Request = ServiceRequest, Response = ServiceResponse, Error = actix_web::Error>,
+{
+    let filter = req.app_data::<web::Data<IpFilter>>();
+    let admitted = match (filter, req.peer_addr()) {
+        (Some(filter), Some(addr)) if !filter.is_open() => filter.admit(addr.ip()).map(Some),
+        _ => Ok(None),
+    };
+    let res = match admitted {
+        Ok(permit) => Ok((srv.call(req), permit)),
+        Err(refusal) => {
+            let (status, rejection) = match refusal {
+                Refusal::Denied => (StatusCode::FORBIDDEN, Rejection::Denied),
+                Refusal::Busy => (StatusCode::TOO_MANY_REQUESTS, Rejection::Busy),
+            };
+            let detail = format!("{} {}", req.method(), req.path());
+            audit(req.app_data(), req.peer_addr(), rejection, &detail);
+            Err(req.into_response(HttpResponse::new(status)))
+        }
+    };
+    async move {
+        match res {
+            // the permit is held until the response is ready
+            Ok((fut, _permit)) => fut.await,
+            Err(res) => Ok(res),
+        }
+    }
+}
+
+/// refuses requests without valid credentials, unless memson is open to every client;
+/// websockets may authenticate over the socket instead
+fn authenticate<S>(
+    req: ServiceRequest,
+    srv: &mut S,
+) -> impl Future<Output = Result<ServiceResponse, actix_web::Error>>
+where
+    S: Service<Request = ServiceRequest, Response = ServiceResponse, Error = actix_web::Error>,
+{
+    let creds = req
+        .headers()
+        .get(header::AUTHORIZATION)
+        .and_then(|x| x.to_str().ok())
+        .map(Credentials::from_header);
+    let user = match (req.app_data::<web::Data<Auth>>(), creds) {
+        (Some(auth), _) if auth.is_open() => Ok(None),
+        (Some(_), None) if req.path() == "/ws" => Ok(None),
+        (Some(auth), Some(Some(creds))) => auth.authenticate(&creds).map(Some),
+        (Some(_), _) => Err(Error::Unauthenticated),
+        (None, _) => Ok(None),
+    };
+    let res = match user {
+        Ok(user) => {
+            if let Some(user) = user {
+                req.extensions_mut().insert(user);
+            }
+            Ok(srv.call(req))
+        }
+        Err(err) => {
+            let detail = format!("{} {}: {}", req.method(), req.path(), err);
+            audit(
+                req.app_data(),
+                req.peer_addr(),
+                Rejection::Unauthenticated,
+                &detail,
+            );
+            let res = HttpResponse::Unauthorized()
+                .header(header::WWW_AUTHENTICATE, "Bearer, Basic")
+                .finish();
+            Err(req.into_response(res))
+        }
+    };
+    async move {
+        match res {
+            Ok(fut) => fut.await,
+            Err(res) => Ok(res),
+        }
+    }
+}
+
+/// reads the static tokens from the `AUTH_TOKENS` env var and the user passwords from
+/// `AUTH_USERS`, both comma separated `name:secret` pairs
+fn auth_from_env() -> Auth {
+    let tokens = env::var("AUTH_TOKENS").unwrap_or_default();
+    let passwords = env::var("AUTH_USERS").unwrap_or_default();
+    Auth::new(&tokens, &passwords)
+        .expect("AUTH_TOKENS and AUTH_USERS must be comma separated name:secret pairs")
+}
+
+/// reads the ip filter from the `ALLOW_IPS` and `DENY_IPS` env vars, comma separated
+/// addresses or CIDR blocks, and the `MAX_REQUESTS_PER_IP` env var
+fn ip_filter_from_env() -> IpFilter {
+    let blocks = |var: &str| {
+        env::var(var)
+            .map(|s| parse_blocks(&s).expect("ip lists must be addresses or CIDR blocks"))
+            .unwrap_or_default()
+    };
+    let max_per_ip = env::var("MAX_REQUESTS_PER_IP")
+        .ok()
+        .map(|s| s.parse().expect("MAX_REQUESTS_PER_IP must be a number"));
+    IpFilter::new(blocks("ALLOW_IPS"), blocks("DENY_IPS"), max_per_ip)
+}
+
+/// the routes of memson
+fn routes(cfg: &mut web::ServiceConfig) {
+    cfg.app_data(web::JsonConfig::default().error_handler(json_error))
+        .service(web::resource("/cmd").route(web::post().to(eval2)))
+        .service(web::resource("/query").route(web::post().to(query2)))
+        .service(web::resource("/audit").route(web::get().to(audit_report)))
+        .service(web::resource("/dump").route(web::get().to(dump)))
+        .service(web::resource("/ws").route(web::get().to(ws::ws)))
+        .service(
+            web::resource("/restore")
+                .app_data(web::PayloadConfig::new(MAX_RESTORE_BYTES))
+                .route(web::post().to(restore)),
+        )
+        .service(web::resource("/").route(web::get().to(summary)));
+}
+
 #[actix_rt::main]
 async fn main() -> std::io::Result<()> {
     std::env::set_var("RUST_LOG", "actix_web=info");
@@ -97,24 +690,137 @@ async fn main() -> std::io::Result<()> {
 
     let addr = host + ":" + &port;
     println!("memson is starting on {}", addr);
-    let db = match Memson::open(db_path) {
+    let default_limit = DefaultLimit::from_env();
+    let preload_keys: Vec<String> = env::var("PRELOAD_KEYS")
+        .map(|keys| keys.split(',').map(|key| key.trim().to_string()).collect())
+        .unwrap_or_default();
+    let db = if preload_keys.is_empty() {
+        Memson::open(db_path)
+    } else {
+        Memson::open_with_priority(db_path, &preload_keys)
+    };
+    let mut db = match db {
         Ok(db) => db,
         Err(_) => panic!("cannot open memson"),
     };
+    if let Ok(path) = env::var("WAL_PATH") {
+        let sync = env::var("WAL_SYNC").is_ok_and(|x| x == "true");
+        db.open_wal(path, sync)
+            .expect("cannot replay the write-ahead log");
+    }
+    db.set_default_limit(default_limit.0);
+    if let Ok(max_bytes) = env::var("MAX_MEMORY") {
+        let max_bytes = max_bytes
+            .parse()
+            .expect("MAX_MEMORY must be a number of bytes");
+        let policy = env::var("EVICTION").unwrap_or_else(|_| "lru".to_string());
+        let eviction = eviction_policy(&policy).expect("EVICTION must be lru, lfu or random");
+        db.set_max_memory(max_bytes, eviction);
+    }
+    if let Ok(bytes) = env::var("SOFT_MEMORY_LIMIT") {
+        let bytes = bytes
+            .parse()
+            .expect("SOFT_MEMORY_LIMIT must be a number of bytes");
+        db.set_soft_limit(bytes);
+    }
+    if let Ok(n) = env::var("QUERY_THREADS") {
+        let n = n.parse().expect("QUERY_THREADS must be a number");
+        db.set_query_threads(n)
+            .expect("cannot start the query threads");
+    }
+    if let Ok(path) = env::var("BACKEND_PATH") {
+        let backend = OnDiskDb::open(path).expect("cannot open the backend");
+        db.set_backend(Box::new(SingleFlight::new(backend)));
+    }
+    if let Ok(dir) = env::var("BACKUP_DIR") {
+        db.set_backup_dir(dir);
+    }
+    if let Ok(names) = env::var("DISABLED_CMDS") {
+        let caps = Capabilities::disabling(&names)
+            .expect("DISABLED_CMDS must be a comma separated list of cmd families");
+        db.set_capabilities(caps);
+    }
+    if let Ok(grants) = env::var("ACLS") {
+        let acls = Acls::parse(&grants).expect(
+            "ACLS must be comma separated user:read or user:write grants, with an optional :prefix",
+        );
+        db.set_acls(acls);
+    }
+
+    let tracer = env::var("TRACE_PATH").ok().map(|path| {
+        let salt = env::var("TRACE_SALT").unwrap_or_default();
+        Tracer::open(path, salt).expect("cannot open the trace")
+    });
+
+    let ip_filter = ip_filter_from_env();
+    let audit = web::Data::new(Audit::default());
+    let auth = web::Data::new(auth_from_env());
 
-    let actor = DbActor { db };
-    let actor_addr = actor.start();
+    #[cfg(feature = "tls")]
+    let tls_config = tls::config_from_env()?;
+    #[cfg(not(feature = "tls"))]
+    if env::var("TLS_CERT").is_ok() || env::var("TLS_KEY").is_ok() {
+        panic!("TLS_CERT and TLS_KEY need memson built with the tls feature");
+    }
+
+    let db = Db::start(db, tracer);
     //let memson = Arc::new(RwLock::new(db));
-    HttpServer::new(move || {
+    let server = HttpServer::new(move || {
         App::new()
+            .wrap_fn(headers)
+            .wrap_fn(authenticate)
+            .wrap_fn(filter_ips)
             //enable logger
-            .wrap(middleware::Logger::default())
-            .data(actor_addr.clone())
-            .service(web::resource("/cmd").route(web::post().to(eval2)))
-            .service(web::resource("/query").route(web::post().to(query2)))
-            .service(web::resource("/").route(web::get().to(summary)))
-    })
-    .bind(addr.clone())?
-    .run()
-    .await
+            .wrap(middleware::Logger::new(
+                r#"%a "%r" %s %b "%{Referer}i" "%{User-Agent}i" %T trace=%{x-trace-id}i"#,
+            ))
+            .data(db.clone())
+            .data(default_limit)
+            .data(ip_filter.clone())
+            .app_data(audit.clone())
+            .app_data(auth.clone())
+            .configure(routes)
+    });
+    #[cfg(feature = "tls")]
+    let server = match tls_config {
+        Some(config) => server.bind_rustls(addr, config)?,
+        None => server.bind(addr)?,
+    };
+    #[cfg(not(feature = "tls"))]
+    let server = server.bind(addr)?;
+    server.run().await
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::tempdir::TempDir;
+    use serde_json::json;
+
+    #[actix_rt::test]
+    async fn index_built_for_dropped_request() {
+        let path = TempDir::new("index-req");
+        let mut memson = Memson::open(&path).unwrap();
+        let rows = json!({"set": ["orders", [{"qty": 1}, {"qty": 2}]]});
+        memson.eval(Cmd::parse(rows).unwrap()).unwrap();
+        let db = Db::start(memson, None);
+        let create = json!({"createIndex": {"key": "orders", "field": "qty"}});
+        let create = Request::Command(Cmd::parse(create).unwrap(), Cancel::default(), None);
+        // the client goes away once the build is under way
+        let mut send = Box::pin(db.send(create));
+        assert!(futures::poll!(&mut send).is_pending());
+        drop(send);
+        let explain = json!({"explain": {"from": "orders", "where": {"==": [{"key": "qty"}, 2]}}});
+        let explain = Cmd::parse(explain).unwrap();
+        let mut indexed = false;
+        for _ in 0..100 {
+            let req = Request::Command(explain.clone(), Cancel::default(), None);
+            if db.send(req).await.unwrap().unwrap()["index"] == json!(true) {
+                indexed = true;
+                break;
+            }
+            actix_rt::time::delay_for(Duration::from_millis(10)).await;
+        }
+        assert!(indexed);
+    }
 }
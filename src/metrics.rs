@@ -0,0 +1,138 @@
+use crate::json_ops::Json;
+use serde_json::json;
+use std::collections::VecDeque;
+use std::time::Duration;
+
+/// the minutes of metrics kept
+pub const HISTORY_MINUTES: usize = 60;
+/// the buckets of the latencies of a minute, where bucket i holds the cmds that took up to
+/// 2^i microseconds
+const LATENCY_BUCKETS: usize = 32;
+
+/// The cmds evaluated within a minute
+#[derive(Clone, Debug, PartialEq)]
+struct Minute {
+    /// minutes since the unix epoch
+    at: u64,
+    ops: u64,
+    errors: u64,
+    latencies: [u64; LATENCY_BUCKETS],
+    /// the approximate bytes of the entries once the minute ended; none until it ends
+    bytes: Option<usize>,
+}
+
+impl Minute {
+    fn new(at: u64) -> Self {
+        Self {
+            at,
+            ops: 0,
+            errors: 0,
+            latencies: [0; LATENCY_BUCKETS],
+            bytes: None,
+        }
+    }
+
+    /// the latency 99% of the cmds took at most, rounded up to a power of two of
+    /// microseconds, in milliseconds
+    fn p99_ms(&self) -> f64 {
+        let rank = (self.ops * 99).div_ceil(100);
+        let mut seen = 0;
+        for (i, n) in self.latencies.iter().enumerate() {
+            seen += n;
+            if seen >= rank {
+                return (1u64 << i) as f64 / 1000.0;
+            }
+        }
+        0.0
+    }
+
+    /// the metrics of the minute, where ops/s are over the seconds of it passed
+    fn describe(&self, secs: u64, bytes: usize) -> Json {
+        json!({
+            "minute": self.at * 60,
+            "ops": self.ops,
+            "opsPerSec": self.ops as f64 / secs.max(1) as f64,
+            "errors": self.errors,
+            "p99Ms": self.p99_ms(),
+            "bytes": self.bytes.unwrap_or(bytes),
+        })
+    }
+}
+
+/// A ring of the metrics of the cmds of each of the last minutes, so a recent incident can
+/// be diagnosed without a metrics stack. Minutes without cmds are left out
+#[derive(Debug, Default)]
+pub struct Metrics {
+    minutes: VecDeque<Minute>,
+}
+
+impl Metrics {
+    /// records a cmd finished at the given seconds since the unix epoch; the bytes of the
+    /// entries are only measured as a minute ends
+    pub fn record<F>(&mut self, now: u64, elapsed: Duration, ok: bool, bytes: F)
+    where
+        F: FnOnce() -> usize,
+    {
+        let at = now / 60;
+        if self.minutes.back().is_none_or(|x| x.at < at) {
+            if let Some(last) = self.minutes.back_mut() {
+                last.bytes = Some(bytes());
+            }
+            if self.minutes.len() == HISTORY_MINUTES {
+                self.minutes.pop_front();
+            }
+            self.minutes.push_back(Minute::new(at));
+        }
+        let minute = self.minutes.back_mut().unwrap();
+        minute.ops += 1;
+        if !ok {
+            minute.errors += 1;
+        }
+        let micros = elapsed.as_micros().max(1);
+        let bucket = (128 - (micros - 1).leading_zeros()) as usize;
+        minute.latencies[bucket.min(LATENCY_BUCKETS - 1)] += 1;
+    }
+
+    /// the metrics of the minutes within the last n minutes, oldest first, given the
+    /// seconds since the unix epoch and the current bytes of the entries
+    pub fn history(&self, minutes: usize, now: u64, bytes: usize) -> Json {
+        let at = now / 60;
+        let from = at.saturating_sub(minutes.saturating_sub(1) as u64);
+        self.minutes
+            .iter()
+            .filter(|x| x.at >= from)
+            .map(|x| {
+                let secs = if x.at == at { now % 60 + 1 } else { 60 };
+                x.describe(secs, bytes)
+            })
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn minutes_aggregated_and_rotated() {
+        let mut metrics = Metrics::default();
+        let ms = Duration::from_millis;
+        for i in 0..100 {
+            let took = if i == 0 { ms(500) } else { ms(1) };
+            metrics.record(60, took, i != 1, || unreachable!());
+        }
+        metrics.record(179, ms(3), true, || 1000);
+        let exp = json!([
+            {"minute": 60, "ops": 100, "opsPerSec": 100.0 / 60.0, "errors": 1, "p99Ms": 1.024, "bytes": 1000},
+            {"minute": 120, "ops": 1, "opsPerSec": 1.0 / 60.0, "errors": 0, "p99Ms": 4.096, "bytes": 7},
+        ]);
+        assert_eq!(exp, metrics.history(2, 179, 7));
+        assert_eq!(1, metrics.history(1, 179, 7).as_array().unwrap().len());
+        for i in 0..HISTORY_MINUTES as u64 {
+            metrics.record(240 + i * 60, ms(1), true, || 0);
+        }
+        let history = metrics.history(HISTORY_MINUTES * 2, 240 + 59 * 60, 0);
+        assert_eq!(HISTORY_MINUTES, history.as_array().unwrap().len());
+        assert_eq!(json!(240), history[0]["minute"]);
+    }
+}
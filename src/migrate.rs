@@ -0,0 +1,111 @@
+use crate::err::Error;
+use crate::ondisk::ivec_to_json;
+use std::convert::TryFrom;
+
+/// the tree holding the metadata of an on disk db, apart from its entries
+const META_TREE: &str = "meta";
+/// the key of the data format version in the meta tree
+const VERSION_KEY: &str = "format_version";
+
+/// A migration upgrading an on disk db from one data format version to the next
+type Migration = fn(&sled::Db) -> Result<(), Error>;
+
+/// the migrations in order, where the migration at position i upgrades version i to i + 1
+const MIGRATIONS: &[Migration] = &[check_entries];
+
+/// the data format version written by this build of memson
+pub const FORMAT_VERSION: u32 = MIGRATIONS.len() as u32;
+
+/// the data format version of an on disk db; 0 if it was written before versions were
+/// stamped
+pub fn format_version(db: &sled::Db) -> Result<u32, Error> {
+    let meta = db.open_tree(META_TREE).map_err(|_| Error::BadIO)?;
+    match meta.get(VERSION_KEY).map_err(|_| Error::BadIO)? {
+        Some(bytes) => {
+            let bytes = <[u8; 4]>::try_from(bytes.as_ref()).map_err(|_| Error::BadIO)?;
+            Ok(u32::from_be_bytes(bytes))
+        }
+        None => Ok(0),
+    }
+}
+
+fn stamp(db: &sled::Db, version: u32) -> Result<(), Error> {
+    let meta = db.open_tree(META_TREE).map_err(|_| Error::BadIO)?;
+    meta.insert(VERSION_KEY, &version.to_be_bytes())
+        .map_err(|_| Error::BadIO)?;
+    Ok(())
+}
+
+/// upgrades an on disk db to the current data format version, stamping the version after
+/// each migration so an interrupted upgrade resumes where it stopped. New dbs are stamped
+/// as is, and dbs written by a newer memson are refused
+pub fn migrate(db: &sled::Db) -> Result<u32, Error> {
+    let version = format_version(db)?;
+    if version > FORMAT_VERSION {
+        return Err(Error::BadFormat(version));
+    }
+    if version == 0 && db.is_empty() {
+        stamp(db, FORMAT_VERSION)?;
+    } else {
+        for (from, migration) in MIGRATIONS.iter().enumerate().skip(version as usize) {
+            migration(db)?;
+            stamp(db, from as u32 + 1)?;
+        }
+    }
+    db.flush().map_err(|_| Error::BadIO)?;
+    Ok(FORMAT_VERSION)
+}
+
+/// version 1 has the layout of unstamped dbs, utf8 keys and json values, so upgrading
+/// only checks every entry can be loaded
+fn check_entries(db: &sled::Db) -> Result<(), Error> {
+    for kv in db.iter() {
+        let (key, val) = kv.map_err(|_| Error::BadIO)?;
+        std::str::from_utf8(key.as_ref()).map_err(|_| Error::BadIO)?;
+        ivec_to_json(&val)?;
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn temp_db() -> sled::Db {
+        sled::Config::new().temporary(true).open().unwrap()
+    }
+
+    #[test]
+    fn new_db_stamped_with_current_version() {
+        let db = temp_db();
+        assert_eq!(Ok(0), format_version(&db));
+        assert_eq!(Ok(FORMAT_VERSION), migrate(&db));
+        assert_eq!(Ok(FORMAT_VERSION), format_version(&db));
+        assert!(db.is_empty());
+    }
+
+    #[test]
+    fn unstamped_db_upgraded() {
+        let db = temp_db();
+        db.insert("a", "[1,2]").unwrap();
+        assert_eq!(Ok(FORMAT_VERSION), migrate(&db));
+        assert_eq!(Ok(FORMAT_VERSION), format_version(&db));
+        assert_eq!(Some("[1,2]".into()), db.get("a").unwrap());
+        assert_eq!(Ok(FORMAT_VERSION), migrate(&db));
+    }
+
+    #[test]
+    fn unloadable_db_not_upgraded() {
+        let db = temp_db();
+        db.insert("a", "{not json").unwrap();
+        assert_eq!(Err(Error::Serialize), migrate(&db));
+        assert_eq!(Ok(0), format_version(&db));
+    }
+
+    #[test]
+    fn newer_version_refused() {
+        let db = temp_db();
+        stamp(&db, FORMAT_VERSION + 1).unwrap();
+        assert_eq!(Err(Error::BadFormat(FORMAT_VERSION + 1)), migrate(&db));
+    }
+}
@@ -1,5 +1,6 @@
 use crate::err::Error;
-use crate::json::Json;
+use crate::json_ops::Json;
+use crate::migrate::migrate;
 use sled::Iter;
 use std::path::Path;
 
@@ -8,8 +9,10 @@ pub struct OnDiskDb {
 }
 
 impl OnDiskDb {
+    /// opens an on disk db, upgrading its data format to the current version
     pub fn open<P: AsRef<Path>>(path: P) -> Result<OnDiskDb, Error> {
         let db = sled::open(path).map_err(|_| Error::BadIO)?;
+        migrate(&db)?;
         Ok(OnDiskDb { sled: db })
     }
 
@@ -33,6 +36,29 @@ impl OnDiskDb {
         }
     }
 
+    pub fn delete(&self, key: &str) -> Result<Option<Json>, Error> {
+        let val = self.sled.remove(key).map_err(|_| Error::BadIO)?;
+        match val {
+            Some(v) => ivec_to_json_opt(&v),
+            None => Ok(None),
+        }
+    }
+
+    /// sets or deletes several entries at once, so either all change or none do
+    pub fn set_many(&self, entries: &[(&str, Option<&Json>)]) -> Result<(), Error> {
+        let mut batch = sled::Batch::default();
+        for (key, val) in entries {
+            match val {
+                Some(val) => {
+                    let bytes = serde_json::to_vec(val).map_err(|_| Error::Serialize)?;
+                    batch.insert(key.as_bytes(), bytes);
+                }
+                None => batch.remove(key.as_bytes()),
+            }
+        }
+        self.sled.apply_batch(batch).map_err(|_| Error::BadIO)
+    }
+
     pub fn iter(&self) -> Iter {
         self.sled.iter()
     }
@@ -0,0 +1,363 @@
+use crate::cmd::{Cmd, Knn, Range, Source};
+use crate::db::{Query, ID_KEY};
+use crate::err::Error;
+use crate::json_ops::Json;
+use serde_json::json;
+use std::borrow::Cow;
+use std::collections::{BTreeSet, HashMap};
+
+/// A stage of a query plan, transforming the rows of the stage before it. Stages borrow
+/// the statements of the query they were planned from
+#[derive(Clone, Debug, PartialEq)]
+pub enum Stage<'q> {
+    /// reads the rows of a key, several keys or a subquery
+    Scan(&'q Source),
+    /// a row for each element of the array field of each row
+    Unnest(&'q str),
+    /// keeps the rows passing the where statement; looked up through an index of the
+    /// scanned key if `index` is set. The selectivity is the estimated fraction of the rows
+    /// passing, if known
+    Filter {
+        filter: Box<Cow<'q, Cmd>>,
+        index: bool,
+        selectivity: Option<f64>,
+    },
+    /// keeps the k rows nearest a vector, nearest first
+    Knn(&'q Knn),
+    Sort {
+        key: &'q str,
+        descend: bool,
+    },
+    /// keeps the rows ordered after a row, to page through the rows of a sort
+    After {
+        key: &'q str,
+        descend: bool,
+        row: &'q Json,
+    },
+    Limit(usize),
+    /// keeps the rows at the positions of a range, for a query with an offset
+    Slice(Range),
+    /// copies only the given fields of the rows
+    Project(BTreeSet<&'q str>),
+}
+
+impl Stage<'_> {
+    /// describes the stage for explain
+    pub fn describe(&self) -> Json {
+        match self {
+            Stage::Scan(Source::Key(key)) => json!({"stage": "scan", "from": key}),
+            Stage::Scan(Source::Keys(keys)) => json!({"stage": "scan", "from": keys}),
+            Stage::Scan(Source::Query(_)) => json!({"stage": "scan", "from": "query"}),
+            Stage::Unnest(field) => json!({"stage": "unnest", "field": field}),
+            Stage::Filter {
+                index, selectivity, ..
+            } => {
+                let mut stage = json!({"stage": "filter", "index": index});
+                if let Some(x) = selectivity {
+                    stage["selectivity"] = Json::from(*x);
+                }
+                stage
+            }
+            Stage::Knn(knn) => {
+                json!({"stage": "knn", "key": knn.key, "k": knn.k, "metric": knn.metric})
+            }
+            Stage::Sort { key, descend } => {
+                json!({"stage": "sort", "key": key, "descend": descend})
+            }
+            Stage::After { key, .. } => json!({"stage": "after", "key": key}),
+            Stage::Limit(n) => json!({"stage": "limit", "n": n}),
+            Stage::Slice(range) => {
+                json!({"stage": "slice", "offset": range.start, "n": range.size})
+            }
+            Stage::Project(fields) => json!({"stage": "project", "fields": fields}),
+        }
+    }
+}
+
+/// How the rows left by the stages of a plan are turned into the query result
+#[derive(Clone, Debug, PartialEq)]
+pub enum Output<'q> {
+    /// the selects evaluated over the rows of each group
+    Group {
+        by: &'q Cmd,
+        selects: Option<&'q HashMap<String, Cmd>>,
+    },
+    /// the selects evaluated over the rows; either all aggregates or none
+    Select(&'q HashMap<String, Cmd>),
+    /// the rows themselves, capped at the default limit if any
+    SelectAll { limit: Option<usize> },
+}
+
+impl Output<'_> {
+    fn describe(&self) -> Json {
+        let names = |selects: &HashMap<String, Cmd>| {
+            let names: BTreeSet<&String> = selects.keys().collect();
+            Json::from(names.into_iter().cloned().collect::<Vec<_>>())
+        };
+        match self {
+            Output::Group { selects, .. } => {
+                json!({"stage": "group", "selects": selects.map(names)})
+            }
+            Output::Select(selects) => json!({"stage": "select", "selects": names(selects)}),
+            Output::SelectAll { limit } => json!({"stage": "selectAll", "limit": limit}),
+        }
+    }
+}
+
+/// The stages evaluating a query, in order, and the output of their rows
+#[derive(Clone, Debug, PartialEq)]
+pub struct Plan<'q> {
+    pub stages: Vec<Stage<'q>>,
+    pub output: Output<'q>,
+}
+
+impl Plan<'_> {
+    /// checks if any filter is answered by an index rather than a scan
+    pub fn uses_index(&self) -> bool {
+        self.stages
+            .iter()
+            .any(|stage| matches!(stage, Stage::Filter { index: true, .. }))
+    }
+
+    /// describes the stages of the plan for explain
+    pub fn describe(&self) -> Json {
+        let stages = self.stages.iter().map(Stage::describe);
+        stages.chain(Some(self.output.describe())).collect()
+    }
+}
+
+/// An optimization rewriting a plan
+pub trait Pass: Send + Sync {
+    fn apply<'q>(&self, qry: &'q Query<'_>, plan: &mut Plan<'q>);
+}
+
+/// Looks up the rows passing a filter of the scanned key through an index of the key,
+/// estimating the rows passing from the histogram of the index
+#[derive(Debug, Default)]
+pub struct IndexSelection;
+
+impl Pass for IndexSelection {
+    fn apply<'q>(&self, qry: &'q Query<'_>, plan: &mut Plan<'q>) {
+        // only the positions of the scanned rows themselves are indexed
+        if let [Stage::Scan(Source::Key(_)), Stage::Filter {
+            filter,
+            index,
+            selectivity,
+        }, ..] = plan.stages.as_mut_slice()
+        {
+            *selectivity = qry.where_selectivity(filter);
+            *index = selectivity.is_some();
+        }
+    }
+}
+
+/// Copies only the fields read by the selects and by statement of the rows left once
+/// filtered, sorted and paged, rather than whole rows. The rows of several keys are copied
+/// anyway to be tagged with their key, so are always projected
+#[derive(Debug, Default)]
+pub struct ProjectionPushdown;
+
+impl Pass for ProjectionPushdown {
+    fn apply<'q>(&self, qry: &'q Query<'_>, plan: &mut Plan<'q>) {
+        let copies = plan.stages.iter().any(|stage| {
+            matches!(
+                stage,
+                Stage::Scan(Source::Keys(_))
+                    | Stage::Filter { .. }
+                    | Stage::Knn(_)
+                    | Stage::Sort { .. }
+                    | Stage::After { .. }
+            )
+        });
+        if let Some(fields) = qry.projected_fields().filter(|_| copies) {
+            plan.stages.push(Stage::Project(fields));
+        }
+    }
+}
+
+/// Plans queries, then applies its passes to the plans in order
+pub struct Planner {
+    passes: Vec<Box<dyn Pass>>,
+}
+
+impl Default for Planner {
+    fn default() -> Self {
+        Planner::new(vec![Box::new(IndexSelection), Box::new(ProjectionPushdown)])
+    }
+}
+
+impl Planner {
+    pub fn new(passes: Vec<Box<dyn Pass>>) -> Self {
+        Planner { passes }
+    }
+
+    /// the plan of a query: scan, unnest, filter, knn, sort, after and limit, then the output
+    pub fn plan<'q>(&self, qry: &'q Query<'_>) -> Result<Plan<'q>, Error> {
+        let cmd = &qry.cmd;
+        let mut stages = vec![Stage::Scan(&cmd.from)];
+        if let Some(field) = &cmd.unnest {
+            stages.push(Stage::Unnest(field));
+        }
+        let filter = match (&qry.filter, &cmd.filter) {
+            (Some(filter), _) => Some(Cow::Borrowed(filter)),
+            (None, Some(filter)) => Some(Cow::Owned(Cmd::parse(filter.clone())?)),
+            (None, None) => None,
+        };
+        if let Some(filter) = filter {
+            stages.push(Stage::Filter {
+                filter: Box::new(filter),
+                index: false,
+                selectivity: None,
+            });
+        }
+        if let Some(knn) = &cmd.knn {
+            if knn.vector.is_empty() {
+                return Err(Error::BadArg(Json::from(knn.vector.clone())));
+            }
+            stages.push(Stage::Knn(knn));
+        }
+        let descend = qry.descend();
+        // pages of rows without a sort or knn are ordered by id, so that paging after the
+        // last row served doesn't skip the rows inserted before it
+        let pages = cmd.after.is_some() || cmd.limit.is_some();
+        let sort = match &cmd.sort {
+            Some(key) => Some(key.as_str()),
+            None if pages && cmd.knn.is_none() => Some(ID_KEY),
+            None => None,
+        };
+        if let Some(key) = sort {
+            stages.push(Stage::Sort { key, descend });
+        }
+        if let Some(row) = &cmd.after {
+            let key = sort.unwrap_or(ID_KEY);
+            stages.push(Stage::After { key, descend, row });
+        }
+        match (cmd.offset, cmd.limit) {
+            (Some(start), size) => stages.push(Stage::Slice(Range {
+                start: Some(start),
+                size,
+                ..Range::default()
+            })),
+            (None, Some(n)) => stages.push(Stage::Limit(n)),
+            (None, None) => (),
+        }
+        let selects = cmd.selects.as_ref();
+        let output = match (&cmd.by, selects) {
+            (Some(by), selects) => Output::Group { by, selects },
+            (None, Some(selects)) if !selects.is_empty() => Output::Select(selects),
+            (None, _) => Output::SelectAll {
+                limit: qry.default_limit.filter(|_| cmd.limit.is_none()),
+            },
+        };
+        let mut plan = Plan { stages, output };
+        for pass in &self.passes {
+            pass.apply(qry, &mut plan);
+        }
+        Ok(plan)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::cmd::QueryCmd;
+    use crate::inmem::InMemDb;
+
+    fn query(db: &InMemDb, qry: Json) -> Query<'_> {
+        let cmd: QueryCmd = serde_json::from_value(qry).unwrap();
+        Query::from(db, cmd)
+    }
+
+    fn orders() -> InMemDb {
+        let mut db = InMemDb::new();
+        let rows = json!([
+            {"customer": "james", "qty": 2, "price": 9.0},
+            {"customer": "ania", "qty": 4, "price": 2.0},
+        ]);
+        db.set("orders", rows);
+        db
+    }
+
+    #[test]
+    fn stages_planned_in_order() {
+        let mut db = orders();
+        let qry = query(
+            &db,
+            json!({
+                "select": {"n": {"sum": {"key": "qty"}}},
+                "from": "orders",
+                "where": {">": [{"key": "qty"}, 1]},
+                "sort": "price",
+                "limit": 1,
+            }),
+        );
+        let exp = json!([
+            {"stage": "scan", "from": "orders"},
+            {"stage": "filter", "index": false},
+            {"stage": "sort", "key": "price", "descend": false},
+            {"stage": "limit", "n": 1},
+            {"stage": "project", "fields": ["qty"]},
+            {"stage": "select", "selects": ["n"]},
+        ]);
+        assert_eq!(exp, Planner::default().plan(&qry).unwrap().describe());
+        let plan = Planner::new(Vec::new()).plan(&qry).unwrap();
+        assert_eq!(4, plan.stages.len());
+        db.create_index("orders", "qty").unwrap();
+        let qry = query(
+            &db,
+            json!({"from": "orders", "where": {">": [{"key": "qty"}, 1]}}),
+        );
+        let plan = Planner::default().plan(&qry).unwrap();
+        assert!(plan.uses_index());
+        let exp = json!({"stage": "filter", "index": true, "selectivity": 1.0});
+        assert_eq!(exp, plan.stages[1].describe());
+        assert_eq!(Output::SelectAll { limit: Some(50) }, plan.output);
+    }
+
+    #[test]
+    fn knn_planned_after_filter() {
+        let db = orders();
+        let qry = query(
+            &db,
+            json!({
+                "from": "orders",
+                "where": {">": [{"key": "qty"}, 1]},
+                "knn": {"key": "v", "vector": [1, 0], "k": 2, "metric": "cosine"},
+                "limit": 1,
+            }),
+        );
+        let plan = Planner::default().plan(&qry).unwrap();
+        let exp = json!({"stage": "knn", "key": "v", "k": 2, "metric": "cosine"});
+        assert_eq!(exp, plan.stages[2].describe());
+        assert_eq!(Stage::Limit(1), plan.stages[3]);
+        let qry = query(
+            &db,
+            json!({"from": "orders", "knn": {"key": "v", "vector": [], "k": 2}}),
+        );
+        assert!(Planner::default().plan(&qry).is_err());
+    }
+
+    #[test]
+    fn pages_sorted_by_id() {
+        let db = orders();
+        let qry = query(
+            &db,
+            json!({"from": "orders", "after": {"_id": 1}, "limit": 1}),
+        );
+        let exp = json!([
+            {"stage": "scan", "from": "orders"},
+            {"stage": "sort", "key": "_id", "descend": false},
+            {"stage": "after", "key": "_id"},
+            {"stage": "limit", "n": 1},
+            {"stage": "selectAll", "limit": null},
+        ]);
+        assert_eq!(exp, Planner::default().plan(&qry).unwrap().describe());
+    }
+
+    #[test]
+    fn bad_filter_fails_planning() {
+        let db = orders();
+        let qry = query(&db, json!({"from": "orders", "where": {">": [1]}}));
+        assert!(Planner::default().plan(&qry).is_err());
+    }
+}
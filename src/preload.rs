@@ -0,0 +1,70 @@
+use crate::err::Error;
+use crate::json_ops::Json;
+use crate::ondisk::ivec_to_json;
+use std::collections::HashSet;
+use std::sync::mpsc::{channel, Receiver, TryRecvError};
+use std::thread;
+
+type Entry = Result<(String, Json), Error>;
+
+/// Loads the entries of an on disk db in a background thread, so memson can answer for
+/// the entries loaded up front while the rest are loading
+pub(crate) struct Preload {
+    entries: Receiver<Entry>,
+    /// keys written since the load began, whose loaded values are stale
+    skip: HashSet<String>,
+}
+
+impl Preload {
+    /// spawns the thread loading every entry other than the keys already loaded
+    pub(crate) fn spawn(sled: sled::Db, loaded: HashSet<String>) -> Self {
+        let (tx, entries) = channel();
+        thread::spawn(move || {
+            for kv in sled.iter() {
+                let entry = kv.map_err(|_| Error::BadIO).and_then(|(key, val)| {
+                    let key = String::from_utf8(key.to_vec()).map_err(|_| Error::BadIO)?;
+                    Ok((key, ivec_to_json(&val)?))
+                });
+                if matches!(&entry, Ok((key, _)) if loaded.contains(key)) {
+                    continue;
+                }
+                if tx.send(entry).is_err() {
+                    break;
+                }
+            }
+        });
+        Self {
+            entries,
+            skip: HashSet::new(),
+        }
+    }
+
+    /// records that a key was written, so its loaded value is dropped
+    pub(crate) fn skip(&mut self, key: &str) {
+        self.skip.insert(key.to_string());
+    }
+
+    /// the entries loaded since last drained, waiting for every entry if asked to; also
+    /// whether the load is finished
+    pub(crate) fn drain(&mut self, wait: bool) -> Result<(Vec<(String, Json)>, bool), Error> {
+        let mut entries = Vec::new();
+        let done = loop {
+            let entry = if wait {
+                match self.entries.recv() {
+                    Ok(entry) => entry,
+                    Err(_) => break true,
+                }
+            } else {
+                match self.entries.try_recv() {
+                    Ok(entry) => entry,
+                    Err(err) => break err == TryRecvError::Disconnected,
+                }
+            };
+            let (key, val) = entry?;
+            if !self.skip.contains(&key) {
+                entries.push((key, val));
+            }
+        };
+        Ok((entries, done))
+    }
+}
@@ -0,0 +1,276 @@
+use crate::cmd::{Cmd, QueryCmd};
+use crate::err::Error;
+use crate::eval::eval_filter;
+use crate::json_ops::Json;
+use serde::Serialize;
+use serde_json::json;
+use std::collections::{BTreeSet, HashMap, VecDeque};
+
+/// the default number of unacknowledged events kept for each subscriber
+pub const REPLAY_CAPACITY: usize = 1024;
+
+/// A change to an entry; the value is null once the entry is deleted
+#[derive(Clone, Debug, PartialEq, Serialize)]
+pub struct Event {
+    pub seq: u64,
+    pub key: String,
+    pub val: Json,
+}
+
+/// The events of the subscribed keys not yet acknowledged by a subscriber
+#[derive(Debug, Default)]
+struct Subscriber {
+    /// the keys subscribed to; every key if empty
+    keys: BTreeSet<String>,
+    /// the condition, as in a query where, of the rows or values sent to the subscriber
+    filter: Option<Cmd>,
+    /// the query whose results are sent to the subscriber, rather than changed values
+    query: Option<QueryCmd>,
+    /// the last result of the query sent to the subscriber
+    last: Json,
+    buffer: VecDeque<Event>,
+    /// the number of events dropped from the full buffer since the last ack
+    missed: u64,
+}
+
+impl Subscriber {
+    fn wants(&self, key: &str) -> bool {
+        self.keys.is_empty() || self.keys.contains(key)
+    }
+
+    /// buffers an event, dropping the oldest event once the buffer is full
+    fn push(&mut self, event: Event, capacity: usize) {
+        if self.buffer.len() >= capacity {
+            self.buffer.pop_front();
+            self.missed += 1;
+        }
+        self.buffer.push_back(event);
+    }
+
+    /// what the subscriber is sent of a changed value: the rows passing the filter of
+    /// those changed, or the value if it passes; none if nothing passes
+    fn select(&self, val: &Json, appended_from: Option<usize>) -> Option<Json> {
+        let filter = match &self.filter {
+            Some(filter) => filter,
+            None => return Some(val.clone()),
+        };
+        let passes = |val: &Json| eval_filter(filter.clone(), val) == Some(true);
+        match val {
+            Json::Array(rows) => {
+                let rows: Vec<Json> = rows
+                    .iter()
+                    .skip(appended_from.unwrap_or(0))
+                    .filter(|row| row.is_object() && passes(row))
+                    .cloned()
+                    .collect();
+                if rows.is_empty() {
+                    None
+                } else {
+                    Some(Json::Array(rows))
+                }
+            }
+            val => Some(val.clone()).filter(|val| passes(val)),
+        }
+    }
+}
+
+/// Subscriptions to the changes of entries, where each subscriber polls for the events it
+/// hasn't acknowledged, so it can resume from its last ack after disconnecting
+#[derive(Debug)]
+pub struct PubSub {
+    next_id: u64,
+    seq: u64,
+    capacity: usize,
+    subscribers: HashMap<u64, Subscriber>,
+}
+
+impl Default for PubSub {
+    fn default() -> Self {
+        PubSub::new(REPLAY_CAPACITY)
+    }
+}
+
+impl PubSub {
+    /// keeps up to capacity unacknowledged events for each subscriber
+    pub fn new(capacity: usize) -> Self {
+        PubSub {
+            next_id: 1,
+            seq: 0,
+            capacity,
+            subscribers: HashMap::new(),
+        }
+    }
+
+    /// checks if anyone is subscribed to the key
+    pub fn is_subscribed(&self, key: &str) -> bool {
+        self.subscribers.values().any(|x| x.wants(key))
+    }
+
+    /// subscribes to the changes of the keys, or of every key if none, optionally only
+    /// to the rows or values passing a filter; returns the id of the subscriber
+    pub fn subscribe(&mut self, keys: Vec<String>, filter: Option<Cmd>) -> u64 {
+        let id = self.next_id;
+        self.next_id += 1;
+        let subscriber = Subscriber {
+            keys: keys.into_iter().collect(),
+            filter,
+            ..Subscriber::default()
+        };
+        self.subscribers.insert(id, subscriber);
+        id
+    }
+
+    pub fn unsubscribe(&mut self, id: u64) -> Result<(), Error> {
+        self.subscribers
+            .remove(&id)
+            .map(|_| ())
+            .ok_or(Error::BadSubscriber(id))
+    }
+
+    /// subscribes to the results of a query, given the keys it reads and its current
+    /// result; returns the id of the subscriber
+    pub fn subscribe_query(&mut self, qry: QueryCmd, keys: BTreeSet<String>, val: Json) -> u64 {
+        let id = self.subscribe(Vec::new(), None);
+        let subscriber = self.subscribers.get_mut(&id).unwrap();
+        subscriber.keys = keys;
+        subscriber.query = Some(qry);
+        subscriber.last = val;
+        id
+    }
+
+    /// the query subscribers reading any of the written keys, with the first such key and
+    /// their query
+    pub fn queries(&self, writes: &BTreeSet<String>) -> Vec<(u64, String, QueryCmd)> {
+        self.subscribers
+            .iter()
+            .filter_map(|(id, x)| {
+                let key = x.keys.intersection(writes).next()?;
+                Some((*id, key.clone(), x.query.clone()?))
+            })
+            .collect()
+    }
+
+    /// buffers a change for the subscribers of the key, dropping their oldest event once
+    /// their buffer is full. Rows appended from the given position are the only rows
+    /// changed
+    pub fn publish(&mut self, key: &str, val: &Json, appended_from: Option<usize>) {
+        self.seq += 1;
+        let capacity = self.capacity;
+        let subscribers = self.subscribers.values_mut();
+        for subscriber in subscribers.filter(|x| x.query.is_none() && x.wants(key)) {
+            let val = match subscriber.select(val, appended_from) {
+                Some(val) => val,
+                None => continue,
+            };
+            let event = Event {
+                seq: self.seq,
+                key: key.to_string(),
+                val,
+            };
+            subscriber.push(event, capacity);
+        }
+    }
+
+    /// buffers the new result of a subscriber's query after the key changed, unless the
+    /// result is unchanged
+    pub fn publish_result(&mut self, id: u64, key: &str, val: Json) {
+        let subscriber = match self.subscribers.get_mut(&id) {
+            Some(subscriber) if subscriber.last != val => subscriber,
+            _ => return,
+        };
+        self.seq += 1;
+        subscriber.last = val.clone();
+        let event = Event {
+            seq: self.seq,
+            key: key.to_string(),
+            val,
+        };
+        subscriber.push(event, self.capacity);
+    }
+
+    /// the unacknowledged events of a subscriber and the number it missed since its last ack
+    pub fn events(&self, id: u64) -> Result<Json, Error> {
+        let subscriber = self.subscribers.get(&id).ok_or(Error::BadSubscriber(id))?;
+        let events: Vec<&Event> = subscriber.buffer.iter().collect();
+        Ok(json!({"events": events, "missed": subscriber.missed}))
+    }
+
+    /// acknowledges the events of a subscriber up to and including the sequence number
+    pub fn ack(&mut self, id: u64, seq: u64) -> Result<(), Error> {
+        let subscriber = self
+            .subscribers
+            .get_mut(&id)
+            .ok_or(Error::BadSubscriber(id))?;
+        while subscriber.buffer.front().is_some_and(|x| x.seq <= seq) {
+            subscriber.buffer.pop_front();
+        }
+        subscriber.missed = 0;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn replay_until_acked() {
+        let mut pubsub = PubSub::new(2);
+        let all = pubsub.subscribe(Vec::new(), None);
+        let a = pubsub.subscribe(vec!["a".to_string()], None);
+        pubsub.publish("a", &json!(1), None);
+        pubsub.publish("b", &json!(2), None);
+        let exp = json!({"events": [{"seq": 1, "key": "a", "val": 1}], "missed": 0});
+        assert_eq!(Ok(exp.clone()), pubsub.events(a));
+        assert_eq!(Ok(exp), pubsub.events(a));
+        pubsub.ack(all, 1).unwrap();
+        let exp = json!({"events": [{"seq": 2, "key": "b", "val": 2}], "missed": 0});
+        assert_eq!(Ok(exp), pubsub.events(all));
+        pubsub.publish("a", &Json::Null, None);
+        pubsub.publish("a", &json!(3), None);
+        let exp = json!({
+            "events": [{"seq": 3, "key": "a", "val": null}, {"seq": 4, "key": "a", "val": 3}],
+            "missed": 1,
+        });
+        assert_eq!(Ok(exp), pubsub.events(a));
+        pubsub.ack(a, 4).unwrap();
+        assert_eq!(Ok(json!({"events": [], "missed": 0})), pubsub.events(a));
+        pubsub.unsubscribe(a).unwrap();
+        assert_eq!(Err(Error::BadSubscriber(a)), pubsub.events(a));
+        assert!(pubsub.is_subscribed("c"));
+    }
+
+    #[test]
+    fn filtered_events() {
+        let mut pubsub = PubSub::new(10);
+        let filter = Cmd::parse(json!({">": [{"key": "price"}, 1000]})).unwrap();
+        let id = pubsub.subscribe(vec!["orders".to_string()], Some(filter));
+        let orders = json!([{"price": 2000}, {"price": 10}, {"price": 1500}, 5000]);
+        pubsub.publish("orders", &orders, None);
+        pubsub.publish("orders", &orders, Some(1));
+        pubsub.publish("orders", &orders, Some(3));
+        pubsub.publish("orders", &json!({"price": 10}), None);
+        pubsub.publish("orders", &json!({"price": 1001}), None);
+        let exp = json!({"events": [
+            {"seq": 1, "key": "orders", "val": [{"price": 2000}, {"price": 1500}]},
+            {"seq": 2, "key": "orders", "val": [{"price": 1500}]},
+            {"seq": 5, "key": "orders", "val": {"price": 1001}},
+        ], "missed": 0});
+        assert_eq!(Ok(exp), pubsub.events(id));
+    }
+
+    #[test]
+    fn query_results_sent_when_changed() {
+        let mut pubsub = PubSub::new(10);
+        let qry: QueryCmd = serde_json::from_value(json!({"from": "t"})).unwrap();
+        let keys = vec!["t".to_string()].into_iter().collect();
+        let id = pubsub.subscribe_query(qry.clone(), keys, json!(1));
+        pubsub.publish("t", &json!([2]), None);
+        let writes = vec!["s".to_string(), "t".to_string()].into_iter().collect();
+        assert_eq!(vec![(id, "t".to_string(), qry)], pubsub.queries(&writes));
+        pubsub.publish_result(id, "t", json!(1));
+        pubsub.publish_result(id, "t", json!(2));
+        let exp = json!({"events": [{"seq": 2, "key": "t", "val": 2}], "missed": 0});
+        assert_eq!(Ok(exp), pubsub.events(id));
+    }
+}
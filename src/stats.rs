@@ -0,0 +1,170 @@
+use crate::json_ops::Json;
+use serde_json::json;
+use std::collections::{BTreeSet, HashMap};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+/// The number of reads and writes of a key and when it was last accessed
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct KeyStats {
+    pub reads: u64,
+    pub writes: u64,
+    /// milliseconds since the unix epoch
+    pub last_access: u64,
+}
+
+/// the most keys whose access statistics are kept
+pub const MAX_TRACKED_KEYS: usize = 100_000;
+
+/// The stats of a tracked key
+#[derive(Debug, Default)]
+struct Tracked {
+    stats: KeyStats,
+    /// the accesses of the key whose place it took, the most accesses it may have missed
+    error: u64,
+}
+
+impl Tracked {
+    fn weight(&self) -> u64 {
+        self.stats.reads + self.stats.writes + self.error
+    }
+}
+
+/// Access statistics of the keys of memson, kept for a fixed number of keys whatever the
+/// number of keys accessed. As in the SpaceSaving sketch, a key not tracked takes the
+/// place of the least accessed one, inheriting its accesses as error
+#[derive(Debug)]
+pub struct AccessStats {
+    capacity: usize,
+    keys: HashMap<String, Tracked>,
+    /// the tracked keys ordered by their accesses with error, so the least accessed is
+    /// found without a scan
+    by_weight: BTreeSet<(u64, String)>,
+}
+
+impl Default for AccessStats {
+    fn default() -> Self {
+        AccessStats::new(MAX_TRACKED_KEYS)
+    }
+}
+
+fn now_millis() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_millis() as u64)
+        .unwrap_or(0)
+}
+
+impl AccessStats {
+    /// keeps the stats of at most the given number of keys
+    pub fn new(capacity: usize) -> Self {
+        AccessStats {
+            capacity: capacity.max(1),
+            keys: HashMap::new(),
+            by_weight: BTreeSet::new(),
+        }
+    }
+
+    /// records the keys read and written by a cmd
+    pub fn record(&mut self, reads: &BTreeSet<String>, writes: &BTreeSet<String>) {
+        if reads.is_empty() && writes.is_empty() {
+            return;
+        }
+        let now = now_millis();
+        for key in reads {
+            self.touch(key, now).reads += 1;
+        }
+        for key in writes {
+            self.touch(key, now).writes += 1;
+        }
+        for key in reads.union(writes) {
+            self.by_weight
+                .insert((self.keys[key].weight(), key.clone()));
+        }
+    }
+
+    /// the stats of a key accessed now, tracking it if it isn't; the key is left out of
+    /// the weights until its accesses are counted
+    fn touch(&mut self, key: &str, now: u64) -> &mut KeyStats {
+        if let Some(tracked) = self.keys.get(key) {
+            self.by_weight.remove(&(tracked.weight(), key.to_string()));
+        } else {
+            let mut error = 0;
+            if self.keys.len() >= self.capacity {
+                if let Some((weight, victim)) = self.by_weight.pop_first() {
+                    self.keys.remove(&victim);
+                    error = weight;
+                }
+            }
+            let tracked = Tracked {
+                error,
+                ..Tracked::default()
+            };
+            self.keys.insert(key.to_string(), tracked);
+        }
+        let stats = &mut self.keys.get_mut(key).unwrap().stats;
+        stats.last_access = now;
+        stats
+    }
+
+    /// drops the stats of a key deleted or expired
+    pub fn remove(&mut self, key: &str) {
+        if let Some(tracked) = self.keys.remove(key) {
+            self.by_weight.remove(&(tracked.weight(), key.to_string()));
+        }
+    }
+
+    /// the stats of a key
+    pub fn get(&self, key: &str) -> Option<&KeyStats> {
+        self.keys.get(key).map(|x| &x.stats)
+    }
+
+    /// the n most read and n most written keys
+    pub fn hot_keys(&self, n: usize) -> Json {
+        json!({
+            "reads": self.top(n, |x| x.reads),
+            "writes": self.top(n, |x| x.writes),
+        })
+    }
+
+    fn top<F: Fn(&KeyStats) -> u64>(&self, n: usize, count: F) -> Json {
+        let mut keys: Vec<(&String, &KeyStats)> = self
+            .keys
+            .iter()
+            .map(|(key, x)| (key, &x.stats))
+            .filter(|(_, x)| count(x) > 0)
+            .collect();
+        keys.sort_by(|(xk, x), (yk, y)| count(y).cmp(&count(x)).then_with(|| xk.cmp(yk)));
+        keys.into_iter()
+            .take(n)
+            .map(|(key, x)| json!({"key": key, "count": count(x), "lastAccess": x.last_access}))
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn keys(keys: &[&str]) -> BTreeSet<String> {
+        keys.iter().map(|x| x.to_string()).collect()
+    }
+
+    #[test]
+    fn least_accessed_keys_replaced() {
+        let mut stats = AccessStats::new(2);
+        stats.record(&keys(&["a", "b"]), &keys(&["a"]));
+        stats.record(&keys(&["a"]), &BTreeSet::new());
+        stats.record(&keys(&["c"]), &BTreeSet::new());
+        assert_eq!(None, stats.get("b"));
+        assert_eq!(2, stats.get("a").unwrap().reads);
+        assert_eq!(1, stats.get("c").unwrap().reads);
+        // c inherited the access of b, but is still outweighed by a
+        stats.record(&keys(&["d"]), &BTreeSet::new());
+        assert_eq!(None, stats.get("c"));
+        assert!(stats.get("a").is_some());
+        stats.remove("a");
+        assert_eq!(None, stats.get("a"));
+        stats.record(&keys(&["e"]), &BTreeSet::new());
+        assert!(stats.get("d").is_some() && stats.get("e").is_some());
+    }
+}
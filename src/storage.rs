@@ -0,0 +1,116 @@
+use crate::json_ops::Json;
+use std::collections::BTreeMap;
+use std::fmt;
+use std::ops::Bound;
+use std::sync::Arc;
+
+/// The entries of a storage engine within a range of keys, in key order
+pub type Entries<'a> = Box<dyn DoubleEndedIterator<Item = (&'a str, &'a Arc<Json>)> + 'a>;
+
+/// The engine holding the entries of the in memory db, so alternative engines can be
+/// developed without changing the evaluator. Entries are iterated in key order, which
+/// the paginated and ranged cmds rely on.
+pub trait Storage: Send + Sync + fmt::Debug {
+    /// the value of a key; none if missing
+    fn get(&self, key: &str) -> Option<&Arc<Json>>;
+
+    /// the mutable value of a key; none if missing
+    fn get_mut(&mut self, key: &str) -> Option<&mut Arc<Json>>;
+
+    /// sets the value of a key, returning the previous value
+    fn set(&mut self, key: String, val: Arc<Json>) -> Option<Arc<Json>>;
+
+    /// removes a key, returning its value
+    fn remove(&mut self, key: &str) -> Option<Arc<Json>>;
+
+    /// the entries whose keys are within the bounds
+    fn range<'a>(&'a self, from: Bound<&str>, to: Bound<&str>) -> Entries<'a>;
+
+    /// the number of entries
+    fn len(&self) -> usize;
+
+    /// every entry
+    fn iter(&self) -> Entries<'_> {
+        self.range(Bound::Unbounded, Bound::Unbounded)
+    }
+
+    fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    fn contains_key(&self, key: &str) -> bool {
+        self.get(key).is_some()
+    }
+}
+
+/// The default engine, an ordered map of the entries
+pub type Cache = BTreeMap<String, Arc<Json>>;
+
+impl Storage for Cache {
+    fn get(&self, key: &str) -> Option<&Arc<Json>> {
+        BTreeMap::get(self, key)
+    }
+
+    fn get_mut(&mut self, key: &str) -> Option<&mut Arc<Json>> {
+        BTreeMap::get_mut(self, key)
+    }
+
+    fn set(&mut self, key: String, val: Arc<Json>) -> Option<Arc<Json>> {
+        self.insert(key, val)
+    }
+
+    fn remove(&mut self, key: &str) -> Option<Arc<Json>> {
+        BTreeMap::remove(self, key)
+    }
+
+    fn range<'a>(&'a self, from: Bound<&str>, to: Bound<&str>) -> Entries<'a> {
+        let entries = BTreeMap::range::<str, _>(self, (from, to));
+        Box::new(entries.map(|(key, val)| (key.as_str(), val)))
+    }
+
+    fn len(&self) -> usize {
+        BTreeMap::len(self)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::cmd::Cmd;
+    use crate::inmem::InMemDb;
+    use serde_json::json;
+
+    #[test]
+    fn cache_ranges_in_key_order() {
+        let mut cache = Cache::new();
+        for key in ["c", "a", "b", "d"] {
+            cache.set(key.to_string(), Arc::new(json!(key)));
+        }
+        let storage: &dyn Storage = &cache;
+        let keys: Vec<&str> = storage
+            .range(Bound::Included("b"), Bound::Excluded("d"))
+            .map(|(key, _)| key)
+            .collect();
+        assert_eq!(vec!["b", "c"], keys);
+        let last = storage.iter().next_back().map(|(key, _)| key);
+        assert_eq!(Some("d"), last);
+        assert!(storage.contains_key("a") && !storage.contains_key("e"));
+        assert_eq!(4, storage.len());
+    }
+
+    #[test]
+    fn db_evaluates_over_storage() {
+        let mut cache = Cache::new();
+        cache.set("a".to_string(), Arc::new(json!([{"x": 1}, {"x": 2}])));
+        let mut db = InMemDb::with_storage(Box::new(cache));
+        let qry =
+            Cmd::parse(json!({"query": {"from": "a", "select": {"x": {"sum": {"key": "x"}}}}}));
+        assert_eq!(Ok(json!({"x": 3})), db.eval(qry.unwrap()));
+        db.eval(Cmd::parse(json!({"set": ["b", 1]})).unwrap())
+            .unwrap();
+        assert_eq!(
+            json!(["a", "b"]),
+            db.eval(Cmd::parse(json!({"keys": null})).unwrap()).unwrap()
+        );
+    }
+}
@@ -0,0 +1,69 @@
+//! Serving memson over tls, so it can be reached outside a trusted network
+//!
+//! Built with the `tls` feature, memson serves https and wss once the `TLS_CERT` and
+//! `TLS_KEY` env vars give the paths of a PEM certificate chain and its private key, in
+//! PKCS#8 or RSA form.
+
+use rustls::internal::pemfile::{certs, pkcs8_private_keys, rsa_private_keys};
+use rustls::{NoClientAuth, ServerConfig};
+use std::env;
+use std::fs::File;
+use std::io::{self, BufReader};
+use std::path::Path;
+
+/// the tls config given by the `TLS_CERT` and `TLS_KEY` env vars, if any
+pub fn config_from_env() -> io::Result<Option<ServerConfig>> {
+    match (env::var("TLS_CERT"), env::var("TLS_KEY")) {
+        (Ok(cert), Ok(key)) => server_config(cert, key).map(Some),
+        (Err(_), Err(_)) => Ok(None),
+        _ => Err(invalid("TLS_CERT and TLS_KEY must be set together")),
+    }
+}
+
+/// the tls config serving the certificate chain in a PEM file with the private key in
+/// another
+pub fn server_config<P, Q>(cert: P, key: Q) -> io::Result<ServerConfig>
+where
+    P: AsRef<Path>,
+    Q: AsRef<Path>,
+{
+    let chain =
+        certs(&mut BufReader::new(File::open(cert)?)).map_err(|_| invalid("bad certificate"))?;
+    if chain.is_empty() {
+        return Err(invalid("no certificate"));
+    }
+    let pem = std::fs::read(key)?;
+    let mut keys = pkcs8_private_keys(&mut &pem[..]).map_err(|_| invalid("bad key"))?;
+    if keys.is_empty() {
+        keys = rsa_private_keys(&mut &pem[..]).map_err(|_| invalid("bad key"))?;
+    }
+    let key = keys.pop().ok_or_else(|| invalid("no private key"))?;
+    let mut config = ServerConfig::new(NoClientAuth::new());
+    config
+        .set_single_cert(chain, key)
+        .map_err(|err| invalid(&err.to_string()))?;
+    Ok(config)
+}
+
+fn invalid(msg: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, msg.to_string())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn config_read_from_pem_files() {
+        let (cert, key) = ("testdata/tls/cert.pem", "testdata/tls/key.pem");
+        assert!(server_config(cert, key).is_ok());
+        let err = server_config(key, key).err().unwrap();
+        assert_eq!("no certificate", err.to_string());
+        let err = server_config(cert, cert).err().unwrap();
+        assert_eq!("no private key", err.to_string());
+        let err = server_config("testdata/tls/missing.pem", key)
+            .err()
+            .unwrap();
+        assert_eq!(io::ErrorKind::NotFound, err.kind());
+    }
+}
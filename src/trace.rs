@@ -0,0 +1,187 @@
+//! Anonymized traces of the cmds a server evaluates, and the cmds replaying them against
+//! another server, so upgrades can be checked against production-like load
+
+use crate::err::Error;
+use crate::json_ops::Json;
+use serde::{Deserialize, Serialize};
+use serde_json::json;
+use std::collections::hash_map::DefaultHasher;
+use std::fs::{self, File, OpenOptions};
+use std::hash::{Hash, Hasher};
+use std::io::{LineWriter, Write};
+use std::path::Path;
+use std::time::{Duration, Instant};
+
+/// the cmds whose replay writes its first key
+const WRITES: &[&str] = &[
+    "set", "getSet", "append", "push", "insert", "merge", "generate",
+];
+/// the cmds whose replay deletes its first key
+const DELETES: &[&str] = &["del", "delMany", "delPattern", "pop"];
+
+/// A cmd evaluated by a server, without its keys or values
+#[derive(Clone, Debug, Serialize, Deserialize, PartialEq)]
+pub struct TraceEvent {
+    /// the milliseconds since tracing started
+    #[serde(rename = "atMs")]
+    pub at_ms: u64,
+    /// the name of the cmd, e.g. `set` or `query`
+    pub cmd: String,
+    /// the hashes of the keys read or written
+    pub keys: Vec<String>,
+    /// the bytes of the cmd as json
+    pub size: usize,
+    /// the microseconds the cmd took to evaluate
+    pub micros: u64,
+}
+
+impl TraceEvent {
+    /// the cmd re-issuing the load of the event: a write of a value of the same size to
+    /// the first key, a delete, or a read of the keys
+    pub fn replay_cmd(&self) -> Json {
+        let key = self.keys.first().map(|x| format!("trace:{}", x));
+        match key {
+            Some(key) if WRITES.contains(&self.cmd.as_str()) => {
+                json!({"set": [key, "x".repeat(self.size)]})
+            }
+            Some(key) if DELETES.contains(&self.cmd.as_str()) => json!({ "del": key }),
+            _ => {
+                let keys: Vec<String> = self.keys.iter().map(|x| format!("trace:{}", x)).collect();
+                json!({ "mget": keys })
+            }
+        }
+    }
+
+    /// how long after a replay starts the event is re-issued, at a speed relative to the
+    /// trace; speeds of zero or less re-issue every event at once
+    pub fn replay_offset(&self, speed: f64) -> Duration {
+        if speed > 0.0 {
+            Duration::from_secs_f64(self.at_ms as f64 / 1000.0 / speed)
+        } else {
+            Duration::ZERO
+        }
+    }
+}
+
+/// the hash of a key salted so it can't be recovered by hashing likely keys, as 16 hex
+/// digits. The same key and salt always hash the same, so access patterns are kept
+pub fn hash_key(salt: &str, key: &str) -> String {
+    let mut hasher = DefaultHasher::new();
+    salt.hash(&mut hasher);
+    key.hash(&mut hasher);
+    format!("{:016x}", hasher.finish())
+}
+
+/// Appends the events of the cmds evaluated to a file, one json line each
+#[derive(Debug)]
+pub struct Tracer {
+    out: LineWriter<File>,
+    salt: String,
+    start: Instant,
+}
+
+impl Tracer {
+    /// appends to the trace at a path, creating it if missing
+    pub fn open<P: AsRef<Path>>(path: P, salt: String) -> Result<Self, Error> {
+        let file = OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(path)
+            .map_err(|_| Error::BadIO)?;
+        Ok(Tracer {
+            out: LineWriter::new(file),
+            salt,
+            start: Instant::now(),
+        })
+    }
+
+    /// records a cmd evaluated, hashing its keys
+    pub fn record<'a, I>(
+        &mut self,
+        cmd: &str,
+        keys: I,
+        size: usize,
+        took: Duration,
+    ) -> Result<(), Error>
+    where
+        I: IntoIterator<Item = &'a str>,
+    {
+        let event = TraceEvent {
+            at_ms: self.start.elapsed().as_millis() as u64,
+            cmd: cmd.to_string(),
+            keys: keys.into_iter().map(|x| hash_key(&self.salt, x)).collect(),
+            size,
+            micros: took.as_micros() as u64,
+        };
+        let mut line = serde_json::to_vec(&event).map_err(|_| Error::Serialize)?;
+        line.push(b'\n');
+        self.out.write_all(&line).map_err(|_| Error::BadIO)
+    }
+}
+
+/// the events of a trace, in the order they were recorded
+pub fn read_trace<P: AsRef<Path>>(path: P) -> Result<Vec<TraceEvent>, Error> {
+    let text = fs::read_to_string(path).map_err(|_| Error::BadIO)?;
+    text.lines()
+        .filter(|line| !line.trim().is_empty())
+        .map(|line| serde_json::from_str(line).map_err(|_| Error::Serialize))
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::tempdir::TempDir;
+
+    #[test]
+    fn keys_hashed_with_salt() {
+        assert_eq!(hash_key("s", "users"), hash_key("s", "users"));
+        assert_ne!(hash_key("s", "users"), hash_key("t", "users"));
+        assert_ne!(hash_key("s", "users"), hash_key("s", "orders"));
+        assert_eq!(16, hash_key("", "").len());
+    }
+
+    #[test]
+    fn recorded_events_read_back() {
+        let dir = TempDir::new("trace");
+        let path = dir.join("trace");
+        let mut tracer = Tracer::open(&path, "salt".to_string()).unwrap();
+        let took = Duration::from_micros(250);
+        tracer.record("set", vec!["users"], 40, took).unwrap();
+        tracer
+            .record("query", vec!["users", "orders"], 90, took)
+            .unwrap();
+        tracer.record("summary", vec![], 9, took).unwrap();
+        drop(tracer);
+        let events = read_trace(&path).unwrap();
+        assert_eq!(3, events.len());
+        assert_eq!(vec![hash_key("salt", "users")], events[0].keys);
+        assert_eq!(250, events[1].micros);
+        let text = fs::read_to_string(&path).unwrap();
+        assert!(!text.contains("users"));
+    }
+
+    #[test]
+    fn events_replayed_as_cmds_of_same_shape() {
+        let event = |cmd: &str, keys: Vec<&str>, at_ms| TraceEvent {
+            at_ms,
+            cmd: cmd.to_string(),
+            keys: keys.into_iter().map(String::from).collect(),
+            size: 3,
+            micros: 1,
+        };
+        let set = event("append", vec!["ab"], 2000);
+        assert_eq!(json!({"set": ["trace:ab", "xxx"]}), set.replay_cmd());
+        assert_eq!(Duration::from_secs(1), set.replay_offset(2.0));
+        assert_eq!(Duration::ZERO, set.replay_offset(0.0));
+        let del = event("del", vec!["ab"], 0);
+        assert_eq!(json!({"del": "trace:ab"}), del.replay_cmd());
+        let read = event("query", vec!["ab", "cd"], 0);
+        let exp = json!({"mget": ["trace:ab", "trace:cd"]});
+        assert_eq!(exp, read.replay_cmd());
+        assert_eq!(
+            json!({"mget": []}),
+            event("summary", vec![], 0).replay_cmd()
+        );
+    }
+}
@@ -0,0 +1,132 @@
+use crate::cmd::Knn;
+use crate::json_ops::Json;
+use rayon::prelude::*;
+use serde::{Deserialize, Serialize};
+use std::borrow::Borrow;
+
+/// How far apart two vectors are
+#[derive(Clone, Copy, Debug, Serialize, Deserialize, PartialEq, Default)]
+#[serde(rename_all = "lowercase")]
+pub enum Metric {
+    /// the euclidean distance
+    #[default]
+    L2,
+    /// one minus the cosine of the angle between the vectors
+    Cosine,
+    /// the negated dot product, so the largest products are nearest
+    Dot,
+}
+
+impl Metric {
+    /// the distance between two vectors of the same length
+    pub fn distance(&self, x: &[f64], y: &[f64]) -> f64 {
+        match self {
+            Metric::L2 => dot_with(x, y, |x, y| (x - y) * (x - y)).sqrt(),
+            Metric::Cosine => {
+                let norms = (dot(x, x) * dot(y, y)).sqrt();
+                if norms == 0.0 {
+                    1.0
+                } else {
+                    1.0 - dot(x, y) / norms
+                }
+            }
+            Metric::Dot => -dot(x, y),
+        }
+    }
+}
+
+fn dot(x: &[f64], y: &[f64]) -> f64 {
+    dot_with(x, y, |x, y| x * y)
+}
+
+/// sums a function of the pairs of elements, over lanes the compiler can vectorize
+fn dot_with(x: &[f64], y: &[f64], f: impl Fn(f64, f64) -> f64) -> f64 {
+    const LANES: usize = 8;
+    let mut sums = [0.0; LANES];
+    let (xs, ys) = (x.chunks_exact(LANES), y.chunks_exact(LANES));
+    let tail: f64 = xs
+        .remainder()
+        .iter()
+        .zip(ys.remainder())
+        .map(|(x, y)| f(*x, *y))
+        .sum();
+    for (x, y) in xs.zip(ys) {
+        for ((sum, x), y) in sums.iter_mut().zip(x).zip(y) {
+            *sum += f(*x, *y);
+        }
+    }
+    sums.iter().sum::<f64>() + tail
+}
+
+/// the vector of a row field; none unless it's an array of numbers
+pub fn row_vector(row: &Json, key: &str) -> Option<Vec<f64>> {
+    match row.get(key)? {
+        Json::Array(vals) => vals.iter().map(Json::as_f64).collect(),
+        _ => None,
+    }
+}
+
+/// the k rows whose vectors are nearest the vector of a knn statement, nearest first.
+/// Rows without a vector of the same length are skipped
+pub fn nearest<R>(rows: Vec<R>, knn: &Knn) -> Vec<R>
+where
+    R: Borrow<Json> + Send,
+{
+    let mut scored: Vec<(f64, R)> = rows
+        .into_par_iter()
+        .filter_map(|row| {
+            let vector = row_vector(row.borrow(), &knn.key)?;
+            if vector.len() != knn.vector.len() {
+                return None;
+            }
+            Some((knn.metric.distance(&knn.vector, &vector), row))
+        })
+        .collect();
+    let cmp = |x: &(f64, R), y: &(f64, R)| x.0.total_cmp(&y.0);
+    if knn.k < scored.len() {
+        scored.select_nth_unstable_by(knn.k, cmp);
+        scored.truncate(knn.k);
+    }
+    scored.sort_by(cmp);
+    scored.into_iter().map(|(_, row)| row).collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    #[test]
+    fn distances_of_metrics() {
+        let (x, y) = ([1.0, 0.0], [0.0, 2.0]);
+        assert!((Metric::L2.distance(&x, &y) - 5f64.sqrt()).abs() < 1e-9);
+        assert_eq!(1.0, Metric::Cosine.distance(&x, &y));
+        assert_eq!(0.0, Metric::Cosine.distance(&y, &[0.0, 5.0]));
+        assert_eq!(-10.0, Metric::Dot.distance(&y, &[3.0, 5.0]));
+        let long: Vec<f64> = (0..19).map(f64::from).collect();
+        assert_eq!(dot(&long, &long), long.iter().map(|x| x * x).sum::<f64>());
+    }
+
+    #[test]
+    fn nearest_rows_first() {
+        let rows = [
+            json!({"id": 1, "v": [0.0, 0.0]}),
+            json!({"id": 2, "v": [5, 5]}),
+            json!({"id": 3, "v": [1.0, 1.0, 1.0]}),
+            json!({"id": 4, "v": [1, 2]}),
+            json!({"id": 5}),
+            json!({"id": 6, "v": [1.0, 1.0]}),
+        ];
+        let knn = Knn {
+            key: "v".to_string(),
+            vector: vec![1.0, 1.0],
+            k: 3,
+            metric: Metric::L2,
+        };
+        let ids: Vec<&Json> = nearest(rows.iter().collect(), &knn)
+            .into_iter()
+            .map(|row| &row["id"])
+            .collect();
+        assert_eq!(vec![&json!(6), &json!(4), &json!(1)], ids);
+    }
+}
@@ -0,0 +1,133 @@
+use crate::cmd::Cmd;
+use crate::err::Error;
+use std::fs::{self, File, OpenOptions};
+use std::io::{ErrorKind, Write};
+use std::path::Path;
+
+/// An append-only log of the cmds that changed entries, one json line each, so they can
+/// be replayed in order on startup
+#[derive(Debug)]
+pub struct Wal {
+    file: File,
+    /// syncs each appended cmd to disk, so it survives power loss and not only a crash
+    sync: bool,
+}
+
+impl Wal {
+    /// opens the log at a path, creating it if missing, along with the cmds logged so far.
+    /// A torn last line, left by a crash mid-append, is cut off
+    pub fn open<P: AsRef<Path>>(path: P, sync: bool) -> Result<(Self, Vec<Cmd>), Error> {
+        let bytes = match fs::read(&path) {
+            Ok(bytes) => bytes,
+            Err(err) if err.kind() == ErrorKind::NotFound => Vec::new(),
+            Err(_) => return Err(Error::BadIO),
+        };
+        let mut cmds = Vec::new();
+        let mut valid = 0;
+        for line in bytes.split_inclusive(|x| *x == b'\n') {
+            let cmd = match line.strip_suffix(b"\n") {
+                Some(line) => serde_json::from_slice(line).ok(),
+                None => None,
+            };
+            match cmd {
+                Some(cmd) => cmds.push(cmd),
+                None => break,
+            }
+            valid += line.len();
+        }
+        let file = OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(&path)
+            .map_err(|_| Error::BadIO)?;
+        if valid < bytes.len() {
+            file.set_len(valid as u64).map_err(|_| Error::BadIO)?;
+        }
+        Ok((Wal { file, sync }, cmds))
+    }
+
+    /// appends a cmd to the log
+    pub fn append(&mut self, cmd: &Cmd) -> Result<(), Error> {
+        let mut line = serde_json::to_vec(cmd).map_err(|_| Error::Serialize)?;
+        line.push(b'\n');
+        self.file.write_all(&line).map_err(|_| Error::BadIO)?;
+        if self.sync {
+            self.file.sync_data().map_err(|_| Error::BadIO)?;
+        }
+        Ok(())
+    }
+
+    /// empties the log once the changes of its cmds are saved elsewhere
+    pub fn truncate(&mut self) -> Result<(), Error> {
+        self.file.set_len(0).map_err(|_| Error::BadIO)?;
+        self.file.sync_all().map_err(|_| Error::BadIO)
+    }
+}
+
+/// checks if a cmd, or any of its sub cmds, changes entries, so must be logged
+pub fn mutates(cmd: &Cmd) -> bool {
+    match cmd {
+        Cmd::Set(_, _, _)
+        | Cmd::GetSet(_, _)
+        | Cmd::Delete(_)
+        | Cmd::DeleteMany(_)
+        | Cmd::DeletePattern(_)
+        | Cmd::Append(_, _)
+        | Cmd::Push(_, _)
+        | Cmd::Pop(_)
+        | Cmd::Insert(_, _)
+        | Cmd::Merge(_, _)
+        | Cmd::MoveRows { .. }
+        | Cmd::Generate(_)
+        | Cmd::Expire(_, _)
+        | Cmd::ExpireAt(_, _)
+        | Cmd::Restore(_) => true,
+        cmd => cmd.children().into_iter().any(mutates),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::tempdir::TempDir;
+    use serde_json::json;
+    use std::io::Read;
+
+    #[test]
+    fn logged_cmds_read_back_in_order() {
+        let dir = TempDir::new("wal");
+        let path = dir.join("wal");
+        let cmds: Vec<Cmd> = vec![
+            json!({"set": ["a", {"key": "b"}, "nx"]}),
+            json!({"insert": ["t", [{"x": 1}]]}),
+            json!({"append": ["t", {"x": 2}]}),
+            json!({"delPattern": "t*"}),
+            json!({"tx": [{"pop": "a"}, {"del": "b"}]}),
+            json!({"merge": ["doc", {"x": null}]}),
+            json!({"expire": ["doc", 30]}),
+            json!({"expireAt": ["doc", 1700000000.5]}),
+        ]
+        .into_iter()
+        .map(|x| Cmd::parse(x).unwrap())
+        .collect();
+        assert!(cmds.iter().all(mutates));
+        assert!(!mutates(&Cmd::parse(json!({"sum": {"key": "t"}})).unwrap()));
+        let (mut wal, logged) = Wal::open(&path, false).unwrap();
+        assert!(logged.is_empty());
+        cmds.iter().for_each(|cmd| wal.append(cmd).unwrap());
+        drop(wal);
+        let mut file = OpenOptions::new().append(true).open(&path).unwrap();
+        file.write_all(br#"{"del": "#).unwrap();
+        let (mut wal, logged) = Wal::open(&path, true).unwrap();
+        assert_eq!(cmds, logged);
+        wal.append(&cmds[0]).unwrap();
+        assert_eq!(cmds.len() + 1, Wal::open(&path, false).unwrap().1.len());
+        wal.truncate().unwrap();
+        let mut text = String::new();
+        File::open(&path)
+            .unwrap()
+            .read_to_string(&mut text)
+            .unwrap();
+        assert!(text.is_empty());
+    }
+}
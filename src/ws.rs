@@ -0,0 +1,329 @@
+//! The websocket interface of memson
+//!
+//! Each text or binary message is a cmd, as posted to `/cmd`, answered in order by
+//! `{"reply": val}` or `{"error": msg}`. The events of the subscriptions made over the
+//! socket are pushed to it as `{"push": id, "events": [...], "missed": n}` and acknowledged
+//! once sent; the subscriptions are dropped once the socket closes. Where memson needs
+//! credentials, sockets opened without them must send `{"auth": creds}` before any cmd.
+
+use crate::auth::{Auth, Credentials, User};
+use crate::cmd::Cmd;
+use crate::db::Cancel;
+use crate::err::Error;
+use crate::json_ops::Json;
+use crate::{Db, Request};
+use actix_codec::{Decoder, Encoder};
+use actix_http::ws::{handshake, CloseCode, CloseReason, Codec, Frame, Message};
+use actix_rt::time::delay_for;
+use actix_web::web::{self, Bytes, BytesMut};
+use actix_web::{HttpRequest, HttpResponse};
+use futures::channel::mpsc::{unbounded, UnboundedSender};
+use futures::lock::Mutex;
+use futures::StreamExt;
+use serde_json::json;
+use std::cell::RefCell;
+use std::collections::BTreeSet;
+use std::rc::Rc;
+use std::time::Duration;
+
+/// how often the subscriptions of a socket are checked for events to push
+pub const PUSH_INTERVAL: Duration = Duration::from_millis(50);
+/// the most bytes of a message sent over a socket
+pub const MAX_MESSAGE_BYTES: usize = 1 << 24;
+
+/// upgrades the request to a websocket evaluating the cmds sent over it
+pub(crate) async fn ws(
+    req: HttpRequest,
+    payload: web::Payload,
+    db: web::Data<Db>,
+) -> Result<HttpResponse, actix_web::Error> {
+    let mut res = handshake(req.head())?;
+    let (out, body) = unbounded();
+    let auth = req.app_data::<web::Data<Auth>>().filter(|x| !x.is_open());
+    let session = Session {
+        db: db.get_ref().clone(),
+        out,
+        subscriptions: Rc::default(),
+        pushing: Rc::default(),
+        auth: auth.cloned(),
+        user: Rc::new(RefCell::new(req.extensions().get::<User>().cloned())),
+    };
+    actix_rt::spawn(session.run(payload));
+    Ok(res.streaming(body))
+}
+
+/// A connected socket, shared by the task reading its cmds and the task pushing its events
+#[derive(Clone)]
+struct Session {
+    db: Db,
+    /// the frames sent to the client, closed once the socket is done
+    out: UnboundedSender<Result<Bytes, actix_web::Error>>,
+    /// the ids of the subscriptions made over the socket
+    subscriptions: Rc<RefCell<BTreeSet<u64>>>,
+    /// held while pushing, so the events of a subscription are only pushed once
+    pushing: Rc<Mutex<()>>,
+    /// the credentials accepted, unless memson is open to every client
+    auth: Option<web::Data<Auth>>,
+    /// the user authenticated on the socket, if any
+    user: Rc<RefCell<Option<User>>>,
+}
+
+impl Session {
+    /// evaluates the cmds of the client until it closes the socket, while pushing the
+    /// events of its subscriptions
+    async fn run(self, mut payload: web::Payload) {
+        let pusher = self.clone();
+        actix_rt::spawn(async move {
+            while !pusher.out.is_closed() {
+                delay_for(PUSH_INTERVAL).await;
+                pusher.push().await;
+            }
+        });
+        let mut codec = Codec::new().max_size(MAX_MESSAGE_BYTES);
+        let mut buf = BytesMut::new();
+        let mut close = None;
+        'read: while let Some(Ok(chunk)) = payload.next().await {
+            buf.extend_from_slice(&chunk);
+            loop {
+                let closing = match codec.decode(&mut buf) {
+                    Ok(Some(frame)) => self.handle(frame).await,
+                    Ok(None) => break,
+                    Err(_) => Err(Some(CloseCode::Protocol.into())),
+                };
+                if let Err(reason) = closing {
+                    close = Some(reason);
+                    break 'read;
+                }
+            }
+        }
+        // the events left are pushed before the subscriptions go
+        self.push().await;
+        let ids = std::mem::take(&mut *self.subscriptions.borrow_mut());
+        for id in ids {
+            let _ = self.eval(Cmd::Unsubscribe(id)).await;
+        }
+        if let Some(reason) = close {
+            self.send(Message::Close(reason));
+        }
+        self.out.close_channel();
+    }
+
+    /// answers a frame of the client, or gives the reason the socket is closing
+    async fn handle(&self, frame: Frame) -> Result<(), Option<CloseReason>> {
+        match frame {
+            Frame::Text(msg) | Frame::Binary(msg) => {
+                let res = match serde_json::from_slice(&msg) {
+                    Ok(val) => self.answer(val).await,
+                    Err(_) => Err(Error::Serialize),
+                };
+                let reply = match res {
+                    Ok(val) => json!({ "reply": val }),
+                    Err(err) => json!({"error": err.to_string()}),
+                };
+                self.send(Message::Text(reply.to_string()));
+                Ok(())
+            }
+            Frame::Ping(msg) => {
+                self.send(Message::Pong(msg));
+                Ok(())
+            }
+            Frame::Pong(_) => Ok(()),
+            Frame::Close(reason) => Err(reason),
+            Frame::Continuation(_) => Err(Some(CloseCode::Unsupported.into())),
+        }
+    }
+
+    /// authenticates the client, or evaluates its cmd once it's authenticated
+    async fn answer(&self, val: Json) -> Result<Json, Error> {
+        let is_auth = val.as_object().is_some_and(|x| x.len() == 1);
+        match val.get("auth") {
+            Some(creds) if is_auth => {
+                let creds: Credentials =
+                    serde_json::from_value(creds.clone()).map_err(|_| Error::BadCredentials)?;
+                let auth = match &self.auth {
+                    Some(auth) => auth,
+                    None => return Ok(Json::Null),
+                };
+                let user = auth.authenticate(&creds)?;
+                let name = Json::from(user.0.clone());
+                *self.user.borrow_mut() = Some(user);
+                Ok(name)
+            }
+            _ if self.auth.is_some() && self.user.borrow().is_none() => Err(Error::Unauthenticated),
+            _ => self.eval_tracked(val).await,
+        }
+    }
+
+    /// evaluates a cmd of the client, keeping track of the subscriptions it makes
+    async fn eval_tracked(&self, val: Json) -> Result<Json, Error> {
+        let cmd = Cmd::parse(val)?;
+        let subscribes = matches!(cmd, Cmd::Subscribe(_, _) | Cmd::SubscribeQuery(_));
+        let unsubscribed = match cmd {
+            Cmd::Unsubscribe(id) => Some(id),
+            _ => None,
+        };
+        let res = self.eval(cmd).await?;
+        let mut subscriptions = self.subscriptions.borrow_mut();
+        match (res.as_u64(), unsubscribed) {
+            (Some(id), None) if subscribes => subscriptions.insert(id),
+            (_, Some(id)) => subscriptions.remove(&id),
+            _ => false,
+        };
+        Ok(res)
+    }
+
+    async fn eval(&self, cmd: Cmd) -> Result<Json, Error> {
+        let user = self.user.borrow().clone();
+        match self
+            .db
+            .send(Request::Command(cmd, Cancel::default(), user))
+            .await
+        {
+            Ok(res) => res,
+            Err(_) => Err(Error::BadIO),
+        }
+    }
+
+    /// sends the unacknowledged events of each subscription of the socket, then
+    /// acknowledges them
+    async fn push(&self) {
+        let _pushing = self.pushing.lock().await;
+        let ids: Vec<u64> = self.subscriptions.borrow().iter().copied().collect();
+        for id in ids {
+            let events = match self.eval(Cmd::Events(id)).await {
+                Ok(events) => events,
+                Err(_) => continue,
+            };
+            let last = events["events"]
+                .as_array()
+                .and_then(|x| x.last())
+                .and_then(|x| x["seq"].as_u64());
+            let last = match last {
+                Some(seq) => seq,
+                None => continue,
+            };
+            let push = json!({"push": id, "events": events["events"], "missed": events["missed"]});
+            self.send(Message::Text(push.to_string()));
+            let _ = self.eval(Cmd::Ack(id, last)).await;
+        }
+    }
+
+    /// sends a message to the client, unless it's gone
+    fn send(&self, msg: Message) {
+        let mut buf = BytesMut::new();
+        if Codec::new().encode(msg, &mut buf).is_ok() {
+            let _ = self.out.unbounded_send(Ok(buf.freeze()));
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::db::Memson;
+    use crate::tempdir::TempDir;
+    use crate::{authenticate, routes};
+    use actix_web::dev::ServiceResponse;
+    use actix_web::http::StatusCode;
+    use actix_web::{test, App};
+
+    /// the frames a client sends, masked as clients must
+    fn client_frames(msgs: Vec<Message>) -> Bytes {
+        let mut codec = Codec::new().client_mode();
+        let mut buf = BytesMut::new();
+        for msg in msgs {
+            codec.encode(msg, &mut buf).unwrap();
+        }
+        buf.freeze()
+    }
+
+    /// the upgrade to a websocket sending the given frames
+    fn upgrade(frames: Bytes) -> test::TestRequest {
+        test::TestRequest::get()
+            .uri("/ws")
+            .header("upgrade", "websocket")
+            .header("connection", "upgrade")
+            .header("sec-websocket-version", "13")
+            .header("sec-websocket-key", "dGhlIHNhbXBsZSBub25jZQ==")
+            .set_payload(frames)
+    }
+
+    /// the frames the server sent, as json
+    async fn server_frames(res: ServiceResponse) -> Vec<Json> {
+        assert_eq!(StatusCode::SWITCHING_PROTOCOLS, res.status());
+        let mut body = BytesMut::from(&test::read_body(res).await[..]);
+        let mut codec = Codec::new().client_mode();
+        let mut frames = Vec::new();
+        while let Some(frame) = codec.decode(&mut body).unwrap() {
+            frames.push(match frame {
+                Frame::Text(msg) => serde_json::from_slice(&msg).unwrap(),
+                Frame::Pong(msg) => json!({ "pong": String::from_utf8(msg.to_vec()).unwrap() }),
+                Frame::Close(reason) => json!({ "close": reason.map(|x| format!("{:?}", x.code)) }),
+                frame => panic!("unexpected frame {:?}", frame),
+            });
+        }
+        frames
+    }
+
+    fn text(val: Json) -> Message {
+        Message::Text(val.to_string())
+    }
+
+    #[actix_rt::test]
+    async fn cmds_answered_and_changes_pushed() {
+        let dir = TempDir::new("ws");
+        let db = Db::start(Memson::open(&dir).unwrap(), None);
+        let mut app = test::init_service(App::new().data(db).configure(routes)).await;
+        let frames = client_frames(vec![
+            text(json!({"subscribe": ["k"]})),
+            Message::Ping(Bytes::from_static(b"p")),
+            text(json!({"insert": ["k", [{"a": 1}]]})),
+            text(json!({"key": "missing"})),
+            Message::Close(Some(CloseCode::Normal.into())),
+        ]);
+        let res = test::call_service(&mut app, upgrade(frames).to_request()).await;
+        let exp = vec![
+            json!({"reply": 1}),
+            json!({"pong": "p"}),
+            json!({"reply": 1}),
+            json!({"error": "bad key: missing"}),
+            json!({"push": 1, "events": [{"seq": 1, "key": "k", "val": [{"a": 1}]}], "missed": 0}),
+            json!({"close": "Normal"}),
+        ];
+        assert_eq!(exp, server_frames(res).await);
+    }
+
+    #[actix_rt::test]
+    async fn cmds_refused_until_authenticated() {
+        let dir = TempDir::new("ws-auth");
+        let db = Db::start(Memson::open(&dir).unwrap(), None);
+        let auth = web::Data::new(Auth::new("ci:t0ken", "").unwrap());
+        let app = App::new()
+            .wrap_fn(authenticate)
+            .data(db)
+            .app_data(auth)
+            .configure(routes);
+        let mut app = test::init_service(app).await;
+        let frames = client_frames(vec![
+            text(json!({"has": "k"})),
+            text(json!({"auth": "t0ke"})),
+            text(json!({"auth": "t0ken"})),
+            text(json!({"has": "k"})),
+        ]);
+        let res = test::call_service(&mut app, upgrade(frames).to_request()).await;
+        let exp = vec![
+            json!({"error": "unauthenticated"}),
+            json!({"error": "bad credentials"}),
+            json!({"reply": "ci"}),
+            json!({"reply": false}),
+        ];
+        assert_eq!(exp, server_frames(res).await);
+        let frames = client_frames(vec![text(json!({"has": "k"}))]);
+        let req = upgrade(frames).header("authorization", "Bearer t0ken");
+        let res = test::call_service(&mut app, req.to_request()).await;
+        assert_eq!(vec![json!({"reply": false})], server_frames(res).await);
+        let req = upgrade(Bytes::new()).header("authorization", "Bearer t0ke");
+        let res = test::call_service(&mut app, req.to_request()).await;
+        assert_eq!(StatusCode::UNAUTHORIZED, res.status());
+    }
+}